@@ -0,0 +1,87 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Access;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde_json::Value;
+use std::collections::HashSet;
+
+// A Zipfian distribution over ranks 0..n, drawn from by searching its
+// cumulative weights.
+struct Zipf {
+    cumulative: Vec<f64>,
+}
+
+impl Zipf {
+    fn new(n: usize, s: f64) -> Zipf {
+        let mut total = 0.0;
+        let cumulative = (1..=n)
+            .map(|rank| {
+                total += 1.0 / (rank as f64).powf(s);
+                total
+            })
+            .collect();
+
+        Zipf { cumulative }
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R) -> usize {
+        let total = self.cumulative.last().copied().unwrap_or(0.0);
+        let target = rng.gen_range(0.0, total);
+
+        match self
+            .cumulative
+            .binary_search_by(|w| w.partial_cmp(&target).expect("weights are finite"))
+        {
+            Ok(i) | Err(i) => i.min(self.cumulative.len() - 1),
+        }
+    }
+}
+
+/// The writes an update phase makes, in order: each object once with
+/// `Access::All`, or as many writes as there are objects with the keys drawn
+/// from `access`, so that some keys are rewritten several times and others
+/// not at all.  `objects` must be in key order for a seeded run to draw the
+/// same keys every time.
+pub fn writes<R: Rng>(
+    access: Access,
+    objects: Vec<(String, Value)>,
+    rng: &mut R,
+) -> Vec<(String, Value)> {
+    let n = objects.len();
+    if n == 0 {
+        return objects;
+    }
+
+    // Which object each write rewrites.
+    let picks: Vec<usize> = match access {
+        Access::All => return objects,
+        Access::Uniform => (0..n).map(|_| rng.gen_range(0, n)).collect(),
+        Access::Zipf(s) => {
+            // Rank the objects in a random order, so that the hottest keys
+            // aren't simply the first ones.
+            let mut ranked: Vec<usize> = (0..n).collect();
+            ranked.shuffle(rng);
+            let zipf = Zipf::new(n, s);
+            (0..n).map(|_| ranked[zipf.sample(rng)]).collect()
+        }
+        Access::Hot(percent) => {
+            let hot = ((n as f64 * percent / 100.0).round() as usize).max(1);
+            let set: Vec<usize> = rand::seq::index::sample(rng, n, hot.min(n)).into_vec();
+            (0..n).map(|_| set[rng.gen_range(0, set.len())]).collect()
+        }
+    };
+
+    let distinct: HashSet<usize> = picks.iter().copied().collect();
+    println!(
+        "Rewriting {} keys drawn {}, {} of {} objects written at least once",
+        n,
+        String::from(access),
+        distinct.len(),
+        n
+    );
+
+    picks.into_iter().map(|i| objects[i].clone()).collect()
+}
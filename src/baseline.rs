@@ -0,0 +1,160 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! Compare a run against the JSON report (`--output`) of an earlier one, so
+//! that a drop in throughput or a rise in p99 latency can fail the run.
+
+use crate::stats::PhaseReport;
+use crate::RunSummary;
+use failure::Error;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufReader;
+
+// Only the parts of the report that are compared are read back.
+#[derive(Deserialize)]
+struct SavedReport {
+    shards: Vec<SavedShard>,
+}
+
+#[derive(Deserialize)]
+struct SavedShard {
+    #[serde(default)]
+    sequential: Vec<SavedPhase>,
+    #[serde(default)]
+    batch: Vec<SavedPhase>,
+}
+
+#[derive(Deserialize)]
+struct SavedPhase {
+    objects_per_sec: f64,
+    latency: SavedLatency,
+}
+
+#[derive(Deserialize)]
+struct SavedLatency {
+    p99_us: u64,
+}
+
+/// Mean throughput and p99 latency over every run of one phase, on every
+/// shard and bucket.
+#[derive(Clone, Copy, Debug)]
+pub struct PhaseSummary {
+    pub objects_per_sec: f64,
+    pub p99_us: f64,
+}
+
+impl PhaseSummary {
+    fn new(runs: &[(f64, u64)]) -> Option<PhaseSummary> {
+        if runs.is_empty() {
+            return None;
+        }
+
+        let n = runs.len() as f64;
+        Some(PhaseSummary {
+            objects_per_sec: runs.iter().map(|r| r.0).sum::<f64>() / n,
+            p99_us: runs.iter().map(|r| r.1 as f64).sum::<f64>() / n,
+        })
+    }
+}
+
+/// The numbers a run is judged by.
+#[derive(Clone, Copy, Debug)]
+pub struct Snapshot {
+    pub sequential: Option<PhaseSummary>,
+    pub batch: Option<PhaseSummary>,
+}
+
+impl Snapshot {
+    /// Read a report written by `--output`.
+    pub fn load(path: &str) -> Result<Snapshot, Error> {
+        let report: SavedReport = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        let runs = |phase: fn(&SavedShard) -> &Vec<SavedPhase>| -> Vec<(f64, u64)> {
+            report
+                .shards
+                .iter()
+                .flat_map(|s| phase(s).iter())
+                .map(|p| (p.objects_per_sec, p.latency.p99_us))
+                .collect()
+        };
+
+        Ok(Snapshot {
+            sequential: PhaseSummary::new(&runs(|s| &s.sequential)),
+            batch: PhaseSummary::new(&runs(|s| &s.batch)),
+        })
+    }
+
+    /// Summarize the shards and buckets of the current run that succeeded.
+    pub fn from_results(results: &[(u32, String, Result<RunSummary, Error>)]) -> Snapshot {
+        let runs = |phase: fn(&RunSummary) -> &Vec<PhaseReport>| -> Vec<(f64, u64)> {
+            results
+                .iter()
+                .filter_map(|(_, _, r)| r.as_ref().ok())
+                .flat_map(|s| phase(s).iter())
+                .map(|p| (p.objects_per_sec, p.latency.p99_us))
+                .collect()
+        };
+
+        Snapshot {
+            sequential: PhaseSummary::new(&runs(|s| &s.sequential)),
+            batch: PhaseSummary::new(&runs(|s| &s.batch)),
+        }
+    }
+}
+
+/// Print how `current` compares with `baseline`, and return a description of
+/// every metric that got worse by more than `threshold` percent.
+pub fn compare(baseline: &Snapshot, current: &Snapshot, threshold: Option<f64>) -> Vec<String> {
+    let mut regressions = vec![];
+
+    println!("\n ==== comparison with baseline ====");
+    println!(
+        "{:>11} {:>8} {:>12} {:>12} {:>9}",
+        "phase", "metric", "baseline", "current", "change"
+    );
+
+    let phases = [
+        ("sequential", baseline.sequential, current.sequential),
+        ("batch", baseline.batch, current.batch),
+    ];
+
+    for (phase, base, cur) in phases.iter() {
+        let (base, cur) = match (base, cur) {
+            (Some(base), Some(cur)) => (base, cur),
+            _ => continue,
+        };
+
+        // Lower throughput and higher latency are both regressions, so flip
+        // the sign of the throughput change to make positive mean worse.
+        let metrics = [
+            ("obj/s", base.objects_per_sec, cur.objects_per_sec, -1.0),
+            ("p99 ms", base.p99_us / 1000.0, cur.p99_us / 1000.0, 1.0),
+        ];
+
+        for (metric, base, cur, worse) in metrics.iter() {
+            let change = if *base > 0.0 {
+                (cur / base - 1.0) * 100.0
+            } else {
+                0.0
+            };
+            let regressed = threshold.map_or(false, |t| change * worse > t);
+
+            println!(
+                "{:>11} {:>8} {:>12.1} {:>12.1} {:>+8.1}%{}",
+                phase,
+                metric,
+                base,
+                cur,
+                change,
+                if regressed { "  REGRESSION" } else { "" }
+            );
+
+            if regressed {
+                regressions.push(format!("{} {} changed by {:+.1}%", phase, metric, change));
+            }
+        }
+    }
+
+    regressions
+}
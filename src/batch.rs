@@ -0,0 +1,168 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use moray::objects::{self, BatchDeleteOp, BatchPutOp, BatchRequest};
+use serde_json::Value;
+use std::collections::HashSet;
+
+/// Groups objects into batch put requests of `size` operations each.  The
+/// final batch holds whatever is left over, so every object is written exactly
+/// once.  Objects whose keys are in `deletes` are deleted instead, in the same
+/// batches as the puts.  Every object is also put into each of the companion
+/// buckets, in the same batch, so a batch of `size` objects holds `size`
+/// operations per bucket.
+///
+/// With a byte budget, a batch is also cut short before the object that would
+/// take its keys and values past that many bytes, since moray rejects requests
+/// bigger than its frame limit.  An object bigger than the budget on its own
+/// still goes out, alone in its batch.
+pub struct PutBatches<I> {
+    objects: I,
+    bucket: String,
+    size: usize,
+    max_bytes: usize,
+    opts: objects::MethodOptions,
+    deletes: HashSet<String>,
+    companions: Vec<String>,
+    // An object that didn't fit in the last batch, to start the next one.
+    held: Option<(String, Value)>,
+    split: usize,
+}
+
+impl<I> PutBatches<I>
+where
+    I: Iterator<Item = (String, Value)>,
+{
+    pub fn new(bucket: &str, objects: I, size: u32) -> PutBatches<I> {
+        PutBatches {
+            objects,
+            bucket: bucket.to_string(),
+            size: size.max(1) as usize,
+            max_bytes: 0,
+            opts: objects::MethodOptions::default(),
+            deletes: HashSet::new(),
+            companions: vec![],
+            held: None,
+            split: 0,
+        }
+    }
+
+    /// Keep each batch's keys and values within `max_bytes` in all, or only
+    /// limit the number of objects if that is zero.
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> PutBatches<I> {
+        self.max_bytes = max_bytes as usize;
+        self
+    }
+
+    /// Delete the objects with these keys rather than putting them.
+    pub fn with_deletes(mut self, deletes: HashSet<String>) -> PutBatches<I> {
+        self.deletes = deletes;
+        self
+    }
+
+    /// Put each object into these buckets too, alongside its own operation,
+    /// the way muskie records a delete log entry with every overwrite.
+    pub fn with_companions(mut self, companions: Vec<String>) -> PutBatches<I> {
+        self.companions = companions;
+        self
+    }
+}
+
+impl<I> PutBatches<I> {
+    /// Change the size of the batches that follow.
+    pub fn set_size(&mut self, size: u32) {
+        self.size = size.max(1) as usize;
+    }
+
+    /// The number of batches so far cut short by the byte budget.
+    pub fn split(&self) -> usize {
+        self.split
+    }
+
+    // The operations for one object: its own, then a put into each
+    // companion bucket.
+    fn ops(&self, key: String, value: Value) -> Vec<BatchRequest> {
+        let mut ops: Vec<BatchRequest> = self
+            .companions
+            .iter()
+            .map(|companion| {
+                BatchRequest::Put(BatchPutOp {
+                    bucket: companion.clone(),
+                    options: self.opts.clone(),
+                    key: key.clone(),
+                    value: value.clone(),
+                })
+            })
+            .collect();
+
+        let op = if self.deletes.contains(&key) {
+            BatchRequest::Delete(BatchDeleteOp {
+                bucket: self.bucket.clone(),
+                options: self.opts.clone(),
+                key,
+            })
+        } else {
+            BatchRequest::Put(BatchPutOp {
+                bucket: self.bucket.clone(),
+                options: self.opts.clone(),
+                key,
+                value,
+            })
+        };
+        ops.insert(0, op);
+        ops
+    }
+
+    // How many bytes of keys and values the object's operations add to a
+    // batch.
+    fn bytes(&self, key: &str, value: &Value) -> usize {
+        let put = key.len() + value.to_string().len();
+        let own = if self.deletes.contains(key) {
+            key.len()
+        } else {
+            put
+        };
+
+        own + put * self.companions.len()
+    }
+}
+
+impl<I> Iterator for PutBatches<I>
+where
+    I: Iterator<Item = (String, Value)>,
+{
+    type Item = Vec<BatchRequest>;
+
+    fn next(&mut self) -> Option<Vec<BatchRequest>> {
+        let mut batch = vec![];
+        let mut objects = 0;
+        let mut bytes = 0;
+
+        while objects < self.size {
+            let (key, value) = match self.held.take().or_else(|| self.objects.next()) {
+                Some(object) => object,
+                None => break,
+            };
+
+            if self.max_bytes > 0 {
+                let size = self.bytes(&key, &value);
+                if objects > 0 && bytes + size > self.max_bytes {
+                    self.held = Some((key, value));
+                    self.split += 1;
+                    break;
+                }
+                bytes += size;
+            }
+
+            objects += 1;
+            batch.extend(self.ops(key, value));
+        }
+
+        if batch.is_empty() {
+            None
+        } else {
+            Some(batch)
+        }
+    }
+}
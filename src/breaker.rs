@@ -0,0 +1,108 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use crate::logging;
+use slog::warn;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct State {
+    // Outcome of the most recent requests, true for success.
+    outcomes: VecDeque<bool>,
+    open_until: Option<Instant>,
+    trips: u32,
+}
+
+/// Stops traffic to a shard for a cooldown period once too many of its recent
+/// requests have failed, e.g. while manatee fails over.
+pub struct CircuitBreaker {
+    state: Mutex<State>,
+    error_rate: f64,
+    window: usize,
+    cooldown: Duration,
+    max_trips: u32,
+}
+
+impl CircuitBreaker {
+    pub fn from_config(config: &Config) -> CircuitBreaker {
+        CircuitBreaker {
+            state: Mutex::new(State {
+                outcomes: VecDeque::new(),
+                open_until: None,
+                trips: 0,
+            }),
+            error_rate: config.breaker_error_rate,
+            window: config.breaker_window.max(1) as usize,
+            cooldown: Duration::from_secs(config.breaker_cooldown_secs),
+            max_trips: config.breaker_max_trips,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.error_rate > 0.0
+    }
+
+    // Block until the breaker is closed.
+    pub fn wait(&self) {
+        let open_until = self.state.lock().unwrap().open_until;
+
+        if let Some(until) = open_until {
+            let now = Instant::now();
+            if until > now {
+                thread::sleep(until - now);
+            }
+        }
+    }
+
+    // Whether a request that just failed should be tried again once the
+    // breaker closes.  That is only worth doing while the breaker is open,
+    // and only until it has tripped so often that the shard looks dead.
+    pub fn should_retry(&self) -> bool {
+        let state = self.state.lock().unwrap();
+
+        match state.open_until {
+            Some(until) => until > Instant::now() && state.trips <= self.max_trips,
+            None => false,
+        }
+    }
+
+    pub fn record(&self, success: bool) {
+        if !self.enabled() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        state.outcomes.push_back(success);
+        if state.outcomes.len() > self.window {
+            state.outcomes.pop_front();
+        }
+
+        if state.outcomes.len() < self.window {
+            return;
+        }
+
+        let failures = state.outcomes.iter().filter(|ok| !**ok).count();
+        let rate = failures as f64 / state.outcomes.len() as f64;
+
+        if rate >= self.error_rate {
+            state.trips += 1;
+            state.open_until = Some(Instant::now() + self.cooldown);
+            state.outcomes.clear();
+
+            warn!(
+                logging::logger(),
+                "circuit breaker tripped: {} of the last {} requests failed, \
+                 pausing for {}s (trip {})",
+                failures,
+                self.window,
+                self.cooldown.as_secs(),
+                state.trips
+            );
+        }
+    }
+}
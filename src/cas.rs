@@ -0,0 +1,214 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use crate::pool::Pool;
+use crate::stats::{PhaseReport, PhaseStats};
+use crate::timeseries::Sampler;
+use crate::workers::{self, Context};
+use crate::{find, latency_csv, retry, trace};
+use failure::Error;
+use libmanta::moray::MantaObject;
+use moray::objects::{self, BatchPutOp, BatchRequest, Etag};
+use opentelemetry::api::KeyValue;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+// One batch of conditional puts: each object's key, object id, and the value
+// to write over it.
+type CasBatch = (usize, Vec<(String, String, Value)>);
+
+// Every worker gets every batch, in the same order, so that they race each
+// other to update the same keys.
+fn shares(
+    test_objects: &HashMap<String, MantaObject>,
+    objects: HashMap<String, Value>,
+    batch_size: u32,
+    workers: u32,
+) -> Vec<Vec<CasBatch>> {
+    let objects: Vec<(String, String, Value)> = objects
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let id = test_objects.get(&key)?.object_id.clone();
+            Some((key, id, value))
+        })
+        .collect();
+    let batches: Vec<CasBatch> = objects
+        .chunks(batch_size.max(1) as usize)
+        .map(|chunk| chunk.to_vec())
+        .enumerate()
+        .collect();
+
+    (0..workers.max(1)).map(|_| batches.clone()).collect()
+}
+
+// Have `config.workers` threads each update every object, `config.batch_size`
+// at a time, with puts conditional on the etag each object had when the
+// worker read it.  A batch that loses the race fails with an
+// EtagConflictError and is read and sent again, up to `config.cas_attempts`
+// times in all.
+pub fn run_cas_test(
+    pool: &Pool,
+    config: &Config,
+    test_objects: &HashMap<String, MantaObject>,
+    objects: HashMap<String, Value>,
+) -> Result<PhaseReport, Error> {
+    let batch_size = config.batch_size.max(1);
+
+    if config.workers < 2 {
+        println!("Only one worker, so conditional puts will never conflict");
+    }
+    println!(
+        "Updating objects with conditional puts in batches of {}, {} workers racing",
+        batch_size,
+        config.workers.max(1)
+    );
+
+    let stats = Arc::new(PhaseStats::new(config, "cas"));
+    let shares = shares(test_objects, objects, batch_size, config.workers);
+    let sampler = Sampler::start(config, &stats);
+    let started = SystemTime::now();
+    let result = workers::run(config, pool, &stats, shares, put_conditionally);
+    sampler.stop();
+    latency_csv::flush();
+    let elapsed = result?;
+
+    let mut report = stats.report(started, elapsed);
+    report.batch_size = Some(batch_size);
+    let attempts = report.requests + report.failed_requests + report.retries;
+
+    println!(
+        "Done updating {} objects in {} batches: {}ms",
+        stats.objects(),
+        stats.requests(),
+        elapsed.as_millis()
+    );
+    if attempts > 0 {
+        println!(
+            "{} of {} attempts conflicted ({:.1}%)",
+            report.conflicts,
+            attempts,
+            report.conflicts as f64 / attempts as f64 * 100.0
+        );
+    }
+    stats.print_throughput(elapsed);
+    stats.print_latency();
+    stats.print_retries();
+    stats.print_failures();
+
+    Ok(report)
+}
+
+// Read the current etag of every object in the batch.  Objects that don't
+// exist are left out and put unconditionally.
+fn read_etags(
+    ctx: &Context,
+    batch: &[(String, String, Value)],
+) -> Result<HashMap<String, String>, Error> {
+    let ids: Vec<String> = batch.iter().map(|(_, id, _)| id.clone()).collect();
+    let filter = find::object_id_filter(&ids);
+    let mut opts = objects::MethodOptions::default();
+    opts.limit = Some(batch.len() as u64);
+
+    ctx.call(|c| {
+        let mut etags = HashMap::new();
+        c.find_objects(&ctx.config.bucket, &filter, &opts, |mobj| {
+            etags.insert(mobj.key.clone(), mobj._etag.clone());
+            Ok(())
+        })?;
+        Ok(etags)
+    })
+}
+
+fn conditional_batch(
+    bucket: &str,
+    batch: &[(String, String, Value)],
+    etags: &HashMap<String, String>,
+) -> Vec<BatchRequest> {
+    batch
+        .iter()
+        .map(|(key, _, value)| {
+            let mut options = objects::MethodOptions::default();
+            if let Some(etag) = etags.get(key) {
+                options.etag = Etag::Specified(etag.clone());
+            }
+
+            BatchRequest::Put(BatchPutOp {
+                bucket: bucket.to_string(),
+                options,
+                key: key.clone(),
+                value: value.clone(),
+            })
+        })
+        .collect()
+}
+
+// The etag reads aren't counted as requests; only the conditional batches
+// are, and their latency leaves out the read before them.
+fn put_conditionally(ctx: &Context, batches: Vec<CasBatch>) -> Result<(), Error> {
+    let opts = objects::MethodOptions::default();
+    let attempts = ctx.config.cas_attempts.max(1);
+
+    for (id, objects) in workers::until_stopped(batches) {
+        let keys: Vec<String> = objects.iter().map(|(key, _, _)| key.clone()).collect();
+        let bytes = objects
+            .iter()
+            .map(|(key, _, value)| (key.len() + value.to_string().len()) as u64)
+            .sum();
+
+        for attempt in 1..=attempts {
+            let etags = read_etags(ctx, &objects)?;
+            let batch = conditional_batch(&ctx.config.bucket, &objects, &etags);
+
+            let start = ctx.pace();
+            let started = SystemTime::now() - start.elapsed();
+            let span = trace::span(
+                "cas",
+                vec![
+                    KeyValue::new("shard", ctx.config.shard.to_string()),
+                    KeyValue::new("batch", id.to_string()),
+                    KeyValue::new("attempt", attempt.to_string()),
+                ],
+            );
+            let result = ctx.call(|c| {
+                c.batch(&batch, &opts, |_| Ok(()))?;
+                Ok(())
+            });
+            latency_csv::record(
+                ctx.config.shard,
+                "cas",
+                &id.to_string(),
+                started,
+                start.elapsed(),
+                &result,
+            );
+            if let Err(e) = &result {
+                trace::set_error(&span, retry::error_name(e));
+            }
+            drop(span);
+
+            match result {
+                Ok(()) => {
+                    ctx.stats
+                        .record_request(keys.len() as u64, bytes, start.elapsed());
+                    break;
+                }
+                Err(ref e) if retry::is_conflict(e) && attempt < attempts => {
+                    ctx.stats.record_conflict();
+                    ctx.stats.record_retry(Duration::from_secs(0));
+                }
+                Err(e) if retry::is_conflict(&e) || ctx.config.continue_on_error => {
+                    eprintln!("conditional batch of {}: {}", keys.len(), e);
+                    ctx.stats.record_failure(keys.clone(), &e);
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    Ok(())
+}
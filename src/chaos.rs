@@ -0,0 +1,277 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! Connection resets injected in the middle of batch phases, to see how the
+//! retry and verification layers cope with them.
+//!
+//! With `--chaos-reset` the run's connections go through a proxy in front of
+//! the moray instance, which can be a real one given with `--host` or the
+//! mock.  While a batch phase runs, each batch request passing through is
+//! interrupted with the configured probability: half the time the connection
+//! is reset before the request reaches moray, and half the time just after,
+//! so that moray may have applied a batch the client saw fail.
+//!
+//! Once the phase is done every interrupted batch is fetched back, to tell
+//! whether moray applied all of it, none of it or, which a transaction should
+//! never allow, part of it.  Later writes to the same keys are taken for the
+//! batch's, so that is only exact when each object is written once, as with
+//! `--access all`.
+
+use crate::config::Config;
+use crate::retry;
+use failure::{err_msg, Error};
+use lazy_static::lazy_static;
+use moray::client::MorayClient;
+use moray::objects;
+use rand::Rng;
+use serde::Serialize;
+use serde_json::Value;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+// A fast message header: version, type, status, message id, crc and the
+// length of the JSON data that follows, which is the last four bytes.
+const HEADER_SIZE: usize = 15;
+const DATA_LEN_OFFSET: usize = 11;
+
+// The probability of interrupting a batch, as the bits of an f64, which is
+// zero outside of batch phases.
+static PROBABILITY: AtomicU64 = AtomicU64::new(0);
+static BATCHES: AtomicU64 = AtomicU64::new(0);
+static RESET_BEFORE: AtomicU64 = AtomicU64::new(0);
+static RESET_AFTER: AtomicU64 = AtomicU64::new(0);
+
+// One operation of an interrupted batch: the object it wrote, or None if it
+// deleted it.
+struct Operation {
+    bucket: String,
+    key: String,
+    value: Option<Value>,
+}
+
+lazy_static! {
+    static ref INTERRUPTED: Mutex<Vec<Vec<Operation>>> = Mutex::new(vec![]);
+}
+
+/// What the resets injected during a phase did.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ChaosReport {
+    pub probability: f64,
+    /// Batch requests sent during the phase, retries included.
+    pub batches: u64,
+    pub reset_before_send: u64,
+    pub reset_after_send: u64,
+    /// Interrupted batches found fully applied afterwards, whether by moray
+    /// before the reset or by a retry.
+    pub applied: u64,
+    pub not_applied: u64,
+    pub partial: u64,
+}
+
+/// Start the proxy in front of `config.host`, returning the address to
+/// connect to instead.
+pub fn start(config: &Config) -> Result<SocketAddr, Error> {
+    let upstream = config
+        .host
+        .ok_or_else(|| err_msg("--chaos-reset needs --host or --mock"))?;
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let addr = listener.local_addr()?;
+
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            let proxied = client.and_then(|client| {
+                let server = TcpStream::connect(upstream)?;
+                proxy(client, server)
+            });
+            if let Err(e) = proxied {
+                eprintln!("chaos proxy: {}", e);
+            }
+        }
+    });
+
+    println!(
+        "Injecting connection resets into {} batches through {}",
+        config.chaos_reset, addr
+    );
+    Ok(addr)
+}
+
+/// Start interrupting batches with `config.chaos_reset`'s probability,
+/// forgetting those interrupted before.
+pub fn arm(config: &Config) {
+    INTERRUPTED.lock().unwrap().clear();
+    BATCHES.store(0, Ordering::Relaxed);
+    RESET_BEFORE.store(0, Ordering::Relaxed);
+    RESET_AFTER.store(0, Ordering::Relaxed);
+    PROBABILITY.store(config.chaos_reset.to_bits(), Ordering::Relaxed);
+}
+
+pub fn disarm() {
+    PROBABILITY.store(0, Ordering::Relaxed);
+}
+
+fn probability() -> f64 {
+    f64::from_bits(PROBABILITY.load(Ordering::Relaxed))
+}
+
+fn proxy(client: TcpStream, server: TcpStream) -> io::Result<()> {
+    let (mut from_server, mut to_client) = (server.try_clone()?, client.try_clone()?);
+    thread::spawn(move || {
+        let _ = io::copy(&mut from_server, &mut to_client);
+        let _ = to_client.shutdown(Shutdown::Both);
+    });
+    thread::spawn(move || forward(client, server));
+
+    Ok(())
+}
+
+// Pass the client's requests on to the server a message at a time, resetting
+// both connections instead when a batch is to be interrupted.
+fn forward(mut client: TcpStream, mut server: TcpStream) {
+    let mut header = [0u8; HEADER_SIZE];
+
+    while client.read_exact(&mut header).is_ok() {
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&header[DATA_LEN_OFFSET..]);
+        let mut data = vec![0u8; u32::from_be_bytes(len) as usize];
+        if client.read_exact(&mut data).is_err() {
+            break;
+        }
+
+        let reset = match interrupt(&data) {
+            Some(after_send) => {
+                if after_send && send(&mut server, &header, &data).is_err() {
+                    break;
+                }
+                true
+            }
+            None => send(&mut server, &header, &data).is_err(),
+        };
+        if reset {
+            break;
+        }
+    }
+
+    let _ = client.shutdown(Shutdown::Both);
+    let _ = server.shutdown(Shutdown::Both);
+}
+
+fn send(server: &mut TcpStream, header: &[u8], data: &[u8]) -> io::Result<()> {
+    server.write_all(header)?;
+    server.write_all(data)
+}
+
+// Whether to interrupt the request in `data`, and if so whether to let it
+// reach the server first.  Only batches are interrupted.
+fn interrupt(data: &[u8]) -> Option<bool> {
+    let p = probability();
+    if p <= 0.0 {
+        return None;
+    }
+    let message: Value = serde_json::from_slice(data).ok()?;
+    if message["m"]["name"].as_str() != Some("batch") {
+        return None;
+    }
+
+    BATCHES.fetch_add(1, Ordering::Relaxed);
+    let mut rng = rand::thread_rng();
+    if !rng.gen_bool(p.min(1.0)) {
+        return None;
+    }
+
+    let after_send = rng.gen_bool(0.5);
+    if after_send {
+        RESET_AFTER.fetch_add(1, Ordering::Relaxed);
+    } else {
+        RESET_BEFORE.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let operations = message["d"][0]
+        .as_array()
+        .map(|requests| requests.iter().map(operation).collect())
+        .unwrap_or_default();
+    INTERRUPTED.lock().unwrap().push(operations);
+
+    Some(after_send)
+}
+
+fn operation(request: &Value) -> Operation {
+    let value = match request["operation"].as_str() {
+        Some("delete") => None,
+        _ => Some(request["value"].clone()),
+    };
+
+    Operation {
+        bucket: request["bucket"].as_str().unwrap_or_default().to_string(),
+        key: request["key"].as_str().unwrap_or_default().to_string(),
+        value,
+    }
+}
+
+/// Fetch back the objects of every batch interrupted since `arm`, count how
+/// many were applied in full, and print what the resets did.
+pub fn check(client: &mut MorayClient, config: &Config) -> Result<ChaosReport, Error> {
+    let interrupted: Vec<Vec<Operation>> = INTERRUPTED.lock().unwrap().drain(..).collect();
+    let opts = objects::MethodOptions::default();
+    let mut report = ChaosReport {
+        probability: config.chaos_reset,
+        batches: BATCHES.load(Ordering::Relaxed),
+        reset_before_send: RESET_BEFORE.load(Ordering::Relaxed),
+        reset_after_send: RESET_AFTER.load(Ordering::Relaxed),
+        ..ChaosReport::default()
+    };
+
+    for (i, batch) in interrupted.iter().enumerate() {
+        let mut applied = 0;
+        for op in batch.iter() {
+            let mut found = None;
+            let fetched = client.get_object(&op.bucket, &op.key, &opts, |mobj| {
+                found = Some(mobj.value.clone());
+                Ok(())
+            });
+            if let Err(e) = fetched {
+                if retry::error_name(&e) != "ObjectNotFoundError" {
+                    return Err(e);
+                }
+            }
+            if found == op.value {
+                applied += 1;
+            }
+        }
+
+        if applied == batch.len() {
+            report.applied += 1;
+        } else if applied == 0 {
+            report.not_applied += 1;
+        } else {
+            report.partial += 1;
+            eprintln!(
+                "interrupted batch {}: {} of {} operations applied",
+                i,
+                applied,
+                batch.len()
+            );
+        }
+    }
+
+    print(&report);
+    Ok(report)
+}
+
+pub fn print(report: &ChaosReport) {
+    println!(
+        "Reset {} of {} batches, {} before sending and {} after",
+        report.reset_before_send + report.reset_after_send,
+        report.batches,
+        report.reset_before_send,
+        report.reset_after_send
+    );
+    println!(
+        "    afterwards {} were applied, {} not applied and {} partially applied",
+        report.applied, report.not_applied, report.partial
+    );
+}
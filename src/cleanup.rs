@@ -0,0 +1,81 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::PAGE_SIZE;
+use failure::Error;
+use moray::buckets;
+use moray::client::MorayClient;
+use moray::objects::{self, BatchDeleteOp, BatchRequest};
+
+// Delete every test object in the bucket matching `filter`, `batch_size` keys
+// per batch request.  Returns the number of objects removed.
+pub fn delete_test_objects(
+    mclient: &mut MorayClient,
+    bucket: &str,
+    filter: &str,
+    batch_size: u32,
+) -> Result<u64, Error> {
+    let opts = objects::MethodOptions::default();
+    let mut removed = 0;
+
+    // Deleting objects shifts findobjects offsets, so always re-read the
+    // first page until the bucket is empty.
+    loop {
+        let mut keys = vec![];
+        let mut find_opts = objects::MethodOptions::default();
+        find_opts.limit = Some(PAGE_SIZE);
+
+        mclient.find_objects(bucket, filter, &find_opts, |mobj| {
+            keys.push(mobj.key.clone());
+            Ok(())
+        })?;
+
+        if keys.is_empty() {
+            break;
+        }
+
+        for chunk in keys.chunks(batch_size.max(1) as usize) {
+            let batch: Vec<BatchRequest> = chunk
+                .iter()
+                .map(|key| {
+                    BatchRequest::Delete(BatchDeleteOp {
+                        bucket: bucket.to_string(),
+                        key: key.clone(),
+                        options: opts.clone(),
+                    })
+                })
+                .collect();
+
+            mclient.batch(&batch, &opts, |_| Ok(()))?;
+            removed += chunk.len() as u64;
+        }
+    }
+
+    Ok(removed)
+}
+
+// A filter matching test objects written under a run id other than those in
+// `keep`, and, if `before` is given, last written before that time in
+// milliseconds since the epoch.  Objects seeded without a run id are left
+// alone.
+pub fn stale_filter(keep: &[String], before: Option<u64>) -> String {
+    let mut clauses = vec![String::from("(_key=*/*)")];
+
+    for run_id in keep.iter() {
+        clauses.push(format!("(!(_key={}/*))", run_id));
+    }
+    if let Some(before) = before {
+        clauses.push(format!("(_mtime<={})", before));
+    }
+
+    format!("(&{})", clauses.concat())
+}
+
+// Drop the bucket entirely.  Moray drops the bucket's table along with it, so
+// there is no need to delete the objects first.
+pub fn drop_bucket(mclient: &mut MorayClient, bucket: &str) -> Result<(), Error> {
+    let bucket_opts = buckets::MethodOptions::default();
+    mclient.delete_bucket(bucket, bucket_opts)?;
+    Ok(())
+}
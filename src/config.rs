@@ -0,0 +1,1176 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+pub static DEFAULT_BUCKET: &str = "rust_batch_test_bucket";
+
+/// Test parameters loaded from a TOML file.  Any field missing from the file
+/// takes its default value, and any option given on the command line overrides
+/// the value from the file.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub shard: u32,
+    pub shards: Vec<u32>,
+    pub domain: String,
+    pub electric_moray: bool,
+    pub host: Option<SocketAddr>,
+    pub dns_ttl: u64,
+    pub nameservers: Vec<IpAddr>,
+    pub bucket: String,
+    pub run_id: Option<String>,
+    pub seed: Option<u64>,
+    pub buckets: Vec<String>,
+    pub companion_buckets: Vec<String>,
+    pub index: BTreeMap<String, Index>,
+    pub objects: u32,
+    pub value_size: ValueSize,
+    pub metadata: Metadata,
+    pub owners: Vec<String>,
+    pub owner_count: u32,
+    pub directory_fraction: f64,
+    pub seed_batch_size: u32,
+    pub seed_workers: u32,
+    pub save_dataset: Option<String>,
+    pub load_dataset: Option<String>,
+    pub batch_size: u32,
+    pub max_batch_bytes: u64,
+    pub passes: u32,
+    pub pass_order: PassOrder,
+    pub access: Access,
+    pub iterations: u32,
+    pub warmup: Warmup,
+    pub duration_secs: u64,
+    pub soak_secs: u64,
+    pub soak_report_interval_secs: u64,
+    pub soak_report: Option<String>,
+    pub workers: u32,
+    pub queue_depth: u32,
+    pub delete_fraction: f64,
+    pub sweep_batch_sizes: Vec<u32>,
+    pub auto_batch_size: bool,
+    pub target_p99_ms: u64,
+    pub batch_size_step: u32,
+    pub max_batch_size: u32,
+    pub sweep_workers: Vec<u32>,
+    pub sweep_connections: Vec<u32>,
+    pub find_queries: u32,
+    pub find_limit: u64,
+    pub reindex_count: u32,
+    pub conflict_fraction: f64,
+    pub cas_attempts: u32,
+    pub rebalance_from: String,
+    pub rebalance_to: String,
+    pub read_back: bool,
+    pub read_back_sample: u32,
+    pub count_check: CountCheck,
+    pub rate: f64,
+    pub burst: u32,
+    pub profile: Profile,
+    pub open_loop: bool,
+    pub connections: u32,
+    pub connections_per_worker: u32,
+    pub max_attempts: u32,
+    pub retry_base_delay_ms: u64,
+    pub retry_max_delay_ms: u64,
+    pub retry_jitter: f64,
+    pub breaker_error_rate: f64,
+    pub breaker_window: u32,
+    pub breaker_cooldown_secs: u64,
+    pub breaker_max_trips: u32,
+    pub reconnect_timeout_secs: u64,
+    pub op_timeout_ms: u64,
+    pub chaos_reset: f64,
+    pub throttle: bool,
+    pub throttle_min_rate: f64,
+    pub continue_on_error: bool,
+    pub requeue_failed: bool,
+    pub timeseries: Option<String>,
+    pub timeseries_interval_ms: u64,
+    pub output: Option<String>,
+    pub baseline: Option<String>,
+    pub fail_on_regression: Option<f64>,
+    pub max_error_rate: Option<f64>,
+    pub latency_csv: Option<String>,
+    pub prometheus_port: Option<u16>,
+    pub status_port: Option<u16>,
+    pub statsd: Option<String>,
+    pub statsd_prefix: String,
+    pub otlp_endpoint: Option<String>,
+    pub progress: bool,
+    pub dashboard: bool,
+    pub preflight: bool,
+    pub dry_run: bool,
+    pub mock: bool,
+    pub mock_latency: MockLatency,
+    pub log_level: LogLevel,
+    pub log_file: Option<String>,
+    pub log_format: LogFormat,
+    pub summary_line: Option<SummaryFormat>,
+    pub timing_sample: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            shard: 1,
+            shards: vec![],
+            domain: String::from("perf2.scloud.host"),
+            electric_moray: false,
+            host: None,
+            dns_ttl: 60,
+            nameservers: vec![],
+            bucket: String::from(DEFAULT_BUCKET),
+            run_id: None,
+            seed: None,
+            buckets: vec![],
+            companion_buckets: vec![],
+            index: manta_indexes(),
+            objects: 100,
+            value_size: ValueSize::Natural,
+            metadata: Metadata::Random,
+            owners: vec![],
+            owner_count: 10,
+            directory_fraction: 0.0,
+            seed_batch_size: 100,
+            seed_workers: 4,
+            save_dataset: None,
+            load_dataset: None,
+            batch_size: 50,
+            max_batch_bytes: 0,
+            passes: 2,
+            pass_order: PassOrder::Alternating,
+            access: Access::All,
+            iterations: 1,
+            warmup: Warmup::None,
+            duration_secs: 0,
+            soak_secs: 0,
+            soak_report_interval_secs: 600,
+            soak_report: None,
+            workers: 1,
+            queue_depth: 4,
+            delete_fraction: 0.0,
+            sweep_batch_sizes: vec![],
+            auto_batch_size: false,
+            target_p99_ms: 500,
+            batch_size_step: 10,
+            max_batch_size: 1000,
+            sweep_workers: vec![],
+            sweep_connections: vec![],
+            find_queries: 100,
+            find_limit: 100,
+            reindex_count: 100,
+            conflict_fraction: 0.1,
+            cas_attempts: 10,
+            rebalance_from: String::from("1.stor.domain"),
+            rebalance_to: String::from("5.stor.domain"),
+            read_back: false,
+            read_back_sample: 0,
+            count_check: CountCheck::Off,
+            rate: 0.0,
+            burst: 1,
+            profile: Profile::None,
+            open_loop: false,
+            connections: 0,
+            connections_per_worker: 1,
+            max_attempts: 3,
+            retry_base_delay_ms: 100,
+            retry_max_delay_ms: 5000,
+            retry_jitter: 0.5,
+            breaker_error_rate: 0.5,
+            breaker_window: 20,
+            breaker_cooldown_secs: 30,
+            breaker_max_trips: 10,
+            reconnect_timeout_secs: 60,
+            op_timeout_ms: 0,
+            chaos_reset: 0.0,
+            throttle: false,
+            throttle_min_rate: 1.0,
+            continue_on_error: false,
+            requeue_failed: false,
+            timeseries: None,
+            timeseries_interval_ms: 1000,
+            output: None,
+            baseline: None,
+            fail_on_regression: None,
+            max_error_rate: None,
+            latency_csv: None,
+            prometheus_port: None,
+            status_port: None,
+            statsd: None,
+            statsd_prefix: String::from("moray_batch_test"),
+            otlp_endpoint: None,
+            progress: true,
+            dashboard: false,
+            preflight: true,
+            dry_run: false,
+            mock: false,
+            mock_latency: MockLatency::default(),
+            log_level: LogLevel::Warn,
+            log_file: None,
+            log_format: LogFormat::Text,
+            summary_line: None,
+            timing_sample: 100,
+        }
+    }
+}
+
+/// One indexed field of the test bucket, as moray takes it in a bucket's
+/// schema: `{ type = "string", unique = true }`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Index {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub unique: bool,
+}
+
+impl Index {
+    fn string() -> Index {
+        Index {
+            kind: String::from("string"),
+            unique: false,
+        }
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+// The fields manta looks objects up by, which the bucket indexes unless the
+// config or a schema file says otherwise.
+fn manta_indexes() -> BTreeMap<String, Index> {
+    ["dirname", "name", "owner", "objectId", "type"]
+        .iter()
+        .map(|field| (field.to_string(), Index::string()))
+        .collect()
+}
+
+// A moray bucket schema as written to a JSON file.  Only the indexes are
+// used; options, pre and post triggers are ignored.
+#[derive(Deserialize)]
+struct Schema {
+    index: BTreeMap<String, Index>,
+}
+
+/// How much of the start of each phase to leave out of its statistics,
+/// written as a number of objects ("500") or seconds ("30s").
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Warmup {
+    None,
+    /// The first N objects written.
+    Objects(u64),
+    /// Every request that completes in the first N seconds.
+    Secs(u64),
+}
+
+impl Default for Warmup {
+    fn default() -> Warmup {
+        Warmup::None
+    }
+}
+
+impl FromStr for Warmup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Warmup, String> {
+        let invalid = |_| format!("invalid warm-up \"{}\", expected e.g. 500 or 30s", s);
+
+        let warmup = match s.strip_suffix('s') {
+            Some(secs) => Warmup::Secs(secs.parse().map_err(invalid)?),
+            None => Warmup::Objects(s.parse().map_err(invalid)?),
+        };
+
+        match warmup {
+            Warmup::Secs(0) | Warmup::Objects(0) => Ok(Warmup::None),
+            warmup => Ok(warmup),
+        }
+    }
+}
+
+impl TryFrom<String> for Warmup {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Warmup, String> {
+        s.parse()
+    }
+}
+
+impl From<Warmup> for String {
+    fn from(warmup: Warmup) -> String {
+        match warmup {
+            Warmup::None => String::from("0"),
+            Warmup::Objects(n) => n.to_string(),
+            Warmup::Secs(n) => format!("{}s", n),
+        }
+    }
+}
+
+/// How seed fills in the fields of the objects it makes, written as "random"
+/// or "manta".
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Metadata {
+    /// Whatever quickcheck generates: random strings in every field.
+    Random,
+    /// Owners, dirnames, names, content types and lengths like real manta
+    /// objects', so that queries on the indexed fields are as selective as
+    /// on a real shard.
+    Manta,
+}
+
+impl Default for Metadata {
+    fn default() -> Metadata {
+        Metadata::Random
+    }
+}
+
+impl FromStr for Metadata {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Metadata, String> {
+        match s {
+            "random" => Ok(Metadata::Random),
+            "manta" => Ok(Metadata::Manta),
+            _ => Err(format!(
+                "invalid metadata \"{}\", expected random or manta",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Metadata {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Metadata, String> {
+        s.parse()
+    }
+}
+
+impl From<Metadata> for String {
+    fn from(metadata: Metadata) -> String {
+        match metadata {
+            Metadata::Random => String::from("random"),
+            Metadata::Manta => String::from("manta"),
+        }
+    }
+}
+
+/// How big seed makes each object's serialized value, written as "natural",
+/// a size such as "2KiB", or "lognormal:MEDIAN:SIGMA" for sizes drawn from a
+/// log-normal distribution, e.g. "lognormal:2KiB:1.0".
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ValueSize {
+    /// Whatever size quickcheck generates the objects at, a few hundred
+    /// bytes.
+    Natural,
+    /// Every object padded to this many bytes.
+    Fixed(u64),
+    /// Objects padded to sizes whose logarithm is normally distributed, with
+    /// half of them smaller than `median` bytes.
+    LogNormal { median: u64, sigma: f64 },
+}
+
+impl Default for ValueSize {
+    fn default() -> ValueSize {
+        ValueSize::Natural
+    }
+}
+
+impl FromStr for ValueSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ValueSize, String> {
+        let invalid = || {
+            format!(
+                "invalid value size \"{}\", expected natural, e.g. 2KiB, or e.g. lognormal:2KiB:1.0",
+                s
+            )
+        };
+
+        if s == "natural" {
+            return Ok(ValueSize::Natural);
+        }
+        match s.strip_prefix("lognormal:") {
+            Some(params) => {
+                let mut params = params.splitn(2, ':');
+                let median = parse_bytes(params.next().unwrap_or("")).map_err(|_| invalid())?;
+                let sigma: f64 = params.next().unwrap_or("").parse().map_err(|_| invalid())?;
+                if sigma < 0.0 {
+                    return Err(invalid());
+                }
+                Ok(ValueSize::LogNormal { median, sigma })
+            }
+            None => parse_bytes(s).map(ValueSize::Fixed).map_err(|_| invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for ValueSize {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<ValueSize, String> {
+        s.parse()
+    }
+}
+
+impl From<ValueSize> for String {
+    fn from(size: ValueSize) -> String {
+        match size {
+            ValueSize::Natural => String::from("natural"),
+            ValueSize::Fixed(n) => n.to_string(),
+            ValueSize::LogNormal { median, sigma } => format!("lognormal:{}:{}", median, sigma),
+        }
+    }
+}
+
+/// Which keys the sequential and batch phases rewrite, written as "all",
+/// "uniform", "zipf:S" for a Zipfian distribution with exponent S, e.g.
+/// "zipf:1.0", or "hot:P" for a hot set of P percent of the keys, e.g. "hot:1".
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Access {
+    /// Every object once.
+    All,
+    /// As many writes as objects, each to a key chosen uniformly.
+    Uniform,
+    /// As many writes as objects, to keys chosen with a Zipfian distribution.
+    Zipf(f64),
+    /// As many writes as objects, all to this percentage of the keys.
+    Hot(f64),
+}
+
+impl Default for Access {
+    fn default() -> Access {
+        Access::All
+    }
+}
+
+impl FromStr for Access {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Access, String> {
+        let invalid = || {
+            format!(
+                "invalid access distribution \"{}\", expected all, uniform, e.g. zipf:1.0 or e.g. hot:1",
+                s
+            )
+        };
+        let param = |p: &str| match p.parse::<f64>() {
+            Ok(p) if p > 0.0 => Ok(p),
+            _ => Err(invalid()),
+        };
+
+        match s {
+            "all" => Ok(Access::All),
+            "uniform" => Ok(Access::Uniform),
+            _ => {
+                if let Some(s) = s.strip_prefix("zipf:") {
+                    param(s).map(Access::Zipf)
+                } else if let Some(percent) = s.strip_prefix("hot:") {
+                    match param(percent)? {
+                        p if p <= 100.0 => Ok(Access::Hot(p)),
+                        _ => Err(invalid()),
+                    }
+                } else {
+                    Err(invalid())
+                }
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for Access {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Access, String> {
+        s.parse()
+    }
+}
+
+impl From<Access> for String {
+    fn from(access: Access) -> String {
+        match access {
+            Access::All => String::from("all"),
+            Access::Uniform => String::from("uniform"),
+            Access::Zipf(s) => format!("zipf:{}", s),
+            Access::Hot(percent) => format!("hot:{}", percent),
+        }
+    }
+}
+
+/// Which of the sequential and batch tests runs first in each comparison
+/// pass, written as "seq-first", "batch-first" or "alternating".
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum PassOrder {
+    SequentialFirst,
+    BatchFirst,
+    Alternating,
+}
+
+impl PassOrder {
+    /// Whether the sequential test runs first in pass `pass`, counting from 1.
+    pub fn sequential_first(self, pass: u32) -> bool {
+        match self {
+            PassOrder::SequentialFirst => true,
+            PassOrder::BatchFirst => false,
+            PassOrder::Alternating => pass % 2 == 1,
+        }
+    }
+}
+
+impl Default for PassOrder {
+    fn default() -> PassOrder {
+        PassOrder::Alternating
+    }
+}
+
+impl FromStr for PassOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PassOrder, String> {
+        match s {
+            "seq-first" => Ok(PassOrder::SequentialFirst),
+            "batch-first" => Ok(PassOrder::BatchFirst),
+            "alternating" => Ok(PassOrder::Alternating),
+            _ => Err(format!(
+                "invalid pass order \"{}\", expected seq-first, batch-first or alternating",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for PassOrder {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<PassOrder, String> {
+        s.parse()
+    }
+}
+
+impl From<PassOrder> for String {
+    fn from(order: PassOrder) -> String {
+        match order {
+            PassOrder::SequentialFirst => String::from("seq-first"),
+            PassOrder::BatchFirst => String::from("batch-first"),
+            PassOrder::Alternating => String::from("alternating"),
+        }
+    }
+}
+
+/// What to do when the bucket doesn't hold exactly the expected number of
+/// test objects after seeding or after a phase, written as "off", "warn" or
+/// "fail".
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum CountCheck {
+    Off,
+    Warn,
+    Fail,
+}
+
+impl Default for CountCheck {
+    fn default() -> CountCheck {
+        CountCheck::Off
+    }
+}
+
+impl FromStr for CountCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CountCheck, String> {
+        match s {
+            "off" => Ok(CountCheck::Off),
+            "warn" => Ok(CountCheck::Warn),
+            "fail" => Ok(CountCheck::Fail),
+            _ => Err(format!(
+                "invalid count check \"{}\", expected off, warn or fail",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for CountCheck {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<CountCheck, String> {
+        s.parse()
+    }
+}
+
+impl From<CountCheck> for String {
+    fn from(check: CountCheck) -> String {
+        match check {
+            CountCheck::Off => String::from("off"),
+            CountCheck::Warn => String::from("warn"),
+            CountCheck::Fail => String::from("fail"),
+        }
+    }
+}
+
+/// The least severe messages logged, by this tool or by the moray client,
+/// written as "off", "error", "warn", "info", "debug" or "trace".
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Default for LogLevel {
+    fn default() -> LogLevel {
+        LogLevel::Warn
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogLevel, String> {
+        match s {
+            "off" => Ok(LogLevel::Off),
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(format!(
+                "invalid log level \"{}\", expected off, error, warn, info, debug or trace",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for LogLevel {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<LogLevel, String> {
+        s.parse()
+    }
+}
+
+impl From<LogLevel> for String {
+    fn from(level: LogLevel) -> String {
+        match level {
+            LogLevel::Off => String::from("off"),
+            LogLevel::Error => String::from("error"),
+            LogLevel::Warn => String::from("warn"),
+            LogLevel::Info => String::from("info"),
+            LogLevel::Debug => String::from("debug"),
+            LogLevel::Trace => String::from("trace"),
+        }
+    }
+}
+
+/// How log messages are written: "text" for people, or "bunyan" for one JSON
+/// object per line in the format the rest of manta logs in, to be read with
+/// the bunyan tool alongside the moray server's logs.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum LogFormat {
+    Text,
+    Bunyan,
+}
+
+impl Default for LogFormat {
+    fn default() -> LogFormat {
+        LogFormat::Text
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "bunyan" => Ok(LogFormat::Bunyan),
+            _ => Err(format!(
+                "invalid log format \"{}\", expected text or bunyan",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for LogFormat {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<LogFormat, String> {
+        s.parse()
+    }
+}
+
+impl From<LogFormat> for String {
+    fn from(format: LogFormat) -> String {
+        match format {
+            LogFormat::Text => String::from("text"),
+            LogFormat::Bunyan => String::from("bunyan"),
+        }
+    }
+}
+
+/// How `--summary-line` writes its line: "json" for a JSON object, or "kv"
+/// for space separated key=value pairs.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum SummaryFormat {
+    Json,
+    KeyValue,
+}
+
+impl FromStr for SummaryFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SummaryFormat, String> {
+        match s {
+            "json" => Ok(SummaryFormat::Json),
+            "kv" => Ok(SummaryFormat::KeyValue),
+            _ => Err(format!(
+                "invalid summary line format \"{}\", expected json or kv",
+                s
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for SummaryFormat {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<SummaryFormat, String> {
+        s.parse()
+    }
+}
+
+impl From<SummaryFormat> for String {
+    fn from(format: SummaryFormat) -> String {
+        match format {
+            SummaryFormat::Json => String::from("json"),
+            SummaryFormat::KeyValue => String::from("kv"),
+        }
+    }
+}
+
+/// How the offered load changes over the course of each phase, written as
+/// "ramp:0-2000ops/60s" to raise the rate steadily from 0 to 2000 requests per
+/// second over a minute, or "step:100,500,1000ops/30s" to hold each rate for
+/// 30 seconds in turn.  The last rate is held once the profile runs out.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Profile {
+    /// A constant rate, or none, as set by `rate`.
+    None,
+    Ramp {
+        from: f64,
+        to: f64,
+        secs: u64,
+    },
+    Steps {
+        rates: Vec<f64>,
+        secs: u64,
+    },
+}
+
+impl Default for Profile {
+    fn default() -> Profile {
+        Profile::None
+    }
+}
+
+impl Profile {
+    /// The rate to send requests at `elapsed` into a phase, if the profile
+    /// sets one.
+    pub fn rate_at(&self, elapsed: Duration) -> Option<f64> {
+        match self {
+            Profile::None => None,
+            Profile::Ramp { from, to, secs } => {
+                let done = if *secs == 0 {
+                    1.0
+                } else {
+                    (elapsed.as_secs_f64() / *secs as f64).min(1.0)
+                };
+                Some(from + (to - from) * done)
+            }
+            Profile::Steps { rates, secs } => {
+                let step = elapsed.as_secs() / (*secs).max(1);
+                let step = (step as usize).min(rates.len() - 1);
+                Some(rates[step])
+            }
+        }
+    }
+
+    /// The same profile with every rate it sets multiplied by `factor`.
+    pub fn scaled(&self, factor: f64) -> Profile {
+        match self {
+            Profile::None => Profile::None,
+            Profile::Ramp { from, to, secs } => Profile::Ramp {
+                from: from * factor,
+                to: to * factor,
+                secs: *secs,
+            },
+            Profile::Steps { rates, secs } => Profile::Steps {
+                rates: rates.iter().map(|rate| rate * factor).collect(),
+                secs: *secs,
+            },
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Profile, String> {
+        let invalid = || {
+            format!(
+                "invalid profile \"{}\", expected e.g. ramp:0-2000ops/60s or step:100,500ops/30s",
+                s
+            )
+        };
+        let rate = |r: &str| r.trim().parse::<f64>().ok().filter(|r| *r >= 0.0);
+
+        if s.is_empty() || s == "none" {
+            return Ok(Profile::None);
+        }
+
+        let (kind, spec) = s.split_at(s.find(':').ok_or_else(invalid)?);
+        let mut parts = spec[1..].splitn(2, '/');
+        let rates = parts.next().unwrap_or("");
+        let rates = rates.strip_suffix("ops").unwrap_or(rates);
+        let secs = parse_duration(parts.next().ok_or_else(invalid)?).map_err(|_| invalid())?;
+
+        match kind {
+            "ramp" => {
+                let mut ends = rates.splitn(2, '-');
+                let from = ends.next().and_then(rate).ok_or_else(invalid)?;
+                let to = ends.next().and_then(rate).ok_or_else(invalid)?;
+                Ok(Profile::Ramp { from, to, secs })
+            }
+            "step" => {
+                let rates = rates
+                    .split(',')
+                    .map(rate)
+                    .collect::<Option<Vec<f64>>>()
+                    .ok_or_else(invalid)?;
+                Ok(Profile::Steps { rates, secs })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for Profile {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Profile, String> {
+        s.parse()
+    }
+}
+
+impl From<Profile> for String {
+    fn from(profile: Profile) -> String {
+        let join = |rates: &[f64]| {
+            rates
+                .iter()
+                .map(|r| r.to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+
+        match profile {
+            Profile::None => String::from("none"),
+            Profile::Ramp { from, to, secs } => format!("ramp:{}-{}ops/{}s", from, to, secs),
+            Profile::Steps { rates, secs } => format!("step:{}ops/{}s", join(&rates), secs),
+        }
+    }
+}
+
+/// How long the mock server waits before answering each RPC, written as a
+/// delay in milliseconds with optional jitter, e.g. "5" or "5~2" for 3 to 7ms,
+/// or per RPC, e.g. "batch=5~2,putobject=1".  A delay without a name applies
+/// to every RPC that isn't named.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct MockLatency {
+    pub default: Option<Delay>,
+    /// Delays by lower-cased RPC name.
+    pub rpcs: BTreeMap<String, Delay>,
+}
+
+/// A delay of `ms` milliseconds, give or take up to `jitter_ms`, drawn
+/// uniformly.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Delay {
+    pub ms: f64,
+    pub jitter_ms: f64,
+}
+
+impl MockLatency {
+    pub fn is_none(&self) -> bool {
+        self.default.is_none() && self.rpcs.is_empty()
+    }
+
+    /// The delay for an RPC, if any.
+    pub fn delay(&self, rpc: &str) -> Option<Delay> {
+        self.rpcs.get(&rpc.to_lowercase()).copied().or(self.default)
+    }
+}
+
+impl FromStr for MockLatency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MockLatency, String> {
+        let invalid = || {
+            format!(
+                "invalid mock latency \"{}\", expected e.g. 5, 5~2 or batch=5~2,putobject=1",
+                s
+            )
+        };
+        let ms = |n: &str| n.trim().parse::<f64>().ok().filter(|n| *n >= 0.0);
+        let mut latency = MockLatency::default();
+
+        if s.is_empty() || s == "none" {
+            return Ok(latency);
+        }
+
+        for part in s.split(',') {
+            let (rpc, delay) = match part.find('=') {
+                Some(i) => (Some(part[..i].trim().to_lowercase()), &part[i + 1..]),
+                None => (None, part),
+            };
+            let mut delay = delay.splitn(2, '~');
+            let delay = Delay {
+                ms: delay.next().and_then(ms).ok_or_else(invalid)?,
+                jitter_ms: match delay.next() {
+                    Some(jitter) => ms(jitter).ok_or_else(invalid)?,
+                    None => 0.0,
+                },
+            };
+
+            match rpc {
+                Some(rpc) if !rpc.is_empty() => {
+                    latency.rpcs.insert(rpc, delay);
+                }
+                Some(_) => return Err(invalid()),
+                None => latency.default = Some(delay),
+            }
+        }
+
+        Ok(latency)
+    }
+}
+
+impl TryFrom<String> for MockLatency {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<MockLatency, String> {
+        s.parse()
+    }
+}
+
+impl From<MockLatency> for String {
+    fn from(latency: MockLatency) -> String {
+        let delay = |d: &Delay| {
+            if d.jitter_ms > 0.0 {
+                format!("{}~{}", d.ms, d.jitter_ms)
+            } else {
+                d.ms.to_string()
+            }
+        };
+
+        let mut parts: Vec<String> = latency.default.iter().map(delay).collect();
+        parts.extend(
+            latency
+                .rpcs
+                .iter()
+                .map(|(rpc, d)| format!("{}={}", rpc, delay(d))),
+        );
+        if parts.is_empty() {
+            String::from("none")
+        } else {
+            parts.join(",")
+        }
+    }
+}
+
+/// Check that a run id is safe to use as a key prefix and in a filter.
+pub fn parse_run_id(s: &str) -> Result<String, String> {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "invalid run id \"{}\", expected letters, digits, - and _",
+            s
+        ))
+    }
+}
+
+/// Parse a length of time in seconds ("90", "90s"), minutes ("10m") or hours
+/// ("2h") into seconds.
+pub fn parse_duration(s: &str) -> Result<u64, String> {
+    let invalid = |_| format!("invalid duration \"{}\", expected e.g. 90s, 10m or 2h", s);
+    let (n, unit) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        _ => (s, 1),
+    };
+
+    n.parse::<u64>().map(|n| n * unit).map_err(invalid)
+}
+
+/// Parse a number of bytes ("2048"), kibibytes ("2K", "2KiB") or mebibytes
+/// ("2M", "2MiB").
+pub fn parse_bytes(s: &str) -> Result<u64, String> {
+    let invalid = |_| format!("invalid size \"{}\", expected e.g. 512, 2KiB or 2MiB", s);
+    let s = s.strip_suffix("iB").unwrap_or(s);
+    let (n, unit) = match s.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&s[..i], 1024),
+        Some((i, 'M')) | Some((i, 'm')) => (&s[..i], 1024 * 1024),
+        _ => (s, 1),
+    };
+
+    n.parse::<u64>().map(|n| n * unit).map_err(invalid)
+}
+
+impl Config {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+        let contents = fs::read_to_string(path)?;
+        toml::from_str(&contents).map_err(Error::from)
+    }
+
+    /// A findobjects filter matching the test objects: those whose keys
+    /// start with the run id, or every object if there isn't one.
+    pub fn key_filter(&self) -> String {
+        match &self.run_id {
+            Some(run_id) => format!("(_key={}/*)", run_id),
+            None => String::from("(objectId=*)"),
+        }
+    }
+
+    /// Read the bucket's indexes from a JSON file holding a moray bucket
+    /// schema, e.g. `{"index": {"owner": {"type": "string"}}}`.
+    pub fn load_schema<P: AsRef<Path>>(path: P) -> Result<BTreeMap<String, Index>, Error> {
+        let contents = fs::read_to_string(path)?;
+        let schema: Schema = serde_json::from_str(&contents)?;
+        Ok(schema.index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_profile() {
+        assert_eq!("none".parse::<Profile>(), Ok(Profile::None));
+        assert_eq!("".parse::<Profile>(), Ok(Profile::None));
+        assert_eq!(
+            "ramp:0-2000ops/60s".parse::<Profile>(),
+            Ok(Profile::Ramp {
+                from: 0.0,
+                to: 2000.0,
+                secs: 60
+            })
+        );
+        assert_eq!(
+            "ramp:10-20/2m".parse::<Profile>(),
+            Ok(Profile::Ramp {
+                from: 10.0,
+                to: 20.0,
+                secs: 120
+            })
+        );
+        assert_eq!(
+            "step:100,500ops/30s".parse::<Profile>(),
+            Ok(Profile::Steps {
+                rates: vec![100.0, 500.0],
+                secs: 30
+            })
+        );
+    }
+
+    #[test]
+    fn reject_bad_profiles() {
+        for bad in &[
+            "ramp",
+            "ramp:0-2000ops",
+            "ramp:2000ops/60s",
+            "ramp:-5-10ops/60s",
+            "step:/30s",
+            "step:100,,500ops/30s",
+            "step:100ops/soon",
+            "sine:100ops/30s",
+        ] {
+            assert!(bad.parse::<Profile>().is_err(), "{} was accepted", bad);
+        }
+    }
+
+    #[test]
+    fn ramp_rate() {
+        let ramp: Profile = "ramp:0-2000ops/60s".parse().unwrap();
+
+        assert_eq!(ramp.rate_at(Duration::from_secs(0)), Some(0.0));
+        assert_eq!(ramp.rate_at(Duration::from_secs(30)), Some(1000.0));
+        assert_eq!(ramp.rate_at(Duration::from_secs(60)), Some(2000.0));
+        assert_eq!(ramp.rate_at(Duration::from_secs(600)), Some(2000.0));
+        assert_eq!(Profile::None.rate_at(Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn step_rate() {
+        let steps: Profile = "step:100,500,50ops/30s".parse().unwrap();
+
+        assert_eq!(steps.rate_at(Duration::from_secs(0)), Some(100.0));
+        assert_eq!(steps.rate_at(Duration::from_millis(29_999)), Some(100.0));
+        assert_eq!(steps.rate_at(Duration::from_secs(30)), Some(500.0));
+        assert_eq!(steps.rate_at(Duration::from_secs(60)), Some(50.0));
+        // The last step holds for the rest of the phase.
+        assert_eq!(steps.rate_at(Duration::from_secs(3600)), Some(50.0));
+    }
+
+    #[test]
+    fn profile_round_trip() {
+        for profile in &[
+            "none",
+            "ramp:0-2000ops/60s",
+            "ramp:0.5-1.5ops/10s",
+            "step:100,500ops/30s",
+        ] {
+            let parsed: Profile = profile.parse().unwrap();
+            assert_eq!(String::from(parsed.clone()).parse::<Profile>(), Ok(parsed));
+        }
+        assert_eq!(
+            String::from(Profile::Steps {
+                rates: vec![100.0, 500.0],
+                secs: 30
+            }),
+            "step:100,500ops/30s"
+        );
+    }
+}
@@ -0,0 +1,106 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use libmanta::moray::MantaObjectShark;
+use rand::rngs::StdRng;
+use rand::Rng;
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Decides how many times a phase goes over the test objects.  Without
+/// `--duration` that is once.  With it the phase keeps cycling until time is
+/// up, altering the objects afresh each cycle so that every write changes
+/// them.
+///
+/// Every worker gives a cycle the same new last shark, and once time is up
+/// each finishes the furthest cycle any worker has begun, so the objects
+/// still agree on their last shark after the phase.
+pub struct Cycles {
+    deadline: Option<Instant>,
+    state: Mutex<CycleState>,
+}
+
+// Mixed into `config.seed` so that the cycles' sharks don't repeat the ones
+// the runner chooses from the same seed.
+const SEED_MIX: u64 = 0x9e37_79b9_7f4a_7c15;
+
+struct CycleState {
+    // The furthest cycle begun by any worker.
+    begun: u64,
+    // The last shark for each cycle after the first, which uses the phase's
+    // original alteration.
+    sharks: Vec<MantaObjectShark>,
+    rng: StdRng,
+}
+
+impl Cycles {
+    /// Start timing the phase.
+    pub fn new(config: &Config) -> Cycles {
+        let deadline = if config.duration_secs > 0 {
+            Some(Instant::now() + Duration::from_secs(config.duration_secs))
+        } else {
+            None
+        };
+
+        Cycles {
+            deadline,
+            state: Mutex::new(CycleState {
+                begun: 0,
+                sharks: vec![],
+                rng: crate::seeded_rng(config.seed.map(|seed| seed ^ SEED_MIX)),
+            }),
+        }
+    }
+
+    /// Whether to begin cycle `cycle`, counting from zero, and if so the
+    /// shark to make the last shark of every object.  Cycle zero is always
+    /// run and never asked about.
+    pub fn next(&self, cycle: u64) -> Option<MantaObjectShark> {
+        let mut state = self.state.lock().unwrap();
+        let in_time = match self.deadline {
+            Some(deadline) => Instant::now() < deadline && !crate::shutdown::requested(),
+            None => false,
+        };
+
+        if !in_time && cycle > state.begun {
+            return None;
+        }
+
+        state.begun = state.begun.max(cycle);
+        while state.sharks.len() < cycle as usize {
+            let shark = random_shark(&mut state.rng);
+            state.sharks.push(shark);
+        }
+
+        Some(state.sharks[cycle as usize - 1].clone())
+    }
+
+    /// Number of cycles over the objects begun so far.
+    pub fn count(&self) -> u64 {
+        self.state.lock().unwrap().begun + 1
+    }
+}
+
+/// A shark in a made up datacenter, used to alter the test objects.
+pub fn random_shark<R: Rng>(rng: &mut R) -> MantaObjectShark {
+    let rand_id: u16 = rng.gen();
+
+    MantaObjectShark {
+        datacenter: crate::random_string(rng, 10),
+        manta_storage_id: format!("{}.stor.domain", rand_id),
+    }
+}
+
+/// Replace the last shark of an altered object.
+pub fn set_last_shark(value: &mut Value, shark: &MantaObjectShark) {
+    if let Some(last) = value
+        .get_mut("sharks")
+        .and_then(Value::as_array_mut)
+        .and_then(|sharks| sharks.last_mut())
+    {
+        *last = serde_json::to_value(shark).unwrap();
+    }
+}
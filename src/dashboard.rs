@@ -0,0 +1,382 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! A live view of the run on the terminal, with `--dashboard`.
+//!
+//! The scrolling output says what each phase came to once it is over, which
+//! on a long run leaves nothing to go on in the meantime.  The dashboard takes
+//! over the terminal instead and shows, for every phase running: how far it
+//! has got, its throughput and p99 latency over the last minute or so, the
+//! errors requests have failed with, and what each worker is doing.  Pressing
+//! q or Ctrl-C stops the run the way SIGINT does.
+
+use crate::config::Config;
+use crate::dump;
+use crate::shutdown;
+use crate::stats::{PhaseStats, WorkerState};
+use crossterm::event::{self, Event, KeyCode, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::tty::IsTty;
+use failure::Error;
+use hdrhistogram::Histogram;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, Stdout};
+use std::sync::mpsc::{self, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tui::backend::CrosstermBackend;
+use tui::layout::{Constraint, Direction, Layout};
+use tui::style::{Color, Style};
+use tui::text::Spans;
+use tui::widgets::{Block, Borders, Paragraph, Row, Sparkline, Table};
+use tui::Terminal;
+
+// How often the dashboard is redrawn, which is also the interval each point
+// of the sparklines covers.
+const REFRESH: Duration = Duration::from_millis(500);
+
+// Points kept for the sparklines, more than a terminal is wide.
+const HISTORY: usize = 512;
+
+type Screen = Terminal<CrosstermBackend<Stdout>>;
+
+// What a phase had done as of the previous refresh.
+struct Seen {
+    objects: u64,
+    latency: Histogram<u64>,
+    errors: BTreeMap<String, u64>,
+}
+
+// Everything shown besides what is read from the running phases each time.
+struct History {
+    started: Instant,
+    // Objects per second and p99 latency in microseconds at each refresh,
+    // across every running phase.
+    throughput: VecDeque<u64>,
+    p99: VecDeque<u64>,
+    // Failed requests of each kind, including those of phases that are over.
+    errors: BTreeMap<String, u64>,
+    seen: HashMap<usize, Seen>,
+}
+
+/// The dashboard, drawn on a thread of its own until it is stopped.
+pub struct Dashboard {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Dashboard {
+    /// Take over the terminal if `config.dashboard` is set.  Nothing is drawn
+    /// if the output isn't a terminal.
+    pub fn start(config: &Config) -> Result<Dashboard, Error> {
+        let mut dashboard = Dashboard {
+            stop: None,
+            handle: None,
+        };
+        if !config.dashboard {
+            return Ok(dashboard);
+        }
+        if !io::stdout().is_tty() {
+            println!("Not drawing the dashboard, the output isn't a terminal");
+            return Ok(dashboard);
+        }
+
+        terminal::enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        execute!(stdout, EnterAlternateScreen)?;
+        let mut screen = Terminal::new(CrosstermBackend::new(stdout))?;
+        screen.hide_cursor()?;
+
+        let (tx, rx) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            let mut history = History {
+                started: Instant::now(),
+                throughput: VecDeque::new(),
+                p99: VecDeque::new(),
+                errors: BTreeMap::new(),
+                seen: HashMap::new(),
+            };
+
+            loop {
+                if let Err(e) = handle_keys(&mut screen) {
+                    eprintln!("dashboard: {}", e);
+                }
+                if !matches!(rx.try_recv(), Err(TryRecvError::Empty)) {
+                    break;
+                }
+
+                let phases = dump::live();
+                history.update(&phases);
+                if let Err(e) = draw(&mut screen, &history, &phases) {
+                    eprintln!("dashboard: {}", e);
+                }
+            }
+
+            restore(&mut screen);
+        });
+
+        dashboard.stop = Some(tx);
+        dashboard.handle = Some(handle);
+        Ok(dashboard)
+    }
+
+    /// Give the terminal back.  Returns whether the dashboard was drawn, and
+    /// so drew over whatever the run printed in the meantime.
+    pub fn stop(mut self) -> bool {
+        drop(self.stop.take());
+        match self.handle.take() {
+            Some(handle) => {
+                let _ = handle.join();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn restore(screen: &mut Screen) {
+    let _ = execute!(screen.backend_mut(), LeaveAlternateScreen);
+    let _ = screen.show_cursor();
+    let _ = terminal::disable_raw_mode();
+}
+
+// Wait out the refresh interval for a key press.  The terminal is in raw mode,
+// so Ctrl-C arrives as a key rather than as SIGINT.
+fn handle_keys(screen: &mut Screen) -> Result<(), Error> {
+    if !event::poll(REFRESH)? {
+        return Ok(());
+    }
+
+    if let Event::Key(key) = event::read()? {
+        let ctrl_c =
+            key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+        if key.code == KeyCode::Char('q') || ctrl_c {
+            // A second stop exits at once, which mustn't leave the terminal
+            // in raw mode.
+            if shutdown::requested() {
+                restore(screen);
+            }
+            shutdown::request();
+        }
+    }
+
+    Ok(())
+}
+
+impl History {
+    fn update(&mut self, phases: &[Arc<PhaseStats>]) {
+        let mut objects = 0;
+        let mut latency = Histogram::<u64>::new(3).expect("latency histogram");
+        let mut seen = HashMap::new();
+
+        for stats in phases.iter() {
+            let id = Arc::as_ptr(stats) as usize;
+            let current = Seen {
+                objects: stats.objects(),
+                latency: stats.latency(),
+                errors: stats.errors(),
+            };
+
+            let mut interval = current.latency.clone();
+            match self.seen.get(&id) {
+                Some(before) => {
+                    objects += current.objects.saturating_sub(before.objects);
+                    if interval.subtract(&before.latency).is_err() {
+                        interval.reset();
+                    }
+                    for (name, count) in current.errors.iter() {
+                        let before = before.errors.get(name).copied().unwrap_or(0);
+                        *self.errors.entry(name.clone()).or_insert(0) +=
+                            count.saturating_sub(before);
+                    }
+                }
+                None => {
+                    objects += current.objects;
+                    for (name, count) in current.errors.iter() {
+                        *self.errors.entry(name.clone()).or_insert(0) += count;
+                    }
+                }
+            }
+            let _ = latency.add(&interval);
+            seen.insert(id, current);
+        }
+        self.seen = seen;
+
+        let rate = objects as f64 / REFRESH.as_secs_f64();
+        push(&mut self.throughput, rate as u64);
+        push(&mut self.p99, latency.value_at_percentile(99.0));
+    }
+}
+
+fn push(points: &mut VecDeque<u64>, point: u64) {
+    if points.len() == HISTORY {
+        points.pop_front();
+    }
+    points.push_back(point);
+}
+
+// The last `width` points, oldest first, to fill a sparkline that wide.
+fn last(points: &VecDeque<u64>, width: u16) -> Vec<u64> {
+    let skip = points.len().saturating_sub(width as usize);
+    points.iter().skip(skip).copied().collect()
+}
+
+fn draw(screen: &mut Screen, history: &History, phases: &[Arc<PhaseStats>]) -> Result<(), Error> {
+    // Whatever the run prints lands on the same screen, so each frame is
+    // drawn afresh over it rather than as changes to the last one.
+    screen.clear()?;
+
+    screen.draw(|f| {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(
+                [
+                    Constraint::Length(phases.len().max(1) as u16 + 3),
+                    Constraint::Length(6),
+                    Constraint::Length(6),
+                    Constraint::Min(4),
+                ]
+                .as_ref(),
+            )
+            .split(f.size());
+        let bottom = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(35), Constraint::Percentage(65)].as_ref())
+            .split(rows[3]);
+
+        let mut lines = vec![Spans::from(format!(
+            "{:.0}s in, q to stop",
+            history.started.elapsed().as_secs_f64()
+        ))];
+        if phases.is_empty() {
+            lines.push(Spans::from("No phase running"));
+        }
+        for stats in phases.iter() {
+            lines.push(Spans::from(phase_line(stats)));
+        }
+        let title = if shutdown::requested() {
+            " moray batch test: stopping "
+        } else {
+            " moray batch test "
+        };
+        f.render_widget(
+            Paragraph::new(lines).block(Block::default().title(title).borders(Borders::ALL)),
+            rows[0],
+        );
+
+        let width = rows[1].width.saturating_sub(2);
+        let throughput = last(&history.throughput, width);
+        let rate = throughput.last().copied().unwrap_or(0);
+        f.render_widget(
+            Sparkline::default()
+                .block(
+                    Block::default()
+                        .title(format!(" objects/s: {} ", rate))
+                        .borders(Borders::ALL),
+                )
+                .style(Style::default().fg(Color::Green))
+                .data(&throughput),
+            rows[1],
+        );
+
+        let p99 = last(&history.p99, width);
+        let latest = p99.last().copied().unwrap_or(0);
+        f.render_widget(
+            Sparkline::default()
+                .block(
+                    Block::default()
+                        .title(format!(" p99 latency: {:.1}ms ", latest as f64 / 1000.0))
+                        .borders(Borders::ALL),
+                )
+                .style(Style::default().fg(Color::Yellow))
+                .data(&p99),
+            rows[2],
+        );
+
+        let errors: Vec<Row> = history
+            .errors
+            .iter()
+            .map(|(name, count)| Row::new(vec![count.to_string(), name.clone()]))
+            .collect();
+        f.render_widget(
+            Table::new(errors)
+                .header(Row::new(vec!["count", "error"]).style(Style::default().fg(Color::Cyan)))
+                .block(Block::default().title(" errors ").borders(Borders::ALL))
+                .widths(&[Constraint::Length(8), Constraint::Min(10)]),
+            bottom[0],
+        );
+
+        let workers: Vec<Row> = phases
+            .iter()
+            .flat_map(|stats| {
+                stats
+                    .worker_status()
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(i, status)| {
+                        let style = match status.state {
+                            WorkerState::Failed => Style::default().fg(Color::Red),
+                            WorkerState::Done => Style::default().fg(Color::DarkGray),
+                            _ => Style::default(),
+                        };
+                        Row::new(vec![
+                            format!("{} {}", stats.shard, stats.phase),
+                            i.to_string(),
+                            format!("{:?}", status.state).to_lowercase(),
+                            status.requests.to_string(),
+                            status.failed_requests.to_string(),
+                            status.last_error.unwrap_or_default(),
+                        ])
+                        .style(style)
+                    })
+            })
+            .collect();
+        f.render_widget(
+            Table::new(workers)
+                .header(
+                    Row::new(vec![
+                        "phase",
+                        "worker",
+                        "state",
+                        "requests",
+                        "failed",
+                        "last error",
+                    ])
+                    .style(Style::default().fg(Color::Cyan)),
+                )
+                .block(Block::default().title(" workers ").borders(Borders::ALL))
+                .widths(&[
+                    Constraint::Length(16),
+                    Constraint::Length(6),
+                    Constraint::Length(8),
+                    Constraint::Length(9),
+                    Constraint::Length(7),
+                    Constraint::Min(10),
+                ]),
+            bottom[1],
+        );
+    })?;
+
+    Ok(())
+}
+
+fn phase_line(stats: &PhaseStats) -> String {
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    format!(
+        "shard {} {}: {:.1}s in, {} requests, {} objects, {} failed, {} retries, \
+         {} timeouts, p50 {:.1}ms p99 {:.1}ms",
+        stats.shard,
+        stats.phase,
+        stats.elapsed().as_secs_f64(),
+        stats.requests(),
+        stats.objects(),
+        stats.failed_requests(),
+        stats.retries(),
+        stats.timeouts(),
+        ms(stats.latency_percentile(50.0)),
+        ms(stats.latency_percentile(99.0))
+    )
+}
@@ -0,0 +1,227 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! Generated test objects saved to a file, so that the same objects can be
+//! seeded again or checked against the bucket later, whatever version of the
+//! tool made them.
+//!
+//! A dataset is JSON lines: a header giving the format version, the run id
+//! the keys are prefixed with and the number of objects, then one
+//! `{"key": ..., "value": ...}` line per object in the order it was written.
+//! Values are kept as moray stores them rather than as manta objects, so a
+//! change to the object type doesn't make old datasets unreadable.
+
+use crate::config::Config;
+use crate::InternalError;
+use failure::Error;
+use libmanta::moray::MantaObject;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Lines, Write};
+
+const VERSION: u32 = 1;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Header {
+    pub version: u32,
+    pub run_id: Option<String>,
+    pub objects: u32,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    key: String,
+    value: Value,
+}
+
+// An entry as written, borrowing the object rather than copying it.
+#[derive(Serialize)]
+struct EntryRef<'a> {
+    key: &'a str,
+    value: &'a Value,
+}
+
+fn invalid(path: &str, line: usize, message: &str) -> Error {
+    InternalError::InvalidDataset(format!("{} line {}: {}", path, line, message)).into()
+}
+
+/// Writes the objects of a run to a dataset as they are generated.  The first
+/// error is kept and returned by `finish`, so that a failed write doesn't
+/// stop the objects being seeded.
+pub struct Writer {
+    path: String,
+    out: BufWriter<File>,
+    written: u32,
+    error: Option<io::Error>,
+}
+
+impl Writer {
+    /// Create the dataset at `path` for `config.objects` objects keyed under
+    /// `config.run_id`.
+    pub fn create(path: &str, config: &Config) -> Result<Writer, Error> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let header = Header {
+            version: VERSION,
+            run_id: config.run_id.clone(),
+            objects: config.objects,
+        };
+        serde_json::to_writer(&mut out, &header)?;
+        out.write_all(b"\n")?;
+
+        Ok(Writer {
+            path: path.to_string(),
+            out,
+            written: 0,
+            error: None,
+        })
+    }
+
+    pub fn write(&mut self, key: &str, value: &Value) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let entry = EntryRef { key, value };
+        let result = serde_json::to_writer(&mut self.out, &entry)
+            .map_err(io::Error::from)
+            .and_then(|_| self.out.write_all(b"\n"));
+        match result {
+            Ok(()) => self.written += 1,
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    pub fn finish(mut self) -> Result<(), Error> {
+        if let Some(e) = self.error.take() {
+            return Err(e.into());
+        }
+        self.out.flush()?;
+        println!("Saved {} objects to {}", self.written, self.path);
+
+        Ok(())
+    }
+}
+
+/// Reads the objects of a dataset back one at a time, in the order they were
+/// saved.
+pub struct Reader {
+    path: String,
+    header: Header,
+    lines: Lines<BufReader<File>>,
+    line: usize,
+}
+
+impl Reader {
+    pub fn open(path: &str) -> Result<Reader, Error> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let first = match lines.next() {
+            Some(line) => line?,
+            None => return Err(invalid(path, 1, "empty file")),
+        };
+        let header: Header =
+            serde_json::from_str(&first).map_err(|e| invalid(path, 1, &e.to_string()))?;
+        if header.version != VERSION {
+            let message = format!("unsupported version {}", header.version);
+            return Err(invalid(path, 1, &message));
+        }
+
+        Ok(Reader {
+            path: path.to_string(),
+            header,
+            lines,
+            line: 1,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+}
+
+impl Iterator for Reader {
+    type Item = Result<(String, Value), Error>;
+
+    fn next(&mut self) -> Option<Result<(String, Value), Error>> {
+        let line = self.lines.next()?;
+        self.line += 1;
+
+        let entry = line.map_err(Error::from).and_then(|line| {
+            serde_json::from_str::<Entry>(&line)
+                .map_err(|e| invalid(&self.path, self.line, &e.to_string()))
+        });
+        Some(entry.map(|entry| (entry.key, entry.value)))
+    }
+}
+
+/// Make `config` describe the objects in `config.load_dataset`, if it is set:
+/// their number, and the run id their keys are under if none was given.
+/// Fails if a different run id was given, as the keys wouldn't match it.
+pub fn adopt(config: &mut Config) -> Result<(), Error> {
+    let path = match &config.load_dataset {
+        Some(path) => path.clone(),
+        None => return Ok(()),
+    };
+    let reader = Reader::open(&path)?;
+    let header = reader.header();
+
+    match (&config.run_id, &header.run_id) {
+        (Some(given), Some(saved)) if given != saved => {
+            let message = format!("{} holds the objects of run {}, not {}", path, saved, given);
+            return Err(InternalError::InvalidDataset(message).into());
+        }
+        (None, Some(saved)) => config.run_id = Some(saved.clone()),
+        _ => (),
+    }
+    config.objects = header.objects;
+
+    Ok(())
+}
+
+// The fields the update phases rewrite, which can't be expected to match the
+// dataset after a bench run.
+static REWRITTEN: &[&str] = &["sharks", "mtime"];
+
+fn unchanging(value: &Value) -> Value {
+    let mut value = value.clone();
+    if let Some(fields) = value.as_object_mut() {
+        for field in REWRITTEN.iter() {
+            fields.remove(*field);
+        }
+    }
+
+    value
+}
+
+/// Check the test objects read back from the bucket against the dataset at
+/// `path`: every saved object should be there, no others, and each should
+/// still hold what was saved apart from the fields the update phases
+/// rewrite.  Returns a description of each difference.
+pub fn compare(
+    path: &str,
+    test_objects: &HashMap<String, MantaObject>,
+) -> Result<Vec<String>, Error> {
+    let mut problems = vec![];
+    let mut seen = HashSet::new();
+
+    for entry in Reader::open(path)? {
+        let (key, saved) = entry?;
+        match test_objects.get(&key) {
+            Some(mobj) => {
+                if unchanging(&serde_json::to_value(mobj)?) != unchanging(&saved) {
+                    problems.push(format!("{} differs from the dataset", key));
+                }
+            }
+            None => problems.push(format!("{} is missing", key)),
+        }
+        seen.insert(key);
+    }
+
+    for key in test_objects.keys().filter(|key| !seen.contains(*key)) {
+        problems.push(format!("{} isn't in the dataset", key));
+    }
+
+    Ok(problems)
+}
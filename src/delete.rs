@@ -0,0 +1,285 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use crate::pool::Pool;
+use crate::stats::{PhaseReport, PhaseStats};
+use crate::timeseries::Sampler;
+use crate::workers::{self, Context};
+use crate::{find, latency_csv, retry, trace};
+use failure::Error;
+use moray::objects::{self, BatchDeleteOp, BatchRequest};
+use opentelemetry::api::KeyValue;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+// Delete each key with its own delobject request, spreading the keys across
+// `config.workers` threads.
+pub fn run_sequential_delete_test(
+    pool: &Pool,
+    config: &Config,
+    keys: Vec<String>,
+) -> Result<PhaseReport, Error> {
+    if config.workers > 1 {
+        println!(
+            "Deleting objects sequentially with {} workers",
+            config.workers
+        );
+    } else {
+        println!("Deleting objects sequentially");
+    }
+
+    let stats = Arc::new(PhaseStats::new(config, "sequential_delete"));
+    let shares = workers::split(keys, config.workers as usize);
+    run_delete_phase(pool, config, stats, shares, delete_objects)
+}
+
+// Delete the keys `config.batch_size` at a time, spreading the batches across
+// `config.workers` threads.
+pub fn run_batch_delete_test(
+    pool: &Pool,
+    config: &Config,
+    keys: Vec<String>,
+) -> Result<PhaseReport, Error> {
+    let batch_size = config.batch_size.max(1);
+
+    if config.workers > 1 {
+        println!(
+            "Deleting objects in batches of {} with {} workers",
+            batch_size, config.workers
+        );
+    } else {
+        println!("Deleting objects in batches of {}", batch_size);
+    }
+
+    let batches: Vec<(usize, Vec<String>)> = keys
+        .chunks(batch_size as usize)
+        .map(|chunk| chunk.to_vec())
+        .enumerate()
+        .collect();
+    let stats = Arc::new(PhaseStats::new(config, "batch_delete"));
+    let shares = workers::split(batches, config.workers as usize);
+    let mut report = run_delete_phase(pool, config, stats, shares, delete_batches)?;
+    report.batch_size = Some(batch_size);
+
+    Ok(report)
+}
+
+// Delete the objects with these object ids through deletemany requests, each
+// filtering on `config.batch_size` of them, spread across `config.workers`
+// threads.
+pub fn run_delete_many_test(
+    pool: &Pool,
+    config: &Config,
+    ids: Vec<String>,
+) -> Result<PhaseReport, Error> {
+    let batch_size = config.batch_size.max(1);
+
+    if config.workers > 1 {
+        println!(
+            "Deleting objects with deletemany, {} at a time with {} workers",
+            batch_size, config.workers
+        );
+    } else {
+        println!("Deleting objects with deletemany, {} at a time", batch_size);
+    }
+
+    let filters: Vec<(usize, Vec<String>, String)> = ids
+        .chunks(batch_size as usize)
+        .enumerate()
+        .map(|(id, chunk)| (id, chunk.to_vec(), find::object_id_filter(chunk)))
+        .collect();
+    let stats = Arc::new(PhaseStats::new(config, "delete_many"));
+    let shares = workers::split(filters, config.workers as usize);
+    let mut report = run_delete_phase(pool, config, stats, shares, delete_many)?;
+    report.batch_size = Some(batch_size);
+
+    Ok(report)
+}
+
+fn run_delete_phase<T>(
+    pool: &Pool,
+    config: &Config,
+    stats: Arc<PhaseStats>,
+    shares: Vec<T>,
+    work: workers::WorkFn<T>,
+) -> Result<PhaseReport, Error>
+where
+    T: Send + 'static,
+{
+    let sampler = Sampler::start(config, &stats);
+    let started = SystemTime::now();
+    let result = workers::run(config, pool, &stats, shares, work);
+    sampler.stop();
+    latency_csv::flush();
+    let elapsed = result?;
+
+    println!(
+        "Done deleting {} objects in {} requests: {}ms",
+        stats.objects(),
+        stats.requests(),
+        elapsed.as_millis()
+    );
+    stats.print_throughput(elapsed);
+    stats.print_latency();
+    stats.print_retries();
+    stats.print_failures();
+
+    Ok(stats.report(started, elapsed))
+}
+
+fn delete_objects(ctx: &Context, keys: Vec<String>) -> Result<(), Error> {
+    let opts = objects::MethodOptions::default();
+    let bucket = ctx.config.bucket.as_str();
+
+    for key in workers::until_stopped(keys) {
+        let start = ctx.pace();
+        let started = SystemTime::now() - start.elapsed();
+        let span = trace::span(
+            "delete",
+            vec![
+                KeyValue::new("shard", ctx.config.shard.to_string()),
+                KeyValue::new("key", key.clone()),
+            ],
+        );
+        let result = ctx.call(|c| {
+            c.delete_object(bucket, &key, &opts, |_| Ok(()))?;
+            Ok(())
+        });
+        latency_csv::record(
+            ctx.config.shard,
+            "sequential_delete",
+            &key,
+            started,
+            start.elapsed(),
+            &result,
+        );
+        if let Err(e) = &result {
+            trace::set_error(&span, retry::error_name(e));
+        }
+        drop(span);
+
+        match result {
+            Ok(()) => ctx
+                .stats
+                .record_request(1, key.len() as u64, start.elapsed()),
+            Err(e) if ctx.config.continue_on_error => {
+                eprintln!("delete {}: {}", key, e);
+                ctx.stats.record_failure(vec![key], &e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+fn delete_batches(ctx: &Context, batches: Vec<(usize, Vec<String>)>) -> Result<(), Error> {
+    let opts = objects::MethodOptions::default();
+
+    for (id, keys) in workers::until_stopped(batches) {
+        let batch: Vec<BatchRequest> = keys
+            .iter()
+            .map(|key| {
+                BatchRequest::Delete(BatchDeleteOp {
+                    bucket: ctx.config.bucket.clone(),
+                    key: key.clone(),
+                    options: opts.clone(),
+                })
+            })
+            .collect();
+        let bytes = keys.iter().map(|key| key.len() as u64).sum();
+
+        let start = ctx.pace();
+        let started = SystemTime::now() - start.elapsed();
+        let span = trace::span(
+            "batch_delete",
+            vec![
+                KeyValue::new("shard", ctx.config.shard.to_string()),
+                KeyValue::new("size", batch.len().to_string()),
+            ],
+        );
+        let result = ctx.call(|c| {
+            c.batch(&batch, &opts, |_| Ok(()))?;
+            Ok(())
+        });
+        latency_csv::record(
+            ctx.config.shard,
+            "batch_delete",
+            &id.to_string(),
+            started,
+            start.elapsed(),
+            &result,
+        );
+        if let Err(e) = &result {
+            trace::set_error(&span, retry::error_name(e));
+        }
+        drop(span);
+
+        match result {
+            Ok(()) => ctx
+                .stats
+                .record_request(keys.len() as u64, bytes, start.elapsed()),
+            Err(e) if ctx.config.continue_on_error => {
+                eprintln!("batch delete of {}: {}", keys.len(), e);
+                ctx.stats.record_failure(keys, &e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+// Each filter is sent with the object ids it matches on.  The phase counts
+// the rows moray says it deleted, and the ids of a request that failed.
+fn delete_many(ctx: &Context, filters: Vec<(usize, Vec<String>, String)>) -> Result<(), Error> {
+    let opts = objects::MethodOptions::default();
+    let bucket = ctx.config.bucket.as_str();
+
+    for (id, ids, filter) in workers::until_stopped(filters) {
+        let start = ctx.pace();
+        let started = SystemTime::now() - start.elapsed();
+        let span = trace::span(
+            "delete_many",
+            vec![
+                KeyValue::new("shard", ctx.config.shard.to_string()),
+                KeyValue::new("size", ids.len().to_string()),
+            ],
+        );
+        let result = ctx.call(|c| {
+            let mut deleted = 0;
+            c.delete_many(bucket, &filter, &opts, |response| {
+                deleted += response["count"].as_u64().unwrap_or(0);
+                Ok(())
+            })?;
+            Ok(deleted)
+        });
+        latency_csv::record(
+            ctx.config.shard,
+            "delete_many",
+            &id.to_string(),
+            started,
+            start.elapsed(),
+            &result,
+        );
+        if let Err(e) = &result {
+            trace::set_error(&span, retry::error_name(e));
+        }
+        drop(span);
+
+        match result {
+            Ok(deleted) => ctx
+                .stats
+                .record_request(deleted, filter.len() as u64, start.elapsed()),
+            Err(e) if ctx.config.continue_on_error => {
+                eprintln!("deletemany of {}: {}", ids.len(), e);
+                ctx.stats.record_failure(ids, &e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
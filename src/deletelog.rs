@@ -0,0 +1,224 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::{Config, Index};
+use crate::pool::Pool;
+use crate::stats::{PhaseReport, PhaseStats};
+use crate::timeseries::Sampler;
+use crate::workers::{self, Context};
+use crate::{get_or_create_bucket, latency_csv, retry, shutdown, trace};
+use failure::Error;
+use libmanta::moray::MantaObject;
+use moray::client::MorayClient;
+use moray::objects::{self, BatchDeleteOp, BatchRequest};
+use opentelemetry::api::KeyValue;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The delete log bucket, next to the test bucket, with the one index the
+/// consumer finds records by.
+pub fn delete_log_config(config: &Config) -> Config {
+    let mut config = config.clone();
+    let mut index = BTreeMap::new();
+    index.insert(
+        String::from("objectId"),
+        Index {
+            kind: String::from("string"),
+            unique: false,
+        },
+    );
+
+    config.bucket = format!("{}_delete_log", config.bucket);
+    config.companion_buckets = vec![];
+    config.index = index;
+    config.duration_secs = 0;
+
+    config
+}
+
+/// Create the delete log bucket if it doesn't exist.
+pub fn create_bucket(client: &mut MorayClient, config: &Config) {
+    get_or_create_bucket(client, &delete_log_config(config));
+}
+
+/// A delete log record for each test object, as muskie records one for an
+/// object it deletes: who owned it, where its copies are, and when it went.
+pub fn records(test_objects: &HashMap<String, MantaObject>) -> Vec<(String, Value)> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let mut records: Vec<(String, Value)> = test_objects
+        .iter()
+        .map(|(key, mobj)| {
+            let record = json!({
+                "objectId": mobj.object_id,
+                "key": mobj.key,
+                "owner": mobj.owner,
+                "sharks": mobj.sharks,
+                "deletedAt": now,
+            });
+            (key.clone(), record)
+        })
+        .collect();
+    records.sort_by(|a, b| a.0.cmp(&b.0));
+
+    records
+}
+
+// The consumer's share: the number of delete instructions made for the
+// storage nodes, counted across the phase.
+struct Consumer {
+    instructions: Arc<AtomicU64>,
+}
+
+/// Consume the delete log the way the garbage collector does, until it is
+/// empty: find `config.batch_size` records, work out which storage nodes
+/// have copies to delete, and remove the records with one batch delete.  Each
+/// find and delete together count as one request, so the throughput is
+/// records consumed per second.  The collector runs one consumer per shard,
+/// so this does too, whatever `config.workers` is.
+pub fn run_consume_test(pool: &Pool, config: &Config) -> Result<PhaseReport, Error> {
+    let config = delete_log_config(config);
+    let batch_size = config.batch_size.max(1);
+
+    println!("Consuming {} in batches of {}", config.bucket, batch_size);
+
+    let instructions = Arc::new(AtomicU64::new(0));
+    let stats = Arc::new(PhaseStats::new(&config, "delete_log_consume"));
+    let shares = vec![Consumer {
+        instructions: Arc::clone(&instructions),
+    }];
+    let sampler = Sampler::start(&config, &stats);
+    let started = SystemTime::now();
+    let result = workers::run(&config, pool, &stats, shares, consume);
+    sampler.stop();
+    latency_csv::flush();
+    let elapsed = result?;
+
+    println!(
+        "Done consuming {} records in {} batches, {} delete instructions: {}ms",
+        stats.objects(),
+        stats.requests(),
+        instructions.load(Ordering::Relaxed),
+        elapsed.as_millis()
+    );
+    stats.print_throughput(elapsed);
+    stats.print_latency();
+    stats.print_retries();
+    stats.print_failures();
+
+    let mut report = stats.report(started, elapsed);
+    report.batch_size = Some(batch_size);
+
+    Ok(report)
+}
+
+// Stand in for the collector's processing of a batch: one delete instruction
+// per distinct storage node holding a copy of each record's object.
+fn process(records: &[(String, Value)]) -> u64 {
+    records
+        .iter()
+        .map(|(_, record)| {
+            let sharks: HashSet<&str> = record["sharks"]
+                .as_array()
+                .map(|sharks| {
+                    sharks
+                        .iter()
+                        .filter_map(|shark| shark["manta_storage_id"].as_str())
+                        .collect()
+                })
+                .unwrap_or_default();
+            sharks.len() as u64
+        })
+        .sum()
+}
+
+fn consume(ctx: &Context, consumer: Consumer) -> Result<(), Error> {
+    let bucket = ctx.config.bucket.as_str();
+    let filter = ctx.config.key_filter();
+    let opts = objects::MethodOptions::default();
+    let mut find_opts = objects::MethodOptions::default();
+    find_opts.limit = Some(u64::from(ctx.config.batch_size.max(1)));
+    let mut id = 0;
+
+    while !shutdown::requested() {
+        let start = ctx.pace();
+        let started = SystemTime::now() - start.elapsed();
+        let span = trace::span(
+            "delete_log_consume",
+            vec![
+                KeyValue::new("shard", ctx.config.shard.to_string()),
+                KeyValue::new("batch", id.to_string()),
+            ],
+        );
+
+        let records = ctx.call(|c| {
+            let mut records = vec![];
+            c.find_objects(bucket, &filter, &find_opts, |mobj| {
+                records.push((mobj.key.clone(), mobj.value.clone()));
+                Ok(())
+            })?;
+            Ok(records)
+        })?;
+        if records.is_empty() {
+            break;
+        }
+
+        let instructions = process(&records);
+        let keys: Vec<String> = records.into_iter().map(|(key, _)| key).collect();
+        let batch: Vec<BatchRequest> = keys
+            .iter()
+            .map(|key| {
+                BatchRequest::Delete(BatchDeleteOp {
+                    bucket: bucket.to_string(),
+                    options: objects::MethodOptions::default(),
+                    key: key.clone(),
+                })
+            })
+            .collect();
+        let bytes = keys.iter().map(|key| key.len() as u64).sum();
+
+        let result = ctx.call(|c| {
+            c.batch(&batch, &opts, |_| Ok(()))?;
+            Ok(())
+        });
+        latency_csv::record(
+            ctx.config.shard,
+            "delete_log_consume",
+            &id.to_string(),
+            started,
+            start.elapsed(),
+            &result,
+        );
+        if let Err(e) = &result {
+            trace::set_error(&span, retry::error_name(e));
+        }
+        drop(span);
+        id += 1;
+
+        // A batch that failed for good leaves its records in the log, where
+        // the next find would return them again, so stop rather than spin.
+        match result {
+            Ok(()) => {
+                consumer
+                    .instructions
+                    .fetch_add(instructions, Ordering::Relaxed);
+                ctx.stats
+                    .record_request(keys.len() as u64, bytes, start.elapsed());
+            }
+            Err(e) if ctx.config.continue_on_error => {
+                eprintln!("batch of {}: {}", keys.len(), e);
+                ctx.stats.record_failure(keys, &e);
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,422 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! Load driven from several machines at once, for shards one client zone
+//! can't saturate.
+//!
+//! A coordinator waits for the given number of agents to connect, then hands
+//! each of them the same command line along with its place among them, and
+//! they all start at once.  Each agent runs the command against its own slice
+//! of the keyspace: its own run id, the coordinator's with `-<agent>` after
+//! it, and its share of the objects and of the offered load.  A bench run
+//! therefore has to be seeded through the coordinator too, with the same run
+//! id and number of agents.
+//!
+//! When an agent is done it sends the coordinator what it would have written
+//! to `--output`, and the coordinator adds the phases up across agents.  The
+//! agents' latency histograms stay with them, so the combined latency
+//! percentiles are those of the slowest agent.
+//!
+//! Messages are a line of JSON each: an `Assignment` from the coordinator and
+//! an `AgentReport` back.
+
+use crate::config::Config;
+use crate::report::ShardReport;
+use crate::stats;
+use crate::{InternalError, RunSummary};
+use failure::{err_msg, Error};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::SystemTime;
+
+// The lists of phases in a shard report that are added up across agents.
+static PHASE_LISTS: &[&str] = &[
+    "sequential",
+    "batch",
+    "sweep",
+    "find",
+    "get",
+    "delete",
+    "update_objects",
+    "sql",
+    "reindex",
+    "unique",
+    "cas",
+    "consistency",
+    "rebalance",
+    "delete_log",
+];
+
+/// What the coordinator hands an agent.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Assignment {
+    /// The agent's place among them, from 0.
+    pub agent: u32,
+    pub agents: u32,
+    /// The options and subcommand to run, as they would be given on the
+    /// command line.
+    pub args: Vec<String>,
+}
+
+/// What an agent sends back once it is done.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AgentReport {
+    pub agent: u32,
+    /// A report for each shard and bucket the agent ran against, as written
+    /// by `--output`.
+    pub shards: Vec<Value>,
+    /// Why the agent couldn't run the command at all, if it couldn't.
+    pub error: Option<String>,
+}
+
+/// How one agent got on, as the coordinator saw it.
+#[derive(Clone, Debug, Serialize)]
+pub struct AgentResult {
+    pub agent: u32,
+    pub addr: String,
+    pub shards: Vec<Value>,
+    pub error: Option<String>,
+}
+
+impl AgentResult {
+    /// Whether the agent, or its run against any shard, failed.
+    pub fn failed(&self) -> bool {
+        self.error.is_some() || self.shards.iter().any(|shard| !shard["error"].is_null())
+    }
+}
+
+/// One phase added up across every agent that ran it.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CombinedPhase {
+    pub shard: u64,
+    /// The list the phase is in, such as "batch", and its place in it.
+    pub kind: String,
+    pub index: usize,
+    pub phase: String,
+    pub agents: u32,
+    pub requests: u64,
+    pub objects: u64,
+    /// The agents' rates added up.
+    pub objects_per_sec: f64,
+    /// Objects over the time from the first agent starting the phase to the
+    /// last finishing it, by the agents' clocks.
+    pub wall_objects_per_sec: f64,
+    pub failed_requests: u64,
+    pub retries: u64,
+    pub timeouts: u64,
+    /// The highest of each percentile among the agents.
+    pub worst_p50_us: u64,
+    pub worst_p99_us: u64,
+    pub max_us: u64,
+}
+
+/// Give `config` the slice of the keyspace and of the load that belongs to
+/// the agent given `assignment`.
+pub fn slice(config: &mut Config, assignment: &Assignment) {
+    let agents = assignment.agents.max(1);
+    let agent = assignment.agent;
+
+    if let Some(run_id) = &config.run_id {
+        config.run_id = Some(format!("{}-{}", run_id, agent));
+    }
+    config.objects = config.objects / agents + u32::from(agent < config.objects % agents);
+    config.rate /= f64::from(agents);
+    config.profile = config.profile.scaled(1.0 / f64::from(agents));
+    config.seed = config.seed.map(|seed| seed.wrapping_add(u64::from(agent)));
+}
+
+/// An agent's connection to its coordinator.
+pub struct Agent {
+    stream: TcpStream,
+    assignment: Assignment,
+}
+
+impl Agent {
+    /// Connect to the coordinator at `addr` and wait for it to hand out the
+    /// work, which it does once every agent has connected.
+    pub fn join(addr: &str) -> Result<Agent, Error> {
+        let stream = TcpStream::connect(addr).map_err(|e| InternalError::Connect {
+            addrs: addr.to_string(),
+            reason: e.to_string(),
+        })?;
+        println!(
+            "Connected to coordinator {}, waiting for the other agents",
+            addr
+        );
+
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+        let assignment: Assignment = serde_json::from_str(&line).map_err(|e| {
+            InternalError::InvalidArguments(format!("bad assignment from coordinator: {}", e))
+        })?;
+        println!(
+            "Running as agent {} of {}: {}",
+            assignment.agent,
+            assignment.agents,
+            assignment.args.join(" ")
+        );
+
+        Ok(Agent { stream, assignment })
+    }
+
+    pub fn assignment(&self) -> &Assignment {
+        &self.assignment
+    }
+
+    /// Send the coordinator the results of the agent's runs.
+    pub fn report(self, results: &[(u32, String, Result<RunSummary, Error>)]) -> Result<(), Error> {
+        let shards = results
+            .iter()
+            .map(|(shard, bucket, result)| {
+                serde_json::to_value(ShardReport::new(*shard, bucket, result))
+            })
+            .collect::<Result<Vec<Value>, _>>()?;
+
+        let agent = self.assignment.agent;
+        self.send(&AgentReport {
+            agent,
+            shards,
+            error: None,
+        })
+    }
+
+    /// Tell the coordinator that the agent couldn't run its command.
+    pub fn fail(self, e: &Error) -> Result<(), Error> {
+        let agent = self.assignment.agent;
+        self.send(&AgentReport {
+            agent,
+            shards: vec![],
+            error: Some(e.to_string()),
+        })
+    }
+
+    fn send(mut self, report: &AgentReport) -> Result<(), Error> {
+        writeln!(self.stream, "{}", serde_json::to_string(report)?)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+}
+
+/// Wait for `agents` agents to connect on `listen`, have them all run `args`
+/// at once, and collect what each of them came to.
+pub fn coordinate(
+    listen: SocketAddr,
+    agents: u32,
+    args: &[String],
+) -> Result<Vec<AgentResult>, Error> {
+    let listener = TcpListener::bind(listen)?;
+    println!(
+        "Waiting for {} agents on {}",
+        agents,
+        listener.local_addr()?
+    );
+
+    let mut connected = vec![];
+    while connected.len() < agents as usize {
+        let (stream, addr) = listener.accept()?;
+        println!(
+            "Agent {} connected from {} ({} of {})",
+            connected.len(),
+            addr,
+            connected.len() + 1,
+            agents
+        );
+        connected.push((stream, addr));
+    }
+
+    println!("Starting {} agents: {}", agents, args.join(" "));
+    let handles: Vec<_> = connected
+        .into_iter()
+        .enumerate()
+        .map(|(i, (stream, addr))| {
+            let assignment = Assignment {
+                agent: i as u32,
+                agents,
+                args: args.to_vec(),
+            };
+            thread::spawn(move || {
+                let result = run_agent(stream, &assignment);
+                let (shards, error) = match result {
+                    Ok(report) => (report.shards, report.error),
+                    Err(e) => (vec![], Some(e.to_string())),
+                };
+                if let Some(e) = &error {
+                    eprintln!("agent {} ({}) failed: {}", assignment.agent, addr, e);
+                } else {
+                    println!("Agent {} ({}) finished", assignment.agent, addr);
+                }
+
+                AgentResult {
+                    agent: assignment.agent,
+                    addr: addr.to_string(),
+                    shards,
+                    error,
+                }
+            })
+        })
+        .collect();
+
+    handles
+        .into_iter()
+        .map(|handle| {
+            handle
+                .join()
+                .map_err(|_| Error::from(InternalError::WorkerPanicked))
+        })
+        .collect()
+}
+
+// Hand an agent its assignment and wait for its report.
+fn run_agent(mut stream: TcpStream, assignment: &Assignment) -> Result<AgentReport, Error> {
+    writeln!(stream, "{}", serde_json::to_string(assignment)?)?;
+    stream.flush()?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    if line.is_empty() {
+        return Err(err_msg("the agent hung up without reporting"));
+    }
+
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Add up each phase across the agents that ran it, phases being matched by
+/// shard and by their place in the run.
+pub fn combine(results: &[AgentResult]) -> Vec<CombinedPhase> {
+    let mut combined: BTreeMap<(u64, usize, usize), CombinedPhase> = BTreeMap::new();
+    // The first start and last finish of each phase.
+    let mut spans: BTreeMap<(u64, usize, usize), (f64, f64)> = BTreeMap::new();
+
+    for shard in results.iter().flat_map(|result| result.shards.iter()) {
+        let shard_id = shard["shard"].as_u64().unwrap_or(0);
+        for (k, kind) in PHASE_LISTS.iter().enumerate() {
+            let phases = match shard[*kind].as_array() {
+                Some(phases) => phases,
+                None => continue,
+            };
+
+            for (index, phase) in phases.iter().enumerate() {
+                let key = (shard_id, k, index);
+                let total = combined.entry(key).or_insert_with(|| CombinedPhase {
+                    shard: shard_id,
+                    kind: kind.to_string(),
+                    index,
+                    phase: phase["phase"].as_str().unwrap_or_default().to_string(),
+                    ..CombinedPhase::default()
+                });
+                let count = |field: &str| phase[field].as_u64().unwrap_or(0);
+                let latency = |field: &str| phase["latency"][field].as_u64().unwrap_or(0);
+
+                total.agents += 1;
+                total.requests += count("requests");
+                total.objects += count("objects");
+                total.objects_per_sec += phase["objects_per_sec"].as_f64().unwrap_or(0.0);
+                total.failed_requests += count("failed_requests");
+                total.retries += count("retries");
+                total.timeouts += count("timeouts");
+                total.worst_p50_us = total.worst_p50_us.max(latency("p50_us"));
+                total.worst_p99_us = total.worst_p99_us.max(latency("p99_us"));
+                total.max_us = total.max_us.max(latency("max_us"));
+
+                let started = phase["started_at"].as_f64().unwrap_or(0.0);
+                let finished = phase["finished_at"].as_f64().unwrap_or(0.0);
+                let span = spans.entry(key).or_insert((started, finished));
+                span.0 = span.0.min(started);
+                span.1 = span.1.max(finished);
+            }
+        }
+    }
+
+    combined
+        .into_iter()
+        .map(|(key, mut total)| {
+            let (started, finished) = spans[&key];
+            if finished > started {
+                total.wall_objects_per_sec = total.objects as f64 / (finished - started);
+            }
+            total
+        })
+        .collect()
+}
+
+pub fn print(results: &[AgentResult], phases: &[CombinedPhase]) {
+    println!("\n ==== combined results of {} agents ====", results.len());
+    for result in results.iter().filter(|result| result.failed()) {
+        println!(
+            "agent {} ({}) failed: {}",
+            result.agent,
+            result.addr,
+            result.error.as_deref().unwrap_or("see its report")
+        );
+    }
+
+    println!(
+        "{:>6} {:>12} {:>7} {:>10} {:>12} {:>12} {:>8} {:>8} {:>10} {:>10}",
+        "shard",
+        "phase",
+        "agents",
+        "objects",
+        "obj/s",
+        "wall obj/s",
+        "failed",
+        "retries",
+        "p50 ms",
+        "p99 ms"
+    );
+    for phase in phases.iter() {
+        println!(
+            "{:>6} {:>12} {:>7} {:>10} {:>12.1} {:>12.1} {:>8} {:>8} {:>10.1} {:>10.1}",
+            phase.shard,
+            phase.phase,
+            phase.agents,
+            phase.objects,
+            phase.objects_per_sec,
+            phase.wall_objects_per_sec,
+            phase.failed_requests,
+            phase.retries,
+            phase.worst_p50_us as f64 / 1000.0,
+            phase.worst_p99_us as f64 / 1000.0
+        );
+    }
+}
+
+/// Machine readable results of a coordinated run, written by the
+/// coordinator's `--output`.
+#[derive(Serialize)]
+pub struct CoordinatorReport<'a> {
+    pub started_at: f64,
+    pub finished_at: f64,
+    pub args: &'a [String],
+    pub agents: &'a [AgentResult],
+    pub phases: &'a [CombinedPhase],
+}
+
+/// Write the results of a coordinated run that started at `started` to
+/// `path` as JSON.
+pub fn write(
+    path: &str,
+    started: SystemTime,
+    args: &[String],
+    results: &[AgentResult],
+    phases: &[CombinedPhase],
+) -> Result<(), Error> {
+    let report = CoordinatorReport {
+        started_at: stats::unix_time(started),
+        finished_at: stats::unix_time(SystemTime::now()),
+        args,
+        agents: results,
+        phases,
+    };
+
+    let writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(writer, &report)?;
+    println!("Wrote results to {}", path);
+
+    Ok(())
+}
@@ -0,0 +1,208 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::{logging, resolver, trace, InternalError};
+use failure::Error;
+use lazy_static::lazy_static;
+use opentelemetry::api::KeyValue;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use slog::warn;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::RwLock;
+
+// We can't use trust-dns-resolver here because it uses futures with a
+// block_on, and calling a block_on from within a block_on is not allowed.
+use resolve::{record::Srv, DnsConfig, DnsResolver};
+
+const DNS_PORT: u16 = 53;
+
+lazy_static! {
+    static ref NAMESERVERS: RwLock<Vec<IpAddr>> = RwLock::new(vec![]);
+}
+
+// Send lookups to these nameservers instead of the ones in /etc/resolv.conf.
+// This is needed in zones whose resolv.conf doesn't point at binder.
+pub fn set_nameservers(nameservers: &[IpAddr]) {
+    *NAMESERVERS.write().unwrap() = nameservers.to_vec();
+}
+
+fn dns_config() -> Result<DnsConfig, Error> {
+    let nameservers = NAMESERVERS.read().unwrap();
+
+    if nameservers.is_empty() {
+        return Ok(DnsConfig::load_default()?);
+    }
+
+    Ok(DnsConfig::with_name_servers(
+        nameservers
+            .iter()
+            .map(|&ip| SocketAddr::new(ip, DNS_PORT))
+            .collect(),
+    ))
+}
+
+fn dns_error(host: &str, reason: String) -> Error {
+    InternalError::DnsResolution {
+        host: host.to_string(),
+        reason,
+    }
+    .into()
+}
+
+// Get every SRV record for the service in the order RFC 2782 says they should
+// be tried.
+pub fn get_srv_records(svc: &str, proto: &str, host: &str) -> Result<Vec<Srv>, Error> {
+    let query = format!("{}.{}.{}", svc, proto, host);
+    let _span = trace::span("dns.srv", vec![KeyValue::new("query", query.clone())]);
+    let records = resolver::srv_records(&query, || {
+        let r = DnsResolver::new(dns_config()?)?;
+        r.resolve_record::<Srv>(&query)
+            .map_err(|e| dns_error(&query, e.to_string()))
+    })?;
+
+    if records.is_empty() {
+        return Err(InternalError::SrvMissing(query).into());
+    }
+
+    Ok(rfc2782_order(records, &mut rand::thread_rng()))
+}
+
+// Order SRV records as described in RFC 2782: lowest priority first, and
+// within each priority a weighted random order, so that a target with twice
+// the weight of another is twice as likely to be tried first.
+pub fn rfc2782_order<R: Rng>(mut records: Vec<Srv>, rng: &mut R) -> Vec<Srv> {
+    let mut ordered = Vec::with_capacity(records.len());
+
+    records.sort_by_key(|r| r.priority);
+
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let class_len = records
+            .iter()
+            .take_while(|r| r.priority == priority)
+            .count();
+        let mut class: Vec<Srv> = records.drain(..class_len).collect();
+
+        // Zero weight records go first, in a random order, so that they can
+        // only be chosen when the pick is zero, giving them a small but
+        // non-zero chance.
+        class.shuffle(rng);
+        class.sort_by_key(|r| r.weight != 0);
+
+        while !class.is_empty() {
+            let total: u32 = class.iter().map(|r| u32::from(r.weight)).sum();
+            // Without a zero weight record to take a pick of zero, it would
+            // go to the first record on top of its weight.
+            let lowest = if class[0].weight == 0 { 0 } else { 1 };
+            let pick = rng.gen_range(lowest, total + 1);
+            let mut running = 0;
+            let mut chosen = class.len() - 1;
+
+            for (i, r) in class.iter().enumerate() {
+                running += u32::from(r.weight);
+                if running >= pick {
+                    chosen = i;
+                    break;
+                }
+            }
+
+            ordered.push(class.remove(chosen));
+        }
+    }
+
+    ordered
+}
+
+pub fn lookup_ip(host: &str) -> Result<IpAddr, Error> {
+    let _span = trace::span("dns.lookup", vec![KeyValue::new("host", host.to_string())]);
+    resolver::host_ip(host, || {
+        let r = DnsResolver::new(dns_config()?)?;
+        let addrs = r
+            .resolve_host(host)
+            .map_err(|e| dns_error(host, e.to_string()))?;
+        match addrs.collect::<Vec<IpAddr>>().first() {
+            Some(a) => Ok(*a),
+            None => Err(dns_error(host, String::from("no addresses"))),
+        }
+    })
+}
+
+// Get the address of every moray instance advertised for the host, in the
+// order they should be tried.  Targets that don't resolve are skipped.
+pub fn get_moray_srv_sockaddrs(host: &str) -> Result<Vec<SocketAddr>, Error> {
+    let mut addrs = vec![];
+
+    for srv_record in get_srv_records("_moray", "_tcp", host)? {
+        match lookup_ip(&srv_record.target) {
+            Ok(ip) => addrs.push(SocketAddr::new(ip, srv_record.port)),
+            Err(e) => warn!(
+                logging::logger(),
+                "unable to resolve {}: {}", srv_record.target, e
+            ),
+        }
+    }
+
+    if addrs.is_empty() {
+        return Err(InternalError::SrvMissing(host.to_string()).into());
+    }
+
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn srv(target: &str, priority: u16, weight: u16) -> Srv {
+        Srv {
+            priority,
+            weight,
+            port: 2020,
+            target: target.to_string(),
+        }
+    }
+
+    fn targets(records: &[Srv]) -> Vec<&str> {
+        records.iter().map(|r| r.target.as_str()).collect()
+    }
+
+    #[test]
+    fn lowest_priority_first() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let records = vec![srv("c", 30, 5), srv("a", 10, 5), srv("b", 20, 5)];
+
+        for _ in 0..100 {
+            let ordered = rfc2782_order(records.clone(), &mut rng);
+            assert_eq!(targets(&ordered), vec!["a", "b", "c"]);
+        }
+    }
+
+    #[test]
+    fn zero_weight_can_be_picked_first() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let records = vec![srv("heavy", 10, 10), srv("zero", 10, 0)];
+
+        let zero_first = (0..1000)
+            .filter(|_| rfc2782_order(records.clone(), &mut rng)[0].target == "zero")
+            .count();
+        // One pick in eleven.
+        assert!(zero_first > 0 && zero_first < 200, "{}", zero_first);
+    }
+
+    #[test]
+    fn picked_in_proportion_to_weight() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let records = vec![srv("one", 10, 1), srv("two", 10, 2)];
+
+        let draws = 30_000;
+        let two_first = (0..draws)
+            .filter(|_| rfc2782_order(records.clone(), &mut rng)[0].target == "two")
+            .count();
+        let ratio = two_first as f64 / (draws - two_first) as f64;
+        assert!(ratio > 1.8 && ratio < 2.2, "{}", ratio);
+    }
+}
@@ -0,0 +1,212 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! Going through a run without moray: the test objects are generated,
+//! altered, batched and serialized just as the run would, but nothing is
+//! sent.  Each phase reports the requests and bytes it would have sent, and
+//! its latencies are the client's own time to prepare each request, so a dry
+//! run also measures the overhead the client adds to a real one.
+//!
+//! The objects a bench run would fetch from the bucket are generated instead.
+
+use crate::batch::PutBatches;
+use crate::config::{Config, Warmup};
+use crate::scenario::{self, Phase, Scenario};
+use crate::stats::{PhaseReport, PhaseStats};
+use crate::{
+    access, alter_objects, batch_objects, batch_payload_size, choose_deletes, gen_test_objects,
+    payload_size, seed_config, seeded_rng, sorted, value_sizes, values, RunSummary,
+};
+use failure::Error;
+use libmanta::moray::MantaObject;
+use rand::rngs::StdRng;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::time::{Instant, SystemTime};
+
+/// The work a dry run goes through.
+pub enum Plan<'a> {
+    Seed,
+    Bench { sequential: bool, batch: bool },
+    Scenario(&'a Scenario),
+}
+
+pub fn run(config: &Config, plan: Plan) -> Result<RunSummary, Error> {
+    println!("Dry run, nothing will be sent to moray");
+    let mut rng = seeded_rng(config.seed);
+    let mut summary = RunSummary::default();
+
+    match plan {
+        Plan::Seed => {
+            seed(config, &mut rng, &mut summary);
+        }
+        Plan::Bench { sequential, batch } => {
+            let objects = generate(config, &mut rng, &mut summary);
+            if sequential {
+                summary
+                    .sequential
+                    .push(sequential_phase(config, &objects, &mut rng));
+            }
+            if batch {
+                summary.batch.push(batch_phase(config, &objects, &mut rng));
+            }
+        }
+        Plan::Scenario(scenario) => {
+            let mut objects = None;
+
+            for (i, step) in scenario.phases.iter().enumerate() {
+                let mut params = step.params.clone();
+                params.remove("drop_bucket");
+                let config = scenario::apply(config, &params)?;
+
+                println!(
+                    "\n ==== scenario phase {} of {}: {:?} ====",
+                    i + 1,
+                    scenario.phases.len(),
+                    step.phase
+                );
+
+                match step.phase {
+                    Phase::Seed => objects = Some(seed(&config, &mut rng, &mut summary)),
+                    Phase::SequentialUpdate | Phase::BatchUpdate | Phase::Comparison => {
+                        if objects.is_none() {
+                            objects = Some(generate(&config, &mut rng, &mut summary));
+                        }
+                        let objects = objects.as_ref().expect("objects generated");
+
+                        if step.phase != Phase::BatchUpdate {
+                            summary
+                                .sequential
+                                .push(sequential_phase(&config, objects, &mut rng));
+                        }
+                        if step.phase != Phase::SequentialUpdate {
+                            summary.batch.push(batch_phase(&config, objects, &mut rng));
+                        }
+                    }
+                    phase => println!("Skipping {:?}, which only reads from moray", phase),
+                }
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+fn generate(
+    config: &Config,
+    rng: &mut StdRng,
+    summary: &mut RunSummary,
+) -> HashMap<String, MantaObject> {
+    let objects = gen_test_objects(config, rng);
+    println!("Generated {} test objects", objects.len());
+    summary.objects = objects.len() as u64;
+
+    objects
+}
+
+fn seed(
+    config: &Config,
+    rng: &mut StdRng,
+    summary: &mut RunSummary,
+) -> HashMap<String, MantaObject> {
+    let objects = generate(config, rng, summary);
+    let config = seed_config(config);
+    let writes = sorted(values(&objects).into_iter().collect());
+
+    let report = simulate(&config, "seed", writes, HashSet::new());
+    let sizes = value_sizes(&objects);
+    sizes.print();
+    summary.seed = Some(report.elapsed);
+    summary.value_sizes = Some(sizes);
+
+    objects
+}
+
+fn sequential_phase(
+    config: &Config,
+    objects: &HashMap<String, MantaObject>,
+    rng: &mut StdRng,
+) -> PhaseReport {
+    let mut config = config.clone();
+    config.batch_size = 0;
+    let altered = alter_objects(objects, rng);
+    let writes = access::writes(config.access, sorted(altered), rng);
+
+    simulate(&config, "sequential", writes, HashSet::new())
+}
+
+fn batch_phase(
+    config: &Config,
+    objects: &HashMap<String, MantaObject>,
+    rng: &mut StdRng,
+) -> PhaseReport {
+    let altered = alter_objects(objects, rng);
+    let deletes = choose_deletes(objects, config.delete_fraction, rng);
+    let writes = access::writes(config.access, sorted(altered), rng);
+
+    simulate(config, "batch", writes, deletes)
+}
+
+// Prepare every request of a phase, putting one object per request if
+// `config.batch_size` is zero and batching them otherwise, and count them as
+// if they had been sent.
+fn simulate(
+    config: &Config,
+    phase: &'static str,
+    writes: Vec<(String, Value)>,
+    deletes: HashSet<String>,
+) -> PhaseReport {
+    let mut config = config.clone();
+    config.warmup = Warmup::None;
+    let stats = PhaseStats::new(&config, phase);
+    let started = SystemTime::now();
+    let start = Instant::now();
+
+    if config.batch_size == 0 {
+        for (key, value) in writes.iter() {
+            let request = Instant::now();
+            let bytes = payload_size(key, value);
+            stats.record_request(1, bytes, request.elapsed());
+        }
+    } else {
+        let mut batches = PutBatches::new(&config.bucket, writes.into_iter(), config.batch_size)
+            .with_deletes(deletes)
+            .with_companions(config.companion_buckets.clone())
+            .with_max_bytes(config.max_batch_bytes);
+        loop {
+            let request = Instant::now();
+            let batch = match batches.next() {
+                Some(batch) => batch,
+                None => break,
+            };
+            let bytes = batch_payload_size(&batch);
+            stats.record_request(
+                batch_objects(&batch, &config.bucket),
+                bytes,
+                request.elapsed(),
+            );
+        }
+    }
+
+    let elapsed = start.elapsed();
+    let mut report = stats.report(started, elapsed);
+    if config.batch_size > 0 {
+        report.batch_size = Some(config.batch_size);
+    }
+
+    println!(
+        "{} would send {} requests for {} objects, {} bytes of keys and values",
+        phase,
+        stats.requests(),
+        stats.objects(),
+        stats.bytes()
+    );
+    println!(
+        "Preparing them took {:.1}ms on the client; per request:",
+        elapsed.as_secs_f64() * 1000.0
+    );
+    stats.print_latency();
+
+    report
+}
@@ -0,0 +1,114 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::stats::PhaseStats;
+use failure::Error;
+use lazy_static::lazy_static;
+use signal_hook::iterator::Signals;
+use std::io::{self, BufRead};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Instant;
+
+// A running phase, and its count of objects when it was last dumped.
+struct Live {
+    stats: Weak<PhaseStats>,
+    last: Option<(Instant, u64)>,
+}
+
+lazy_static! {
+    static ref LIVE: Mutex<Vec<Live>> = Mutex::new(vec![]);
+}
+
+/// Include `stats` in dumps for as long as its phase holds on to it.
+pub fn track(stats: &Arc<PhaseStats>) {
+    let mut live = LIVE.lock().unwrap();
+    live.retain(|phase| phase.stats.strong_count() > 0);
+    live.push(Live {
+        stats: Arc::downgrade(stats),
+        last: None,
+    });
+}
+
+/// The stats of every phase still running.
+pub fn live() -> Vec<Arc<PhaseStats>> {
+    let mut live = LIVE.lock().unwrap();
+    live.retain(|phase| phase.stats.strong_count() > 0);
+    live.iter()
+        .filter_map(|phase| phase.stats.upgrade())
+        .collect()
+}
+
+/// Print the stats of every running phase whenever the process gets SIGUSR1
+/// or Enter is pressed, without stopping the run.
+pub fn install() -> Result<(), Error> {
+    let signals = Signals::new(&[signal_hook::SIGUSR1])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            dump();
+        }
+    });
+
+    // Stdin may well not be a terminal, in which case it is simply closed
+    // or never written to.
+    thread::spawn(|| {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            if line.is_err() {
+                break;
+            }
+            dump();
+        }
+    });
+
+    Ok(())
+}
+
+// Each phase's totals so far, its throughput since the last dump (or since it
+// started) and its latency percentiles.
+fn dump() {
+    let mut live = LIVE.lock().unwrap();
+    live.retain(|phase| phase.stats.strong_count() > 0);
+
+    println!("\n ==== stats dump ====");
+    if live.is_empty() {
+        println!("No phase running");
+    }
+
+    for phase in live.iter_mut() {
+        let stats = match phase.stats.upgrade() {
+            Some(stats) => stats,
+            None => continue,
+        };
+
+        let now = Instant::now();
+        let objects = stats.objects();
+        let (interval, rate) = match phase.last {
+            Some((then, before)) => {
+                let secs = (now - then).as_secs_f64();
+                (secs, (objects - before) as f64 / secs.max(0.001))
+            }
+            None => {
+                let secs = stats.elapsed().as_secs_f64();
+                (secs, objects as f64 / secs.max(0.001))
+            }
+        };
+        phase.last = Some((now, objects));
+
+        println!(
+            "shard {} {}: {:.1}s in, {} requests, {} objects, {} failed requests, {} retries",
+            stats.shard,
+            stats.phase,
+            stats.elapsed().as_secs_f64(),
+            stats.requests(),
+            objects,
+            stats.failed_requests(),
+            stats.retries()
+        );
+        println!("  {:.1} objects/s over the last {:.1}s", rate, interval);
+        if let Some(line) = stats.latency_summary() {
+            println!("  {}", line);
+        }
+    }
+}
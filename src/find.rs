@@ -0,0 +1,166 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use crate::pool::Pool;
+use crate::stats::{PhaseReport, PhaseStats};
+use crate::timeseries::Sampler;
+use crate::workers::{self, Context};
+use crate::{latency_csv, retry, trace};
+use failure::Error;
+use libmanta::moray::MantaObject;
+use moray::objects;
+use opentelemetry::api::KeyValue;
+use rand::Rng;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+// Indexed fields of the test bucket that the queries filter on, in turn.
+static FIELDS: &[&str] = &["owner", "dirname", "type"];
+
+// Build `count` equality filters over the indexed fields, each matching the
+// value of a randomly chosen test object so that every query finds something.
+pub fn filters(test_objects: &HashMap<String, MantaObject>, count: u32) -> Vec<String> {
+    let values: Vec<Value> = test_objects
+        .values()
+        .map(|obj| serde_json::to_value(obj).unwrap())
+        .collect();
+    let mut rng = rand::thread_rng();
+
+    if values.is_empty() {
+        return vec![];
+    }
+
+    (0..count as usize)
+        .map(|i| {
+            let field = FIELDS[i % FIELDS.len()];
+            let value = &values[rng.gen_range(0, values.len())];
+            match value[field].as_str() {
+                Some(v) => format!("({}={})", field, escape(v)),
+                None => format!("({}=*)", field),
+            }
+        })
+        .collect()
+}
+
+/// A filter matching the objects with any of these object ids.
+pub fn object_id_filter(ids: &[String]) -> String {
+    let clauses: String = ids
+        .iter()
+        .map(|id| format!("(objectId={})", escape(id)))
+        .collect();
+
+    format!("(|{})", clauses)
+}
+
+// Escape the characters that are special in an LDAP filter value.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '*' => escaped.push_str("\\2a"),
+            '(' => escaped.push_str("\\28"),
+            ')' => escaped.push_str("\\29"),
+            '\\' => escaped.push_str("\\5c"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+// Run `config.find_queries` findobjects queries, each returning at most
+// `config.find_limit` records, spread across `config.workers` threads.  The
+// objects counted for the phase are the records the queries returned.
+pub fn run_find_test(
+    pool: &Pool,
+    config: &Config,
+    test_objects: &HashMap<String, MantaObject>,
+) -> Result<PhaseReport, Error> {
+    println!(
+        "Running {} findobjects queries with a limit of {} and {} workers",
+        config.find_queries,
+        config.find_limit,
+        config.workers.max(1)
+    );
+
+    let stats = Arc::new(PhaseStats::new(config, "find"));
+    let shares = workers::split(
+        filters(test_objects, config.find_queries),
+        config.workers as usize,
+    );
+    let sampler = Sampler::start(config, &stats);
+    let started = SystemTime::now();
+    let result = workers::run(config, pool, &stats, shares, find_objects);
+    sampler.stop();
+    latency_csv::flush();
+    let elapsed = result?;
+
+    println!(
+        "Done running {} queries returning {} records: {}ms",
+        stats.requests(),
+        stats.objects(),
+        elapsed.as_millis()
+    );
+    stats.print_throughput(elapsed);
+    stats.print_latency();
+    stats.print_retries();
+    stats.print_failures();
+
+    Ok(stats.report(started, elapsed))
+}
+
+fn find_objects(ctx: &Context, filters: Vec<String>) -> Result<(), Error> {
+    let mut opts = objects::MethodOptions::default();
+    opts.limit = Some(ctx.config.find_limit);
+    let bucket = ctx.config.bucket.as_str();
+
+    for filter in workers::until_stopped(&filters) {
+        let start = ctx.pace();
+        let started = SystemTime::now() - start.elapsed();
+        let span = trace::span(
+            "find",
+            vec![
+                KeyValue::new("shard", ctx.config.shard.to_string()),
+                KeyValue::new("filter", filter.clone()),
+            ],
+        );
+        let result = ctx.call(|c| {
+            let mut records = 0;
+            let mut bytes = 0;
+            c.find_objects(bucket, filter, &opts, |mobj| {
+                records += 1;
+                bytes += (mobj.key.len() + mobj.value.to_string().len()) as u64;
+                Ok(())
+            })?;
+            Ok((records, bytes))
+        });
+        latency_csv::record(
+            ctx.config.shard,
+            "find",
+            filter,
+            started,
+            start.elapsed(),
+            &result,
+        );
+        if let Err(e) = &result {
+            trace::set_error(&span, retry::error_name(e));
+        }
+        drop(span);
+
+        match result {
+            Ok((records, bytes)) => ctx.stats.record_request(records, bytes, start.elapsed()),
+            Err(e) if ctx.config.continue_on_error => {
+                eprintln!("find {}: {}", filter, e);
+                ctx.stats.record_failure(vec![filter.clone()], &e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,114 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use crate::pool::Pool;
+use crate::stats::{PhaseReport, PhaseStats};
+use crate::timeseries::Sampler;
+use crate::workers::{self, Context};
+use crate::{latency_csv, retry, trace, InternalError};
+use failure::Error;
+use libmanta::moray::MantaObject;
+use moray::objects;
+use opentelemetry::api::KeyValue;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+// Fetch every test object with its own getobject request, spread across
+// `config.workers` threads, checking that each comes back as a valid manta
+// object.
+pub fn run_get_test(
+    pool: &Pool,
+    config: &Config,
+    test_objects: &HashMap<String, MantaObject>,
+) -> Result<PhaseReport, Error> {
+    if config.workers > 1 {
+        println!("Fetching objects with {} workers", config.workers);
+    } else {
+        println!("Fetching objects sequentially");
+    }
+
+    let stats = Arc::new(PhaseStats::new(config, "get"));
+    let shares = workers::split(test_objects.keys().cloned(), config.workers as usize);
+    let sampler = Sampler::start(config, &stats);
+    let started = SystemTime::now();
+    let result = workers::run(config, pool, &stats, shares, get_objects);
+    sampler.stop();
+    latency_csv::flush();
+    let elapsed = result?;
+
+    println!(
+        "Done fetching {} objects: {}ms",
+        stats.objects(),
+        elapsed.as_millis()
+    );
+    stats.print_throughput(elapsed);
+    stats.print_latency();
+    stats.print_retries();
+    stats.print_failures();
+
+    Ok(stats.report(started, elapsed))
+}
+
+fn get_objects(ctx: &Context, keys: Vec<String>) -> Result<(), Error> {
+    let opts = objects::MethodOptions::default();
+    let bucket = ctx.config.bucket.as_str();
+
+    for key in workers::until_stopped(keys) {
+        let start = ctx.pace();
+        let started = SystemTime::now() - start.elapsed();
+        let span = trace::span(
+            "get",
+            vec![
+                KeyValue::new("shard", ctx.config.shard.to_string()),
+                KeyValue::new("key", key.clone()),
+            ],
+        );
+        let result = ctx
+            .call(|c| {
+                let mut value = None;
+                c.get_object(bucket, &key, &opts, |mobj| {
+                    value = Some(mobj.value.clone());
+                    Ok(())
+                })?;
+                Ok(value)
+            })
+            .and_then(|value| {
+                let value = value.unwrap_or_default();
+                let bytes = (key.len() + value.to_string().len()) as u64;
+                match serde_json::from_value::<MantaObject>(value) {
+                    Ok(_) => Ok(bytes),
+                    Err(e) => Err(InternalError::VerificationFailed(format!(
+                        "{} is not a valid manta object: {}",
+                        key, e
+                    ))
+                    .into()),
+                }
+            });
+        latency_csv::record(
+            ctx.config.shard,
+            "get",
+            &key,
+            started,
+            start.elapsed(),
+            &result,
+        );
+        if let Err(e) = &result {
+            trace::set_error(&span, retry::error_name(e));
+        }
+        drop(span);
+
+        match result {
+            Ok(bytes) => ctx.stats.record_request(1, bytes, start.elapsed()),
+            Err(e) if ctx.config.continue_on_error => {
+                eprintln!("get {}: {}", key, e);
+                ctx.stats.record_failure(vec![key], &e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::retry;
+use crate::stats;
+use failure::Error;
+use lazy_static::lazy_static;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+static CSV_HEADER: &str = "time,shard,phase,id,req_id,duration_us,result";
+
+lazy_static! {
+    static ref OUTPUT: Mutex<Option<BufWriter<File>>> = Mutex::new(None);
+}
+
+/// Write a row for every put and batch request, from every shard, to `path`.
+pub fn open(path: &str) -> Result<(), Error> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "{}", CSV_HEADER)?;
+
+    *OUTPUT.lock().unwrap() = Some(writer);
+    Ok(())
+}
+
+/// Record one request.  `id` is the object key for a put, or the batch
+/// number for a batch, and the result is either "ok" or the kind of error.
+pub fn record<T>(
+    shard: u32,
+    phase: &str,
+    id: &str,
+    started: SystemTime,
+    duration: Duration,
+    result: &Result<T, Error>,
+) {
+    record_request(shard, phase, id, "", started, duration, result)
+}
+
+/// Record one request along with the request id moray logged it under.
+pub fn record_request<T>(
+    shard: u32,
+    phase: &str,
+    id: &str,
+    req_id: &str,
+    started: SystemTime,
+    duration: Duration,
+    result: &Result<T, Error>,
+) {
+    let mut output = OUTPUT.lock().unwrap();
+    let writer = match output.as_mut() {
+        Some(writer) => writer,
+        None => return,
+    };
+
+    let result = match result {
+        Ok(_) => String::from("ok"),
+        Err(e) => retry::error_name(e),
+    };
+
+    if let Err(e) = writeln!(
+        writer,
+        "{:.6},{},{},{},{},{},{}",
+        stats::unix_time(started),
+        shard,
+        phase,
+        id,
+        req_id,
+        duration.as_micros(),
+        result
+    ) {
+        eprintln!("Failed to write latency CSV row: {}", e);
+    }
+}
+
+/// Flush buffered rows to the file.  Called at the end of every phase rather
+/// than after every row to keep the writes out of the request timings.
+pub fn flush() {
+    if let Some(writer) = OUTPUT.lock().unwrap().as_mut() {
+        if let Err(e) = writer.flush() {
+            eprintln!("Failed to write latency CSV: {}", e);
+        }
+    }
+}
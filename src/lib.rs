@@ -0,0 +1,2509 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! Benchmarks comparing sequential moray puts against batched puts.
+//!
+//! The `BenchRunner` type drives the same seed, update, verify, and cleanup
+//! steps as the `batch_test` binary so that other tools can embed them.
+
+#[macro_use]
+extern crate serde_json;
+
+#[macro_use]
+extern crate failure;
+
+pub mod access;
+pub mod baseline;
+pub mod batch;
+pub mod breaker;
+pub mod cas;
+pub mod chaos;
+pub mod cleanup;
+pub mod config;
+pub mod cycles;
+pub mod dashboard;
+pub mod dataset;
+pub mod delete;
+pub mod deletelog;
+pub mod distributed;
+pub mod dns;
+pub mod dryrun;
+pub mod dump;
+pub mod find;
+pub mod get;
+pub mod latency_csv;
+pub mod logging;
+pub mod metadata;
+pub mod metrics;
+pub mod mock;
+pub mod pool;
+pub mod preflight;
+pub mod probes;
+pub mod progress;
+pub mod readback;
+pub mod rebalance;
+pub mod reindex;
+pub mod report;
+pub mod requeue;
+pub mod resolver;
+pub mod response;
+pub mod retry;
+pub mod scenario;
+pub mod shutdown;
+pub mod significance;
+pub mod soak;
+pub mod sql;
+pub mod stats;
+pub mod statsd;
+pub mod status;
+pub mod summary_line;
+pub mod throttle;
+pub mod timeseries;
+pub mod timing;
+pub mod trace;
+pub mod tuner;
+pub mod unique;
+pub mod update;
+pub mod version;
+
+mod workers;
+
+use batch::PutBatches;
+use config::{Access, Config, CountCheck, Metadata, Profile, ValueSize, Warmup};
+use cueball::connection_pool::types::ConnectionPoolOptions;
+use cycles::Cycles;
+use failure::{Compat, Error};
+use libmanta::moray::{MantaObject, MantaObjectShark};
+use moray::buckets;
+use moray::client::MorayClient;
+use moray::objects::{self, BatchRequest};
+use opentelemetry::api::KeyValue;
+use pool::Pool;
+use probes::moray_batch_test;
+use progress::Progress;
+use quickcheck::{Arbitrary, StdGen};
+use requeue::Requeue;
+use response::ServerInfo;
+use serde_json::Value;
+use significance::MannWhitney;
+use slog::{debug, error, o, warn};
+use stats::{Estimate, PhaseReport, PhaseStats, ValueSizes};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use timeseries::Sampler;
+use timing::Step;
+use tuner::Tuner;
+use uuid::Uuid;
+use version::ServerVersion;
+use workers::{Context, Queue};
+
+use rand::distributions::{Alphanumeric, Distribution, LogNormal};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{thread_rng, FromEntropy, Rng, SeedableRng};
+use std::net::SocketAddr;
+
+pub static PAGE_SIZE: u64 = 1000;
+
+#[derive(Debug, Fail)]
+pub enum InternalError {
+    #[fail(display = "invalid arguments: {}", _0)]
+    InvalidArguments(String),
+    #[fail(display = "DNS lookup of {} failed: {}", host, reason)]
+    DnsResolution { host: String, reason: String },
+    #[fail(display = "no usable moray SRV records for {}", _0)]
+    SrvMissing(String),
+    #[fail(display = "unable to connect to moray at {}: {}", addrs, reason)]
+    Connect { addrs: String, reason: String },
+    #[fail(
+        display = "bucket {} doesn't have the expected indexes: {}",
+        bucket, problems
+    )]
+    BucketSchema { bucket: String, problems: String },
+    #[fail(display = "put of {} failed: {}", key, source)]
+    PutFailed {
+        key: String,
+        #[cause]
+        source: Compat<Error>,
+    },
+    #[fail(display = "batch {} failed: {}", index, source)]
+    BatchFailed {
+        index: usize,
+        #[cause]
+        source: Compat<Error>,
+    },
+    #[fail(display = "no response in {}ms", _0)]
+    Timeout(u64),
+    #[fail(display = "shard still overloaded after retries: {}", _0)]
+    Overload(String),
+    #[fail(display = "no test objects in {}, run seed first", _0)]
+    NoTestObjects(String),
+    #[fail(display = "too many errors: {}", _0)]
+    ErrorRateExceeded(String),
+    #[fail(display = "{} of {} runs failed", failed, runs)]
+    RunsFailed { failed: usize, runs: usize },
+    #[fail(display = "verification failed: {}", _0)]
+    VerificationFailed(String),
+    #[fail(display = "worker thread panicked")]
+    WorkerPanicked,
+    #[fail(display = "regression from baseline: {}", _0)]
+    Regression(String),
+    #[fail(display = "invalid scenario: {}", _0)]
+    InvalidScenario(String),
+    #[fail(display = "invalid dataset: {}", _0)]
+    InvalidDataset(String),
+    #[fail(display = "pre-flight check failed: {}", _0)]
+    PreflightFailed(String),
+}
+
+impl InternalError {
+    /// The name errors of this kind are counted under.
+    pub fn class(&self) -> &'static str {
+        match self {
+            InternalError::InvalidArguments(_) => "InvalidArguments",
+            InternalError::DnsResolution { .. } => "DnsResolution",
+            InternalError::SrvMissing(_) => "SrvMissing",
+            InternalError::Connect { .. } => "Connect",
+            InternalError::BucketSchema { .. } => "BucketSchema",
+            // A timeout or an overload is worth telling apart from any other
+            // reason a put or batch failed.
+            InternalError::PutFailed { source, .. } => match error_class(source.get_ref()) {
+                "Moray" => "PutFailed",
+                class => class,
+            },
+            InternalError::BatchFailed { source, .. } => match error_class(source.get_ref()) {
+                "Moray" => "BatchFailed",
+                class => class,
+            },
+            InternalError::Timeout(_) => "Timeout",
+            InternalError::Overload(_) => "Overload",
+            InternalError::NoTestObjects(_) => "NoTestObjects",
+            InternalError::ErrorRateExceeded(_) => "ErrorRateExceeded",
+            InternalError::RunsFailed { .. } => "RunsFailed",
+            InternalError::VerificationFailed(_) => "VerificationFailed",
+            InternalError::WorkerPanicked => "WorkerPanicked",
+            InternalError::Regression(_) => "Regression",
+            InternalError::InvalidScenario(_) => "InvalidScenario",
+            InternalError::InvalidDataset(_) => "InvalidDataset",
+            InternalError::PreflightFailed(_) => "PreflightFailed",
+        }
+    }
+
+    /// Whether the error kept the run from getting started: bad arguments, a
+    /// shard that couldn't be found or reached, or a bucket that isn't ready.
+    pub fn is_setup(&self) -> bool {
+        matches!(
+            self,
+            InternalError::InvalidArguments(_)
+                | InternalError::DnsResolution { .. }
+                | InternalError::SrvMissing(_)
+                | InternalError::Connect { .. }
+                | InternalError::BucketSchema { .. }
+                | InternalError::NoTestObjects(_)
+                | InternalError::InvalidScenario(_)
+                | InternalError::InvalidDataset(_)
+                | InternalError::PreflightFailed(_)
+        )
+    }
+}
+
+/// The class an error is counted under: its InternalError variant, or for an
+/// error straight from moray or the network, the kind of trouble it reports.
+pub fn error_class(e: &Error) -> &'static str {
+    if let Some(internal) = e.downcast_ref::<InternalError>() {
+        return internal.class();
+    }
+
+    if retry::is_overload(e) {
+        "Overload"
+    } else if retry::is_connection_error(e) {
+        "Connect"
+    } else if retry::is_conflict(e) {
+        "Conflict"
+    } else {
+        "Moray"
+    }
+}
+
+/// Results of each phase of a run against one bucket on one shard.
+#[derive(Clone, Debug, Default)]
+pub struct RunSummary {
+    pub objects: u64,
+    pub seed: Option<Duration>,
+    /// Sizes of the test objects the phases wrote.
+    pub value_sizes: Option<ValueSizes>,
+    pub sequential: Vec<PhaseReport>,
+    pub batch: Vec<PhaseReport>,
+    /// Batch phases run with different batch sizes by a sweep.
+    pub sweep: Vec<PhaseReport>,
+    pub find: Vec<PhaseReport>,
+    pub get: Vec<PhaseReport>,
+    /// Sequential, batch and deletemany delete phases.
+    pub delete: Vec<PhaseReport>,
+    pub update_objects: Vec<PhaseReport>,
+    pub sql: Vec<PhaseReport>,
+    /// The reindex phase and the batch phase run alongside it.
+    pub reindex: Vec<PhaseReport>,
+    /// Batch puts to the unique-index bucket, some of them conflicting.
+    pub unique: Vec<PhaseReport>,
+    /// Conditional batch puts raced by every worker.
+    pub cas: Vec<PhaseReport>,
+    /// Sequential and batch puts of the same alteration, whose results were
+    /// compared.
+    pub consistency: Vec<PhaseReport>,
+    /// Objects moved off a storage node the way the rebalancer does.
+    pub rebalance: Vec<PhaseReport>,
+    /// Records written to a delete log and consumed from it.
+    pub delete_log: Vec<PhaseReport>,
+    /// The version each moray instance reported before the run.
+    pub server_versions: Vec<ServerVersion>,
+}
+
+/// Count errors by class across `results`: the requests every phase gave up
+/// on, and each run that failed outright.
+pub fn error_classes(
+    results: &[(u32, String, Result<RunSummary, Error>)],
+) -> BTreeMap<String, u64> {
+    let mut classes = BTreeMap::new();
+
+    for (_, _, result) in results.iter() {
+        match result {
+            Ok(summary) => {
+                for phase in summary.phases() {
+                    for (class, count) in phase.error_classes.iter() {
+                        *classes.entry(class.clone()).or_insert(0) += count;
+                    }
+                }
+            }
+            Err(e) => *classes.entry(error_class(e).to_string()).or_insert(0) += 1,
+        }
+    }
+
+    classes
+}
+
+impl RunSummary {
+    pub fn new(objects: usize) -> RunSummary {
+        RunSummary {
+            objects: objects as u64,
+            ..RunSummary::default()
+        }
+    }
+
+    /// Every phase of the run.
+    pub fn phases(&self) -> impl Iterator<Item = &PhaseReport> {
+        self.sequential
+            .iter()
+            .chain(self.batch.iter())
+            .chain(self.sweep.iter())
+            .chain(self.find.iter())
+            .chain(self.get.iter())
+            .chain(self.delete.iter())
+            .chain(self.update_objects.iter())
+            .chain(self.sql.iter())
+            .chain(self.reindex.iter())
+            .chain(self.unique.iter())
+            .chain(self.cas.iter())
+            .chain(self.consistency.iter())
+            .chain(self.rebalance.iter())
+            .chain(self.delete_log.iter())
+    }
+
+    /// Mean objects per second across every sequential phase.
+    pub fn sequential_rate(&self) -> f64 {
+        objects_per_sec(&self.sequential)
+    }
+
+    /// Mean objects per second across every batch phase.
+    pub fn batch_rate(&self) -> f64 {
+        objects_per_sec(&self.batch)
+    }
+
+    /// How many times faster batches were than sequential puts.
+    pub fn speedup(&self) -> f64 {
+        let sequential = self.sequential_rate();
+        if sequential == 0.0 {
+            return 0.0;
+        }
+        self.batch_rate() / sequential
+    }
+
+    /// Print every pass of both phases side by side, with each pass's
+    /// throughput relative to the first pass of the same phase.
+    pub fn print_comparison(&self) {
+        println!("\n ==== comparison ====");
+        println!(
+            "{:>6} {:>11} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            "pass", "phase", "obj/s", "vs pass 1", "p50 ms", "p99 ms", "max ms"
+        );
+
+        let passes = self
+            .sequential
+            .iter()
+            .chain(self.batch.iter())
+            .map(|r| r.pass)
+            .max()
+            .unwrap_or(0);
+        // Label rows PASS.ITERATION if phases ran more than once per pass.
+        let iterated = self
+            .sequential
+            .iter()
+            .chain(self.batch.iter())
+            .any(|r| r.iteration > 1);
+
+        for pass in 1..=passes {
+            for phases in [&self.sequential, &self.batch].iter() {
+                for report in phases.iter().filter(|r| r.pass == pass) {
+                    print_comparison_row(report, &phases[0], iterated);
+                }
+            }
+        }
+
+        println!(
+            "{:>6} {:>11} {:>10.1}",
+            "all",
+            "sequential",
+            self.sequential_rate()
+        );
+        println!("{:>6} {:>11} {:>10.1}", "all", "batch", self.batch_rate());
+        println!("Batch speedup: {:.2}x", self.speedup());
+    }
+
+    /// Print the mean, standard deviation, and 95% confidence interval of the
+    /// throughput and p99 latency of every phase that ran more than once.
+    pub fn print_statistics(&self) {
+        for phases in [&self.sequential, &self.batch].iter() {
+            if phases.len() < 2 {
+                continue;
+            }
+
+            let rates: Vec<f64> = phases.iter().map(|r| r.objects_per_sec).collect();
+            let p99s: Vec<f64> = phases
+                .iter()
+                .map(|r| r.latency.p99_us as f64 / 1000.0)
+                .collect();
+
+            println!("\n{} ({} runs):", phases[0].phase, phases.len());
+            print_estimate("obj/s", &Estimate::new(&rates));
+            print_estimate("p99 ms", &Estimate::new(&p99s));
+        }
+
+        if let Some(test) = self.throughput_test() {
+            println!(
+                "\nMann-Whitney U = {}, p = {:.4}{}: the difference in throughput is {}",
+                test.u,
+                test.p,
+                if test.exact {
+                    ""
+                } else {
+                    " (normal approximation)"
+                },
+                if test.significant() {
+                    "significant"
+                } else {
+                    "not significant"
+                }
+            );
+        }
+    }
+
+    /// Test whether sequential and batch throughput differ by more than the
+    /// variation between runs.  Needs at least two runs of each.
+    pub fn throughput_test(&self) -> Option<MannWhitney> {
+        let rates = |phases: &[PhaseReport]| -> Vec<f64> {
+            phases.iter().map(|r| r.objects_per_sec).collect()
+        };
+        significance::mann_whitney(&rates(&self.sequential), &rates(&self.batch))
+    }
+}
+
+fn print_batch_sweep(reports: &[PhaseReport]) {
+    println!("\n ==== batch size sweep ====");
+    println!(
+        "{:>10} {:>10} {:>10} {:>10}",
+        "batch size", "obj/s", "p50 ms", "p99 ms"
+    );
+
+    for report in reports.iter() {
+        println!(
+            "{:>10} {:>10.1} {:>10.1} {:>10.1}",
+            report.batch_size.unwrap_or(0),
+            report.objects_per_sec,
+            report.latency.p50_us as f64 / 1000.0,
+            report.latency.p99_us as f64 / 1000.0
+        );
+    }
+}
+
+// One row per worker count, with the sequential results followed by the
+// results of each batch size.
+fn print_worker_sweep(reports: &[PhaseReport], batch_sizes: &[u32]) {
+    println!("\n ==== worker sweep ====");
+    print_sweep_matrix(reports, batch_sizes, "workers", |r| r.workers);
+}
+
+fn print_connection_sweep(reports: &[PhaseReport], batch_sizes: &[u32]) {
+    println!("\n ==== connection sweep ====");
+    print_sweep_matrix(reports, batch_sizes, "conns/wkr", |r| {
+        r.connections_per_worker
+    });
+}
+
+// A row of throughput and p99 latency for each value of `key` the sweep ran
+// with, sequential first and then each batch size.
+fn print_sweep_matrix<F>(reports: &[PhaseReport], batch_sizes: &[u32], label: &str, key: F)
+where
+    F: Fn(&PhaseReport) -> u32,
+{
+    let mut header = format!("{:>9} {:>12} {:>10}", label, "seq obj/s", "seq p99");
+    for size in batch_sizes.iter() {
+        header.push_str(&format!(
+            " {:>12} {:>10}",
+            format!("b{} obj/s", size),
+            format!("b{} p99", size)
+        ));
+    }
+
+    println!("{}", header);
+
+    let mut values: Vec<u32> = reports.iter().map(|r| key(r)).collect();
+    values.dedup();
+
+    for value in values {
+        let find = |batch_size: Option<u32>| {
+            reports
+                .iter()
+                .find(|r| key(r) == value && r.batch_size == batch_size)
+        };
+        let cells = |report: Option<&PhaseReport>| match report {
+            Some(r) => format!(
+                " {:>12.1} {:>10.1}",
+                r.objects_per_sec,
+                r.latency.p99_us as f64 / 1000.0
+            ),
+            None => format!(" {:>12} {:>10}", "-", "-"),
+        };
+
+        let mut row = format!("{:>9}", value);
+        row.push_str(&cells(find(None)));
+        for &size in batch_sizes.iter() {
+            row.push_str(&cells(find(Some(size))));
+        }
+        println!("{}", row);
+    }
+}
+
+fn print_estimate(name: &str, estimate: &Estimate) {
+    println!(
+        "    {:>8}: mean {:.1} +/- {:.1} (95% CI), stddev {:.1}",
+        name, estimate.mean, estimate.ci95, estimate.stddev
+    );
+}
+
+fn print_comparison_row(report: &PhaseReport, first: &PhaseReport, iterated: bool) {
+    let ms = |us: u64| us as f64 / 1000.0;
+    let delta = if first.objects_per_sec > 0.0 {
+        format!(
+            "{:+.1}%",
+            (report.objects_per_sec / first.objects_per_sec - 1.0) * 100.0
+        )
+    } else {
+        String::from("-")
+    };
+
+    let pass = if iterated {
+        format!("{}.{}", report.pass, report.iteration)
+    } else {
+        report.pass.to_string()
+    };
+
+    println!(
+        "{:>6} {:>11} {:>10.1} {:>10} {:>10.1} {:>10.1} {:>10.1}",
+        pass,
+        report.phase,
+        report.objects_per_sec,
+        delta,
+        ms(report.latency.p50_us),
+        ms(report.latency.p99_us),
+        ms(report.latency.max_us)
+    );
+}
+
+fn objects_per_sec(phases: &[PhaseReport]) -> f64 {
+    let secs: f64 = phases.iter().map(|p| p.elapsed.as_secs_f64()).sum();
+    if secs == 0.0 {
+        return 0.0;
+    }
+    phases.iter().map(|p| p.objects).sum::<u64>() as f64 / secs
+}
+
+/// Runs the seed, benchmark, verify, and cleanup steps against the shard and
+/// bucket described by a `Config`.
+pub struct BenchRunner {
+    client: MorayClient,
+    pool: Option<Pool>,
+    config: Config,
+    // Generates the test objects and their alterations, from `config.seed`.
+    rng: StdRng,
+}
+
+impl BenchRunner {
+    /// Connect to the target described by `config`.
+    pub fn new(config: Config) -> Result<BenchRunner, Error> {
+        configure_network(&config);
+        let client = create_client(&Target::from_config(&config))?;
+        Ok(BenchRunner::with_client(client, config))
+    }
+
+    /// Use an already connected client for seeding, fetching, verification,
+    /// and cleanup.  The benchmark workers still open their own connections
+    /// using the target described by `config`.
+    pub fn with_client(client: MorayClient, config: Config) -> BenchRunner {
+        BenchRunner {
+            client,
+            pool: None,
+            rng: seeded_rng(config.seed),
+            config,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn client(&mut self) -> &mut MorayClient {
+        &mut self.client
+    }
+
+    // The benchmark workers' connections are only needed by the benchmarks
+    // themselves, so don't open them until the first one runs.
+    fn connect_pool(&mut self) -> Result<(), Error> {
+        if self.pool.is_none() {
+            self.pool = Some(Pool::connect(&self.config)?);
+        }
+        Ok(())
+    }
+
+    /// Create the bucket if it doesn't exist and seed it with
+    /// `config.objects` new test objects, in batches of
+    /// `config.seed_batch_size` written by `config.seed_workers` threads, or
+    /// one putobject request at a time if the batch size is zero.  The
+    /// objects are generated as they are written rather than all up front, so
+    /// only their sizes are kept.  They are read from `config.load_dataset`
+    /// instead if it is set, and saved to `config.save_dataset` if that is.
+    pub fn seed(&mut self) -> Result<ValueSizes, Error> {
+        dataset::adopt(&mut self.config)?;
+        get_or_create_bucket(&mut self.client, &self.config);
+
+        let config = seed_config(&self.config);
+        let pool = Pool::connect(&config)?;
+        let stats = Arc::new(PhaseStats::new(&config, "seed"));
+        let mut sizes = Vec::with_capacity(config.objects as usize);
+        let mut directories = 0;
+        let mut saved = match &config.save_dataset {
+            Some(path) => Some(dataset::Writer::create(path, &config)?),
+            None => None,
+        };
+        let mut load_error = None;
+
+        let objects: Box<dyn Iterator<Item = (String, Value)> + '_> = match &config.load_dataset {
+            Some(path) => {
+                println!("Seeding {} objects from {}", config.objects, path);
+                let reader = dataset::Reader::open(path)?;
+                // Stop at the first bad entry, and fail once the objects
+                // before it are written.
+                Box::new(reader.scan(&mut load_error, |error, entry| match entry {
+                    Ok(entry) => Some(entry),
+                    Err(e) => {
+                        **error = Some(e);
+                        None
+                    }
+                }))
+            }
+            None => {
+                println!("Seeding {} objects", config.objects);
+                Box::new(
+                    ObjectGen::new(&self.config, &mut self.rng)
+                        .map(|(key, mobj)| (key, serde_json::to_value(mobj).unwrap())),
+                )
+            }
+        };
+        let objects = objects.inspect(|(key, value)| {
+            if value["type"] == metadata::DIRECTORY {
+                directories += 1;
+            }
+            sizes.push(value_size(value));
+            if let Some(saved) = &mut saved {
+                saved.write(key, value);
+            }
+        });
+        let sampler = Sampler::start(&config, &stats);
+        let progress = Progress::watch(&config, &stats, u64::from(config.objects));
+        let elapsed = write_stream(&pool, &config, &stats, objects);
+        sampler.stop();
+        progress.finish();
+        latency_csv::flush();
+        let elapsed = elapsed?;
+        if let Some(e) = load_error {
+            return Err(e);
+        }
+        if let Some(saved) = saved {
+            saved.finish()?;
+        }
+
+        println!(
+            "Done seeding {} objects, {} of them directories: {}ms",
+            stats.objects(),
+            directories,
+            elapsed.as_millis()
+        );
+        stats.print_throughput(elapsed);
+        stats.print_latency();
+        stats.print_retries();
+        stats.print_failures();
+
+        self.check_count("seeding")?;
+        Ok(ValueSizes::new(sizes))
+    }
+
+    /// Count the objects in the bucket and, as `config.count_check` says,
+    /// warn or fail unless there are exactly `config.objects` of them.
+    /// Leftovers from an earlier run that crashed show up here.
+    pub fn check_count(&mut self, after: &str) -> Result<(), Error> {
+        if self.config.count_check == CountCheck::Off || shutdown::requested() {
+            return Ok(());
+        }
+
+        let filter = self.config.key_filter();
+        let count = count_objects(&mut self.client, &self.config.bucket, &filter)?;
+        if count == u64::from(self.config.objects) {
+            return Ok(());
+        }
+
+        let drift = format!(
+            "expected {} objects in {} after {}, found {}",
+            self.config.objects, self.config.bucket, after, count
+        );
+        if self.config.count_check == CountCheck::Fail {
+            return Err(InternalError::VerificationFailed(drift).into());
+        }
+        eprintln!("Warning: {}", drift);
+
+        Ok(())
+    }
+
+    /// Read back every test object currently in the bucket.  Unlike seeding,
+    /// this holds all of them in memory at once.
+    pub fn fetch_objects(&mut self) -> Result<HashMap<String, MantaObject>, Error> {
+        let filter = self.config.key_filter();
+        let progress = Progress::new(&self.config, "fetch", u64::from(self.config.objects));
+        let test_objects =
+            fetch_test_objects(&mut self.client, &self.config.bucket, &filter, &progress);
+        progress.finish();
+        test_objects
+    }
+
+    /// Alter every object and write them back one at a time.
+    pub fn run_sequential(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+    ) -> Result<PhaseReport, Error> {
+        self.connect_pool()?;
+        let pool = self.pool.as_ref().expect("pool connected");
+        let altered_objects = alter_objects(test_objects, &mut self.rng);
+        let writes = access::writes(self.config.access, sorted(altered_objects), &mut self.rng);
+        let expected = self.expected(&writes.iter().cloned().collect());
+
+        let mut report = run_sequential_test(pool, &self.config, writes)?;
+        self.read_back(&mut report, expected)?;
+        self.check_count(report.phase)?;
+        Ok(report)
+    }
+
+    /// Alter every object and write them back `config.batch_size` at a time.
+    pub fn run_batch(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+    ) -> Result<PhaseReport, Error> {
+        self.connect_pool()?;
+        let pool = self.pool.as_ref().expect("pool connected");
+        let batch_objects = alter_objects(test_objects, &mut self.rng);
+        let deletes = choose_deletes(test_objects, self.config.delete_fraction, &mut self.rng);
+        let deleted: Vec<(String, Value)> = deletes
+            .iter()
+            .map(|key| (key.clone(), batch_objects[key].clone()))
+            .collect();
+
+        let writes = access::writes(self.config.access, sorted(batch_objects), &mut self.rng);
+        let expected = self.expected(&writes.iter().cloned().collect());
+
+        chaos::arm(&self.config);
+        let result = run_batch_test(pool, &self.config, writes, deletes);
+        chaos::disarm();
+        let mut report = result?;
+        if self.config.chaos_reset > 0.0 {
+            report.chaos = Some(chaos::check(&mut self.client, &self.config)?);
+        }
+
+        // Unless a later cycle already put them back, restore the deleted
+        // objects as this phase would have updated them, so that the next
+        // phase starts from every object and they still agree on their last
+        // shark.
+        if report.cycles == 1 && !deleted.is_empty() {
+            self.restore(deleted)?;
+        }
+
+        self.read_back(&mut report, expected)?;
+        self.check_count(report.phase)?;
+        Ok(report)
+    }
+
+    // The objects a phase is about to write, kept to read back afterwards if
+    // `config.read_back` is set.
+    fn expected(&self, objects: &HashMap<String, Value>) -> Option<readback::Expected> {
+        if self.config.read_back {
+            Some(readback::Expected::new(objects.clone()))
+        } else {
+            None
+        }
+    }
+
+    // Read back the objects the phase that made `report` wrote, and fail
+    // unless they all hold what it wrote or `config.continue_on_error` is set.
+    fn read_back(
+        &mut self,
+        report: &mut PhaseReport,
+        expected: Option<readback::Expected>,
+    ) -> Result<(), Error> {
+        let expected = match expected {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+        // A phase that was stopped didn't write everything it meant to.
+        if shutdown::requested() {
+            return Ok(());
+        }
+
+        let read_back =
+            readback::read_back(&mut self.client, &self.config, &expected, report.cycles)?;
+        let ok = read_back.ok();
+        report.read_back = Some(read_back);
+
+        if !ok && !self.config.continue_on_error {
+            return Err(InternalError::VerificationFailed(format!(
+                "{} phase did not persist what it wrote",
+                report.phase
+            ))
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Rewrite the indexed fields of the test objects with updateobjects
+    /// requests of `config.batch_size` objects each.
+    pub fn run_update_objects(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+    ) -> Result<PhaseReport, Error> {
+        self.connect_pool()?;
+        let pool = self.pool.as_ref().expect("pool connected");
+        update::run_update_objects_test(pool, &self.config, test_objects)
+    }
+
+    /// Alter every object and write them straight to the bucket's table with
+    /// the sql RPC, `config.batch_size` rows per statement.
+    pub fn run_sql(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+    ) -> Result<PhaseReport, Error> {
+        self.connect_pool()?;
+        let pool = self.pool.as_ref().expect("pool connected");
+        let sql_objects = alter_objects(test_objects, &mut self.rng);
+        sql::run_sql_test(pool, &self.config, sql_objects)
+    }
+
+    /// Add an index to the bucket and reindex every object while running the
+    /// batch test, then take the index away again.
+    pub fn run_reindex(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+    ) -> Result<Vec<PhaseReport>, Error> {
+        let client = create_client(&Target::from_config(&self.config))?;
+        reindex::add_index(&mut self.client, &self.config)?;
+
+        let config = self.config.clone();
+        let reindexer = thread::spawn(move || reindex::run_reindex_test(client, config));
+        let batch = self.run_batch(test_objects);
+        let reindexed = reindexer
+            .join()
+            .map_err(|_| Error::from(InternalError::WorkerPanicked))
+            .and_then(|r| r);
+
+        reindex::remove_index(&mut self.client, &self.config)?;
+
+        let mut batch = batch?;
+        batch.phase = "reindex_batch";
+        Ok(vec![reindexed?, batch])
+    }
+
+    /// Alter every object and write them to a variant of the bucket with a
+    /// unique index, `config.conflict_fraction` of them conflicting.
+    pub fn run_unique(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+    ) -> Result<PhaseReport, Error> {
+        unique::create_bucket(&mut self.client, &self.config);
+        self.connect_pool()?;
+        let pool = self.pool.as_ref().expect("pool connected");
+        unique::run_unique_test(
+            pool,
+            &self.config,
+            alter_objects(test_objects, &mut self.rng),
+        )
+    }
+
+    /// Alter every object and have every worker race to write them all with
+    /// puts conditional on their etags.
+    pub fn run_cas(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+    ) -> Result<PhaseReport, Error> {
+        self.connect_pool()?;
+        let pool = self.pool.as_ref().expect("pool connected");
+        let cas_objects = alter_objects(test_objects, &mut self.rng);
+        cas::run_cas_test(pool, &self.config, test_objects, cas_objects)
+    }
+
+    /// Move every test object with a copy on `config.rebalance_from` to
+    /// `config.rebalance_to` the way the rebalancer does, then put the test
+    /// objects back as they were.
+    pub fn run_rebalance(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+    ) -> Result<PhaseReport, Error> {
+        self.connect_pool()?;
+        let pool = self.pool.as_ref().expect("pool connected");
+        let report = rebalance::run_rebalance_test(
+            &mut self.client,
+            pool,
+            &self.config,
+            &self.config.rebalance_from,
+            &self.config.rebalance_to,
+        )?;
+        self.restore(values(test_objects))?;
+
+        Ok(report)
+    }
+
+    /// Record the deletion of every test object in the delete log bucket, in
+    /// batches of `config.batch_size`, then consume the log the way the
+    /// garbage collector does.  The test objects themselves are left alone.
+    pub fn run_delete_log(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+    ) -> Result<Vec<PhaseReport>, Error> {
+        let config = deletelog::delete_log_config(&self.config);
+        deletelog::create_bucket(&mut self.client, &self.config);
+        self.connect_pool()?;
+        let pool = self.pool.as_ref().expect("pool connected");
+
+        let records = deletelog::records(test_objects);
+        let mut insert = run_batch_test(pool, &config, records, HashSet::new())?;
+        insert.phase = "delete_log_insert";
+        let consume = deletelog::run_consume_test(pool, &self.config)?;
+
+        Ok(vec![insert, consume])
+    }
+
+    /// Write the same alteration of the objects sequentially and then in
+    /// batches, with a different alteration in between so that the batches
+    /// have something to change, and check that both left the bucket holding
+    /// the same values.
+    pub fn run_consistency(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+    ) -> Result<Vec<PhaseReport>, Error> {
+        // Every cycle after the first would alter the objects afresh.
+        let mut config = self.config.clone();
+        config.duration_secs = 0;
+
+        self.connect_pool()?;
+        let altered_objects = alter_objects(test_objects, &mut self.rng);
+
+        let pool = self.pool.as_ref().expect("pool connected");
+        let altered_objects = sorted(altered_objects);
+        let mut sequential = run_sequential_test(pool, &config, altered_objects.clone())?;
+        sequential.phase = "consistency_sequential";
+        let filter = config.key_filter();
+        let after_sequential = readback::fetch_values(&mut self.client, &config.bucket, &filter)?;
+
+        let between = alter_objects(test_objects, &mut self.rng);
+        self.restore(between.into_iter().collect())?;
+
+        let pool = self.pool.as_ref().expect("pool connected");
+        let mut batch = run_batch_test(pool, &config, altered_objects, HashSet::new())?;
+        batch.phase = "consistency_batch";
+        let after_batch = readback::fetch_values(&mut self.client, &config.bucket, &filter)?;
+
+        let consistency =
+            readback::compare(("sequential", &after_sequential), ("batch", &after_batch));
+        if !consistency.ok() && !config.continue_on_error {
+            return Err(InternalError::VerificationFailed(String::from(
+                "sequential and batch puts left different objects",
+            ))
+            .into());
+        }
+
+        Ok(vec![sequential, batch])
+    }
+
+    /// Query the test objects by their indexed fields.
+    pub fn run_find(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+    ) -> Result<PhaseReport, Error> {
+        self.connect_pool()?;
+        let pool = self.pool.as_ref().expect("pool connected");
+        find::run_find_test(pool, &self.config, test_objects)
+    }
+
+    /// Fetch every test object one at a time, then again with
+    /// `config.workers` workers if there are more than one.
+    pub fn run_get(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+    ) -> Result<Vec<PhaseReport>, Error> {
+        self.connect_pool()?;
+        let pool = self.pool.as_ref().expect("pool connected");
+        let mut config = self.config.clone();
+        let mut reports = vec![];
+
+        config.workers = 1;
+        reports.push(get::run_get_test(pool, &config, test_objects)?);
+
+        if self.config.workers > 1 {
+            reports.push(get::run_get_test(pool, &self.config, test_objects)?);
+        }
+
+        Ok(reports)
+    }
+
+    /// Delete every test object one at a time, then in batches of
+    /// `config.batch_size`, then with deletemany filters covering
+    /// `config.batch_size` objects each, putting the objects back after each,
+    /// and print how they compare.  The deletemany phase is skipped if the
+    /// bucket's schema doesn't index `objectId`, which its filters match on.
+    pub fn run_delete_comparison(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+    ) -> Result<Vec<PhaseReport>, Error> {
+        let keys: Vec<String> = test_objects.keys().cloned().collect();
+
+        self.connect_pool()?;
+        let pool = self.pool.as_ref().expect("pool connected");
+        let sequential = delete::run_sequential_delete_test(pool, &self.config, keys.clone())?;
+        self.restore(values(test_objects))?;
+
+        let pool = self.pool.as_ref().expect("pool connected");
+        let batch = delete::run_batch_delete_test(pool, &self.config, keys)?;
+        self.restore(values(test_objects))?;
+
+        let many = if self.config.index.contains_key("objectId") {
+            let ids = test_objects
+                .values()
+                .map(|obj| obj.object_id.clone())
+                .collect();
+            let pool = self.pool.as_ref().expect("pool connected");
+            let many = delete::run_delete_many_test(pool, &self.config, ids)?;
+            self.restore(values(test_objects))?;
+            Some(many)
+        } else {
+            println!("Skipping deletemany: the bucket's schema doesn't index objectId");
+            None
+        };
+
+        if sequential.objects_per_sec > 0.0 {
+            println!(
+                "Batch delete speedup: {:.2}x",
+                batch.objects_per_sec / sequential.objects_per_sec
+            );
+            if let Some(many) = &many {
+                println!(
+                    "deletemany speedup: {:.2}x ({:.1} rows/s)",
+                    many.objects_per_sec / sequential.objects_per_sec,
+                    many.objects_per_sec
+                );
+            }
+        }
+
+        Ok(vec![sequential, batch].into_iter().chain(many).collect())
+    }
+
+    // Put back objects that a phase deleted.
+    fn restore(&mut self, objects: Vec<(String, Value)>) -> Result<(), Error> {
+        let opts = objects::MethodOptions::default();
+
+        println!("Restoring {} objects", objects.len());
+        let size = self.config.batch_size;
+        let batches = PutBatches::new(&self.config.bucket, objects.into_iter(), size)
+            .with_max_bytes(self.config.max_batch_bytes);
+        for batch in batches {
+            self.client.batch(&batch, &opts, |_| Ok(()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Run the sequential test `config.iterations` times in a row as part of
+    /// pass `pass`.
+    pub fn run_sequential_pass(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+        pass: u32,
+    ) -> Result<Vec<PhaseReport>, Error> {
+        self.iterate(pass, |runner| runner.run_sequential(test_objects))
+    }
+
+    /// Run the batch test `config.iterations` times in a row as part of pass
+    /// `pass`.
+    pub fn run_batch_pass(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+        pass: u32,
+    ) -> Result<Vec<PhaseReport>, Error> {
+        self.iterate(pass, |runner| runner.run_batch(test_objects))
+    }
+
+    fn iterate<F>(&mut self, pass: u32, mut phase: F) -> Result<Vec<PhaseReport>, Error>
+    where
+        F: FnMut(&mut BenchRunner) -> Result<PhaseReport, Error>,
+    {
+        let iterations = self.config.iterations.max(1);
+        let mut reports = vec![];
+
+        for iteration in 1..=iterations {
+            if iteration > 1 && shutdown::requested() {
+                break;
+            }
+            if iterations > 1 {
+                println!("---- iteration {} of {} ----", iteration, iterations);
+            }
+
+            let mut report = phase(self)?;
+            report.pass = pass;
+            report.iteration = iteration;
+            reports.push(report);
+        }
+
+        Ok(reports)
+    }
+
+    /// Run the batch test once for each of `config.sweep_batch_sizes`,
+    /// altering the objects afresh each time, and print the throughput and
+    /// p99 latency of each batch size.
+    pub fn run_batch_sweep(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+    ) -> Result<Vec<PhaseReport>, Error> {
+        let batch_size = self.config.batch_size;
+        let mut reports = vec![];
+
+        for size in self.config.sweep_batch_sizes.clone() {
+            if shutdown::requested() {
+                break;
+            }
+            println!("\n ==== batch size {} ====", size);
+            self.config.batch_size = size;
+            let result = self.run_batch(test_objects);
+            self.config.batch_size = batch_size;
+            reports.push(result?);
+        }
+
+        print_batch_sweep(&reports);
+
+        Ok(reports)
+    }
+
+    /// Run the sequential test and the batch test at each of
+    /// `config.sweep_workers` worker counts, and print a matrix of the
+    /// results.  The batch test runs once for each of
+    /// `config.sweep_batch_sizes`, or just with `config.batch_size`.
+    pub fn run_worker_sweep(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+    ) -> Result<Vec<PhaseReport>, Error> {
+        let original = self.config.clone();
+        let batch_sizes = if original.sweep_batch_sizes.is_empty() {
+            vec![original.batch_size]
+        } else {
+            original.sweep_batch_sizes.clone()
+        };
+
+        let result = self.sweep_workers(test_objects, &original.sweep_workers, &batch_sizes);
+
+        // Without a fixed number of connections the pool was sized for the
+        // last worker count.
+        if original.connections == 0 {
+            self.pool = None;
+        }
+        self.config = original;
+
+        let reports = result?;
+        print_worker_sweep(&reports, &batch_sizes);
+
+        Ok(reports)
+    }
+
+    /// Run the sequential test and the batch test with each of
+    /// `config.sweep_connections` connections per worker, at a fixed number
+    /// of workers, and print a matrix of the results.  The batch test runs
+    /// once for each of `config.sweep_batch_sizes`, or just with
+    /// `config.batch_size`.
+    pub fn run_connection_sweep(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+    ) -> Result<Vec<PhaseReport>, Error> {
+        let original = self.config.clone();
+        let batch_sizes = if original.sweep_batch_sizes.is_empty() {
+            vec![original.batch_size]
+        } else {
+            original.sweep_batch_sizes.clone()
+        };
+
+        let result =
+            self.sweep_connections(test_objects, &original.sweep_connections, &batch_sizes);
+
+        // The pool was sized for the last count.
+        self.pool = None;
+        self.config = original;
+
+        let reports = result?;
+        print_connection_sweep(&reports, &batch_sizes);
+
+        Ok(reports)
+    }
+
+    fn sweep_connections(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+        counts: &[u32],
+        batch_sizes: &[u32],
+    ) -> Result<Vec<PhaseReport>, Error> {
+        let mut reports = vec![];
+
+        for &count in counts.iter() {
+            if shutdown::requested() {
+                break;
+            }
+            self.config.connections_per_worker = count;
+            self.pool = None;
+
+            println!("\n ==== {} connections per worker, sequential ====", count);
+            reports.push(self.run_sequential(test_objects)?);
+
+            for &size in batch_sizes.iter() {
+                if shutdown::requested() {
+                    break;
+                }
+                println!(
+                    "\n ==== {} connections per worker, batch size {} ====",
+                    count, size
+                );
+                self.config.batch_size = size;
+                reports.push(self.run_batch(test_objects)?);
+            }
+        }
+
+        Ok(reports)
+    }
+
+    fn sweep_workers(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+        worker_counts: &[u32],
+        batch_sizes: &[u32],
+    ) -> Result<Vec<PhaseReport>, Error> {
+        let mut reports = vec![];
+
+        for &workers in worker_counts.iter() {
+            if shutdown::requested() {
+                break;
+            }
+            self.config.workers = workers;
+            // Without a fixed number of connections each worker gets its
+            // own, so the pool has to grow with the workers.
+            if self.config.connections == 0 {
+                self.pool = None;
+            }
+
+            println!("\n ==== {} workers, sequential ====", workers);
+            reports.push(self.run_sequential(test_objects)?);
+
+            for &size in batch_sizes.iter() {
+                if shutdown::requested() {
+                    break;
+                }
+                println!("\n ==== {} workers, batch size {} ====", workers, size);
+                self.config.batch_size = size;
+                reports.push(self.run_batch(test_objects)?);
+            }
+        }
+
+        Ok(reports)
+    }
+
+    /// Run `config.passes` passes of both tests, in `config.pass_order`.
+    pub fn run_comparison(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+    ) -> Result<RunSummary, Error> {
+        let mut summary = RunSummary::new(test_objects.len());
+
+        println!(" ==== running comparison ====");
+
+        for pass in 1..=self.config.passes {
+            if shutdown::requested() {
+                break;
+            }
+            self.comparison_pass(test_objects, pass, &mut summary)?;
+        }
+
+        summary.print_comparison();
+        summary.print_statistics();
+
+        Ok(summary)
+    }
+
+    // By default the order of the two tests alternates on each pass so that
+    // neither strategy consistently benefits from running second.
+    fn comparison_pass(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+        pass: u32,
+        summary: &mut RunSummary,
+    ) -> Result<(), Error> {
+        if self.config.pass_order.sequential_first(pass) {
+            println!("\n ==== pass {}, sequential first then batch ====", pass);
+            let sequential = self.run_sequential_pass(test_objects, pass)?;
+            summary.sequential.extend(sequential);
+            if !shutdown::requested() {
+                let batch = self.run_batch_pass(test_objects, pass)?;
+                summary.batch.extend(batch);
+            }
+        } else {
+            println!("\n ==== pass {}, batch first then sequential ====", pass);
+            let batch = self.run_batch_pass(test_objects, pass)?;
+            summary.batch.extend(batch);
+            if !shutdown::requested() {
+                let sequential = self.run_sequential_pass(test_objects, pass)?;
+                summary.sequential.extend(sequential);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Keep running passes of the sequential test, the batch test, or both,
+    /// until `config.soak_secs` have passed.  Interim reports are printed by
+    /// `soak::Reporter` as the run goes.
+    pub fn run_soak(
+        &mut self,
+        test_objects: &HashMap<String, MantaObject>,
+        sequential: bool,
+        batch: bool,
+    ) -> Result<RunSummary, Error> {
+        let mut summary = RunSummary::new(test_objects.len());
+        let deadline = Instant::now() + Duration::from_secs(self.config.soak_secs);
+
+        println!(" ==== running soak test ====");
+
+        let mut pass = 1;
+        while Instant::now() < deadline && !shutdown::requested() {
+            if sequential && batch {
+                self.comparison_pass(test_objects, pass, &mut summary)?;
+            } else if sequential {
+                println!("\n ==== soak pass {} ====", pass);
+                let sequential = self.run_sequential_pass(test_objects, pass)?;
+                summary.sequential.extend(sequential);
+            } else {
+                println!("\n ==== soak pass {} ====", pass);
+                let batch = self.run_batch_pass(test_objects, pass)?;
+                summary.batch.extend(batch);
+            }
+            pass += 1;
+        }
+
+        if sequential && batch {
+            summary.print_comparison();
+        }
+        summary.print_statistics();
+
+        Ok(summary)
+    }
+
+    /// Every alteration replaces the last shark of every object with the same
+    /// shark, so after a benchmark run all objects other than directories
+    /// should share their last shark.  Also check that the bucket holds
+    /// `config.objects` objects and, if `config.load_dataset` is set, that
+    /// they are the objects saved there.
+    pub fn verify(&mut self) -> Result<(), Error> {
+        dataset::adopt(&mut self.config)?;
+        println!("Fetching test objects");
+        let test_objects = self.fetch_objects()?;
+        let mut last_sharks: HashMap<String, u32> = HashMap::new();
+        let mut problems = vec![];
+
+        for obj in test_objects.values() {
+            if metadata::is_directory(obj) {
+                continue;
+            }
+            match obj.sharks.last() {
+                Some(shark) => {
+                    let id = format!("{}/{}", shark.datacenter, shark.manta_storage_id);
+                    *last_sharks.entry(id).or_insert(0) += 1;
+                }
+                None => problems.push(format!("{} has no sharks", obj.object_id)),
+            }
+        }
+
+        if test_objects.len() != self.config.objects as usize {
+            problems.push(format!(
+                "expected {} objects, found {}",
+                self.config.objects,
+                test_objects.len()
+            ));
+        }
+
+        if last_sharks.len() > 1 {
+            problems.push(format!(
+                "objects disagree on their last shark: {:?}",
+                last_sharks
+            ));
+        }
+
+        if let Some(path) = &self.config.load_dataset {
+            println!("Comparing test objects with {}", path);
+            problems.extend(dataset::compare(path, &test_objects)?);
+        }
+
+        if !problems.is_empty() {
+            for p in problems.iter() {
+                eprintln!("{}", p);
+            }
+            return Err(InternalError::VerificationFailed(format!(
+                "{} problems found",
+                problems.len()
+            ))
+            .into());
+        }
+
+        println!("Verified {} objects", test_objects.len());
+        Ok(())
+    }
+
+    /// Delete the test objects of every run but those in `keep` from the
+    /// bucket and its companions, leaving any written in the last
+    /// `older_than`.
+    pub fn gc(&mut self, keep: &[String], older_than: Option<Duration>) -> Result<(), Error> {
+        let before = older_than.map(|age| {
+            SystemTime::now()
+                .checked_sub(age)
+                .unwrap_or(UNIX_EPOCH)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64
+        });
+        let filter = cleanup::stale_filter(keep, before);
+
+        for bucket in test_buckets(&self.config) {
+            let removed = cleanup::delete_test_objects(
+                &mut self.client,
+                bucket,
+                &filter,
+                self.config.batch_size,
+            )?;
+            println!("Removed {} stale objects from {}", removed, bucket);
+        }
+
+        Ok(())
+    }
+
+    /// Remove the test objects, or drop the whole bucket if `drop_bucket` is
+    /// set, along with any companion buckets.
+    pub fn cleanup(&mut self, drop_bucket: bool) -> Result<(), Error> {
+        let filter = self.config.key_filter();
+
+        for bucket in test_buckets(&self.config) {
+            if drop_bucket {
+                cleanup::drop_bucket(&mut self.client, bucket)?;
+                println!("Dropped bucket {}", bucket);
+                continue;
+            }
+
+            let removed = cleanup::delete_test_objects(
+                &mut self.client,
+                bucket,
+                &filter,
+                self.config.batch_size,
+            )?;
+            println!("Removed {} objects from {}", removed, bucket);
+        }
+
+        Ok(())
+    }
+}
+
+/// Where to find the moray service.
+#[derive(Clone, Debug)]
+pub enum Target {
+    /// Look up `<shard>.moray.<domain>` in binder.
+    SrvLookup { shard: u32, domain: String },
+    /// Look up `electric-moray.<domain>` in binder.  Electric-moray routes each
+    /// key to its shard through the vnode ring.
+    ElectricMoray { domain: String },
+    /// Connect to a known moray instance, for labs and COAL where binder has
+    /// no SRV records.
+    Direct(SocketAddr),
+}
+
+impl Target {
+    pub fn from_config(config: &Config) -> Target {
+        if let Some(host) = config.host {
+            Target::Direct(host)
+        } else if config.electric_moray {
+            Target::ElectricMoray {
+                domain: config.domain.clone(),
+            }
+        } else {
+            Target::SrvLookup {
+                shard: config.shard,
+                domain: config.domain.clone(),
+            }
+        }
+    }
+
+    // The name the SRV record is published under.
+    pub(crate) fn domain_name(&self) -> String {
+        match self {
+            Target::SrvLookup { shard, domain } => format!("{}.moray.{}", shard, domain),
+            Target::ElectricMoray { domain } => format!("electric-moray.{}", domain),
+            Target::Direct(addr) => addr.to_string(),
+        }
+    }
+
+    // The addresses of every moray instance for this target, in the order
+    // they should be tried.
+    pub fn addrs(&self) -> Result<Vec<SocketAddr>, Error> {
+        match self {
+            Target::Direct(addr) => Ok(vec![*addr]),
+            _ => dns::get_moray_srv_sockaddrs(&self.domain_name()),
+        }
+    }
+}
+
+/// Apply the DNS settings in `config`, which every connection in
+/// the process shares.
+pub fn configure_network(config: &Config) {
+    resolver::set_ttl(Duration::from_secs(config.dns_ttl));
+    dns::set_nameservers(&config.nameservers);
+}
+
+// Create a moray client for a specific moray instance.  A moray client is a
+// pool of its own, which is kept to a single fast connection so that the
+// number of clients the run opens is the number of connections it makes.
+pub(crate) fn connect(sock_addr: SocketAddr) -> Result<MorayClient, Error> {
+    let _span = trace::span(
+        "connect",
+        vec![KeyValue::new("addr", sock_addr.to_string())],
+    );
+    let log = logging::logger().new(o!("addr" => sock_addr.to_string()));
+    debug!(log, "connecting to moray");
+
+    let opts = ConnectionPoolOptions {
+        max_connections: Some(1),
+        claim_timeout: None,
+        log: Some(log.clone()),
+        rebalancer_action_delay: None,
+        decoherence_interval: None,
+        connection_check_interval: None,
+    };
+
+    timing::time(Step::Connect, || {
+        MorayClient::new(sock_addr, log, Some(opts))
+    })
+    .map_err(Error::from)
+}
+
+// Connect to the first instance that accepts a connection, trying them in
+// order.
+fn connect_any(addrs: &[SocketAddr]) -> Result<MorayClient, Error> {
+    let mut last_err = None;
+
+    for &addr in addrs.iter() {
+        match connect(addr) {
+            Ok(client) => return Ok(client),
+            Err(e) => {
+                eprintln!("Unable to connect to {}: {}", addr, e);
+                last_err = Some(e);
+            }
+        }
+    }
+
+    let addrs: Vec<String> = addrs.iter().map(|a| a.to_string()).collect();
+    Err(InternalError::Connect {
+        addrs: addrs.join(", "),
+        reason: last_err.map_or_else(|| String::from("no addresses"), |e| e.to_string()),
+    }
+    .into())
+}
+
+// Create a moray client for the target.  For the SRV based targets this will
+// query binder for the SRV records for us, pick an instance according to their
+// priorities and weights, and fall through to the next instance if one can't
+// be reached.
+pub fn create_client(target: &Target) -> Result<MorayClient, Error> {
+    connect_any(&target.addrs()?)
+}
+
+fn random_string<R: Rng>(rng: &mut R, len: usize) -> String {
+    rng.sample_iter(&Alphanumeric).take(len).collect()
+}
+
+/// A random number generator that always produces the same numbers for the
+/// same seed, or seeded from the system if there is none.
+pub fn seeded_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+/// A new run id: a ULID, so that ids sort by when they were made.
+pub fn new_run_id() -> String {
+    static ALPHABET: &[u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u128;
+    let random: u128 = thread_rng().gen::<u128>() & ((1 << 80) - 1);
+    let ulid = (millis & ((1 << 48) - 1)) << 80 | random;
+
+    (0..26)
+        .rev()
+        .map(|i| ALPHABET[((ulid >> (i * 5)) & 0x1f) as usize] as char)
+        .collect()
+}
+
+/// Generate `config.objects` random manta objects from `rng`, with fields as
+/// `config.metadata` says, padded out to `config.value_size` and keyed by
+/// their object ids under the run id if there is one.  About
+/// `config.directory_fraction` of them are directory entries instead.
+pub fn gen_test_objects(config: &Config, rng: &mut StdRng) -> HashMap<String, MantaObject> {
+    ObjectGen::new(config, rng).collect()
+}
+
+/// The test objects `gen_test_objects` makes, one at a time, so that a large
+/// dataset can be written without holding all of it.  The same seed makes
+/// the same objects in the same order.
+pub struct ObjectGen<'a> {
+    config: &'a Config,
+    g: StdGen<&'a mut StdRng>,
+    generator: Option<metadata::Generator>,
+    remaining: u32,
+}
+
+impl<'a> ObjectGen<'a> {
+    pub fn new(config: &'a Config, rng: &'a mut StdRng) -> ObjectGen<'a> {
+        let mut g = StdGen::new(rng, 10);
+        let generator = match config.metadata {
+            Metadata::Random => None,
+            Metadata::Manta => Some(metadata::Generator::new(config, &mut g)),
+        };
+
+        ObjectGen {
+            config,
+            g,
+            generator,
+            remaining: config.objects,
+        }
+    }
+}
+
+impl<'a> Iterator for ObjectGen<'a> {
+    type Item = (String, MantaObject);
+
+    fn next(&mut self) -> Option<(String, MantaObject)> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let g = &mut self.g;
+        let mut mobj = MantaObject::arbitrary(g);
+        if let Some(generator) = &self.generator {
+            generator.fill(&mut mobj, g);
+        }
+        let mut sharks = vec![];
+
+        // first pass: 1 or 2
+        // second pass: 3 or 4
+        for i in 0..2 {
+            let shark_num = g.gen_range(1 + i * 2, 3 + i * 2);
+
+            let shark = MantaObjectShark {
+                datacenter: String::from("foo"), //todo
+                manta_storage_id: format!("{}.stor.domain", shark_num),
+            };
+            sharks.push(shark);
+        }
+        mobj.sharks = sharks;
+
+        if g.gen_bool(self.config.directory_fraction.clamp(0.0, 1.0)) {
+            metadata::make_directory(&mut mobj);
+        }
+        if let Some(size) = sample_size(self.config.value_size, g) {
+            pad(&mut mobj, size, g);
+        }
+
+        let key = match &self.config.run_id {
+            Some(run_id) => format!("{}/{}", run_id, mobj.object_id),
+            None => mobj.object_id.clone(),
+        };
+        Some((key, mobj))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining as usize, Some(self.remaining as usize))
+    }
+}
+
+// The header that pads objects out to `config.value_size`.  Moray stores
+// headers without looking at them, and manta objects already carry a few.
+const PADDING_HEADER: &str = "m-batch-test-padding";
+
+fn sample_size<R: Rng>(value_size: ValueSize, rng: &mut R) -> Option<u64> {
+    match value_size {
+        ValueSize::Natural => None,
+        ValueSize::Fixed(size) => Some(size),
+        ValueSize::LogNormal { median, sigma } => {
+            let sizes = LogNormal::new((median.max(1) as f64).ln(), sigma);
+            Some(sizes.sample(rng).round() as u64)
+        }
+    }
+}
+
+/// The length of an object's value serialized as JSON.
+pub fn value_size<T: serde::Serialize>(value: &T) -> u64 {
+    serde_json::to_string(value)
+        .map(|s| s.len() as u64)
+        .unwrap_or(0)
+}
+
+// Pad the object's headers with random characters, which don't compress, until
+// its serialized value is `size` bytes.  Objects already bigger are left alone.
+fn pad<R: Rng>(mobj: &mut MantaObject, size: u64, rng: &mut R) {
+    if !mobj.headers.is_object() {
+        mobj.headers = json!({});
+    }
+    mobj.headers[PADDING_HEADER] = json!("");
+
+    let unpadded = value_size(mobj);
+    if size > unpadded {
+        mobj.headers[PADDING_HEADER] = json!(random_string(rng, (size - unpadded) as usize));
+    } else if let Some(headers) = mobj.headers.as_object_mut() {
+        headers.remove(PADDING_HEADER);
+    }
+}
+
+/// The sizes of the test objects' serialized values.
+pub fn value_sizes(test_objects: &HashMap<String, MantaObject>) -> ValueSizes {
+    ValueSizes::new(test_objects.values().map(value_size))
+}
+
+// Page through every test object in the bucket.  findobjects returns at most
+// `limit` records per request, so keep asking for the next page until a short
+// one comes back, moving `progress` along a page at a time.  Each page starts
+// past the highest `_id` seen so far rather than at an offset, since moray
+// doesn't promise the same order from one request to the next.
+pub fn fetch_test_objects(
+    mclient: &mut MorayClient,
+    bucket: &str,
+    filter: &str,
+    progress: &Progress,
+) -> Result<HashMap<String, MantaObject>, Error> {
+    let mut test_objects = HashMap::new();
+    let mut opts = objects::MethodOptions::default();
+    let mut next_id = 0;
+    let mut bad_objects = 0;
+
+    loop {
+        let mut page_count = 0;
+        opts.limit = Some(PAGE_SIZE);
+
+        mclient.find_objects(bucket, &page_filter(filter, next_id), &opts, |mobj| {
+            page_count += 1;
+            next_id = next_id.max(mobj._id + 1);
+            match serde_json::from_value::<MantaObject>(mobj.value.clone()) {
+                Ok(obj) => {
+                    test_objects.insert(mobj.key.clone(), obj);
+                }
+                Err(_) => bad_objects += 1,
+            }
+            Ok(())
+        })?;
+
+        progress.inc(page_count);
+        if page_count < PAGE_SIZE {
+            break;
+        }
+    }
+
+    if bad_objects > 0 {
+        eprintln!(
+            "Warning: skipped {} objects that are not valid manta objects",
+            bad_objects
+        );
+    }
+
+    Ok(test_objects)
+}
+
+/// `filter` narrowed to the objects whose `_id` is at least `next_id`, for
+/// paging through findobjects.
+pub(crate) fn page_filter(filter: &str, next_id: u64) -> String {
+    format!("(&(_id>={}){})", next_id, filter)
+}
+
+/// The test bucket's schema, indexing the fields in `config.index`.
+pub fn bucket_config(config: &Config) -> Value {
+    json!({ "index": config.index })
+}
+
+/// The number of objects in `bucket` matching `filter`, as moray counts them
+/// for a findobjects request.
+pub fn count_objects(mclient: &mut MorayClient, bucket: &str, filter: &str) -> Result<u64, Error> {
+    let mut opts = objects::MethodOptions::default();
+    let mut count = 0;
+    opts.limit = Some(1);
+
+    mclient.find_objects(bucket, filter, &opts, |mobj| {
+        count = mobj._count;
+        Ok(())
+    })?;
+
+    Ok(count)
+}
+
+/// Create the test bucket and each of its companion buckets, if they don't
+/// exist.
+pub fn get_or_create_bucket(mclient: &mut MorayClient, config: &Config) {
+    let bucket_opts = buckets::MethodOptions::default();
+    let ignore_callback = |_bucket: &buckets::Bucket| Ok(());
+
+    println!("===get or create bucket===");
+    for bucket in test_buckets(config) {
+        if mclient
+            .get_bucket(bucket, bucket_opts.clone(), ignore_callback)
+            .is_ok()
+        {
+            continue;
+        }
+
+        match mclient.create_bucket(bucket, bucket_config(config), bucket_opts.clone()) {
+            Ok(()) => {
+                println!("Bucket {} Created Successfully", bucket);
+            }
+            Err(e) => {
+                eprintln!("Error Creating Bucket {}: {}", bucket, e);
+            }
+        }
+    }
+}
+
+/// The test bucket followed by its companion buckets.
+pub fn test_buckets(config: &Config) -> impl Iterator<Item = &str> {
+    std::iter::once(config.bucket.as_str())
+        .chain(config.companion_buckets.iter().map(|b| b.as_str()))
+}
+
+// Make each write with its own putobject request, spreading the writes across
+// `config.workers` threads.
+pub fn run_sequential_test(
+    pool: &Pool,
+    config: &Config,
+    objects: Vec<(String, Value)>,
+) -> Result<PhaseReport, Error> {
+    if config.workers > 1 {
+        println!(
+            "Updating objects sequentially with {} workers",
+            config.workers
+        );
+    } else {
+        println!("Updating objects sequentially");
+    }
+
+    let stats = Arc::new(PhaseStats::new(config, "sequential"));
+    let requeue = Requeue::new(config, &objects, &HashSet::new());
+    let cycles = Arc::new(Cycles::new(config));
+    let sampler = Sampler::start(config, &stats);
+    let progress = Progress::watch(config, &stats, objects.len() as u64);
+    let started = SystemTime::now();
+    let result = if config.open_loop {
+        put_open_loop(pool, config, &stats, &cycles, objects)
+    } else {
+        let shares = workers::split(objects, config.workers as usize)
+            .into_iter()
+            .map(|objects| ObjectShare {
+                objects,
+                cycles: Arc::clone(&cycles),
+            })
+            .collect();
+        workers::run(config, pool, &stats, shares, put_objects)
+    };
+    sampler.stop();
+    progress.finish();
+    latency_csv::flush();
+    let elapsed = result?;
+
+    println!(
+        "Done updating objects sequentially : {}ms",
+        elapsed.as_millis()
+    );
+    if config.duration_secs > 0 {
+        println!("Made {} passes over the objects", cycles.count());
+    }
+    stats.print_throughput(elapsed);
+    stats.print_latency();
+    stats.print_retries();
+    stats.print_failures();
+
+    let mut report = stats.report(started, elapsed);
+    report.cycles = cycles.count();
+    if let Some(requeue) = &requeue {
+        report.requeue = requeue.run(pool, config, &stats, 0)?;
+    }
+
+    Ok(report)
+}
+
+// A sequential worker's objects, and the cycles it shares with the other
+// workers for how often to go over them.
+struct ObjectShare {
+    objects: Vec<(String, Value)>,
+    cycles: Arc<Cycles>,
+}
+
+fn put_objects(ctx: &Context, share: ObjectShare) -> Result<(), Error> {
+    let mut objects = share.objects;
+    let mut cycle = 0;
+
+    loop {
+        put_cycle(ctx, &objects)?;
+
+        cycle += 1;
+        match share.cycles.next(cycle) {
+            Some(shark) => {
+                for (_, obj) in objects.iter_mut() {
+                    cycles::set_last_shark(obj, &shark);
+                }
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+fn put_cycle(ctx: &Context, objects: &[(String, Value)]) -> Result<(), Error> {
+    for (key, obj) in workers::until_stopped(objects) {
+        put_object(ctx, key, obj, ctx.pace())?;
+    }
+
+    Ok(())
+}
+
+// Open-loop, the objects are handed to the workers one at a time on the
+// pacer's schedule rather than split between them up front.
+fn put_open_loop(
+    pool: &Pool,
+    config: &Config,
+    stats: &Arc<PhaseStats>,
+    cycles: &Cycles,
+    objects: Vec<(String, Value)>,
+) -> Result<Duration, Error> {
+    let (dispatcher, queue) = workers::queue(config, stats);
+    let shares = (0..config.workers.max(1))
+        .map(|_| Arc::clone(&queue))
+        .collect();
+    let workers = workers::spawn(config, pool, stats, shares, put_queued);
+    let start = workers.start();
+
+    send_cycles(cycles, objects, |objects| {
+        objects
+            .iter()
+            .all(|(key, obj)| dispatcher.send(1, (key.clone(), obj.clone())))
+    });
+
+    // Closing the queue tells the workers there is nothing more to put.
+    drop(dispatcher);
+    workers.join()?;
+
+    Ok(start.elapsed())
+}
+
+fn put_queued(ctx: &Context, queue: Queue<(String, Value)>) -> Result<(), Error> {
+    while let Some((start, (key, obj))) = workers::next(ctx, &queue) {
+        put_object(ctx, &key, &obj, start)?;
+    }
+
+    Ok(())
+}
+
+// Options for one request, with a request id of its own for moray to log it
+// under, so that a slow or failed request can be found in the server's logs.
+// Retries of the request keep the same id.
+fn request_options() -> (String, objects::MethodOptions) {
+    let req_id = Uuid::new_v4().to_string();
+    let mut opts = objects::MethodOptions::default();
+    opts.req_id = req_id.clone();
+
+    (req_id, opts)
+}
+
+// Put one object, measuring its latency from `start`.
+fn put_object(ctx: &Context, key: &str, obj: &Value, start: Instant) -> Result<(), Error> {
+    let (req_id, opts) = request_options();
+    let bucket = ctx.config.bucket.as_str();
+    let sampled = timing::sampled();
+    let bytes = time_if(sampled, Step::Serialize, || payload_size(key, obj));
+    let started = SystemTime::now() - start.elapsed();
+    let span = trace::span(
+        "put",
+        vec![
+            KeyValue::new("shard", ctx.config.shard.to_string()),
+            KeyValue::new("key", key.to_string()),
+            KeyValue::new("req_id", req_id.clone()),
+        ],
+    );
+    moray_batch_test::put__start!(|| (ctx.config.shard, key));
+    // The request may be left running on a connection that hangs, so it
+    // keeps its own copy of everything it sends.
+    let (op_bucket, op_key, op_obj, opts) = (
+        bucket.to_string(),
+        key.to_string(),
+        obj.clone(),
+        Arc::new(opts),
+    );
+    let result = ctx.call_with_timeout(move |c| {
+        time_if(sampled, Step::RoundTrip, || {
+            c.put_object(&op_bucket, &op_key, op_obj.clone(), &opts, |_| Ok(()))
+        })?;
+        Ok(())
+    });
+    moray_batch_test::put__done!(|| (ctx.config.shard, key, result.is_ok() as u8));
+    latency_csv::record_request(
+        ctx.config.shard,
+        "sequential",
+        key,
+        &req_id,
+        started,
+        start.elapsed(),
+        &result,
+    );
+    log_request(ctx, "put", &req_id, start.elapsed(), &result);
+    if let Err(e) = &result {
+        trace::set_error(&span, retry::error_name(e));
+    }
+    drop(span);
+
+    match result {
+        Ok(()) => ctx.stats.record_request(1, bytes, start.elapsed()),
+        Err(e) if ctx.config.continue_on_error => {
+            eprintln!("put {} (req_id {}): {}", key, req_id, e);
+            ctx.stats.record_failure(vec![key.to_string()], &e);
+        }
+        Err(e) => {
+            return Err(InternalError::PutFailed {
+                key: key.to_string(),
+                source: e.compat(),
+            }
+            .into())
+        }
+    }
+
+    Ok(())
+}
+
+// Write the objects as `objects` yields them, without holding them all: in
+// batches of `config.batch_size`, or with one putobject request each if that
+// is zero, handed to `config.workers` threads through the queue.
+fn write_stream<I>(
+    pool: &Pool,
+    config: &Config,
+    stats: &Arc<PhaseStats>,
+    objects: I,
+) -> Result<Duration, Error>
+where
+    I: Iterator<Item = (String, Value)>,
+{
+    if config.batch_size == 0 {
+        let (dispatcher, queue) = workers::queue(config, stats);
+        let shares = (0..config.workers.max(1))
+            .map(|_| Arc::clone(&queue))
+            .collect();
+        let workers = workers::spawn(config, pool, stats, shares, put_queued);
+        let start = workers.start();
+
+        for (key, value) in objects {
+            if !dispatcher.send(1, (key, value)) {
+                break;
+            }
+        }
+
+        drop(dispatcher);
+        workers.join()?;
+        return Ok(start.elapsed());
+    }
+
+    let (dispatcher, queue) = workers::queue(config, stats);
+    let shares = (0..config.workers.max(1))
+        .map(|_| BatchQueue {
+            queue: Arc::clone(&queue),
+            tuner: None,
+        })
+        .collect();
+    let workers = workers::spawn(config, pool, stats, shares, submit_batches);
+    let start = workers.start();
+
+    let batches = PutBatches::new(&config.bucket, objects, config.batch_size)
+        .with_max_bytes(config.max_batch_bytes);
+    for (id, batch) in batches.enumerate() {
+        if !dispatcher.send(batch_objects(&batch, &config.bucket), (id, batch)) {
+            break;
+        }
+    }
+
+    // Closing the queue tells the workers there is nothing more to write.
+    drop(dispatcher);
+    workers.join()?;
+
+    Ok(start.elapsed())
+}
+
+// The config seeding writes the objects with: one plain pass over them, as
+// fast as `config.seed_workers` can write them.
+fn seed_config(config: &Config) -> Config {
+    let mut config = config.clone();
+
+    config.batch_size = config.seed_batch_size;
+    config.workers = config.seed_workers.max(1);
+    config.companion_buckets = vec![];
+    config.access = Access::All;
+    config.warmup = Warmup::None;
+    config.duration_secs = 0;
+    config.auto_batch_size = false;
+    config.rate = 0.0;
+    config.profile = Profile::None;
+    config.open_loop = false;
+    config.read_back = false;
+
+    config
+}
+
+// The objects in key order, so that a seeded run sends the same objects in
+// the same requests every time, and draws the same keys to rewrite.
+fn sorted(objects: HashMap<String, Value>) -> Vec<(String, Value)> {
+    let mut objects: Vec<(String, Value)> = objects.into_iter().collect();
+    objects.sort_by(|a, b| a.0.cmp(&b.0));
+    objects
+}
+
+// The test objects as they are, ready to be put back.
+fn values(test_objects: &HashMap<String, MantaObject>) -> Vec<(String, Value)> {
+    test_objects
+        .iter()
+        .map(|(key, obj)| (key.clone(), serde_json::to_value(obj).unwrap()))
+        .collect()
+}
+
+// Choose `fraction` of the test objects at random to delete rather than update.
+fn choose_deletes<R: Rng>(
+    test_objects: &HashMap<String, MantaObject>,
+    fraction: f64,
+    rng: &mut R,
+) -> HashSet<String> {
+    let count = (test_objects.len() as f64 * fraction.clamp(0.0, 1.0)).round() as usize;
+    let mut keys: Vec<&String> = test_objects.keys().collect();
+    keys.sort();
+
+    keys.choose_multiple(rng, count)
+        .map(|key| (*key).clone())
+        .collect()
+}
+
+pub fn alter_objects<R: Rng>(
+    objects: &HashMap<String, MantaObject>,
+    rng: &mut R,
+) -> HashMap<String, Value> {
+    let mut altered_objects: HashMap<String, Value> = HashMap::new();
+    let shark = cycles::random_shark(rng);
+    // Directories have no sharks, so they are touched instead, as adding an
+    // entry to one updates its mtime.
+    let touched: u64 = rng.gen_range(1, 60_000);
+
+    println!(
+        "Altering objects.  datacenter: {} | storage id: {}",
+        shark.datacenter, shark.manta_storage_id
+    );
+
+    for (k, v) in objects.iter() {
+        let mut mobj: MantaObject = v.clone();
+        if metadata::is_directory(&mobj) {
+            mobj.mtime += touched;
+        } else {
+            mobj.sharks.pop();
+            mobj.sharks.push(shark.clone());
+        }
+
+        let mobj_value = serde_json::to_value(mobj).unwrap();
+        altered_objects.insert(k.clone(), mobj_value);
+    }
+    altered_objects
+}
+
+// Write the objects `config.batch_size` at a time.  The batches are handed to
+// `config.workers` threads through a queue holding at most `config.queue_depth`
+// batches.
+//
+// Objects whose keys are in `deletes` are deleted instead of updated in the
+// first cycle, in the same batches as the updates.  Later cycles put them back.
+pub fn run_batch_test(
+    pool: &Pool,
+    config: &Config,
+    objects: Vec<(String, Value)>,
+    deletes: HashSet<String>,
+) -> Result<PhaseReport, Error> {
+    let batch_size = config.batch_size;
+
+    let tuner = if config.auto_batch_size {
+        Some(Arc::new(Tuner::from_config(config)))
+    } else {
+        None
+    };
+
+    if config.auto_batch_size {
+        println!(
+            "Updating objects in batches tuned for a p99 of {}ms, starting at {}",
+            config.target_p99_ms, batch_size
+        );
+    } else if config.workers > 1 {
+        println!(
+            "Updating objects in batches of {} with {} workers",
+            batch_size, config.workers
+        );
+    } else {
+        println!("Updating objects in batches of {}", batch_size);
+    }
+    if config.max_batch_bytes > 0 {
+        println!(
+            "Splitting batches at {} KiB of keys and values",
+            config.max_batch_bytes / 1024
+        );
+    }
+    if !deletes.is_empty() {
+        println!(
+            "Deleting {} of the objects in the same batches",
+            deletes.len()
+        );
+    }
+    if !config.companion_buckets.is_empty() {
+        println!(
+            "Putting each object into {} in the same batches",
+            config.companion_buckets.join(", ")
+        );
+    }
+
+    let stats = Arc::new(PhaseStats::new(config, "batch"));
+    let requeue = Requeue::new(config, &objects, &deletes);
+    let (dispatcher, queue) = workers::queue(config, &stats);
+    let shares = (0..config.workers.max(1))
+        .map(|_| BatchQueue {
+            queue: Arc::clone(&queue),
+            tuner: tuner.clone(),
+        })
+        .collect();
+    let workers = workers::spawn(config, pool, &stats, shares, submit_batches);
+    let sampler = Sampler::start(config, &stats);
+    let progress = Progress::watch(config, &stats, objects.len() as u64);
+    let started = SystemTime::now();
+    let start = workers.start();
+    let cycles = Cycles::new(config);
+
+    let mut id = 0;
+    let mut split = 0;
+    let mut deletes = Some(deletes);
+    send_cycles(&cycles, objects, |objects| {
+        let mut batches = PutBatches::new(&config.bucket, objects.iter().cloned(), batch_size)
+            .with_deletes(deletes.take().unwrap_or_default())
+            .with_companions(config.companion_buckets.clone())
+            .with_max_bytes(config.max_batch_bytes);
+        loop {
+            if let Some(tuner) = &tuner {
+                batches.set_size(tuner.size());
+            }
+
+            let batch = match batches.next() {
+                Some(batch) => batch,
+                None => {
+                    split += batches.split();
+                    return true;
+                }
+            };
+
+            let objects = batch_objects(&batch, &config.bucket);
+            if !dispatcher.send(objects, (id, batch)) {
+                return false;
+            }
+            id += 1;
+        }
+    });
+
+    // Closing the queue tells the workers there is nothing more to submit.
+    drop(dispatcher);
+    let result = workers.join();
+    sampler.stop();
+    progress.finish();
+    latency_csv::flush();
+    result?;
+
+    let elapsed = start.elapsed();
+    println!(
+        "Done updating {} objects in {} batches: {}ms",
+        stats.objects(),
+        stats.requests(),
+        elapsed.as_millis()
+    );
+    if config.duration_secs > 0 {
+        println!("Made {} passes over the objects", cycles.count());
+    }
+    if split > 0 {
+        println!(
+            "{} batches were cut short to stay within the byte limit",
+            split
+        );
+    }
+    stats.print_throughput(elapsed);
+    stats.print_latency();
+    stats.print_retries();
+    stats.print_failures();
+
+    let mut report = stats.report(started, elapsed);
+    report.batch_size = Some(batch_size);
+    report.cycles = cycles.count();
+
+    if let Some(tuner) = &tuner {
+        let converged = tuner.converged();
+        println!("Batch size converged on {}", converged);
+        report.batch_size = Some(converged);
+    }
+    if let Some(requeue) = &requeue {
+        report.requeue = requeue.run(pool, config, &stats, batch_size)?;
+    }
+
+    Ok(report)
+}
+
+// Go over `objects` with `send_cycle`, then again, altered afresh, for as long
+// as `cycles` says to.  `send_cycle` returns false to stop early because the
+// workers have exited.
+fn send_cycles<F>(cycles: &Cycles, mut objects: Vec<(String, Value)>, mut send_cycle: F)
+where
+    F: FnMut(&[(String, Value)]) -> bool,
+{
+    let mut cycle = 0;
+
+    while send_cycle(&objects) {
+        cycle += 1;
+        match cycles.next(cycle) {
+            Some(shark) => {
+                for (_, obj) in objects.iter_mut() {
+                    cycles::set_last_shark(obj, &shark);
+                }
+            }
+            None => break,
+        }
+    }
+}
+
+// A batch worker's view of the queue of batches to submit, and the tuner to
+// report back to if the batch size is being tuned.
+struct BatchQueue {
+    queue: Queue<(usize, Vec<BatchRequest>)>,
+    tuner: Option<Arc<Tuner>>,
+}
+
+fn submit_batches(ctx: &Context, share: BatchQueue) -> Result<(), Error> {
+    while let Some((start, (id, batch))) = workers::next(ctx, &share.queue) {
+        let batch = Arc::new(batch);
+        let (req_id, opts) = request_options();
+        let sampled = timing::sampled();
+        let bytes = time_if(sampled, Step::Serialize, || batch_payload_size(&batch));
+        let started = SystemTime::now() - start.elapsed();
+        let span = trace::span(
+            "batch",
+            vec![
+                KeyValue::new("shard", ctx.config.shard.to_string()),
+                KeyValue::new("batch", id.to_string()),
+                KeyValue::new("size", batch.len().to_string()),
+                KeyValue::new("req_id", req_id.clone()),
+            ],
+        );
+        moray_batch_test::batch__start!(|| (ctx.config.shard, id as u64, batch.len() as u64));
+        let (op_batch, opts) = (Arc::clone(&batch), Arc::new(opts));
+        let result = ctx
+            .call_with_timeout(move |c| {
+                let attempt = Instant::now();
+                let mut server = ServerInfo::default();
+                time_if(sampled, Step::RoundTrip, || {
+                    c.batch(&op_batch, &opts, |records| {
+                        server = ServerInfo::from_records(records);
+                        Ok(())
+                    })
+                })?;
+                Ok((attempt.elapsed(), server))
+            })
+            .map(|(client, server)| check_server_info(ctx, &req_id, client, &server));
+        moray_batch_test::batch__done!(|| (
+            ctx.config.shard,
+            id as u64,
+            batch.len() as u64,
+            result.is_ok() as u8
+        ));
+        latency_csv::record_request(
+            ctx.config.shard,
+            "batch",
+            &id.to_string(),
+            &req_id,
+            started,
+            start.elapsed(),
+            &result,
+        );
+        log_request(ctx, "batch", &req_id, start.elapsed(), &result);
+        if let Err(e) = &result {
+            trace::set_error(&span, retry::error_name(e));
+        }
+        drop(span);
+        if let Some(tuner) = &share.tuner {
+            tuner.record(start.elapsed(), result.is_ok());
+        }
+
+        match result {
+            Ok(()) => ctx.stats.record_request(
+                batch_objects(&batch, &ctx.config.bucket),
+                bytes,
+                start.elapsed(),
+            ),
+            Err(e) if ctx.config.continue_on_error => {
+                eprintln!("batch of {} (req_id {}): {}", batch.len(), req_id, e);
+                ctx.stats
+                    .record_failure(batch_keys(&batch, &ctx.config.bucket), &e);
+            }
+            Err(e) => {
+                return Err(InternalError::BatchFailed {
+                    index: id,
+                    source: e.compat(),
+                }
+                .into())
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Log a request's id with how long it took, at debug level if it succeeded.  A
+// failure is logged as an error whether or not the phase carries on, so that
+// the request can be looked up on the server.
+fn log_request<T>(
+    ctx: &Context,
+    op: &str,
+    req_id: &str,
+    latency: Duration,
+    result: &Result<T, Error>,
+) {
+    let log = ctx.log.new(o!(
+        "op" => op.to_string(),
+        "req_id" => req_id.to_string(),
+        "latency_us" => latency.as_micros() as u64,
+    ));
+    match result {
+        Ok(_) => debug!(log, "request done"),
+        Err(e) => error!(log, "request failed"; "error" => %e),
+    }
+}
+
+// Count what the server reported about a request that the client saw take
+// `client`, and say so if it logged the request under some other id than the
+// one it was sent with.
+fn check_server_info(ctx: &Context, req_id: &str, client: Duration, server: &ServerInfo) {
+    if let Some(latency) = server.latency {
+        ctx.stats.record_server_latency(client, latency);
+        debug!(ctx.log, "server latency";
+            "req_id" => req_id,
+            "server_us" => latency.as_micros() as u64,
+            "client_us" => client.as_micros() as u64);
+    }
+    match &server.req_id {
+        Some(logged) if logged != req_id => {
+            warn!(ctx.log, "server logged request under another id";
+                "req_id" => req_id, "server_req_id" => logged.as_str())
+        }
+        _ => (),
+    }
+}
+
+// Run `f`, timing it as `step` if the request was sampled.  Each attempt is
+// timed on its own, leaving out the wait for the connection and any backoff.
+fn time_if<T, F: FnOnce() -> T>(sampled: bool, step: Step, f: F) -> T {
+    if sampled {
+        timing::time(step, f)
+    } else {
+        f()
+    }
+}
+
+// Size of the key and the serialized JSON value sent for an object.
+fn payload_size(key: &str, value: &Value) -> u64 {
+    (key.len() + value.to_string().len()) as u64
+}
+
+fn batch_payload_size(batch: &[BatchRequest]) -> u64 {
+    batch
+        .iter()
+        .map(|req| match req {
+            BatchRequest::Put(op) => payload_size(&op.key, &op.value),
+            BatchRequest::Delete(op) => op.key.len() as u64,
+            _ => 0,
+        })
+        .sum()
+}
+
+// Keys of the objects a batch writes to `bucket`, leaving out the operations
+// against companion buckets.
+fn batch_keys(batch: &[BatchRequest], bucket: &str) -> Vec<String> {
+    batch
+        .iter()
+        .filter_map(|req| match req {
+            BatchRequest::Put(op) if op.bucket == bucket => Some(op.key.clone()),
+            BatchRequest::Delete(op) if op.bucket == bucket => Some(op.key.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+// The objects a batch counts for: its operations against `bucket`.
+fn batch_objects(batch: &[BatchRequest], bucket: &str) -> u64 {
+    batch_keys(batch, bucket).len() as u64
+}
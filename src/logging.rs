@@ -0,0 +1,69 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::{Config, LogFormat, LogLevel};
+use failure::Error;
+use lazy_static::lazy_static;
+use slog::{o, Discard, Drain, Level, Logger};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::{Mutex, RwLock};
+
+// The name bunyan records carry, as manta services log under their own.
+const NAME: &str = "batch_test";
+
+lazy_static! {
+    // Everything is discarded until `init` is called.
+    static ref ROOT: RwLock<Logger> = RwLock::new(Logger::root(Discard, o!()));
+}
+
+fn slog_level(level: LogLevel) -> Option<Level> {
+    match level {
+        LogLevel::Off => None,
+        LogLevel::Error => Some(Level::Error),
+        LogLevel::Warn => Some(Level::Warning),
+        LogLevel::Info => Some(Level::Info),
+        LogLevel::Debug => Some(Level::Debug),
+        LogLevel::Trace => Some(Level::Trace),
+    }
+}
+
+/// Log messages of `config.log_level` and above, from this tool and from the
+/// moray client, to `config.log_file` or else to stderr, in
+/// `config.log_format`.  The file is appended to, so that runs can share one.
+pub fn init(config: &Config) -> Result<(), Error> {
+    let level = match slog_level(config.log_level) {
+        Some(level) => level,
+        None => return Ok(()),
+    };
+    let out: Box<dyn Write + Send> = match &config.log_file {
+        Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        None => Box::new(io::stderr()),
+    };
+
+    let values = o!("build-id" => "0.1.0");
+    let root = match config.log_format {
+        LogFormat::Text => {
+            let decorator = slog_term::PlainSyncDecorator::new(out);
+            let drain = Mutex::new(slog_term::FullFormat::new(decorator).build())
+                .filter_level(level)
+                .fuse();
+            Logger::root(drain, values)
+        }
+        LogFormat::Bunyan => {
+            let drain = Mutex::new(slog_bunyan::with_name(NAME, out).build())
+                .filter_level(level)
+                .fuse();
+            Logger::root(drain, values)
+        }
+    };
+    *ROOT.write().unwrap() = root;
+
+    Ok(())
+}
+
+/// The logger everything logs through, moray clients included.
+pub fn logger() -> Logger {
+    ROOT.read().unwrap().clone()
+}
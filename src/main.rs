@@ -2,49 +2,531 @@
  * Copyright 2019 Joyent, Inc.
  */
 
-#[macro_use]
-extern crate serde_json;
-
-#[macro_use]
-extern crate failure;
-
+use batch_test::baseline::{self, Snapshot};
+use batch_test::config::{
+    self, Access, Config, CountCheck, LogFormat, LogLevel, Metadata, MockLatency, PassOrder,
+    Profile, SummaryFormat, ValueSize, Warmup,
+};
+use batch_test::dashboard::Dashboard;
+use batch_test::distributed::{self, Agent};
+use batch_test::dryrun::{self, Plan};
+use batch_test::mock::MockServer;
+use batch_test::scenario::{self, Scenario};
+use batch_test::version::{self, ServerVersion};
+use batch_test::{chaos, dataset, dump, logging, pool, preflight, shutdown, timing};
+use batch_test::{latency_csv, metrics, probes, report, soak, statsd, status, summary_line};
+use batch_test::{timeseries, trace};
+use batch_test::{BenchRunner, InternalError, RunSummary};
 use clap::Clap;
-use failure::Error;
-use libmanta::moray::{MantaObject, MantaObjectShark};
-use moray::buckets;
-use moray::client::MorayClient;
-use moray::objects::{self, BatchPutOp, BatchRequest};
-use quickcheck::{Arbitrary, StdThreadGen};
-use serde_json::Value;
-use slog::{o, Drain, Logger};
-use std::collections::HashMap;
-use std::sync::Mutex;
-
-use rand::distributions::Alphanumeric;
-use rand::seq::SliceRandom;
-use rand::{thread_rng, Rng};
-use std::net::{IpAddr, SocketAddr};
-
-// We can't use trust-dns-resolver here because it uses futures with a
-// block_on, and calling a block_on from within a block_on is not allowed.
-use resolve::resolve_host;
-use resolve::{record::Srv, DnsConfig, DnsResolver};
-
-static BUCKET_NAME: &str = "rust_batch_test_bucket";
-
-#[derive(Debug, Fail)]
-enum InternalError {
-    #[fail(display = "catchall")]
-    CatchAll,
-}
+use failure::{err_msg, Error};
+use std::collections::BTreeSet;
+use std::iter;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::num::ParseFloatError;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(Clap)]
 #[clap(version = "1.0", author = "Rui Loura <rjloura@gmail.com")]
 struct Arguments {
-    #[clap(short, long, default_value = "100")]
-    num_objects: u32,
-    #[clap(long, default_value = "50")]
-    batch_size: u32,
+    /// TOML file describing the test parameters.  Options given on the
+    /// command line take precedence over values in the file.
+    #[clap(short, long)]
+    config: Option<String>,
+    /// Moray shard number to run against [default: 1]
+    #[clap(short, long)]
+    shard: Option<u32>,
+    /// Comma separated list of shards to run against concurrently.  Overrides
+    /// --shard.
+    #[clap(long, use_delimiter = true)]
+    shards: Vec<u32>,
+    /// Domain name used to look up the shard's SRV record
+    /// [default: perf2.scloud.host]
+    #[clap(short, long)]
+    domain: Option<String>,
+    /// Go through electric-moray instead of talking to a shard directly.
+    /// Keys are routed to shards by electric-moray, and --shard is ignored.
+    #[clap(long)]
+    electric_moray: bool,
+    /// Connect directly to the moray instance at IP:PORT instead of looking
+    /// up its SRV record.  --shard, --domain and --electric-moray are ignored.
+    #[clap(long)]
+    host: Option<SocketAddr>,
+    /// Seconds to cache SRV and address lookups for [default: 60]
+    #[clap(long)]
+    dns_ttl: Option<u64>,
+    /// Nameserver (e.g. binder) to send SRV and address lookups to, instead
+    /// of the ones in /etc/resolv.conf.  May be given more than once.
+    #[clap(long = "nameserver", use_delimiter = true)]
+    nameservers: Vec<IpAddr>,
+    /// Bucket to write test objects to [default: rust_batch_test_bucket]
+    #[clap(short, long)]
+    bucket: Option<String>,
+    /// Prefix of the keys of this run's test objects, so that runs against
+    /// the same bucket don't touch each other's objects.  seed makes one up
+    /// if it isn't given; without one, other commands use every object in
+    /// the bucket
+    #[clap(long, parse(try_from_str = config::parse_run_id))]
+    run_id: Option<String>,
+    /// Seed for the random numbers the test objects and their alterations
+    /// are made from, to repeat an earlier run exactly.  seed and bench
+    /// choose and print one if it isn't given
+    #[clap(long)]
+    seed: Option<u64>,
+    /// Comma separated list of buckets to seed and run against concurrently,
+    /// each with its own statistics.  Overrides --bucket.
+    #[clap(long, use_delimiter = true)]
+    buckets: Vec<String>,
+    /// Comma separated list of buckets, such as a delete log, that every
+    /// batch also puts each of its objects into.  They are created by seed
+    /// and emptied by cleanup along with the test bucket.
+    #[clap(long, use_delimiter = true)]
+    companion_buckets: Vec<String>,
+    /// Count the objects in the bucket after seeding and after each phase,
+    /// and warn or fail if there aren't exactly as many as expected: off,
+    /// warn or fail [default: off]
+    #[clap(long)]
+    count_check: Option<CountCheck>,
+    /// JSON file holding the bucket's schema, whose indexes (with their types
+    /// and unique flags) replace the default manta ones
+    #[clap(long)]
+    schema: Option<String>,
+    /// Write the run parameters and the results of every phase to FILE as
+    /// JSON
+    #[clap(short, long)]
+    output: Option<String>,
+    /// Export spans for every put, batch, DNS lookup and connection to the
+    /// OpenTelemetry collector at HOST:PORT
+    #[clap(long)]
+    otlp_endpoint: Option<String>,
+    /// Don't show progress bars for seeding, fetching and each update phase
+    #[clap(long)]
+    no_progress: bool,
+    /// Take over the terminal with a live view of the run's throughput,
+    /// latency, errors and workers instead of progress bars
+    #[clap(long)]
+    dashboard: bool,
+    /// Don't check that the shard can be reached and that the bucket has the
+    /// expected indexes before seeding or benchmarking
+    #[clap(long)]
+    no_preflight: bool,
+    /// Generate, alter, batch and serialize the objects of seed, bench or a
+    /// scenario without sending anything, and report what would have been
+    /// sent
+    #[clap(long)]
+    dry_run: bool,
+    /// Run against a moray server kept in memory by this process instead of
+    /// a shard
+    #[clap(long)]
+    mock: bool,
+    /// Delay the mock's answers by this many milliseconds, with optional
+    /// jitter, for every RPC or per RPC, e.g. 5, 5~2 or batch=5~2,putobject=1
+    #[clap(long)]
+    mock_latency: Option<MockLatency>,
+    /// Least severe messages to log, from this tool and the moray client:
+    /// off, error, warn, info, debug or trace [default: warn]
+    #[clap(long)]
+    log_level: Option<LogLevel>,
+    /// Append log messages to FILE instead of writing them to stderr
+    #[clap(long)]
+    log_file: Option<String>,
+    /// Write log messages as text, or as bunyan JSON to read alongside
+    /// moray's own logs [default: text]
+    #[clap(long)]
+    log_format: Option<LogFormat>,
+    /// Print one last line summing up the run for CI jobs to parse, as a
+    /// json object or as kv pairs
+    #[clap(long)]
+    summary_line: Option<SummaryFormat>,
+    /// Serve the running phases' progress, rates and recent errors as JSON at
+    /// http://<host>:PORT/status for the length of the run
+    #[clap(long)]
+    status_port: Option<u16>,
+    /// Time the encoding and round trip of one put or batch request in
+    /// every N, alongside every DNS lookup and connection; 0 times none of
+    /// the requests [default: 100]
+    #[clap(long)]
+    timing_sample: Option<u32>,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Clap, Clone)]
+enum Command {
+    /// Create the bucket if needed and seed it with test objects
+    Seed(SeedArgs),
+    /// Run update benchmarks against previously seeded test objects
+    Bench(BenchArgs),
+    /// Verify that every test object holds the result of the last update
+    Verify(VerifyArgs),
+    /// Delete all test objects from the bucket
+    Cleanup(CleanupArgs),
+    /// Delete the test objects of runs that are no longer active
+    Gc(GcArgs),
+    /// Run the phases listed in a scenario file, one after the other
+    Scenario(ScenarioArgs),
+    /// Wait for agents on other machines to connect, have each of them run
+    /// the command given after -- against its own slice of the keyspace, and
+    /// add up their results
+    Coordinator(CoordinatorArgs),
+    /// Connect to a coordinator and run the share of its command it hands
+    /// out
+    Agent(AgentArgs),
+}
+
+#[derive(Clap, Clone)]
+struct SeedArgs {
+    /// Number of test objects to seed [default: 100]
+    #[clap(short, long = "objects")]
+    num_objects: Option<u32>,
+    /// Pad each object's value to this serialized size: natural, a size such
+    /// as 2KiB, or lognormal:MEDIAN:SIGMA, e.g. lognormal:2KiB:1.0
+    /// [default: natural]
+    #[clap(long)]
+    value_size: Option<ValueSize>,
+    /// How to fill in the objects' fields: random, or manta for owners,
+    /// dirnames, content types and lengths like real objects' [default:
+    /// random]
+    #[clap(long)]
+    metadata: Option<Metadata>,
+    /// Comma separated owner uuids for --metadata manta [default: made up]
+    #[clap(long, use_delimiter = true)]
+    owners: Vec<String>,
+    /// Number of owner uuids to make up for --metadata manta when --owners
+    /// isn't given [default: 10]
+    #[clap(long)]
+    owner_count: Option<u32>,
+    /// Fraction of the objects, between 0 and 1, made directory entries:
+    /// type directory, with no sharks or content [default: 0]
+    #[clap(long)]
+    directory_fraction: Option<f64>,
+    /// Number of objects per batch put when seeding [default: 100]
+    #[clap(long)]
+    batch_size: Option<u32>,
+    /// Number of threads writing the batches [default: 4]
+    #[clap(long)]
+    workers: Option<u32>,
+    /// Seed with one putobject request per object instead of batches, for
+    /// comparison
+    #[clap(long)]
+    sequential: bool,
+    /// Save the objects, keys and all, to FILE as they are seeded, to seed
+    /// or verify the same objects again later
+    #[clap(long)]
+    save_dataset: Option<String>,
+    /// Seed the objects saved to FILE by an earlier --save-dataset instead of
+    /// making new ones.  Their run id is used unless --run-id is given
+    #[clap(long)]
+    load_dataset: Option<String>,
+}
+
+#[derive(Clap, Clone)]
+struct BenchArgs {
+    /// Number of put operations per batch request [default: 50]
+    #[clap(long)]
+    batch_size: Option<u32>,
+    /// Also cut batches short so their keys and values stay within this
+    /// size (e.g. 2MiB), for large values [default: no limit]
+    #[clap(long, parse(try_from_str = config::parse_bytes))]
+    max_batch_bytes: Option<u64>,
+    /// Comma separated batch sizes to run the batch test with, one after the
+    /// other, instead of comparing sequential and batch updates
+    #[clap(long, use_delimiter = true)]
+    sweep_batch_sizes: Vec<u32>,
+    /// Comma separated worker counts to run both tests with, one after the
+    /// other.  Combined with --sweep-batch-sizes every batch size is run at
+    /// every worker count
+    #[clap(long, use_delimiter = true)]
+    sweep_workers: Vec<u32>,
+    /// Comma separated numbers of connections per worker to run both tests
+    /// with, one after the other, at the same number of workers
+    #[clap(long, use_delimiter = true)]
+    sweep_connections: Vec<u32>,
+    /// Adjust the batch size while the batch test runs, growing it while the
+    /// p99 latency stays under --target-p99-ms and halving it otherwise, and
+    /// report the size it settles on.  --batch-size is the starting size
+    #[clap(long)]
+    auto_batch_size: bool,
+    /// p99 batch latency the tuned batch size should stay under
+    /// [default: 500]
+    #[clap(long)]
+    target_p99_ms: Option<u64>,
+    /// Amount the tuned batch size grows by at a time [default: 10]
+    #[clap(long)]
+    batch_size_step: Option<u32>,
+    /// Largest batch size to try when tuning [default: 1000]
+    #[clap(long)]
+    max_batch_size: Option<u32>,
+    /// After the update phases, run findobjects queries filtering on the
+    /// owner, dirname and type of the test objects
+    #[clap(long)]
+    find: bool,
+    /// Number of findobjects queries to run [default: 100]
+    #[clap(long)]
+    find_queries: Option<u32>,
+    /// Most records each findobjects query returns [default: 100]
+    #[clap(long)]
+    find_limit: Option<u64>,
+    /// After the update phases, fetch every test object with getobject, one
+    /// at a time and then with --workers, checking each is a valid manta
+    /// object
+    #[clap(long)]
+    get: bool,
+    /// After the update phases, delete every test object one at a time, then
+    /// in batches of --batch-size, then with deletemany filters covering
+    /// --batch-size objects each, restoring them after each.  The
+    /// deletemany phase needs objectId indexed and is skipped otherwise.
+    #[clap(long)]
+    delete: bool,
+    /// After the update phases, rewrite the indexed fields of the test
+    /// objects with updateobjects requests of --batch-size objects each, and
+    /// compare them to the batch puts
+    #[clap(long)]
+    update_objects: bool,
+    /// After the update phases, write altered objects straight to the
+    /// bucket's table with the sql RPC, --batch-size rows per UPDATE, to see
+    /// how much of the batch path's cost is moray's own
+    #[clap(long)]
+    sql: bool,
+    /// After the update phases, add an index to the bucket and reindex it
+    /// while running the batch test, then remove the index
+    #[clap(long)]
+    reindex: bool,
+    /// Rows reindexed by each reindexobjects call [default: 100]
+    #[clap(long)]
+    reindex_count: Option<u32>,
+    /// After the update phases, write altered objects in batches to a
+    /// variant of the bucket with a unique index, with --conflict-fraction of
+    /// them conflicting, and compare them to the batch puts
+    #[clap(long)]
+    unique: bool,
+    /// Fraction of the objects, between 0 and 1, given the same unique value
+    /// as another object in their batch [default: 0.1]
+    #[clap(long)]
+    conflict_fraction: Option<f64>,
+    /// After the update phases, have every worker race to update every
+    /// object in batches of puts conditional on each object's etag,
+    /// retrying batches that lose the race
+    #[clap(long)]
+    cas: bool,
+    /// Attempts per conditional batch, including the first, before a
+    /// conflicting batch is counted as failed [default: 10]
+    #[clap(long)]
+    cas_attempts: Option<u32>,
+    /// After the update phases, write one alteration of the objects both
+    /// sequentially and in batches, and check that both leave the bucket
+    /// holding identical objects
+    #[clap(long)]
+    consistency: bool,
+    /// After the update phases, move every object with a copy on
+    /// --rebalance-from to --rebalance-to the way the manta rebalancer does:
+    /// scan for them, rewrite the shark and write them back in conditional
+    /// batches.  The objects are put back afterwards
+    #[clap(long)]
+    rebalance: bool,
+    /// After the update phases, record the deletion of every object in a
+    /// delete log bucket and consume the log in batches the way manta's
+    /// garbage collector does: find, process, batch delete
+    #[clap(long)]
+    delete_log: bool,
+    /// Storage id to evacuate with --rebalance [default: 1.stor.domain]
+    #[clap(long)]
+    rebalance_from: Option<String>,
+    /// Storage id to move objects to with --rebalance [default: 5.stor.domain]
+    #[clap(long)]
+    rebalance_to: Option<String>,
+    /// After each sequential and batch phase, fetch the objects it wrote and
+    /// check that each holds the value it was written with, and that every
+    /// object's checksum matches the one taken when it was generated
+    #[clap(long)]
+    read_back: bool,
+    /// Number of randomly chosen objects to fetch with --read-back, or zero
+    /// for all of them [default: 0]
+    #[clap(long)]
+    read_back_sample: Option<u32>,
+    /// Number of sequential/batch comparison passes [default: 2]
+    #[clap(short, long)]
+    passes: Option<u32>,
+    /// Which test runs first in each pass: seq-first, batch-first or
+    /// alternating [default: alternating]
+    #[clap(long)]
+    pass_order: Option<PassOrder>,
+    /// Which keys the update phases rewrite: all (each object once),
+    /// uniform, zipf:S (e.g. zipf:1.0) or hot:PERCENT (e.g. hot:1)
+    /// [default: all]
+    #[clap(long)]
+    access: Option<Access>,
+    /// Number of times to run each phase in a row, within each pass, to
+    /// measure how much the results vary [default: 1]
+    #[clap(long)]
+    iterations: Option<u32>,
+    /// Leave the start of each phase out of its statistics, either a number
+    /// of objects (500) or of seconds (30s)
+    #[clap(long)]
+    warmup: Option<Warmup>,
+    /// Keep each phase going over the objects, altering them afresh each
+    /// time, until this much time has passed (e.g. 90s, 10m or 2h) instead of
+    /// stopping after one pass over them
+    #[clap(long, parse(try_from_str = config::parse_duration))]
+    duration: Option<u64>,
+    /// Keep running passes, altering and updating the objects afresh each
+    /// time, until this much time has passed (e.g. 8h), printing interim
+    /// statistics along the way
+    #[clap(long, parse(try_from_str = config::parse_duration))]
+    soak: Option<u64>,
+    /// How often a soak test prints interim statistics, each covering only
+    /// the time since the last (e.g. 10m) [default: 10m]
+    #[clap(long, parse(try_from_str = config::parse_duration))]
+    soak_report_interval: Option<u64>,
+    /// Also write each soak test interim report to FILE as JSON lines
+    #[clap(long)]
+    soak_report: Option<String>,
+    /// Number of threads, each with its own client, used to issue the
+    /// sequential puts and the batch requests [default: 1]
+    #[clap(short, long)]
+    workers: Option<u32>,
+    /// Number of batches that may be built and queued waiting for a free
+    /// worker [default: 4]
+    #[clap(long)]
+    queue_depth: Option<u32>,
+    /// Fraction of the objects, between 0 and 1, that the batch test deletes
+    /// in the same batches as it updates the rest.  They are put back after
+    /// the phase [default: 0]
+    #[clap(long)]
+    delete_fraction: Option<f64>,
+    /// Send requests at this many per second, shared by all workers, and
+    /// measure latency from when each request was due rather than when it
+    /// was sent.  There must be enough workers to keep up.  Zero sends
+    /// requests as fast as the workers allow [default: 0]
+    #[clap(long)]
+    rate: Option<f64>,
+    /// Number of requests the --rate token bucket lets workers send at once
+    /// to catch up after a slow request.  Above one, latency is measured from
+    /// when each request was sent [default: 1]
+    #[clap(long)]
+    burst: Option<u32>,
+    /// Vary the rate requests are sent at over each phase, e.g.
+    /// ramp:0-2000ops/60s to raise it steadily or step:100,500,1000ops/30s to
+    /// hold each rate in turn.  Overrides --rate
+    #[clap(long)]
+    profile: Option<Profile>,
+    /// Send requests on the --rate or --profile schedule whether or not
+    /// earlier ones have completed, rather than each worker waiting for its
+    /// last request.  Requests are queued for the workers, up to
+    /// --queue-depth, and any that find the queue full are shed and counted
+    #[clap(long)]
+    open_loop: bool,
+    /// Number of moray connections shared round-robin by the workers.  Zero
+    /// gives each worker its own connection [default: 0]
+    #[clap(long)]
+    connections: Option<u32>,
+    /// Number of connections each worker holds and sends its requests on in
+    /// turn, when --connections isn't given [default: 1]
+    #[clap(long)]
+    connections_per_worker: Option<u32>,
+    /// Attempts per put or batch, including the first, before a transient
+    /// error is treated as a failure [default: 3]
+    #[clap(long)]
+    max_attempts: Option<u32>,
+    /// Delay before the first retry, doubling with each retry after that
+    /// [default: 100]
+    #[clap(long)]
+    retry_base_delay_ms: Option<u64>,
+    /// Longest delay between retries [default: 5000]
+    #[clap(long)]
+    retry_max_delay_ms: Option<u64>,
+    /// Fraction of each retry delay, between 0 and 1, that may be randomly
+    /// removed [default: 0.5]
+    #[clap(long)]
+    retry_jitter: Option<f64>,
+    /// Fraction of recent requests, between 0 and 1, that must fail to trip
+    /// the shard's circuit breaker.  Zero disables the breaker [default: 0.5]
+    #[clap(long)]
+    breaker_error_rate: Option<f64>,
+    /// Number of recent requests the error rate is measured over
+    /// [default: 20]
+    #[clap(long)]
+    breaker_window: Option<u32>,
+    /// Seconds to pause traffic to the shard once the breaker trips
+    /// [default: 30]
+    #[clap(long)]
+    breaker_cooldown_secs: Option<u64>,
+    /// Number of times the breaker may trip before failures are no longer
+    /// retried [default: 10]
+    #[clap(long)]
+    breaker_max_trips: Option<u32>,
+    /// Seconds to keep trying to reconnect a lost connection, re-resolving
+    /// the shard each time, before the request on it fails [default: 60]
+    #[clap(long)]
+    reconnect_timeout_secs: Option<u64>,
+    /// Give up on a put or batch attempt that hasn't been answered in this
+    /// many milliseconds, retrying it on a new connection.  Zero waits for
+    /// ever [default: 0]
+    #[clap(long)]
+    op_timeout_ms: Option<u64>,
+    /// Reset the connection carrying a batch request with this probability
+    /// during batch phases, before or after the request reaches moray, and
+    /// check afterwards which interrupted batches were applied.  Needs
+    /// --host or --mock [default: 0]
+    #[clap(long)]
+    chaos_reset: Option<f64>,
+    /// Halve the rate requests are sent at whenever moray reports that it is
+    /// overloaded, and raise it again gradually once it stops
+    #[clap(long)]
+    throttle: bool,
+    /// Lowest rate, in requests per second, --throttle backs off to
+    /// [default: 1]
+    #[clap(long)]
+    throttle_min_rate: Option<f64>,
+    /// Keep going when a put or batch fails for good, and list the objects
+    /// that were not written at the end of each phase
+    #[clap(long)]
+    continue_on_error: bool,
+    /// With --continue-on-error, write the keys of failed requests again
+    /// once each sequential or batch phase is over, and report how many were
+    /// written the second time
+    #[clap(long)]
+    requeue_failed: bool,
+    /// Write throughput, errors and latency percentiles for every interval of
+    /// each phase to FILE, as CSV or, if FILE ends in .json or .jsonl, one
+    /// JSON object per line
+    #[clap(long)]
+    timeseries: Option<String>,
+    /// Length of each time series and StatsD interval [default: 1000]
+    #[clap(long)]
+    timeseries_interval_ms: Option<u64>,
+    /// JSON report from an earlier run, written by --output, to compare this
+    /// run's throughput and p99 latency with
+    #[clap(long)]
+    baseline: Option<String>,
+    /// Exit with an error if throughput drops, or p99 latency rises, by more
+    /// than this percentage of the baseline, e.g. 10%
+    #[clap(long, parse(try_from_str = parse_percent))]
+    fail_on_regression: Option<f64>,
+    /// Stop the run, letting the requests in flight finish, once more than
+    /// this percentage of a phase's requests have failed for good, e.g. 1%.
+    /// Only counts with --continue-on-error, as otherwise the first failure
+    /// stops the run
+    #[clap(long, parse(try_from_str = parse_percent))]
+    max_error_rate: Option<f64>,
+    /// Write the start time, duration and result of every put and batch
+    /// request to FILE as CSV
+    #[clap(long)]
+    latency_csv: Option<String>,
+    /// Serve request, error and retry counts and latency histograms at
+    /// http://<host>:PORT/metrics for Prometheus to scrape during the run
+    #[clap(long)]
+    prometheus_port: Option<u16>,
+    /// Send counts and latency percentiles for every interval to the StatsD
+    /// server at HOST:PORT
+    #[clap(long)]
+    statsd: Option<String>,
+    /// Prefix for the names of metrics sent to StatsD
+    /// [default: moray_batch_test]
+    #[clap(long)]
+    statsd_prefix: Option<String>,
 
     // Can't find docs on how to use "conflicts_with" but simply adding
     //      conflicts_with = "sequential_only"
@@ -55,264 +537,1157 @@ struct Arguments {
     sequential_only: bool,
 }
 
-// Get the SRV record which gives us the target and port of the moray service.
-fn get_srv_record(svc: &str, proto: &str, host: &str) -> Result<Srv, Error> {
-    let query = format!("{}.{}.{}", svc, proto, host);
-    let r = DnsResolver::new(DnsConfig::load_default()?)?;
-    r.resolve_record::<Srv>(&query)?
-        .choose(&mut rand::thread_rng())
-        .map(|r| r.to_owned())
-        .ok_or_else(|| InternalError::CatchAll.into())
+#[derive(Clap, Clone)]
+struct CleanupArgs {
+    /// Number of delete operations per batch request [default: 50]
+    #[clap(long)]
+    batch_size: Option<u32>,
+    /// Drop the bucket itself instead of deleting objects one batch at a time
+    #[clap(long)]
+    drop_bucket: bool,
 }
 
-fn lookup_ip(host: &str) -> Result<IpAddr, Error> {
-    match resolve_host(host)?.collect::<Vec<IpAddr>>().first() {
-        Some(a) => Ok(*a),
-        None => Err(InternalError::CatchAll.into()),
-    }
+#[derive(Clap, Clone)]
+struct GcArgs {
+    /// Comma separated run ids whose objects are still in use and must be
+    /// kept
+    #[clap(long, use_delimiter = true, parse(try_from_str = config::parse_run_id))]
+    keep: Vec<String>,
+    /// Only delete objects last written longer ago than this (e.g. 10m, 2h)
+    #[clap(long, parse(try_from_str = config::parse_duration))]
+    older_than: Option<u64>,
+    /// Number of delete operations per batch request [default: 50]
+    #[clap(long)]
+    batch_size: Option<u32>,
 }
 
-fn get_moray_srv_sockaddr(host: &str) -> Result<SocketAddr, Error> {
-    let srv_record = get_srv_record("_moray", "_tcp", &host)?;
-    dbg!(&srv_record);
-
-    let ip = lookup_ip(&srv_record.target)?;
+#[derive(Clap, Clone)]
+struct ScenarioArgs {
+    /// JSON (or .toml) file listing the phases to run and their parameters
+    file: String,
+}
 
-    Ok(SocketAddr::new(ip, srv_record.port))
+#[derive(Clap, Clone)]
+struct CoordinatorArgs {
+    /// Address to wait for agents on [default: 0.0.0.0:7070]
+    #[clap(long)]
+    listen: Option<SocketAddr>,
+    /// Number of agents to wait for before starting any of them
+    #[clap(long)]
+    agents: u32,
+    /// The options and subcommand for every agent to run, e.g.
+    /// -- --shard 1 bench --workers 8
+    #[clap(last = true)]
+    args: Vec<String>,
 }
 
-// Create a moray client using the shard and the domain name only.  This will
-// query binder for the SRV record for us.
-pub fn create_client(shard: u32, domain: &str) -> Result<MorayClient, Error> {
-    let domain_name = format!("{}.moray.{}", shard, domain);
-    let sock_addr = get_moray_srv_sockaddr(&domain_name)?;
-    let plain = slog_term::PlainSyncDecorator::new(std::io::sink());
-    let log = Logger::root(
-        Mutex::new(slog_term::FullFormat::new(plain).build()).fuse(),
-        o!("build-id" => "0.1.0"),
-    );
+#[derive(Clap, Clone)]
+struct AgentArgs {
+    /// HOST:PORT of the coordinator to take work from
+    coordinator: String,
+}
 
-    MorayClient::new(sock_addr, log, None).map_err(Error::from)
+#[derive(Clap, Clone)]
+struct VerifyArgs {
+    /// Number of test objects expected in the bucket [default: 100]
+    #[clap(short, long = "objects")]
+    num_objects: Option<u32>,
+    /// Also check that the bucket holds the objects saved to FILE by seed
+    /// --save-dataset, apart from the fields bench rewrites
+    #[clap(long)]
+    load_dataset: Option<String>,
 }
 
-fn random_string(len: usize) -> String {
-    thread_rng().sample_iter(&Alphanumeric).take(len).collect()
+// Parse a percentage given as "10%" or just "10".
+fn parse_percent(s: &str) -> Result<f64, ParseFloatError> {
+    s.trim_end_matches('%').parse()
 }
 
-fn gen_test_objects(num_objects: u32) -> HashMap<String, MantaObject> {
-    let mut test_objects = HashMap::new();
-    let mut g = StdThreadGen::new(10);
-    let mut rng = rand::thread_rng();
+impl Arguments {
+    // Load the config file, if one was given, and apply any command line
+    // overrides on top of it.
+    fn to_config(&self) -> Result<Config, Error> {
+        let mut config = match &self.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
 
-    for _ in 0..num_objects {
-        let mut mobj = MantaObject::arbitrary(&mut g);
-        let mut sharks = vec![];
+        if let Some(shard) = self.shard {
+            config.shard = shard;
+        }
+        if !self.shards.is_empty() {
+            config.shards = self.shards.clone();
+        }
+        if let Some(domain) = &self.domain {
+            config.domain = domain.clone();
+        }
+        if self.electric_moray {
+            config.electric_moray = true;
+        }
+        if self.host.is_some() {
+            config.host = self.host;
+        }
+        if let Some(dns_ttl) = self.dns_ttl {
+            config.dns_ttl = dns_ttl;
+        }
+        if !self.nameservers.is_empty() {
+            config.nameservers = self.nameservers.clone();
+        }
+        if let Some(bucket) = &self.bucket {
+            config.bucket = bucket.clone();
+        }
+        if self.run_id.is_some() {
+            config.run_id = self.run_id.clone();
+        }
+        if self.seed.is_some() {
+            config.seed = self.seed;
+        }
+        if !self.buckets.is_empty() {
+            config.buckets = self.buckets.clone();
+        }
+        if !self.companion_buckets.is_empty() {
+            config.companion_buckets = self.companion_buckets.clone();
+        }
+        if let Some(check) = self.count_check {
+            config.count_check = check;
+        }
+        if let Some(schema) = &self.schema {
+            config.index = Config::load_schema(schema)?;
+        }
+        if self.output.is_some() {
+            config.output = self.output.clone();
+        }
+        if self.otlp_endpoint.is_some() {
+            config.otlp_endpoint = self.otlp_endpoint.clone();
+        }
+        if self.no_progress {
+            config.progress = false;
+        }
+        if self.dashboard {
+            config.dashboard = true;
+            config.progress = false;
+        }
+        if self.no_preflight {
+            config.preflight = false;
+        }
+        if self.dry_run {
+            config.dry_run = true;
+        }
+        if self.mock {
+            config.mock = true;
+        }
+        if let Some(latency) = &self.mock_latency {
+            config.mock_latency = latency.clone();
+        }
+        if let Some(level) = self.log_level {
+            config.log_level = level;
+        }
+        if self.log_file.is_some() {
+            config.log_file = self.log_file.clone();
+        }
+        if let Some(format) = self.log_format {
+            config.log_format = format;
+        }
+        if self.summary_line.is_some() {
+            config.summary_line = self.summary_line;
+        }
+        if self.status_port.is_some() {
+            config.status_port = self.status_port;
+        }
+        if let Some(every) = self.timing_sample {
+            config.timing_sample = every;
+        }
 
-        // first pass: 1 or 2
-        // second pass: 3 or 4
-        for i in 0..2 {
-            let shark_num = rng.gen_range(1 + i * 2, 3 + i * 2);
+        if let Command::Seed(_) | Command::Bench(_) | Command::Scenario(_) = &self.command {
+            if config.seed.is_none() {
+                let seed = rand::random();
+                println!(
+                    "Random seed {}, pass --seed {} to repeat this run",
+                    seed, seed
+                );
+                config.seed = Some(seed);
+            }
+        }
 
-            let shark = MantaObjectShark {
-                datacenter: String::from("foo"), //todo
-                manta_storage_id: format!("{}.stor.domain", shark_num),
-            };
-            sharks.push(shark);
+        match &self.command {
+            Command::Seed(seed) => {
+                if let Some(num_objects) = seed.num_objects {
+                    config.objects = num_objects;
+                }
+                if let Some(size) = seed.value_size {
+                    config.value_size = size;
+                }
+                if let Some(metadata) = seed.metadata {
+                    config.metadata = metadata;
+                }
+                if !seed.owners.is_empty() {
+                    config.owners = seed.owners.clone();
+                }
+                if let Some(count) = seed.owner_count {
+                    config.owner_count = count;
+                }
+                if let Some(fraction) = seed.directory_fraction {
+                    config.directory_fraction = fraction;
+                }
+                if let Some(batch_size) = seed.batch_size {
+                    config.seed_batch_size = batch_size;
+                }
+                if let Some(workers) = seed.workers {
+                    config.seed_workers = workers;
+                }
+                if seed.sequential {
+                    config.seed_batch_size = 0;
+                }
+                if seed.save_dataset.is_some() {
+                    config.save_dataset = seed.save_dataset.clone();
+                }
+                if seed.load_dataset.is_some() {
+                    config.load_dataset = seed.load_dataset.clone();
+                }
+                // A loaded dataset's keys are already under a run id.
+                dataset::adopt(&mut config)?;
+                if config.run_id.is_none() {
+                    let run_id = batch_test::new_run_id();
+                    println!(
+                        "Seeding run {}, pass --run-id {} to later commands",
+                        run_id, run_id
+                    );
+                    config.run_id = Some(run_id);
+                }
+            }
+            Command::Bench(bench) => {
+                if let Some(batch_size) = bench.batch_size {
+                    config.batch_size = batch_size;
+                }
+                if let Some(max) = bench.max_batch_bytes {
+                    config.max_batch_bytes = max;
+                }
+                if !bench.sweep_batch_sizes.is_empty() {
+                    config.sweep_batch_sizes = bench.sweep_batch_sizes.clone();
+                }
+                if !bench.sweep_workers.is_empty() {
+                    config.sweep_workers = bench.sweep_workers.clone();
+                }
+                if let Some(queries) = bench.find_queries {
+                    config.find_queries = queries;
+                }
+                if let Some(limit) = bench.find_limit {
+                    config.find_limit = limit;
+                }
+                if let Some(count) = bench.reindex_count {
+                    config.reindex_count = count;
+                }
+                if let Some(fraction) = bench.conflict_fraction {
+                    config.conflict_fraction = fraction;
+                }
+                if let Some(attempts) = bench.cas_attempts {
+                    config.cas_attempts = attempts;
+                }
+                if let Some(from) = &bench.rebalance_from {
+                    config.rebalance_from = from.clone();
+                }
+                if let Some(to) = &bench.rebalance_to {
+                    config.rebalance_to = to.clone();
+                }
+                if bench.read_back {
+                    config.read_back = true;
+                }
+                if let Some(sample) = bench.read_back_sample {
+                    config.read_back_sample = sample;
+                }
+                if bench.auto_batch_size {
+                    config.auto_batch_size = true;
+                }
+                if let Some(target) = bench.target_p99_ms {
+                    config.target_p99_ms = target;
+                }
+                if let Some(step) = bench.batch_size_step {
+                    config.batch_size_step = step;
+                }
+                if let Some(max) = bench.max_batch_size {
+                    config.max_batch_size = max;
+                }
+                if let Some(passes) = bench.passes {
+                    config.passes = passes;
+                }
+                if let Some(order) = bench.pass_order {
+                    config.pass_order = order;
+                }
+                if let Some(access) = bench.access {
+                    config.access = access;
+                }
+                if let Some(iterations) = bench.iterations {
+                    config.iterations = iterations;
+                }
+                if let Some(warmup) = bench.warmup {
+                    config.warmup = warmup;
+                }
+                if let Some(duration) = bench.duration {
+                    config.duration_secs = duration;
+                }
+                if let Some(soak) = bench.soak {
+                    config.soak_secs = soak;
+                }
+                if let Some(interval) = bench.soak_report_interval {
+                    config.soak_report_interval_secs = interval;
+                }
+                if bench.soak_report.is_some() {
+                    config.soak_report = bench.soak_report.clone();
+                }
+                if let Some(workers) = bench.workers {
+                    config.workers = workers;
+                }
+                if let Some(queue_depth) = bench.queue_depth {
+                    config.queue_depth = queue_depth;
+                }
+                if let Some(fraction) = bench.delete_fraction {
+                    config.delete_fraction = fraction;
+                }
+                if let Some(rate) = bench.rate {
+                    config.rate = rate;
+                }
+                if let Some(burst) = bench.burst {
+                    config.burst = burst.max(1);
+                }
+                if let Some(profile) = bench.profile.clone() {
+                    config.profile = profile;
+                }
+                if bench.open_loop {
+                    config.open_loop = true;
+                }
+                if let Some(connections) = bench.connections {
+                    config.connections = connections;
+                }
+                if let Some(per_worker) = bench.connections_per_worker {
+                    config.connections_per_worker = per_worker;
+                }
+                if !bench.sweep_connections.is_empty() {
+                    config.sweep_connections = bench.sweep_connections.clone();
+                }
+                if let Some(max_attempts) = bench.max_attempts {
+                    config.max_attempts = max_attempts;
+                }
+                if let Some(delay) = bench.retry_base_delay_ms {
+                    config.retry_base_delay_ms = delay;
+                }
+                if let Some(delay) = bench.retry_max_delay_ms {
+                    config.retry_max_delay_ms = delay;
+                }
+                if let Some(jitter) = bench.retry_jitter {
+                    config.retry_jitter = jitter;
+                }
+                if let Some(rate) = bench.breaker_error_rate {
+                    config.breaker_error_rate = rate;
+                }
+                if let Some(window) = bench.breaker_window {
+                    config.breaker_window = window;
+                }
+                if let Some(cooldown) = bench.breaker_cooldown_secs {
+                    config.breaker_cooldown_secs = cooldown;
+                }
+                if let Some(max_trips) = bench.breaker_max_trips {
+                    config.breaker_max_trips = max_trips;
+                }
+                if let Some(timeout) = bench.reconnect_timeout_secs {
+                    config.reconnect_timeout_secs = timeout;
+                }
+                if let Some(timeout) = bench.op_timeout_ms {
+                    config.op_timeout_ms = timeout;
+                }
+                if let Some(probability) = bench.chaos_reset {
+                    config.chaos_reset = probability;
+                }
+                if bench.throttle {
+                    config.throttle = true;
+                }
+                if let Some(rate) = bench.throttle_min_rate {
+                    config.throttle_min_rate = rate;
+                }
+                if bench.continue_on_error {
+                    config.continue_on_error = true;
+                }
+                if bench.requeue_failed {
+                    config.requeue_failed = true;
+                }
+                if bench.timeseries.is_some() {
+                    config.timeseries = bench.timeseries.clone();
+                }
+                if let Some(interval) = bench.timeseries_interval_ms {
+                    config.timeseries_interval_ms = interval;
+                }
+                if bench.baseline.is_some() {
+                    config.baseline = bench.baseline.clone();
+                }
+                if bench.fail_on_regression.is_some() {
+                    config.fail_on_regression = bench.fail_on_regression;
+                }
+                if bench.max_error_rate.is_some() {
+                    config.max_error_rate = bench.max_error_rate;
+                }
+                if bench.latency_csv.is_some() {
+                    config.latency_csv = bench.latency_csv.clone();
+                }
+                if bench.prometheus_port.is_some() {
+                    config.prometheus_port = bench.prometheus_port;
+                }
+                if bench.statsd.is_some() {
+                    config.statsd = bench.statsd.clone();
+                }
+                if let Some(prefix) = &bench.statsd_prefix {
+                    config.statsd_prefix = prefix.clone();
+                }
+            }
+            Command::Verify(verify) => {
+                if let Some(num_objects) = verify.num_objects {
+                    config.objects = num_objects;
+                }
+                if verify.load_dataset.is_some() {
+                    config.load_dataset = verify.load_dataset.clone();
+                }
+            }
+            Command::Cleanup(cleanup) => {
+                if let Some(batch_size) = cleanup.batch_size {
+                    config.batch_size = batch_size;
+                }
+            }
+            Command::Gc(gc) => {
+                if let Some(batch_size) = gc.batch_size {
+                    config.batch_size = batch_size;
+                }
+            }
+            Command::Scenario(_) | Command::Coordinator(_) | Command::Agent(_) => {}
         }
-        mobj.sharks = sharks;
 
-        test_objects.insert(mobj.object_id.clone(), mobj);
+        Ok(config)
     }
-
-    test_objects
 }
 
 //
 // --- Main Line ---
 //
 
-fn main() -> Result<(), Error> {
-    let args: Arguments = Arguments::parse();
-    let opts = objects::MethodOptions::default();
-    let bucket_opts = buckets::MethodOptions::default();
-    let mut mclient = create_client(1, "perf2.scloud.host")?;
+// Exit statuses, so that automation can tell how a run went without reading
+// its output.  A run stopped by a signal exits as if killed by it.
+const EXIT_FAILED: i32 = 1;
+const EXIT_SETUP: i32 = 2;
+const EXIT_ERROR_RATE: i32 = 3;
+const EXIT_REGRESSION: i32 = 4;
 
-    let ignore_callback = |_bucket: &buckets::Bucket| Ok(());
+// Where a coordinator waits for agents unless told otherwise.
+const COORDINATOR_PORT: u16 = 7070;
 
-    println!("===get or create bucket===");
-    if mclient
-        .get_bucket(BUCKET_NAME, bucket_opts.clone(), ignore_callback)
-        .is_err()
-    {
-        let bucket_config = json!({
-            "index": {
-                "dirname": {
-                  "type": "string"
-                },
-                "name": {
-                  "type": "string"
-                },
-                "owner": {
-                  "type": "string"
-                },
-                "objectId": {
-                  "type": "string"
-                },
-                "type": {
-                  "type": "string"
-                }
+fn main() {
+    let mut args: Arguments = Arguments::parse();
+    // An agent runs with the arguments its coordinator hands it instead.
+    let mut agent = None;
+    if let Command::Agent(agent_args) = &args.command {
+        match join(agent_args) {
+            Ok((assigned, joined)) => {
+                args = assigned;
+                agent = Some(joined);
             }
-        });
+            Err(e) => exit(EXIT_SETUP, &e),
+        }
+    }
 
-        match mclient.create_bucket(BUCKET_NAME, bucket_config, bucket_opts) {
-            Ok(()) => {
-                println!("Bucket Created Successfully");
-            }
-            Err(e) => {
-                eprintln!("Error Creating Bucket: {}", e);
+    let (mut config, reporter) = match setup(&args) {
+        Ok(setup) => setup,
+        Err(e) => {
+            if let Some(agent) = agent {
+                let _ = agent.fail(&e);
             }
+            exit(EXIT_SETUP, &e)
+        }
+    };
+
+    let result = match (&args.command, agent) {
+        (Command::Coordinator(coordinator), _) => coordinate(&config, coordinator),
+        (command, Some(agent)) => {
+            distributed::slice(&mut config, agent.assignment());
+            run_agent(config, command, agent)
+        }
+        (command, None) => run(config, command),
+    };
+    reporter.stop();
+    trace::shutdown();
+
+    if let Some(reason) = shutdown::aborted() {
+        exit(
+            EXIT_ERROR_RATE,
+            &InternalError::ErrorRateExceeded(reason).into(),
+        );
+    }
+    match result {
+        Ok(()) if shutdown::requested() => process::exit(shutdown::exit_status()),
+        Ok(()) => (),
+        Err(e) => exit(exit_status(&e), &e),
+    }
+}
+
+// The status to exit with after a run failed with `e`.
+fn exit_status(e: &Error) -> i32 {
+    match e.downcast_ref::<InternalError>() {
+        Some(InternalError::Regression(_)) => EXIT_REGRESSION,
+        Some(InternalError::ErrorRateExceeded(_)) => EXIT_ERROR_RATE,
+        Some(internal) if internal.is_setup() => EXIT_SETUP,
+        _ => EXIT_FAILED,
+    }
+}
+
+fn exit(status: i32, e: &Error) -> ! {
+    eprintln!("Error: {}", e);
+    process::exit(status)
+}
+
+// Load the config and start everything the run reports to, before anything is
+// sent to moray.
+fn setup(args: &Arguments) -> Result<(Config, soak::Reporter), Error> {
+    let mut config = args.to_config()?;
+
+    logging::init(&config)?;
+    timing::init(&config);
+    probes::register()?;
+
+    if (config.electric_moray || config.host.is_some()) && !config.shards.is_empty() {
+        return Err(InternalError::InvalidArguments(String::from(
+            "--shards can't be used with --electric-moray or --host",
+        ))
+        .into());
+    }
+    if config.mock {
+        if config.electric_moray || config.host.is_some() || !config.shards.is_empty() {
+            return Err(InternalError::InvalidArguments(String::from(
+                "--mock can't be used with --electric-moray, --host or --shards",
+            ))
+            .into());
         }
+        config.host = Some(MockServer::start(&config)?.addr());
+    } else if !config.mock_latency.is_none() {
+        return Err(InternalError::InvalidArguments(String::from(
+            "--mock-latency only applies with --mock",
+        ))
+        .into());
+    }
+    if config.chaos_reset > 0.0 {
+        config.host = Some(chaos::start(&config)?);
+    }
+
+    // Every shard writes its samples and latencies to the same files.
+    if let Some(path) = &config.timeseries {
+        timeseries::open(path)?;
+    }
+    if let Some(path) = &config.latency_csv {
+        latency_csv::open(path)?;
+    }
+    if let Some(port) = config.prometheus_port {
+        metrics::serve(port)?;
+    }
+    if let Some(port) = config.status_port {
+        status::serve(port)?;
+    }
+    if let Some(addr) = &config.statsd {
+        statsd::open(addr, &config.statsd_prefix)?;
+    }
+    if let Some(endpoint) = &config.otlp_endpoint {
+        trace::init(endpoint);
     }
 
-    println!("Creating test objects");
-    let test_objects = gen_test_objects(args.num_objects);
+    let reporter = soak::Reporter::start(&config)?;
+    shutdown::install()?;
+    // The dashboard shows what a dump would, and reads the keyboard itself.
+    if !config.dashboard {
+        dump::install()?;
+    }
 
-    println!("Seeding {} objects", args.num_objects);
+    Ok((config, reporter))
+}
 
-    for (key, obj) in test_objects.iter() {
-        let val = serde_json::to_value(obj).unwrap();
+fn run(config: Config, command: &Command) -> Result<(), Error> {
+    let started = SystemTime::now();
+    let dashboard = Dashboard::start(&config)?;
+    let results = run_all(&config, command);
 
-        mclient
-            .put_object(BUCKET_NAME, key, val, &opts, |_| Ok(()))
-            .expect("put object");
+    // The dashboard was drawn over the summaries printed during the run.
+    let drawn_over = dashboard.stop();
+    if shutdown::requested() {
+        println!("\n ==== stopped early, results so far ====");
+    }
+    if drawn_over || (shutdown::requested() && results.len() == 1) {
+        let summaries: Vec<_> = results
+            .iter()
+            .filter_map(|(shard, bucket, result)| {
+                result
+                    .as_ref()
+                    .ok()
+                    .map(|summary| (*shard, bucket.as_str(), summary.clone()))
+            })
+            .collect();
+        print_summaries(&summaries);
     }
 
-    if args.batch_only {
-        println!("Running batch only test");
-        let batch_objects = alter_objects(&test_objects);
-        run_batch_test(&mut mclient, batch_objects, args.batch_size)?;
-    } else if args.sequential_only {
-        println!("Running sequential only test");
-        let altered_objects = alter_objects(&test_objects);
-        run_sequential_test(&mut mclient, altered_objects)?;
-    } else {
-        run_comparison(&mut mclient, &test_objects, args.batch_size)?;
+    timing::print();
+    pool::print_connections();
+    print_error_classes(&results);
+    write_report(&config, started, &results)?;
+    // Partial results, or a dry run's, would only look like a regression.
+    let mut baseline = Ok(());
+    if let Command::Bench(_) | Command::Scenario(_) = command {
+        if !shutdown::requested() && !config.dry_run {
+            baseline = check_baseline(&config, &results);
+        }
+    }
+    if let Some(format) = config.summary_line {
+        summary_line::print(format, &results, &baseline);
+    }
+    baseline?;
+
+    outcome(results)
+}
+
+// Run `command` against every shard and bucket.
+fn run_all(config: &Config, command: &Command) -> Vec<(u32, String, Result<RunSummary, Error>)> {
+    let targets = targets(config);
+    if targets.len() != 1 {
+        return run_targets(config, command, targets);
+    }
+
+    let (shard, bucket) = targets.into_iter().next().expect("one target");
+    let mut config = config.clone();
+    config.shard = shard;
+    config.bucket = bucket.clone();
+    vec![(shard, bucket, run_command(config, command))]
+}
+
+// How the run went as a whole: the error of a run against a single shard and
+// bucket, or how many of several failed.
+fn outcome(mut results: Vec<(u32, String, Result<RunSummary, Error>)>) -> Result<(), Error> {
+    if results.len() == 1 {
+        return results.pop().expect("one result").2.map(|_| ());
+    }
+    let failed = results
+        .iter()
+        .filter(|(_, _, result)| result.is_err())
+        .count();
+    if failed > 0 {
+        return Err(InternalError::RunsFailed {
+            failed,
+            runs: results.len(),
+        }
+        .into());
     }
 
     Ok(())
 }
 
-fn run_comparison(
-    mclient: &mut MorayClient,
-    test_objects: &HashMap<String, MantaObject>,
-    batch_size: u32,
-) -> Result<(), Error> {
-    println!(" ==== running comparison ====");
-    println!(" ==== pass 1, sequential first then batch ====");
+// Parse the arguments a coordinator hands its agents.
+fn assigned_arguments(args: &[String]) -> Result<Arguments, Error> {
+    let argv = iter::once("batch_test").chain(args.iter().map(String::as_str));
+    let args = Arguments::try_parse_from(argv)
+        .map_err(|e| InternalError::InvalidArguments(e.to_string()))?;
 
-    let altered_objects = alter_objects(test_objects);
-    run_sequential_test(mclient, altered_objects)?;
+    if let Command::Coordinator(_) | Command::Agent(_) = args.command {
+        return Err(InternalError::InvalidArguments(String::from(
+            "agents can't be told to coordinate or to be agents themselves",
+        ))
+        .into());
+    }
 
-    let batch_objects = alter_objects(test_objects);
-    run_batch_test(mclient, batch_objects, batch_size)?;
+    Ok(args)
+}
 
-    println!("\n ==== pass 2, batch first then sequential ====");
+// Connect to the coordinator and wait for the arguments to run with.
+fn join(args: &AgentArgs) -> Result<(Arguments, Agent), Error> {
+    let agent = Agent::join(&args.coordinator)?;
 
-    let batch_objects = alter_objects(test_objects);
-    run_batch_test(mclient, batch_objects, batch_size)?;
+    match assigned_arguments(&agent.assignment().args) {
+        Ok(args) => Ok((args, agent)),
+        Err(e) => {
+            let _ = agent.fail(&e);
+            Err(e)
+        }
+    }
+}
 
-    let seq_objects = alter_objects(test_objects);
-    run_sequential_test(mclient, seq_objects)?;
+// Run an agent's share of the coordinator's command and report back to it.
+fn run_agent(config: Config, command: &Command, agent: Agent) -> Result<(), Error> {
+    let results = run_all(&config, command);
+    agent.report(&results)?;
 
-    Ok(())
+    outcome(results)
 }
 
-fn run_sequential_test(
-    mclient: &mut MorayClient,
-    objects: HashMap<String, Value>,
-) -> Result<(), Error> {
-    println!("Updating objects sequentially");
-    let opts = objects::MethodOptions::default();
-    let start = std::time::Instant::now();
-    for (key, obj) in objects.iter() {
-        mclient
-            .put_object(BUCKET_NAME, key, obj.clone(), &opts, |_| Ok(()))
-            .expect("put object");
+// Have the agents run the command given after --, each against its own slice
+// of the keyspace, and add up their results.
+fn coordinate(config: &Config, args: &CoordinatorArgs) -> Result<(), Error> {
+    let started = SystemTime::now();
+    if args.agents == 0 {
+        return Err(
+            InternalError::InvalidArguments(String::from("--agents has to be at least 1")).into(),
+        );
+    }
+
+    // Every agent has to use the same run id for its slice of the keys to
+    // be found by the next command.
+    let mut forwarded = args.args.clone();
+    if assigned_arguments(&forwarded)?.run_id.is_none() {
+        let run_id = batch_test::new_run_id();
+        println!(
+            "Coordinating run {}, pass --run-id {} to later commands",
+            run_id, run_id
+        );
+        forwarded.splice(0..0, vec![String::from("--run-id"), run_id]);
+    }
+
+    let listen = args
+        .listen
+        .unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, COORDINATOR_PORT)));
+    let results = distributed::coordinate(listen, args.agents, &forwarded)?;
+    let phases = distributed::combine(&results);
+    distributed::print(&results, &phases);
+    if let Some(path) = &config.output {
+        distributed::write(path, started, &forwarded, &results, &phases)?;
+    }
+
+    let failed = results.iter().filter(|result| result.failed()).count();
+    if failed > 0 {
+        return Err(InternalError::RunsFailed {
+            failed,
+            runs: results.len(),
+        }
+        .into());
     }
-    println!(
-        "Done updating objects sequentially : {}ms",
-        start.elapsed().as_millis()
-    );
 
     Ok(())
 }
 
-fn alter_objects(objects: &HashMap<String, MantaObject>) -> HashMap<String, Value> {
-    let mut rng = rand::thread_rng();
-    let mut altered_objects: HashMap<String, Value> = HashMap::new();
-    let rand_string = random_string(10);
-    let rand_id: u16 = rng.gen();
+fn print_error_classes(results: &[(u32, String, Result<RunSummary, Error>)]) {
+    let classes = batch_test::error_classes(results);
+    if classes.is_empty() {
+        return;
+    }
 
-    println!(
-        "Altering objects.  datacenter: {} | storage id: {}",
-        rand_string, rand_id
-    );
+    println!("Errors by class:");
+    for (class, count) in classes.iter() {
+        println!("    {:>8} {}", count, class);
+    }
+}
 
-    for (k, v) in objects.iter() {
-        let mut mobj: MantaObject = v.clone();
-        mobj.sharks.pop();
-        let shark = MantaObjectShark {
-            datacenter: rand_string.clone(),
-            manta_storage_id: format!("{}.stor.domain", rand_id),
-        };
-        mobj.sharks.push(shark);
+// Every shard and bucket to run against: each of --shards (or --shard) paired
+// with each of --buckets (or --bucket).
+fn targets(config: &Config) -> Vec<(u32, String)> {
+    let shards = if config.shards.is_empty() {
+        vec![config.shard]
+    } else {
+        config.shards.clone()
+    };
+    let buckets = if config.buckets.is_empty() {
+        vec![config.bucket.clone()]
+    } else {
+        config.buckets.clone()
+    };
 
-        let mobj_value = serde_json::to_value(mobj).unwrap();
-        altered_objects.insert(k.clone(), mobj_value);
+    shards
+        .iter()
+        .flat_map(|&shard| buckets.iter().map(move |bucket| (shard, bucket.clone())))
+        .collect()
+}
+
+fn write_report(
+    config: &Config,
+    started: SystemTime,
+    results: &[(u32, String, Result<RunSummary, Error>)],
+) -> Result<(), Error> {
+    match &config.output {
+        Some(path) => report::write(path, config, started, results),
+        None => Ok(()),
     }
-    altered_objects
 }
 
-fn run_batch_test(
-    mclient: &mut MorayClient,
-    objects: HashMap<String, Value>,
-    batch_size: u32,
+// Compare the run with the baseline, if one was given, and fail if anything
+// regressed by more than --fail-on-regression.
+fn check_baseline(
+    config: &Config,
+    results: &[(u32, String, Result<RunSummary, Error>)],
 ) -> Result<(), Error> {
-    println!("Updating objects in batches of {}", batch_size);
-    let mut batch: Vec<BatchRequest> = vec![];
-    let mut batch_count = 0;
-    let opts = objects::MethodOptions::default();
-    let start = std::time::Instant::now();
+    let path = match &config.baseline {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    let regressions = baseline::compare(
+        &Snapshot::load(path)?,
+        &Snapshot::from_results(results),
+        config.fail_on_regression,
+    );
+
+    if !regressions.is_empty() {
+        return Err(InternalError::Regression(regressions.join(", ")).into());
+    }
+
+    Ok(())
+}
+
+// Run the command against every shard and bucket at once, one thread each,
+// then report the results for each of them and for all of them together.
+fn run_targets(
+    config: &Config,
+    command: &Command,
+    targets: Vec<(u32, String)>,
+) -> Vec<(u32, String, Result<RunSummary, Error>)> {
+    if config.buckets.len() > 1 {
+        println!(
+            "Running against buckets {:?} on shards {:?}",
+            config.buckets,
+            targets
+                .iter()
+                .map(|(shard, _)| *shard)
+                .collect::<BTreeSet<u32>>()
+        );
+    } else {
+        println!("Running against shards {:?}", config.shards);
+    }
+
+    let handles: Vec<_> = targets
+        .into_iter()
+        .enumerate()
+        .map(|(i, (shard, bucket))| {
+            let mut config = config.clone();
+            let command = command.clone();
+            config.shard = shard;
+            config.bucket = bucket.clone();
+            // Every target makes the same objects from the same seed, so one
+            // of them saving the dataset is enough.
+            if i > 0 {
+                config.save_dataset = None;
+            }
+            // The targets' bars would draw over each other.
+            config.progress = false;
+
+            let handle = thread::spawn(move || run_command(config, &command));
+            (shard, bucket, handle)
+        })
+        .collect();
 
-    for (key, value) in objects.iter() {
-        batch.push(BatchRequest::Put(BatchPutOp {
-            bucket: BUCKET_NAME.to_string(),
-            options: opts.clone(),
-            key: key.clone(),
-            value: value.clone(),
-        }));
+    let results: Vec<_> = handles
+        .into_iter()
+        .map(|(shard, bucket, handle)| {
+            let result = handle
+                .join()
+                .unwrap_or_else(|_| Err(err_msg("thread panicked")));
+            (shard, bucket, result)
+        })
+        .collect();
 
-        batch_count += 1;
+    let mut summaries = vec![];
 
-        if batch_count == batch_size {
-            mclient.batch(&batch, &opts, |_| Ok(()))?;
-            batch.clear();
-            batch_count = 0;
+    for (shard, bucket, result) in results.iter() {
+        match result {
+            Ok(summary) => summaries.push((*shard, bucket.as_str(), summary.clone())),
+            Err(e) => eprintln!("shard {} bucket {}: {}", shard, bucket, e),
         }
     }
 
-    if !batch.is_empty() {
+    print_summaries(&summaries);
+
+    results
+}
+
+fn print_summaries(summaries: &[(u32, &str, RunSummary)]) {
+    let mut seq_total = 0.0;
+    let mut batch_total = 0.0;
+    let width = summaries
+        .iter()
+        .map(|(_, bucket, _)| bucket.len())
+        .max()
+        .unwrap_or(0)
+        .max("bucket".len());
+
+    println!("\n ==== per-shard and per-bucket results ====");
+    println!(
+        "{:>8} {:>w$} {:>10} {:>10} {:>12} {:>12}",
+        "shard",
+        "bucket",
+        "objects",
+        "seed ms",
+        "seq obj/s",
+        "batch obj/s",
+        w = width
+    );
+
+    for (shard, bucket, summary) in summaries.iter() {
+        let seed_ms = summary
+            .seed
+            .map(|d| d.as_millis().to_string())
+            .unwrap_or_else(|| String::from("-"));
+
         println!(
-            "Warning: issuing batch request with {} operations",
-            batch.len()
+            "{:>8} {:>w$} {:>10} {:>10} {:>12.1} {:>12.1}",
+            shard,
+            bucket,
+            summary.objects,
+            seed_ms,
+            summary.sequential_rate(),
+            summary.batch_rate(),
+            w = width
         );
-        mclient.batch(&batch, &opts, |_| Ok(()))?;
+
+        seq_total += summary.sequential_rate();
+        batch_total += summary.batch_rate();
     }
 
     println!(
-        "Done updating objects in batches: {}ms",
-        start.elapsed().as_millis()
+        "{:>8} {:>w$} {:>10} {:>10} {:>12.1} {:>12.1}",
+        "all",
+        "",
+        summaries.iter().map(|(_, _, s)| s.objects).sum::<u64>(),
+        "",
+        seq_total,
+        batch_total,
+        w = width
     );
+}
 
-    Ok(())
+fn run_command(config: Config, command: &Command) -> Result<RunSummary, Error> {
+    if config.dry_run {
+        return dry_run(&config, command);
+    }
+    if config.preflight {
+        match command {
+            Command::Seed(_) | Command::Scenario(_) => preflight::check(&config, false)?,
+            Command::Bench(_) => preflight::check(&config, true)?,
+            _ => (),
+        }
+    }
+    let versions = match command {
+        Command::Seed(_) | Command::Bench(_) | Command::Scenario(_) => server_versions(&config),
+        _ => vec![],
+    };
+    let mut runner = BenchRunner::new(config)?;
+
+    let result = match command {
+        Command::Seed(_) => {
+            let start = Instant::now();
+            let sizes = runner.seed()?;
+            let mut summary = RunSummary::new(runner.config().objects as usize);
+            summary.seed = Some(start.elapsed());
+            sizes.print();
+            summary.value_sizes = Some(sizes);
+            Ok(summary)
+        }
+        Command::Bench(bench) => run_bench(&mut runner, bench),
+        Command::Verify(_) => runner.verify().map(|_| RunSummary::default()),
+        Command::Cleanup(cleanup) => runner
+            .cleanup(cleanup.drop_bucket)
+            .map(|_| RunSummary::default()),
+        Command::Gc(gc) => runner
+            .gc(&gc.keep, gc.older_than.map(Duration::from_secs))
+            .map(|_| RunSummary::default()),
+        Command::Scenario(args) => {
+            let scenario = Scenario::from_file(&args.file)?;
+            scenario::run(&mut runner, &scenario)
+        }
+    };
+
+    result.map(|summary| RunSummary {
+        server_versions: versions,
+        ..summary
+    })
+}
+
+fn dry_run(config: &Config, command: &Command) -> Result<RunSummary, Error> {
+    match command {
+        Command::Seed(_) => dryrun::run(config, Plan::Seed),
+        Command::Bench(args) => dryrun::run(
+            config,
+            Plan::Bench {
+                sequential: !args.batch_only,
+                batch: !args.sequential_only,
+            },
+        ),
+        Command::Scenario(args) => {
+            let scenario = Scenario::from_file(&args.file)?;
+            dryrun::run(config, Plan::Scenario(&scenario))
+        }
+        _ => Err(InternalError::InvalidArguments(String::from(
+            "--dry-run only applies to seed, bench and scenario",
+        ))
+        .into()),
+    }
+}
+
+// The version of every moray instance the run is about to use, for the
+// report.  Not knowing them isn't worth failing the run over.
+fn server_versions(config: &Config) -> Vec<ServerVersion> {
+    match version::query(config) {
+        Ok(versions) => {
+            version::print(&versions);
+            versions
+        }
+        Err(e) => {
+            eprintln!("Unable to get moray versions: {}", e);
+            vec![]
+        }
+    }
+}
+
+fn run_bench(runner: &mut BenchRunner, args: &BenchArgs) -> Result<RunSummary, Error> {
+    let bucket = runner.config().bucket.clone();
+
+    println!("Fetching test objects");
+    let test_objects = runner.fetch_objects()?;
+    if test_objects.is_empty() {
+        return Err(InternalError::NoTestObjects(bucket).into());
+    }
+    println!("Found {} test objects", test_objects.len());
+
+    let config = runner.config();
+    if config.open_loop && config.rate <= 0.0 && config.profile == Profile::None {
+        return Err(InternalError::InvalidArguments(String::from(
+            "--open-loop needs a --rate or --profile to send requests at",
+        ))
+        .into());
+    }
+
+    if config.connections > 0
+        && (config.connections_per_worker > 1 || !config.sweep_connections.is_empty())
+    {
+        return Err(InternalError::InvalidArguments(String::from(
+            "--connections-per-worker and --sweep-connections can't be used with --connections",
+        ))
+        .into());
+    }
+    if !config.sweep_connections.is_empty() && !config.sweep_workers.is_empty() {
+        return Err(InternalError::InvalidArguments(String::from(
+            "--sweep-connections and --sweep-workers can't be used together",
+        ))
+        .into());
+    }
+
+    let sizes = batch_test::value_sizes(&test_objects);
+    sizes.print();
+
+    let mut summary = RunSummary::new(test_objects.len());
+
+    if !runner.config().sweep_connections.is_empty() {
+        println!("Running connection sweep");
+        summary.sweep = runner.run_connection_sweep(&test_objects)?;
+    } else if !runner.config().sweep_workers.is_empty() {
+        println!("Running worker sweep");
+        summary.sweep = runner.run_worker_sweep(&test_objects)?;
+    } else if !runner.config().sweep_batch_sizes.is_empty() {
+        println!("Running batch size sweep");
+        summary.sweep = runner.run_batch_sweep(&test_objects)?;
+    } else if runner.config().soak_secs > 0 {
+        summary = runner.run_soak(&test_objects, !args.batch_only, !args.sequential_only)?;
+    } else if args.batch_only {
+        println!("Running batch only test");
+        summary.batch = runner.run_batch_pass(&test_objects, 1)?;
+        summary.print_statistics();
+    } else if args.sequential_only {
+        println!("Running sequential only test");
+        summary.sequential = runner.run_sequential_pass(&test_objects, 1)?;
+        summary.print_statistics();
+    } else {
+        summary = runner.run_comparison(&test_objects)?;
+    }
+
+    if args.find && !shutdown::requested() {
+        println!("\n ==== findobjects ====");
+        summary.find.push(runner.run_find(&test_objects)?);
+    }
+    if args.get && !shutdown::requested() {
+        println!("\n ==== getobject ====");
+        summary.get = runner.run_get(&test_objects)?;
+    }
+    if args.delete && !shutdown::requested() {
+        println!("\n ==== delete ====");
+        summary.delete = runner.run_delete_comparison(&test_objects)?;
+        runner.check_count("the delete phases")?;
+    }
+    if args.update_objects && !shutdown::requested() {
+        println!("\n ==== updateobjects ====");
+        let report = runner.run_update_objects(&test_objects)?;
+        if summary.batch_rate() > 0.0 {
+            println!(
+                "updateobjects vs batch put: {:.2}x",
+                report.objects_per_sec / summary.batch_rate()
+            );
+        }
+        summary.update_objects.push(report);
+        runner.check_count("updateobjects")?;
+    }
+    if args.sql && !shutdown::requested() {
+        println!("\n ==== sql ====");
+        let report = runner.run_sql(&test_objects)?;
+        if report.objects_per_sec > 0.0 && summary.batch_rate() > 0.0 {
+            println!(
+                "Batch put reaches {:.1}% of raw sql throughput",
+                summary.batch_rate() / report.objects_per_sec * 100.0
+            );
+        }
+        summary.sql.push(report);
+        runner.check_count("the sql phase")?;
+    }
+    if args.reindex && !shutdown::requested() {
+        println!("\n ==== reindex ====");
+        summary.reindex = runner.run_reindex(&test_objects)?;
+        if summary.batch_rate() > 0.0 {
+            println!(
+                "Batch put during reindex: {:.1} objects/s, {:+.1}% vs without",
+                summary.reindex[1].objects_per_sec,
+                (summary.reindex[1].objects_per_sec / summary.batch_rate() - 1.0) * 100.0
+            );
+        }
+    }
+    if args.unique && !shutdown::requested() {
+        println!("\n ==== unique index conflicts ====");
+        let report = runner.run_unique(&test_objects)?;
+        if report.requests + report.failed_requests > 0 && summary.batch_rate() > 0.0 {
+            println!(
+                "{:.1}% of batches conflicted, batch put {:+.1}% vs without conflicts",
+                report.conflicts as f64 / (report.requests + report.failed_requests) as f64 * 100.0,
+                (report.objects_per_sec / summary.batch_rate() - 1.0) * 100.0
+            );
+        }
+        summary.unique.push(report);
+    }
+    if args.cas && !shutdown::requested() {
+        println!("\n ==== conditional puts ====");
+        let report = runner.run_cas(&test_objects)?;
+        if summary.batch_rate() > 0.0 {
+            println!(
+                "Effective conditional batch put throughput: {:.1} objects/s, {:+.1}% vs unconditional",
+                report.objects_per_sec,
+                (report.objects_per_sec / summary.batch_rate() - 1.0) * 100.0
+            );
+        }
+        summary.cas.push(report);
+        runner.check_count("the conditional puts")?;
+    }
+    if args.consistency && !shutdown::requested() {
+        println!("\n ==== sequential and batch consistency ====");
+        summary.consistency = runner.run_consistency(&test_objects)?;
+        runner.check_count("the consistency check")?;
+    }
+    if args.rebalance && !shutdown::requested() {
+        println!("\n ==== rebalance ====");
+        let report = runner.run_rebalance(&test_objects)?;
+        if summary.batch_rate() > 0.0 {
+            println!(
+                "Evacuated {:.1} objects/s end to end, {:.1}% of plain batch put throughput",
+                report.objects_per_sec,
+                report.objects_per_sec / summary.batch_rate() * 100.0
+            );
+        }
+        summary.rebalance.push(report);
+        runner.check_count("the rebalance")?;
+    }
+    if args.delete_log && !shutdown::requested() {
+        println!("\n ==== delete log ====");
+        summary.delete_log = runner.run_delete_log(&test_objects)?;
+    }
+
+    summary.value_sizes = Some(sizes);
+
+    Ok(summary)
 }
@@ -0,0 +1,149 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use libmanta::moray::MantaObject;
+use rand::distributions::{Distribution, LogNormal};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+// The directories under each owner's /stor, one level at a time.  Few names
+// per level keep the number of distinct dirnames close to a real account's.
+static LEVELS: &[&[&str]] = &[
+    &["logs", "data", "images", "backups", "reports", "public"],
+    &["2019", "2020", "archive", "current"],
+    &[
+        "01", "02", "03", "04", "05", "06", "07", "08", "09", "10", "11", "12",
+    ],
+];
+
+// Content types and the extensions that go with them, the first ones most
+// common.
+static TYPES: &[(&str, &str)] = &[
+    ("application/octet-stream", "bin"),
+    ("text/plain", "txt"),
+    ("application/x-gzip", "gz"),
+    ("image/jpeg", "jpg"),
+    ("application/json", "json"),
+    ("video/mp4", "mp4"),
+];
+
+/// The `type` of a manta directory entry.
+pub static DIRECTORY: &str = "directory";
+
+// Objects modified within the last year.
+const MAX_AGE_MS: u64 = 365 * 24 * 60 * 60 * 1000;
+
+// What modification times count back from when the objects are made from a
+// seed, so that the same seed makes the same objects whenever it is run:
+// 2020-01-01T00:00:00Z.
+const SEEDED_NOW_MS: u64 = 1_577_836_800_000;
+
+/// Makes the indexed fields of generated objects look like those of real
+/// manta objects: owners from a fixed set of account uuids, dirnames a few
+/// levels deep under each owner's /stor, and plausible names, content types,
+/// lengths and modification times.
+pub struct Generator {
+    owners: Vec<String>,
+    lengths: LogNormal,
+    now_ms: u64,
+}
+
+impl Generator {
+    /// A generator for `config.owners`, or `config.owner_count` accounts made
+    /// up from `rng` if none are given.  Modification times go back from a
+    /// fixed date rather than from now if `config.seed` is set.
+    pub fn new<R: Rng>(config: &Config, rng: &mut R) -> Generator {
+        let owners = if config.owners.is_empty() {
+            (0..config.owner_count.max(1))
+                .map(|_| Uuid::from_random_bytes(rng.gen()).to_string())
+                .collect()
+        } else {
+            config.owners.clone()
+        };
+        let now_ms = match config.seed {
+            Some(_) => SEEDED_NOW_MS,
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        };
+
+        Generator {
+            owners,
+            // Half of all objects under 64 KiB, with a long tail of large
+            // ones.
+            lengths: LogNormal::new((64.0 * 1024.0_f64).ln(), 2.0),
+            now_ms,
+        }
+    }
+
+    /// Overwrite the fields manta indexes and looks objects up by.  The
+    /// object id, vnode and sharks are left as they are.
+    pub fn fill<R: Rng>(&self, mobj: &mut MantaObject, rng: &mut R) {
+        let owner = self.owners.choose(rng).expect("at least one owner").clone();
+        let mut dirname = format!("/{}/stor", owner);
+        for level in LEVELS.iter().take(rng.gen_range(0, LEVELS.len() + 1)) {
+            dirname.push('/');
+            dirname.push_str(level.choose(rng).expect("levels aren't empty"));
+        }
+
+        // Skewed towards the first, most common content types.
+        let common = rng.gen_range(1, TYPES.len() + 1);
+        let (content_type, ext) = TYPES[rng.gen_range(0, common)];
+        let name = format!("{}.{}", crate::random_string(rng, 12).to_lowercase(), ext);
+        let md5: [u8; 16] = rng.gen();
+
+        mobj.key = format!("{}/{}", dirname, name);
+        mobj.dirname = dirname;
+        mobj.name = name;
+        mobj.creator = owner.clone();
+        mobj.owner = owner;
+        mobj.content_length = self.lengths.sample(rng).round() as u64;
+        mobj.content_md5 = base64(&md5);
+        mobj.content_type = content_type.to_string();
+        mobj.etag = mobj.object_id.clone();
+        mobj.obj_type = String::from("object");
+        mobj.mtime = self.now_ms - rng.gen_range(0, MAX_AGE_MS);
+    }
+}
+
+/// Turn the object into a directory entry, as mkdir writes one: no copies on
+/// any storage node, and no content.
+pub fn make_directory(mobj: &mut MantaObject) {
+    mobj.obj_type = DIRECTORY.to_string();
+    mobj.sharks = vec![];
+    mobj.content_length = 0;
+    mobj.content_md5 = String::new();
+    mobj.content_type = String::from("application/x-json-stream; type=directory");
+    mobj.etag = String::new();
+}
+
+pub fn is_directory(mobj: &MantaObject) -> bool {
+    mobj.obj_type == DIRECTORY
+}
+
+// Base64 with padding, as manta stores an object's content MD5.
+fn base64(bytes: &[u8]) -> String {
+    static ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity((bytes.len() + 2) / 3 * 4);
+
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (u32::from(*b) << (16 - i * 8)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((n >> (18 - i * 6)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
@@ -0,0 +1,101 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use failure::Error;
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec,
+    IntCounterVec, TextEncoder,
+};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::thread;
+
+lazy_static! {
+    pub static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "moray_batch_test_requests_total",
+        "Put and batch requests that succeeded",
+        &["shard", "phase"]
+    )
+    .unwrap();
+    pub static ref OBJECTS: IntCounterVec = register_int_counter_vec!(
+        "moray_batch_test_objects_total",
+        "Objects written by successful requests",
+        &["shard", "phase"]
+    )
+    .unwrap();
+    pub static ref ERRORS: IntCounterVec = register_int_counter_vec!(
+        "moray_batch_test_errors_total",
+        "Put and batch requests that failed after any retries",
+        &["shard", "phase", "error"]
+    )
+    .unwrap();
+    pub static ref RETRIES: IntCounterVec = register_int_counter_vec!(
+        "moray_batch_test_retries_total",
+        "Put and batch requests that were retried",
+        &["shard", "phase"]
+    )
+    .unwrap();
+    pub static ref CONNECTION_DROPS: IntCounterVec = register_int_counter_vec!(
+        "moray_batch_test_connection_drops_total",
+        "Connections to moray that were lost and reconnected",
+        &["shard"]
+    )
+    .unwrap();
+    pub static ref LATENCY: HistogramVec = register_histogram_vec!(
+        "moray_batch_test_request_duration_seconds",
+        "Time taken by successful put and batch requests, including retries",
+        &["shard", "phase"],
+        // 0.5ms to ~16s
+        exponential_buckets(0.0005, 2.0, 16).unwrap()
+    )
+    .unwrap();
+}
+
+/// Serve the metrics in the Prometheus text format at
+/// `http://<host>:<port>/metrics` for the rest of the run.
+pub fn serve(port: u16) -> Result<(), Error> {
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))?;
+    println!("Serving metrics on port {}", port);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.map_err(Error::from).and_then(handle_connection);
+
+            if let Err(e) = result {
+                eprintln!("metrics request failed: {}", e);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// Answer a single request.  Only the request line is looked at, which is all
+// a Prometheus scrape needs.
+fn handle_connection(mut stream: TcpStream) -> Result<(), Error> {
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    if path != "/metrics" {
+        stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")?;
+        return Ok(());
+    }
+
+    let encoder = TextEncoder::new();
+    let mut body = vec![];
+    encoder.encode(&prometheus::gather(), &mut body)?;
+
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        encoder.format_type(),
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+
+    Ok(())
+}
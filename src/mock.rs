@@ -0,0 +1,600 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! An in-process moray server backed by memory, for running the tool without
+//! a Manta deployment.
+//!
+//! It speaks the fast protocol and answers the RPCs the benchmark makes of a
+//! shard: bucket lookups and creation, putobject, getobject, delobject,
+//! findobjects, batch and version.  Filters are matched well enough to page
+//! through a run's objects: conjunctions, disjunctions, negation, equality
+//! with `*` wildcards and numeric comparisons.  Anything else is answered
+//! with an error naming the RPC, so a phase that needs it fails plainly.
+//!
+//! Puts with an etag are conditional, as on a real shard, and a batch is
+//! applied all or nothing.  Failures can be injected with
+//! `MockServer::fail_next`, to see that they are retried.
+//!
+//! With `--mock-latency` each RPC is answered only after a delay, so that the
+//! client's batching and concurrency can be profiled against a server whose
+//! latency is known.  The delay is slept on the connection's thread before the
+//! request is handled, and there are enough threads for each connection of a
+//! run to have its own, so requests on different connections are delayed
+//! concurrently as they would be on a shard.
+
+use crate::config::{Config, Delay, MockLatency};
+use crate::logging;
+use failure::{err_msg, Error};
+use lazy_static::lazy_static;
+use rand::Rng;
+use rust_fast::protocol::{FastMessage, FastMessageData};
+use rust_fast::server;
+use serde_json::{Map, Value};
+use slog::Logger;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener as StdTcpListener};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::TcpListener;
+use tokio::prelude::*;
+use tokio::reactor::Handle;
+use tokio::runtime;
+use uuid::Uuid;
+
+// The version the mock reports, that of the moray it stands in for.
+const VERSION: u64 = 2;
+
+// Threads answering requests when they are delayed, which bounds the number
+// of connections whose requests can be delayed at once.
+const DELAY_THREADS: usize = 256;
+
+#[derive(Clone, Debug)]
+struct Record {
+    id: u64,
+    value: Value,
+    etag: String,
+    mtime: u64,
+}
+
+#[derive(Default)]
+struct Bucket {
+    config: Value,
+    records: BTreeMap<String, Record>,
+}
+
+#[derive(Default)]
+struct Store {
+    buckets: HashMap<String, Bucket>,
+    next_id: u64,
+}
+
+lazy_static! {
+    static ref CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+}
+
+/// A running mock server.  It lasts as long as the process.
+pub struct MockServer {
+    addr: SocketAddr,
+    // How many more requests of each RPC to fail.
+    failures: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+impl MockServer {
+    /// Start a server on a free port on the loopback interface, delaying its
+    /// answers by `config.mock_latency`.
+    pub fn start(config: &Config) -> Result<MockServer, Error> {
+        let listener = StdTcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let addr = listener.local_addr()?;
+        let listener = TcpListener::from_std(listener, &Handle::default())?;
+        let store = Arc::new(Mutex::new(Store::default()));
+        let latency = Arc::new(config.mock_latency.clone());
+        let failures = Arc::new(Mutex::new(HashMap::new()));
+        let injected = Arc::clone(&failures);
+        let log = logging::logger();
+
+        let mut builder = runtime::Builder::new();
+        if !latency.is_none() {
+            builder.core_threads(DELAY_THREADS);
+        }
+        let mut runtime = builder.build()?;
+
+        let server = listener
+            .incoming()
+            .map_err(|e| eprintln!("mock moray: accept failed: {}", e))
+            .for_each(move |socket| {
+                let store = Arc::clone(&store);
+                let latency = Arc::clone(&latency);
+                let injected = Arc::clone(&injected);
+                let id = CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+                let handler = move |msg: &FastMessage, _log: &Logger| {
+                    delay(&latency, &msg.data.m.name);
+                    if let Some(e) = injected_failure(&injected, &msg.data.m.name) {
+                        return Err(e);
+                    }
+                    handle(&store, msg)
+                };
+                tokio::spawn(server::make_task(socket, handler, Some(id), &log));
+                Ok(())
+            });
+        runtime.spawn(server);
+        thread::spawn(move || runtime.shutdown_on_idle().wait());
+
+        println!("Mock moray listening on {}", addr);
+        if !config.mock_latency.is_none() {
+            println!(
+                "Mock moray answer delays in ms: {}",
+                String::from(config.mock_latency.clone())
+            );
+        }
+        Ok(MockServer { addr, failures })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Answer the next `count` requests of `rpc`, e.g. "batch", with a
+    /// NoDatabasePeersError, as a shard that briefly lost its database would.
+    pub fn fail_next(&self, rpc: &str, count: u32) {
+        *self
+            .failures
+            .lock()
+            .unwrap()
+            .entry(rpc.to_string())
+            .or_insert(0) += count;
+    }
+}
+
+// The error to answer a request of `rpc` with, if it is to fail.
+fn injected_failure(failures: &Mutex<HashMap<String, u32>>, rpc: &str) -> Option<Error> {
+    let mut failures = failures.lock().unwrap();
+    let remaining = failures.get_mut(rpc).filter(|n| **n > 0)?;
+    *remaining -= 1;
+
+    Some(err_msg(format!(
+        "NoDatabasePeersError: {} failed by the mock",
+        rpc
+    )))
+}
+
+// Sleep for the delay set for `rpc`, if any.
+fn delay(latency: &MockLatency, rpc: &str) {
+    let Delay { ms, jitter_ms } = match latency.delay(rpc) {
+        Some(delay) => delay,
+        None => return,
+    };
+    let jitter = if jitter_ms > 0.0 {
+        rand::thread_rng().gen_range(-jitter_ms, jitter_ms)
+    } else {
+        0.0
+    };
+
+    let ms = (ms + jitter).max(0.0);
+    if ms > 0.0 {
+        thread::sleep(Duration::from_secs_f64(ms / 1000.0));
+    }
+}
+
+fn handle(store: &Mutex<Store>, msg: &FastMessage) -> Result<Vec<FastMessage>, Error> {
+    let method = msg.data.m.name.as_str();
+    let args = msg.data.d.as_array().cloned().unwrap_or_default();
+    let mut store = store.lock().unwrap();
+
+    let records = match method {
+        "getBucket" => vec![store.get_bucket(string_arg(&args)?)?],
+        "createBucket" => {
+            store.create_bucket(str_at(&args, 0)?, args.get(1).cloned())?;
+            vec![]
+        }
+        "putObject" => {
+            let (bucket, key) = (str_at(&args, 0)?, str_at(&args, 1)?);
+            let value = args.get(2).cloned().unwrap_or(Value::Null);
+            let etag = store.put(bucket, key, value, args.get(3))?;
+            vec![json!({ "etag": etag })]
+        }
+        "getObject" => {
+            let (bucket, key) = (str_at(&args, 0)?, str_at(&args, 1)?);
+            vec![store.get(bucket, key)?]
+        }
+        "delObject" => {
+            let (bucket, key) = (str_at(&args, 0)?, str_at(&args, 1)?);
+            store.delete(bucket, key, args.get(2))?;
+            vec![]
+        }
+        "findObjects" => {
+            let (bucket, filter) = (str_at(&args, 0)?, str_at(&args, 1)?);
+            store.find(bucket, filter, args.get(2))?
+        }
+        "batch" => {
+            let requests = args.get(0).and_then(Value::as_array).cloned();
+            let requests = requests.ok_or_else(|| err_msg("InvalidQueryError: no requests"))?;
+            vec![store.batch(&requests)?]
+        }
+        "version" => vec![json!({ "version": VERSION })],
+        _ => {
+            return Err(err_msg(format!(
+                "NotImplementedError: the mock doesn't implement {}",
+                method
+            )))
+        }
+    };
+
+    let mut responses: Vec<FastMessage> = records
+        .into_iter()
+        .map(|record| {
+            FastMessage::data(
+                msg.id,
+                FastMessageData::new(method.to_string(), json!([record])),
+            )
+        })
+        .collect();
+    responses.push(FastMessage::end(msg.id, method.to_string()));
+
+    Ok(responses)
+}
+
+fn str_at(args: &[Value], i: usize) -> Result<&str, Error> {
+    args.get(i)
+        .and_then(Value::as_str)
+        .ok_or_else(|| err_msg(format!("InvalidQueryError: argument {} isn't a string", i)))
+}
+
+// The first string argument, wherever the client put the options.
+fn string_arg(args: &[Value]) -> Result<&str, Error> {
+    args.iter()
+        .find_map(Value::as_str)
+        .ok_or_else(|| err_msg("InvalidQueryError: no bucket name"))
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn bucket_not_found(name: &str) -> Error {
+    err_msg(format!("BucketNotFoundError: {} does not exist", name))
+}
+
+// The etag a put or delete is conditional on, if any.
+fn expected_etag(options: Option<&Value>) -> Option<&str> {
+    options.and_then(|opts| opts["etag"].as_str())
+}
+
+impl Store {
+    fn get_bucket(&self, name: &str) -> Result<Value, Error> {
+        let bucket = self
+            .buckets
+            .get(name)
+            .ok_or_else(|| bucket_not_found(name))?;
+        let mut record = bucket.config.as_object().cloned().unwrap_or_else(Map::new);
+        record.insert("name".to_string(), json!(name));
+        record.entry("index").or_insert_with(|| json!({}));
+        record.insert("mtime".to_string(), json!(now_ms().to_string()));
+
+        Ok(Value::Object(record))
+    }
+
+    fn create_bucket(&mut self, name: &str, config: Option<Value>) -> Result<(), Error> {
+        if self.buckets.contains_key(name) {
+            return Err(err_msg(format!(
+                "BucketConflictError: {} already exists",
+                name
+            )));
+        }
+        self.buckets.insert(
+            name.to_string(),
+            Bucket {
+                config: config.unwrap_or_else(|| json!({})),
+                records: BTreeMap::new(),
+            },
+        );
+
+        Ok(())
+    }
+
+    fn bucket(&mut self, name: &str) -> Result<&mut Bucket, Error> {
+        self.buckets
+            .get_mut(name)
+            .ok_or_else(|| bucket_not_found(name))
+    }
+
+    fn put(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        value: Value,
+        options: Option<&Value>,
+    ) -> Result<String, Error> {
+        self.check_etag(bucket, key, options)?;
+        self.next_id += 1;
+        let id = self.next_id;
+        let etag = Uuid::new_v4().to_simple().to_string().to_uppercase();
+        let record = Record {
+            id,
+            value,
+            etag: etag.clone(),
+            mtime: now_ms(),
+        };
+        self.bucket(bucket)?.records.insert(key.to_string(), record);
+
+        Ok(etag)
+    }
+
+    fn check_etag(
+        &mut self,
+        bucket: &str,
+        key: &str,
+        options: Option<&Value>,
+    ) -> Result<(), Error> {
+        let expected = match expected_etag(options) {
+            Some(etag) => etag,
+            None => return Ok(()),
+        };
+        let current = self
+            .bucket(bucket)?
+            .records
+            .get(key)
+            .map(|r| r.etag.as_str());
+        if current != Some(expected) {
+            return Err(err_msg(format!(
+                "EtagConflictError: {}::{} has etag {}, not {}",
+                bucket,
+                key,
+                current.unwrap_or("null"),
+                expected
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn get(&mut self, bucket: &str, key: &str) -> Result<Value, Error> {
+        let record = self.bucket(bucket)?.records.get(key).cloned();
+        let record = record.ok_or_else(|| {
+            err_msg(format!(
+                "ObjectNotFoundError: {}::{} does not exist",
+                bucket, key
+            ))
+        })?;
+
+        Ok(object(bucket, key, &record, 1))
+    }
+
+    fn delete(&mut self, bucket: &str, key: &str, options: Option<&Value>) -> Result<(), Error> {
+        self.check_etag(bucket, key, options)?;
+        match self.bucket(bucket)?.records.remove(key) {
+            Some(_) => Ok(()),
+            None => Err(err_msg(format!(
+                "ObjectNotFoundError: {}::{} does not exist",
+                bucket, key
+            ))),
+        }
+    }
+
+    fn find(
+        &mut self,
+        bucket: &str,
+        filter: &str,
+        options: Option<&Value>,
+    ) -> Result<Vec<Value>, Error> {
+        let filter = Filter::parse(filter)?;
+        let offset = options.and_then(|o| o["offset"].as_u64()).unwrap_or(0) as usize;
+        let limit = options.and_then(|o| o["limit"].as_u64()).unwrap_or(1000) as usize;
+
+        // In `_id` order, so that paging on `_id` sees every object.
+        let mut matching: Vec<(&String, &Record)> = self
+            .bucket(bucket)?
+            .records
+            .iter()
+            .filter(|(key, record)| filter.matches(&fields(key, record)))
+            .collect();
+        matching.sort_by_key(|(_, record)| record.id);
+        let count = matching.len() as u64;
+
+        Ok(matching
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|(key, record)| object(bucket, key, record, count))
+            .collect())
+    }
+
+    // Apply every request in a batch or none of them: each is tried against a
+    // copy of the buckets, which replaces them only if all succeed.
+    fn batch(&mut self, requests: &[Value]) -> Result<Value, Error> {
+        let saved = self
+            .buckets
+            .iter()
+            .map(|(name, bucket)| (name.clone(), bucket.records.clone()))
+            .collect::<HashMap<_, _>>();
+        let next_id = self.next_id;
+        let mut etags = vec![];
+
+        for request in requests.iter() {
+            let result = self.apply(request);
+            match result {
+                Ok(Some(etag)) => etags.push(etag),
+                Ok(None) => (),
+                Err(e) => {
+                    for (name, records) in saved.into_iter() {
+                        if let Some(bucket) = self.buckets.get_mut(&name) {
+                            bucket.records = records;
+                        }
+                    }
+                    self.next_id = next_id;
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(json!({ "etags": etags }))
+    }
+
+    fn apply(&mut self, request: &Value) -> Result<Option<Value>, Error> {
+        let bucket = request["bucket"].as_str().unwrap_or_default();
+        let key = request["key"].as_str().unwrap_or_default();
+        let options = request.get("options");
+
+        match request["operation"].as_str().unwrap_or("put") {
+            "put" => {
+                let etag = self.put(bucket, key, request["value"].clone(), options)?;
+                Ok(Some(json!({ "bucket": bucket, "key": key, "etag": etag })))
+            }
+            "delete" => {
+                self.delete(bucket, key, options)?;
+                Ok(None)
+            }
+            operation => Err(err_msg(format!(
+                "NotImplementedError: the mock doesn't implement batch {}",
+                operation
+            ))),
+        }
+    }
+}
+
+// An object as findobjects and getobject return it.
+fn object(bucket: &str, key: &str, record: &Record, count: u64) -> Value {
+    json!({
+        "bucket": bucket,
+        "key": key,
+        "value": record.value,
+        "_id": record.id,
+        "_etag": record.etag,
+        "_mtime": record.mtime,
+        "_txn_snap": null,
+        "_count": count,
+    })
+}
+
+// The fields a filter can match an object on: those of its value and the
+// ones moray keeps for every object.
+fn fields(key: &str, record: &Record) -> Value {
+    let mut fields = record.value.as_object().cloned().unwrap_or_else(Map::new);
+    fields.insert("_key".to_string(), json!(key));
+    fields.insert("_id".to_string(), json!(record.id));
+    fields.insert("_etag".to_string(), json!(record.etag));
+    fields.insert("_mtime".to_string(), json!(record.mtime));
+
+    Value::Object(fields)
+}
+
+// An LDAP search filter, as moray takes them.
+enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    Equal(String, String),
+    AtLeast(String, f64),
+    AtMost(String, f64),
+}
+
+impl Filter {
+    fn parse(filter: &str) -> Result<Filter, Error> {
+        let (parsed, rest) = Filter::parse_one(filter.trim())?;
+        if !rest.trim().is_empty() {
+            return Err(invalid_filter(filter));
+        }
+
+        Ok(parsed)
+    }
+
+    // Parse the filter at the start of `s`, returning it and what follows it.
+    fn parse_one(s: &str) -> Result<(Filter, &str), Error> {
+        let inner = s.strip_prefix('(').ok_or_else(|| invalid_filter(s))?;
+
+        let (filter, rest) = match inner.chars().next() {
+            Some('&') => {
+                let (filters, rest) = Filter::parse_list(&inner[1..])?;
+                (Filter::And(filters), rest)
+            }
+            Some('|') => {
+                let (filters, rest) = Filter::parse_list(&inner[1..])?;
+                (Filter::Or(filters), rest)
+            }
+            Some('!') => {
+                let (filter, rest) = Filter::parse_one(&inner[1..])?;
+                (Filter::Not(Box::new(filter)), rest)
+            }
+            _ => {
+                let end = inner.find(')').ok_or_else(|| invalid_filter(s))?;
+                (Filter::parse_comparison(&inner[..end])?, &inner[end..])
+            }
+        };
+
+        let rest = rest.strip_prefix(')').ok_or_else(|| invalid_filter(s))?;
+        Ok((filter, rest))
+    }
+
+    fn parse_list(mut s: &str) -> Result<(Vec<Filter>, &str), Error> {
+        let mut filters = vec![];
+        while s.starts_with('(') {
+            let (filter, rest) = Filter::parse_one(s)?;
+            filters.push(filter);
+            s = rest;
+        }
+
+        Ok((filters, s))
+    }
+
+    fn parse_comparison(s: &str) -> Result<Filter, Error> {
+        let number = |v: &str| v.parse::<f64>().map_err(|_| invalid_filter(s));
+
+        if let Some(i) = s.find(">=") {
+            Ok(Filter::AtLeast(s[..i].to_string(), number(&s[i + 2..])?))
+        } else if let Some(i) = s.find("<=") {
+            Ok(Filter::AtMost(s[..i].to_string(), number(&s[i + 2..])?))
+        } else if let Some(i) = s.find('=') {
+            Ok(Filter::Equal(s[..i].to_string(), s[i + 1..].to_string()))
+        } else {
+            Err(invalid_filter(s))
+        }
+    }
+
+    fn matches(&self, value: &Value) -> bool {
+        match self {
+            Filter::And(filters) => filters.iter().all(|f| f.matches(value)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches(value)),
+            Filter::Not(filter) => !filter.matches(value),
+            Filter::Equal(field, pattern) => match &value[field.as_str()] {
+                Value::Null => false,
+                Value::String(s) => glob_matches(pattern, s),
+                other => glob_matches(pattern, &other.to_string()),
+            },
+            Filter::AtLeast(field, n) => value[field.as_str()].as_f64().map_or(false, |v| v >= *n),
+            Filter::AtMost(field, n) => value[field.as_str()].as_f64().map_or(false, |v| v <= *n),
+        }
+    }
+}
+
+fn invalid_filter(filter: &str) -> Error {
+    err_msg(format!("InvalidQueryError: invalid filter {}", filter))
+}
+
+// Whether `s` matches `pattern`, in which `*` stands for any run of
+// characters.
+fn glob_matches(pattern: &str, s: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == s;
+    }
+
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !s.starts_with(first) || s.len() < first.len() + last.len() || !s.ends_with(last) {
+        return false;
+    }
+
+    let mut rest = &s[first.len()..s.len() - last.len()];
+    for part in parts[1..parts.len() - 1].iter() {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+
+    true
+}
@@ -0,0 +1,217 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::breaker::CircuitBreaker;
+use crate::config::Config;
+use crate::retry::{self, RetryPolicy};
+use crate::timing::{self, Step};
+use crate::{create_client, logging, metrics, resolver, shutdown, Target};
+use failure::Error;
+use moray::client::MorayClient;
+use serde::Serialize;
+use slog::{info, warn};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A moray client shared between benchmark workers.  Workers should only hold
+/// the lock for the duration of a single request.
+pub type PooledClient = Arc<Mutex<MorayClient>>;
+
+/// One of the pool's connections, as the workers sharing it see it.  A
+/// connection a request was abandoned on is replaced here, so that every
+/// worker sharing it moves on to the new one rather than waiting on the lock
+/// the abandoned request still holds.
+pub type ClientSlot = Arc<Mutex<PooledClient>>;
+
+/// A fixed set of moray connections, spread across every moray instance
+/// advertised for the shard, that are handed out round-robin.
+pub struct Pool {
+    clients: Vec<ClientSlot>,
+    next: AtomicUsize,
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl Pool {
+    /// Open `config.connections` connections, or `config.workers` times
+    /// `config.connections_per_worker` if that is zero.  Each connection
+    /// picks its moray instance using the SRV priorities and weights, falling
+    /// through to the next instance if one is unreachable.
+    pub fn connect(config: &Config) -> Result<Pool, Error> {
+        let size = if config.connections > 0 {
+            config.connections
+        } else {
+            config.workers.max(1) * config.connections_per_worker.max(1)
+        };
+        let target = Target::from_config(config);
+
+        let clients = (0..size)
+            .map(|_| create_client(&target))
+            .collect::<Result<Vec<MorayClient>, Error>>()?;
+
+        println!(
+            "Opened {} connections, one fast connection per moray client",
+            clients.len()
+        );
+
+        Ok(Pool::from_clients(
+            clients,
+            CircuitBreaker::from_config(config),
+        ))
+    }
+
+    pub fn from_clients(clients: Vec<MorayClient>, breaker: CircuitBreaker) -> Pool {
+        Pool {
+            clients: clients
+                .into_iter()
+                .map(|c| Arc::new(Mutex::new(Arc::new(Mutex::new(c)))))
+                .collect(),
+            next: AtomicUsize::new(0),
+            breaker: Arc::new(breaker),
+        }
+    }
+
+    /// The next client in round-robin order.
+    pub fn get(&self) -> PooledClient {
+        Arc::clone(&*self.slot().lock().unwrap())
+    }
+
+    /// The next connection in round-robin order, for a caller that replaces
+    /// it with `replace` when a request on it has to be abandoned.
+    pub fn slot(&self) -> ClientSlot {
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.clients.len();
+        Arc::clone(&self.clients[i])
+    }
+
+    /// Every client in the pool talks to the same shard, so they share a
+    /// single circuit breaker.
+    pub fn breaker(&self) -> Arc<CircuitBreaker> {
+        Arc::clone(&self.breaker)
+    }
+
+    pub fn size(&self) -> usize {
+        self.clients.len()
+    }
+}
+
+// Connections lost, and what came of reconnecting them, across every pool in
+// the process.
+static DROPS: AtomicU64 = AtomicU64::new(0);
+static RECONNECT_ATTEMPTS: AtomicU64 = AtomicU64::new(0);
+static RECONNECTS: AtomicU64 = AtomicU64::new(0);
+static FAILED_RECONNECTS: AtomicU64 = AtomicU64::new(0);
+
+/// How the run's connections fared.  Connections abandoned because a request
+/// on them timed out count as dropped.  The time each reconnect took is under
+/// `reconnect` in the timing breakdown.
+#[derive(Debug, Serialize)]
+pub struct ConnectionReport {
+    pub drops: u64,
+    pub reconnect_attempts: u64,
+    pub reconnects: u64,
+    pub failed_reconnects: u64,
+}
+
+pub fn connection_report() -> ConnectionReport {
+    ConnectionReport {
+        drops: DROPS.load(Ordering::Relaxed),
+        reconnect_attempts: RECONNECT_ATTEMPTS.load(Ordering::Relaxed),
+        reconnects: RECONNECTS.load(Ordering::Relaxed),
+        failed_reconnects: FAILED_RECONNECTS.load(Ordering::Relaxed),
+    }
+}
+
+/// Replace the connection in `slot` with a new one, unless a worker sharing it
+/// already has since `abandoned` was taken from it.
+pub fn replace(slot: &ClientSlot, abandoned: &PooledClient, config: &Config) {
+    if !Arc::ptr_eq(&*slot.lock().unwrap(), abandoned) {
+        return;
+    }
+
+    if let Ok(client) = reconnect(config) {
+        let mut current = slot.lock().unwrap();
+        if Arc::ptr_eq(&*current, abandoned) {
+            *current = Arc::new(Mutex::new(client));
+        }
+    }
+}
+
+pub fn print_connections() {
+    let report = connection_report();
+    if report.drops == 0 {
+        return;
+    }
+
+    println!(
+        "{} connections dropped, {} reconnected in {} attempts, {} could not be",
+        report.drops, report.reconnects, report.reconnect_attempts, report.failed_reconnects
+    );
+}
+
+// Run `op` against the client, holding its lock for the duration.  If the
+// operation fails because the connection was lost, reconnect to the target
+// and try the operation once more.
+pub fn with_reconnect<T, F>(client: &PooledClient, config: &Config, mut op: F) -> Result<T, Error>
+where
+    F: FnMut(&mut MorayClient) -> Result<T, Error>,
+{
+    let mut mclient = client.lock().unwrap();
+
+    match op(&mut *mclient) {
+        Err(e) if retry::is_connection_error(&e) => {
+            warn!(logging::logger(), "lost connection to moray, reconnecting"; "error" => %e);
+            *mclient = reconnect(config)?;
+            op(&mut *mclient)
+        }
+        result => result,
+    }
+}
+
+/// Connect to the target again, forgetting the cached DNS results each time
+/// so that a moray instance that moved is found, and backing off between
+/// attempts as retries do.  Gives up once `config.reconnect_timeout_secs`
+/// have passed, so that a failover has time to finish without a dead shard
+/// holding the run up for ever.
+pub fn reconnect(config: &Config) -> Result<MorayClient, Error> {
+    let log = logging::logger();
+    let policy = RetryPolicy::from_config(config);
+    let timeout = Duration::from_secs(config.reconnect_timeout_secs);
+    let started = Instant::now();
+    let mut attempt = 1;
+
+    DROPS.fetch_add(1, Ordering::Relaxed);
+    metrics::CONNECTION_DROPS
+        .with_label_values(&[config.shard.to_string().as_str()])
+        .inc();
+
+    loop {
+        resolver::invalidate();
+        RECONNECT_ATTEMPTS.fetch_add(1, Ordering::Relaxed);
+
+        match create_client(&Target::from_config(config)) {
+            Ok(client) => {
+                RECONNECTS.fetch_add(1, Ordering::Relaxed);
+                timing::record(Step::Reconnect, started.elapsed());
+                info!(log, "reconnected to moray";
+                    "attempts" => attempt,
+                    "ms" => started.elapsed().as_millis() as u64);
+                return Ok(client);
+            }
+            Err(e) => {
+                let delay = policy.delay(attempt);
+                if started.elapsed() + delay > timeout || shutdown::requested() {
+                    FAILED_RECONNECTS.fetch_add(1, Ordering::Relaxed);
+                    return Err(e);
+                }
+                warn!(log, "unable to reconnect to moray";
+                    "attempt" => attempt,
+                    "delay_ms" => delay.as_millis() as u64,
+                    "error" => %e);
+                thread::sleep(delay);
+                attempt += 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! Checks made before a run starts writing, so that a missing SRV record, an
+//! unreachable shard or a bucket with the wrong schema is reported plainly up
+//! front rather than as put errors minutes into the run.
+
+use crate::config::{Config, Index};
+use crate::{configure_network, connect, retry, InternalError, Target};
+use failure::Error;
+use moray::buckets;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+fn failed(message: String) -> Error {
+    InternalError::PreflightFailed(message).into()
+}
+
+/// Check that the target's moray instances can be found and reached and that
+/// the test bucket's indexes are the ones `config.index` describes, printing
+/// what was found.  A bucket that doesn't exist yet is only a problem if
+/// `bucket_must_exist`, as seeding creates it.
+pub fn check(config: &Config, bucket_must_exist: bool) -> Result<(), Error> {
+    configure_network(config);
+    let target = Target::from_config(config);
+    let name = target.domain_name();
+
+    println!("===pre-flight checks===");
+    let addrs = target
+        .addrs()
+        .map_err(|e| failed(format!("no moray instances found for {}: {}", name, e)))?;
+    println!("{} moray instances for {}", addrs.len(), name);
+
+    let opts = buckets::MethodOptions::default();
+    let mut reachable = 0;
+    let mut bucket = None;
+    let mut lookup_error = None;
+
+    for addr in addrs.iter() {
+        let started = Instant::now();
+        let mut client = match connect(*addr) {
+            Ok(client) => client,
+            Err(e) => {
+                println!("    {}: unreachable: {}", addr, e);
+                continue;
+            }
+        };
+        let connected = started.elapsed();
+
+        let asked = Instant::now();
+        let mut found = None;
+        let result = client.get_bucket(&config.bucket, opts.clone(), |b: &buckets::Bucket| {
+            found = Some(b.index.clone());
+            Ok(())
+        });
+        match result {
+            Ok(()) => bucket = found.or(bucket),
+            Err(e) if retry::error_name(&e) == "BucketNotFoundError" => (),
+            Err(e) => {
+                println!("    {}: connected but didn't answer: {}", addr, e);
+                lookup_error = Some(e);
+                continue;
+            }
+        }
+
+        reachable += 1;
+        println!(
+            "    {}: connected in {:.1}ms, answered in {:.1}ms",
+            addr,
+            connected.as_secs_f64() * 1000.0,
+            asked.elapsed().as_secs_f64() * 1000.0
+        );
+    }
+
+    if reachable == 0 {
+        let reason = lookup_error.map_or_else(String::new, |e| format!(": {}", e));
+        return Err(failed(format!(
+            "none of the {} moray instances for {} answered{}",
+            addrs.len(),
+            name,
+            reason
+        )));
+    }
+
+    match bucket {
+        Some(index) => {
+            let problems = schema_problems(&config.index, &index);
+            if !problems.is_empty() {
+                return Err(InternalError::BucketSchema {
+                    bucket: config.bucket.clone(),
+                    problems: problems.join(", "),
+                }
+                .into());
+            }
+            println!("Bucket {} has the expected indexes", config.bucket);
+        }
+        None if bucket_must_exist => {
+            return Err(failed(format!(
+                "bucket {} doesn't exist, run seed first",
+                config.bucket
+            )));
+        }
+        None => println!("Bucket {} will be created", config.bucket),
+    }
+
+    Ok(())
+}
+
+// How the bucket's indexes differ from the expected ones.  Indexes the bucket
+// has on top of those are fine.
+fn schema_problems(expected: &BTreeMap<String, Index>, actual: &Value) -> Vec<String> {
+    let mut problems = vec![];
+
+    for (field, index) in expected.iter() {
+        let have = &actual[field];
+        if have.is_null() {
+            problems.push(format!("no index on {}", field));
+            continue;
+        }
+        if have["type"].as_str() != Some(index.kind.as_str()) {
+            problems.push(format!(
+                "{} is indexed as {}, not {}",
+                field, have["type"], index.kind
+            ));
+        }
+        if have["unique"].as_bool().unwrap_or(false) != index.unique {
+            problems.push(format!(
+                "{} is {}unique",
+                field,
+                if index.unique { "not " } else { "" }
+            ));
+        }
+    }
+
+    problems
+}
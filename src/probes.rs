@@ -0,0 +1,28 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! USDT probes fired around every put and batch request, so that dtrace can
+//! line up what the benchmark is doing with the moray server probes, e.g.
+//!
+//!     dtrace -n 'moray_batch_test*:::batch-done { @[arg3] = count(); }'
+
+use failure::Error;
+
+#[usdt::provider]
+pub mod moray_batch_test {
+    /// shard, key
+    fn put__start(_: u32, _: &str) {}
+    /// shard, key, 1 if the put succeeded
+    fn put__done(_: u32, _: &str, _: u8) {}
+    /// shard, batch number, operations in the batch
+    fn batch__start(_: u32, _: u64, _: u64) {}
+    /// shard, batch number, operations in the batch, 1 if it succeeded
+    fn batch__done(_: u32, _: u64, _: u64, _: u8) {}
+}
+
+/// Make the probes visible to dtrace.  They don't fire until this is called.
+pub fn register() -> Result<(), Error> {
+    usdt::register_probes()?;
+    Ok(())
+}
@@ -0,0 +1,92 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use crate::stats::PhaseStats;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// How often a watched phase's bar is brought up to date.
+const REFRESH: Duration = Duration::from_millis(200);
+
+/// A progress bar on the terminal showing how much of a phase is done, how
+/// fast it is going and when it should finish.  Nothing is drawn if
+/// `config.progress` is off or the output isn't a terminal.
+pub struct Progress {
+    bar: ProgressBar,
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Progress {
+    /// A bar for `total` objects, moved along with `inc`.
+    pub fn new(config: &Config, label: &str, total: u64) -> Progress {
+        let bar = if config.progress {
+            ProgressBar::new(total)
+        } else {
+            ProgressBar::hidden()
+        };
+        bar.set_style(ProgressStyle::default_bar().template(
+            "{prefix:>12} [{elapsed_precise}] {wide_bar} {pos}/{len} {per_sec} eta {eta}",
+        ));
+        bar.set_prefix(label);
+
+        Progress {
+            bar,
+            stop: None,
+            handle: None,
+        }
+    }
+
+    /// A bar for a phase writing `total` objects, following its stats until
+    /// it is finished.  A phase that runs for `config.duration_secs` instead
+    /// goes over the objects as many times as it can, so its bar only counts
+    /// them; any other phase's total is kept in its stats for `/status`.
+    pub fn watch(config: &Config, stats: &Arc<PhaseStats>, total: u64) -> Progress {
+        let mut progress = Progress::new(config, stats.phase, total);
+        if config.duration_secs == 0 {
+            stats.set_total(total);
+        } else {
+            progress.bar.set_style(
+                ProgressStyle::default_spinner()
+                    .template("{prefix:>12} [{elapsed_precise}] {spinner} {pos} {per_sec}"),
+            );
+        }
+        if progress.bar.is_hidden() {
+            return progress;
+        }
+
+        let (tx, rx) = mpsc::channel::<()>();
+        let bar = progress.bar.clone();
+        let stats = Arc::clone(stats);
+        let handle = thread::spawn(move || loop {
+            let done = !matches!(rx.recv_timeout(REFRESH), Err(RecvTimeoutError::Timeout));
+            bar.set_position(stats.completed());
+            if done {
+                break;
+            }
+        });
+
+        progress.stop = Some(tx);
+        progress.handle = Some(handle);
+        progress
+    }
+
+    pub fn inc(&self, n: u64) {
+        self.bar.inc(n);
+    }
+
+    /// Stop following the phase and clear the bar, leaving the phase's own
+    /// summary to say how it went.
+    pub fn finish(mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        self.bar.finish_and_clear();
+    }
+}
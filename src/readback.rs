@@ -0,0 +1,333 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use crate::{cycles, page_filter, retry, PAGE_SIZE};
+use failure::Error;
+use libmanta::moray::MantaObjectShark;
+use moray::client::MorayClient;
+use moray::objects;
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+// Mismatches printed in full; the rest are only counted.
+const MAX_PRINTED: usize = 10;
+
+/// What reading objects back after a phase found.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ReadBack {
+    pub objects: u64,
+    pub missing: u64,
+    pub mismatches: u64,
+    /// Objects whose checksum was compared, which is every object found.
+    pub checksummed: u64,
+    pub corrupt: u64,
+}
+
+impl ReadBack {
+    pub fn ok(&self) -> bool {
+        self.missing == 0 && self.mismatches == 0 && self.corrupt == 0
+    }
+}
+
+/// The objects a phase is about to write, with a checksum of each taken as
+/// it was generated.
+pub struct Expected {
+    values: HashMap<String, Value>,
+    checksums: HashMap<String, u64>,
+}
+
+impl Expected {
+    pub fn new(values: HashMap<String, Value>) -> Expected {
+        let checksums = values
+            .iter()
+            .map(|(key, value)| (key.clone(), checksum(value)))
+            .collect();
+
+        Expected { values, checksums }
+    }
+}
+
+// FNV-1a, 64 bit.
+fn fnv(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn hash_value(hash: u64, value: &Value) -> u64 {
+    match value {
+        Value::Object(map) => {
+            let mut fields: Vec<&String> = map.keys().collect();
+            fields.sort();
+            let hash = fields.into_iter().fold(fnv(hash, b"{"), |hash, field| {
+                hash_value(fnv(fnv(hash, field.as_bytes()), b":"), &map[field])
+            });
+            fnv(hash, b"}")
+        }
+        Value::Array(items) => {
+            let hash = items.iter().fold(fnv(hash, b"["), |hash, item| {
+                fnv(hash_value(hash, item), b",")
+            });
+            fnv(hash, b"]")
+        }
+        other => fnv(hash, other.to_string().as_bytes()),
+    }
+}
+
+/// A checksum of a value's serialized form.  Object fields are taken in
+/// sorted order, so the checksum doesn't depend on the order moray returns
+/// them in.
+pub fn checksum(value: &Value) -> u64 {
+    hash_value(0xcbf2_9ce4_8422_2325, value)
+}
+
+// The top level fields of an object that differ between what was written and
+// what was read back.
+fn differences(expected: &Value, found: &Value) -> Vec<String> {
+    let empty = serde_json::Map::new();
+    let expected = expected.as_object().unwrap_or(&empty);
+    let found = found.as_object().unwrap_or(&empty);
+
+    let mut fields: Vec<String> = expected
+        .keys()
+        .chain(found.keys())
+        .filter(|field| expected.get(*field) != found.get(*field))
+        .cloned()
+        .collect();
+    fields.sort();
+    fields.dedup();
+
+    fields
+}
+
+/// Fetch `config.read_back_sample` of the objects a phase wrote, or all of
+/// them if that is zero, and check that each holds the value it was written
+/// with.  Then check the checksum of every object in the bucket against the
+/// one taken when it was generated.  A phase that made several `cycles` over
+/// the objects left them with a last shark chosen along the way, so that
+/// shark is only checked to be the same on every object.
+pub fn read_back(
+    client: &mut MorayClient,
+    config: &Config,
+    expected: &Expected,
+    cycles: u64,
+) -> Result<ReadBack, Error> {
+    let opts = objects::MethodOptions::default();
+    let stored = fetch_values(client, &config.bucket, &config.key_filter())?;
+    let (expected, checksums) = (&expected.values, &expected.checksums);
+    let mut keys: Vec<&String> = expected.keys().collect();
+    let mut result = ReadBack::default();
+
+    // The last shark of the final cycle, taken from any object in the bucket.
+    let last_shark: Option<MantaObjectShark> = if cycles > 1 {
+        stored
+            .values()
+            .filter_map(|value| value["sharks"].as_array()?.last().cloned())
+            .find_map(|shark| serde_json::from_value(shark).ok())
+    } else {
+        None
+    };
+    let expect = |key: &str| {
+        let mut value = expected[key].clone();
+        if let Some(shark) = &last_shark {
+            cycles::set_last_shark(&mut value, shark);
+        }
+        value
+    };
+
+    if config.read_back_sample > 0 && (config.read_back_sample as usize) < keys.len() {
+        keys = keys
+            .choose_multiple(&mut rand::thread_rng(), config.read_back_sample as usize)
+            .cloned()
+            .collect();
+    }
+
+    println!("Reading back {} of {} objects", keys.len(), expected.len());
+
+    for key in keys.into_iter() {
+        let mut found = None;
+        let fetched = client.get_object(&config.bucket, key, &opts, |mobj| {
+            found = Some(mobj.value.clone());
+            Ok(())
+        });
+        if let Err(e) = fetched {
+            if retry::error_name(&e) != "ObjectNotFoundError" {
+                return Err(e);
+            }
+        }
+        result.objects += 1;
+
+        let found = match found {
+            Some(found) => found,
+            None => {
+                result.missing += 1;
+                if (result.missing + result.mismatches) as usize <= MAX_PRINTED {
+                    eprintln!("{}: not found", key);
+                }
+                continue;
+            }
+        };
+
+        let expected = expect(key);
+        let fields = differences(&expected, &found);
+        if !fields.is_empty() {
+            result.mismatches += 1;
+            if (result.missing + result.mismatches) as usize <= MAX_PRINTED {
+                eprintln!("{}: {} differ", key, fields.join(", "));
+                for field in fields.iter() {
+                    eprintln!("    expected {}: {}", field, expected[field.as_str()]);
+                    eprintln!("    found    {}: {}", field, found[field.as_str()]);
+                }
+            }
+        }
+    }
+
+    // Comparing checksums is cheap enough to cover every object, not just
+    // the sample.
+    for (key, found) in stored.iter() {
+        let want = match checksums.get(key) {
+            Some(_) if last_shark.is_some() => checksum(&expect(key)),
+            Some(want) => *want,
+            None => continue,
+        };
+
+        result.checksummed += 1;
+        if checksum(found) != want {
+            result.corrupt += 1;
+            if result.corrupt as usize <= MAX_PRINTED {
+                eprintln!(
+                    "{}: checksum {:016x}, expected {:016x}",
+                    key,
+                    checksum(found),
+                    want
+                );
+            }
+        }
+    }
+
+    if result.ok() {
+        println!(
+            "All {} objects read back as written, {} checksums matched",
+            result.objects, result.checksummed
+        );
+    } else {
+        println!(
+            "{} of {} objects read back were missing, {} did not match; \
+             {} of {} checksums did not match",
+            result.missing, result.objects, result.mismatches, result.corrupt, result.checksummed
+        );
+    }
+
+    Ok(result)
+}
+
+/// Every object in `bucket` matching `filter`, keyed by key, with the value
+/// moray returns for it.  Paged by `_id`, as `fetch_test_objects` is.
+pub fn fetch_values(
+    client: &mut MorayClient,
+    bucket: &str,
+    filter: &str,
+) -> Result<HashMap<String, Value>, Error> {
+    let mut values = HashMap::new();
+    let mut opts = objects::MethodOptions::default();
+    let mut next_id = 0;
+
+    loop {
+        let mut page_count = 0;
+        opts.limit = Some(PAGE_SIZE);
+
+        client.find_objects(bucket, &page_filter(filter, next_id), &opts, |mobj| {
+            page_count += 1;
+            next_id = next_id.max(mobj._id + 1);
+            values.insert(mobj.key.clone(), mobj.value.clone());
+            Ok(())
+        })?;
+
+        if page_count < PAGE_SIZE {
+            break;
+        }
+    }
+
+    Ok(values)
+}
+
+/// How the bucket's contents after one strategy compare with its contents
+/// after another wrote the same objects.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Consistency {
+    pub objects: u64,
+    /// Objects only found after one of the two.
+    pub missing: u64,
+    pub mismatches: u64,
+}
+
+impl Consistency {
+    pub fn ok(&self) -> bool {
+        self.missing == 0 && self.mismatches == 0
+    }
+}
+
+/// Compare the bucket's contents after the `first` strategy with its contents
+/// after the `second`, printing the objects that differ.
+pub fn compare(
+    first: (&str, &HashMap<String, Value>),
+    second: (&str, &HashMap<String, Value>),
+) -> Consistency {
+    let (first_name, first) = first;
+    let (second_name, second) = second;
+    let mut result = Consistency::default();
+    let mut keys: Vec<&String> = first.keys().chain(second.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    for key in keys.into_iter() {
+        result.objects += 1;
+        let printed = (result.missing + result.mismatches) as usize;
+
+        match (first.get(key), second.get(key)) {
+            (Some(a), Some(b)) => {
+                let fields = differences(a, b);
+                if fields.is_empty() {
+                    continue;
+                }
+                result.mismatches += 1;
+                if printed < MAX_PRINTED {
+                    eprintln!("{}: {} differ", key, fields.join(", "));
+                    for field in fields.iter() {
+                        eprintln!("    {:<10} {}: {}", first_name, field, a[field.as_str()]);
+                        eprintln!("    {:<10} {}: {}", second_name, field, b[field.as_str()]);
+                    }
+                }
+            }
+            (found, _) => {
+                result.missing += 1;
+                if printed < MAX_PRINTED {
+                    let only = if found.is_some() {
+                        first_name
+                    } else {
+                        second_name
+                    };
+                    eprintln!("{}: only found after {}", key, only);
+                }
+            }
+        }
+    }
+
+    if result.ok() {
+        println!(
+            "{} and {} left all {} objects identical",
+            first_name, second_name, result.objects
+        );
+    } else {
+        println!(
+            "{} and {} disagree: {} of {} objects differ, {} found after only one",
+            first_name, second_name, result.mismatches, result.objects, result.missing
+        );
+    }
+
+    result
+}
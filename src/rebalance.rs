@@ -0,0 +1,231 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use crate::pool::Pool;
+use crate::stats::{PhaseReport, PhaseStats};
+use crate::timeseries::Sampler;
+use crate::workers::{self, Context, Queue};
+use crate::{latency_csv, retry, trace, PAGE_SIZE};
+use failure::Error;
+use libmanta::moray::MantaObjectShark;
+use moray::client::MorayClient;
+use moray::objects::{self, BatchPutOp, BatchRequest, Etag};
+use opentelemetry::api::KeyValue;
+use serde_json::Value;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+// One object to move: its key, the etag it was found with, and its value
+// with the shark rewritten.
+type Move = (String, String, Value);
+
+// Point the shark entry on `from` at `to` instead, as the rebalancer does once
+// it has copied the object.  Returns false if the object has no copy on
+// `from`.
+fn rewrite_shark(value: &mut Value, from: &str, to: &MantaObjectShark) -> bool {
+    let sharks = match value["sharks"].as_array_mut() {
+        Some(sharks) => sharks,
+        None => return false,
+    };
+
+    let mut moved = false;
+    for shark in sharks.iter_mut() {
+        if shark["manta_storage_id"] == from {
+            *shark = json!(to);
+            moved = true;
+        }
+    }
+
+    moved
+}
+
+// Page through the test objects and hand those with a copy on `from` to
+// `send` in batches of `config.batch_size`.  Moray can't filter on an entry of
+// the sharks array, so like the rebalancer's scan this checks each object's
+// sharks itself.  Returns the number of objects scanned.
+fn find_moves<F>(
+    client: &mut MorayClient,
+    config: &Config,
+    from: &str,
+    to: &MantaObjectShark,
+    mut send: F,
+) -> Result<u64, Error>
+where
+    F: FnMut(Vec<Move>) -> bool,
+{
+    let filter = config.key_filter();
+    let batch_size = config.batch_size.max(1) as usize;
+    let mut opts = objects::MethodOptions::default();
+    let mut offset = 0;
+    let mut batch = vec![];
+
+    loop {
+        let mut page = vec![];
+        opts.limit = Some(PAGE_SIZE);
+        opts.offset = Some(offset);
+
+        client.find_objects(&config.bucket, &filter, &opts, |mobj| {
+            page.push((mobj.key.clone(), mobj._etag.clone(), mobj.value.clone()));
+            Ok(())
+        })?;
+
+        offset += page.len() as u64;
+        let last = (page.len() as u64) < PAGE_SIZE;
+
+        for (key, etag, mut value) in page.into_iter() {
+            if !rewrite_shark(&mut value, from, to) {
+                continue;
+            }
+            batch.push((key, etag, value));
+            if batch.len() == batch_size && !send(std::mem::take(&mut batch)) {
+                return Ok(offset);
+            }
+        }
+
+        if last {
+            break;
+        }
+    }
+
+    if !batch.is_empty() {
+        send(batch);
+    }
+
+    Ok(offset)
+}
+
+/// Mimic the manta rebalancer evacuating a storage node: scan the test
+/// objects for those with a copy on `from`, point that copy at `to`, and
+/// write them back in batches of `config.batch_size` with puts conditional on
+/// the etag each was found with.  Batches are written by `config.workers`
+/// threads while the scan goes on, and throughput is measured end to end,
+/// from the start of the scan to the last batch written.
+pub fn run_rebalance_test(
+    client: &mut MorayClient,
+    pool: &Pool,
+    config: &Config,
+    from: &str,
+    to: &str,
+) -> Result<PhaseReport, Error> {
+    let batch_size = config.batch_size.max(1);
+    let destination = MantaObjectShark {
+        datacenter: String::from("rebalance"),
+        manta_storage_id: to.to_string(),
+    };
+
+    println!(
+        "Evacuating objects from {} to {} in batches of {}",
+        from, to, batch_size
+    );
+
+    let stats = Arc::new(PhaseStats::new(config, "rebalance"));
+    let (dispatcher, queue) = workers::queue(config, &stats);
+    let shares = (0..config.workers.max(1))
+        .map(|_| Arc::clone(&queue))
+        .collect();
+    let workers = workers::spawn(config, pool, &stats, shares, put_moves);
+    let sampler = Sampler::start(config, &stats);
+    let started = SystemTime::now();
+    let start = workers.start();
+
+    let mut id = 0;
+    let scanned = find_moves(client, config, from, &destination, |batch| {
+        id += 1;
+        dispatcher.send(batch.len() as u64, (id, batch))
+    });
+
+    // Closing the queue tells the workers there is nothing more to write.
+    drop(dispatcher);
+    let result = workers.join();
+    sampler.stop();
+    latency_csv::flush();
+    let scanned = scanned?;
+    result?;
+
+    let elapsed = start.elapsed();
+    println!(
+        "Evacuated {} of {} objects scanned in {} batches: {}ms",
+        stats.objects(),
+        scanned,
+        stats.requests(),
+        elapsed.as_millis()
+    );
+    stats.print_throughput(elapsed);
+    stats.print_latency();
+    stats.print_retries();
+    stats.print_failures();
+
+    let mut report = stats.report(started, elapsed);
+    report.batch_size = Some(batch_size);
+
+    Ok(report)
+}
+
+fn put_moves(ctx: &Context, queue: Queue<(usize, Vec<Move>)>) -> Result<(), Error> {
+    let opts = objects::MethodOptions::default();
+
+    while let Some((start, (id, moves))) = workers::next(ctx, &queue) {
+        let keys: Vec<String> = moves.iter().map(|(key, _, _)| key.clone()).collect();
+        let bytes = moves
+            .iter()
+            .map(|(key, _, value)| (key.len() + value.to_string().len()) as u64)
+            .sum();
+        let batch: Vec<BatchRequest> = moves
+            .into_iter()
+            .map(|(key, etag, value)| {
+                let mut options = objects::MethodOptions::default();
+                options.etag = Etag::Specified(etag);
+
+                BatchRequest::Put(BatchPutOp {
+                    bucket: ctx.config.bucket.clone(),
+                    options,
+                    key,
+                    value,
+                })
+            })
+            .collect();
+
+        let started = SystemTime::now() - start.elapsed();
+        let span = trace::span(
+            "rebalance",
+            vec![
+                KeyValue::new("shard", ctx.config.shard.to_string()),
+                KeyValue::new("batch", id.to_string()),
+                KeyValue::new("size", batch.len().to_string()),
+            ],
+        );
+        let result = ctx.call(|c| {
+            c.batch(&batch, &opts, |_| Ok(()))?;
+            Ok(())
+        });
+        latency_csv::record(
+            ctx.config.shard,
+            "rebalance",
+            &id.to_string(),
+            started,
+            start.elapsed(),
+            &result,
+        );
+        if let Err(e) = &result {
+            trace::set_error(&span, retry::error_name(e));
+        }
+        drop(span);
+
+        // An object changed since the scan read it is left for the next
+        // scan, as the rebalancer would.
+        match result {
+            Ok(()) => ctx
+                .stats
+                .record_request(keys.len() as u64, bytes, start.elapsed()),
+            Err(e) if retry::is_conflict(&e) || ctx.config.continue_on_error => {
+                eprintln!("batch of {}: {}", keys.len(), e);
+                ctx.stats.record_failure(keys, &e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
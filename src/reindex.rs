@@ -0,0 +1,111 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use crate::stats::{PhaseReport, PhaseStats};
+use crate::{bucket_config, latency_csv};
+use failure::Error;
+use moray::buckets;
+use moray::client::MorayClient;
+use moray::objects;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// A field every manta object carries but the test bucket doesn't index,
+/// which the reindex test adds an index for.
+pub static REINDEX_FIELD: &str = "contentLength";
+
+// Moray only reindexes rows written under an older schema version, so every
+// schema change needs a higher version than the last.  Milliseconds since the
+// epoch keep rising across runs.
+fn next_version() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn update_schema(
+    client: &mut MorayClient,
+    config: &Config,
+    index_field: bool,
+) -> Result<(), Error> {
+    let mut schema = bucket_config(config);
+
+    if index_field {
+        schema["index"][REINDEX_FIELD] = json!({ "type": "number" });
+    }
+    schema["options"] = json!({ "version": next_version() });
+
+    client.update_bucket(&config.bucket, schema, buckets::MethodOptions::default())?;
+    Ok(())
+}
+
+/// Add an index on `REINDEX_FIELD` to the bucket, leaving every existing row
+/// to be reindexed.
+pub fn add_index(client: &mut MorayClient, config: &Config) -> Result<(), Error> {
+    println!("Adding an index on {} to {}", REINDEX_FIELD, config.bucket);
+    update_schema(client, config, true)
+}
+
+/// Put the bucket's schema back as it was before `add_index`.
+pub fn remove_index(client: &mut MorayClient, config: &Config) -> Result<(), Error> {
+    println!(
+        "Removing the index on {} from {}",
+        REINDEX_FIELD, config.bucket
+    );
+    update_schema(client, config, false)
+}
+
+// Reindex the bucket `config.reindex_count` rows at a time until moray
+// reports there is nothing left to do.  Each reindexobjects call is a
+// request, and the rows it processed are its objects.
+pub fn run_reindex_test(mut client: MorayClient, config: Config) -> Result<PhaseReport, Error> {
+    let opts = objects::MethodOptions::default();
+    let bucket = config.bucket.as_str();
+    let stats = PhaseStats::new(&config, "reindex");
+
+    println!(
+        "Reindexing {}, {} rows at a time",
+        bucket, config.reindex_count
+    );
+
+    let started = SystemTime::now();
+    let start = Instant::now();
+    loop {
+        let call = Instant::now();
+        let call_started = SystemTime::now();
+        let mut processed = 0;
+
+        let result = client.reindex_objects(bucket, config.reindex_count, &opts, |res| {
+            processed = res["processed"].as_u64().unwrap_or(0);
+            Ok(())
+        });
+        latency_csv::record(
+            config.shard,
+            "reindex",
+            "reindex",
+            call_started,
+            call.elapsed(),
+            &result,
+        );
+        result?;
+
+        if processed == 0 {
+            break;
+        }
+        stats.record_request(processed, 0, call.elapsed());
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "Done reindexing {} rows in {} calls: {}ms",
+        stats.objects(),
+        stats.requests(),
+        elapsed.as_millis()
+    );
+    stats.print_throughput(elapsed);
+    stats.print_latency();
+
+    Ok(stats.report(started, elapsed))
+}
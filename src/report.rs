@@ -0,0 +1,142 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use crate::pool::{self, ConnectionReport};
+use crate::stats::{self, PhaseReport, ValueSizes};
+use crate::timing::{self, StepReport};
+use crate::version::ServerVersion;
+use crate::{error_classes, RunSummary};
+use failure::Error;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::time::SystemTime;
+
+/// Machine readable results of a run, written by `--output`.
+#[derive(Serialize)]
+pub struct Report<'a> {
+    pub started_at: f64,
+    pub finished_at: f64,
+    pub parameters: &'a Config,
+    pub shards: Vec<ShardReport<'a>>,
+    /// Time spent in each step of connecting and sending requests, across
+    /// every shard.
+    pub timing: Vec<StepReport>,
+    pub connections: ConnectionReport,
+    /// Requests given up on and runs that failed, by class of error, across
+    /// every shard.
+    pub error_classes: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize)]
+pub struct ShardReport<'a> {
+    pub shard: u32,
+    pub bucket: &'a str,
+    pub objects: u64,
+    pub seed_ms: Option<u64>,
+    pub value_sizes: Option<&'a ValueSizes>,
+    pub sequential: &'a [PhaseReport],
+    pub batch: &'a [PhaseReport],
+    pub sweep: &'a [PhaseReport],
+    pub find: &'a [PhaseReport],
+    pub get: &'a [PhaseReport],
+    pub delete: &'a [PhaseReport],
+    pub update_objects: &'a [PhaseReport],
+    pub sql: &'a [PhaseReport],
+    pub reindex: &'a [PhaseReport],
+    pub unique: &'a [PhaseReport],
+    pub cas: &'a [PhaseReport],
+    pub consistency: &'a [PhaseReport],
+    pub rebalance: &'a [PhaseReport],
+    pub delete_log: &'a [PhaseReport],
+    pub server_versions: &'a [ServerVersion],
+    /// Why the run against this shard failed, if it did.
+    pub error: Option<String>,
+}
+
+impl<'a> ShardReport<'a> {
+    pub fn new(
+        shard: u32,
+        bucket: &'a str,
+        result: &'a Result<RunSummary, Error>,
+    ) -> ShardReport<'a> {
+        match result {
+            Ok(summary) => ShardReport {
+                shard,
+                bucket,
+                objects: summary.objects,
+                seed_ms: summary.seed.map(|d| d.as_millis() as u64),
+                value_sizes: summary.value_sizes.as_ref(),
+                sequential: &summary.sequential,
+                batch: &summary.batch,
+                sweep: &summary.sweep,
+                find: &summary.find,
+                get: &summary.get,
+                delete: &summary.delete,
+                update_objects: &summary.update_objects,
+                sql: &summary.sql,
+                reindex: &summary.reindex,
+                unique: &summary.unique,
+                cas: &summary.cas,
+                consistency: &summary.consistency,
+                rebalance: &summary.rebalance,
+                delete_log: &summary.delete_log,
+                server_versions: &summary.server_versions,
+                error: None,
+            },
+            Err(e) => ShardReport {
+                shard,
+                bucket,
+                objects: 0,
+                seed_ms: None,
+                value_sizes: None,
+                sequential: &[],
+                batch: &[],
+                sweep: &[],
+                find: &[],
+                get: &[],
+                delete: &[],
+                update_objects: &[],
+                sql: &[],
+                reindex: &[],
+                unique: &[],
+                cas: &[],
+                consistency: &[],
+                rebalance: &[],
+                delete_log: &[],
+                server_versions: &[],
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+/// Write the results of a run that started at `started` to `path` as JSON.
+pub fn write(
+    path: &str,
+    config: &Config,
+    started: SystemTime,
+    results: &[(u32, String, Result<RunSummary, Error>)],
+) -> Result<(), Error> {
+    let report = Report {
+        started_at: stats::unix_time(started),
+        finished_at: stats::unix_time(SystemTime::now()),
+        parameters: config,
+        shards: results
+            .iter()
+            .map(|(shard, bucket, result)| ShardReport::new(*shard, bucket, result))
+            .collect(),
+        timing: timing::report(),
+        connections: pool::connection_report(),
+        error_classes: error_classes(results),
+    };
+
+    let writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(writer, &report)?;
+    println!("Wrote results to {}", path);
+
+    Ok(())
+}
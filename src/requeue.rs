@@ -0,0 +1,151 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! Another go at the keys a phase failed to write, once the phase is over.
+//!
+//! A bulk updater can't leave objects behind because the shard had a bad
+//! minute, so with `--requeue-failed` the keys of every request that failed
+//! for good are queued up as the phase goes and written again after it, the
+//! same way the phase wrote them, before the objects are read back.  The
+//! phase's own numbers are left as they were; what the second attempt came
+//! to is reported on its own.
+
+use crate::batch::PutBatches;
+use crate::config::Config;
+use crate::pool::{self, Pool};
+use crate::retry::{self, RetryPolicy};
+use crate::stats::PhaseStats;
+use crate::{batch_keys, request_options};
+use failure::Error;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// What writing a phase's failed keys again came to.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RequeueReport {
+    pub keys: u64,
+    /// Keys written the second time round.
+    pub persisted: u64,
+    /// Keys that failed again and were not written.
+    pub failed: u64,
+    /// Number of failed requests for each kind of error.
+    pub errors: BTreeMap<String, u64>,
+}
+
+/// The values a phase is writing, kept to write its failed keys again.
+pub struct Requeue {
+    values: HashMap<String, Value>,
+    deletes: HashSet<String>,
+}
+
+impl Requeue {
+    /// Keep `objects` and `deletes` if `config.requeue_failed` is set.
+    pub fn new(
+        config: &Config,
+        objects: &[(String, Value)],
+        deletes: &HashSet<String>,
+    ) -> Option<Requeue> {
+        if !config.requeue_failed {
+            return None;
+        }
+
+        Some(Requeue {
+            values: objects.iter().cloned().collect(),
+            deletes: deletes.clone(),
+        })
+    }
+
+    /// Write the keys that failed during the phase `stats` belongs to again,
+    /// with putobject and delobject requests if `batch_size` is zero and in
+    /// batches of that many objects otherwise.  Returns None if nothing
+    /// failed.
+    pub fn run(
+        &self,
+        pool: &Pool,
+        config: &Config,
+        stats: &PhaseStats,
+        batch_size: u32,
+    ) -> Result<Option<RequeueReport>, Error> {
+        let mut keys = stats.failed_keys();
+        keys.sort();
+        keys.dedup();
+        // Only the phase's own keys can be written again.
+        keys.retain(|key| self.values.contains_key(key) || self.deletes.contains(key));
+        if keys.is_empty() {
+            return Ok(None);
+        }
+
+        println!("Writing the {} failed keys again", keys.len());
+        // A key to delete is sent without a value if the phase didn't have
+        // one for it.
+        let writes: Vec<(String, Value)> = keys
+            .iter()
+            .map(|key| {
+                let value = self.values.get(key).cloned().unwrap_or(Value::Null);
+                (key.clone(), value)
+            })
+            .collect();
+        let mut report = RequeueReport {
+            keys: keys.len() as u64,
+            ..RequeueReport::default()
+        };
+        let requeue_stats = PhaseStats::new(config, "requeue");
+        let policy = RetryPolicy::from_config(config);
+        let client = pool.get();
+
+        let mut record = |keys: Vec<String>, result: Result<(), Error>| match result {
+            Ok(()) => report.persisted += keys.len() as u64,
+            Err(e) => {
+                eprintln!("{} keys failed again: {}", keys.len(), e);
+                report.failed += keys.len() as u64;
+                *report.errors.entry(retry::error_name(&e)).or_insert(0) += 1;
+            }
+        };
+
+        if batch_size == 0 {
+            for (key, value) in writes.into_iter() {
+                let delete = self.deletes.contains(&key);
+                let result = retry::retry(&policy, &requeue_stats, || {
+                    let (_, opts) = request_options();
+                    pool::with_reconnect(&client, config, |c| {
+                        if delete {
+                            c.delete_object(&config.bucket, &key, &opts, |_| Ok(()))
+                                .map_err(Error::from)
+                        } else {
+                            c.put_object(&config.bucket, &key, value.clone(), &opts, |_| Ok(()))
+                                .map_err(Error::from)
+                        }
+                    })
+                });
+                record(vec![key], result);
+            }
+        } else {
+            let deletes = keys
+                .iter()
+                .filter(|key| self.deletes.contains(*key))
+                .cloned()
+                .collect();
+            let batches = PutBatches::new(&config.bucket, writes.into_iter(), batch_size)
+                .with_deletes(deletes)
+                .with_companions(config.companion_buckets.clone())
+                .with_max_bytes(config.max_batch_bytes);
+            for batch in batches {
+                let result = retry::retry(&policy, &requeue_stats, || {
+                    let (_, opts) = request_options();
+                    pool::with_reconnect(&client, config, |c| {
+                        c.batch(&batch, &opts, |_| Ok(())).map_err(Error::from)
+                    })
+                });
+                record(batch_keys(&batch, &config.bucket), result);
+            }
+        }
+
+        println!(
+            "{} of {} failed keys were written the second time, {} failed for good",
+            report.persisted, report.keys, report.failed
+        );
+        Ok(Some(report))
+    }
+}
@@ -0,0 +1,102 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! A process wide cache of SRV and address lookups.
+//!
+//! The `resolve` crate doesn't hand back the TTL of the records it returns, so
+//! every entry is kept for the same configurable TTL instead.  Entries are
+//! dropped early with `invalidate` when a connection to a cached address is
+//! lost, so that the next connection attempt sees fresh records.
+
+use crate::timing::{self, Step};
+use failure::Error;
+use lazy_static::lazy_static;
+use resolve::record::Srv;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+struct Entry<T> {
+    value: T,
+    expires: Instant,
+}
+
+struct Cache {
+    ttl: Duration,
+    srv: HashMap<String, Entry<Vec<Srv>>>,
+    hosts: HashMap<String, Entry<IpAddr>>,
+}
+
+lazy_static! {
+    static ref CACHE: Mutex<Cache> = Mutex::new(Cache {
+        ttl: DEFAULT_TTL,
+        srv: HashMap::new(),
+        hosts: HashMap::new(),
+    });
+}
+
+pub fn set_ttl(ttl: Duration) {
+    CACHE.lock().unwrap().ttl = ttl;
+}
+
+// Forget every cached lookup.
+pub fn invalidate() {
+    let mut cache = CACHE.lock().unwrap();
+    cache.srv.clear();
+    cache.hosts.clear();
+}
+
+// Return the cached SRV records for `query`, calling `resolve` to look them up
+// if they aren't cached or have expired.
+pub fn srv_records<F>(query: &str, resolve: F) -> Result<Vec<Srv>, Error>
+where
+    F: FnOnce() -> Result<Vec<Srv>, Error>,
+{
+    if let Some(entry) = CACHE.lock().unwrap().srv.get(query) {
+        if entry.expires > Instant::now() {
+            return Ok(entry.value.clone());
+        }
+    }
+
+    // Don't hold the lock across the lookup itself.
+    let records = timing::time(Step::Dns, resolve)?;
+    let mut cache = CACHE.lock().unwrap();
+    let expires = Instant::now() + cache.ttl;
+
+    cache.srv.insert(
+        query.to_string(),
+        Entry {
+            value: records.clone(),
+            expires,
+        },
+    );
+
+    Ok(records)
+}
+
+// Return the cached address for `host`, calling `resolve` to look it up if it
+// isn't cached or has expired.
+pub fn host_ip<F>(host: &str, resolve: F) -> Result<IpAddr, Error>
+where
+    F: FnOnce() -> Result<IpAddr, Error>,
+{
+    if let Some(entry) = CACHE.lock().unwrap().hosts.get(host) {
+        if entry.expires > Instant::now() {
+            return Ok(entry.value);
+        }
+    }
+
+    let ip = timing::time(Step::Dns, resolve)?;
+    let mut cache = CACHE.lock().unwrap();
+    let expires = Instant::now() + cache.ttl;
+
+    cache
+        .hosts
+        .insert(host.to_string(), Entry { value: ip, expires });
+
+    Ok(ip)
+}
@@ -0,0 +1,48 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! What moray says about a request in its response, beyond the result.
+//!
+//! Moray versions that report how long the server spent handling a batch do
+//! so in the records of the response, in milliseconds, along with the request
+//! id they logged it under.  Older servers send neither, in which case only
+//! the client's view of the request is available.
+
+use serde_json::Value;
+use std::time::Duration;
+
+// Fields the server's handler time may be reported under, in milliseconds.
+static LATENCY_FIELDS: &[&str] = &["latency", "_latency", "elapsed"];
+static REQ_ID_FIELDS: &[&str] = &["req_id", "_req_id"];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ServerInfo {
+    pub req_id: Option<String>,
+    pub latency: Option<Duration>,
+}
+
+impl ServerInfo {
+    /// Gather whatever the server reported in the records of a response.  A
+    /// later record's fields take the place of an earlier one's.
+    pub fn from_records(records: &[Value]) -> ServerInfo {
+        let mut info = ServerInfo::default();
+
+        for record in records.iter() {
+            if let Some(req_id) = REQ_ID_FIELDS
+                .iter()
+                .find_map(|field| record[*field].as_str())
+            {
+                info.req_id = Some(req_id.to_string());
+            }
+            if let Some(ms) = LATENCY_FIELDS
+                .iter()
+                .find_map(|field| record[*field].as_f64())
+            {
+                info.latency = Some(Duration::from_micros((ms * 1000.0) as u64));
+            }
+        }
+
+        info
+    }
+}
@@ -0,0 +1,182 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use crate::logging;
+use crate::stats::PhaseStats;
+use crate::InternalError;
+use failure::Error;
+use rand::Rng;
+use slog::debug;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+// Moray errors that are worth retrying: the server was too busy or had lost
+// its database connection, neither of which says anything about the request.
+static TRANSIENT_ERRORS: &[&str] = &[
+    "OverloadedError",
+    "ThrottledError",
+    "NoDatabasePeersError",
+    "ConnectionClosedError",
+    "ConnectionTimeoutError",
+    "QueryTimeoutError",
+    "PoolFullError",
+];
+
+// Moray errors that reject a request because of what is already stored rather
+// than because anything is wrong with the shard.
+static CONFLICT_ERRORS: &[&str] = &["UniqueAttributeError", "EtagConflictError"];
+
+// Moray errors that say the shard has more work than it can take.
+static OVERLOAD_ERRORS: &[&str] = &["OverloadedError", "ThrottledError"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorClass {
+    /// The request may succeed if tried again.
+    Transient,
+    /// The request will fail no matter how often it is retried, e.g.
+    /// EtagConflictError or a schema error.
+    Permanent,
+}
+
+pub fn classify(e: &Error) -> ErrorClass {
+    if is_connection_error(e) {
+        return ErrorClass::Transient;
+    }
+    // Reconnecting gave up, or the request went unanswered for
+    // --op-timeout-ms or was turned away while the shard was overloaded, none
+    // of which says the next try will fail too.
+    if matches!(
+        e.downcast_ref::<InternalError>(),
+        Some(InternalError::Connect { .. })
+            | Some(InternalError::Timeout(_))
+            | Some(InternalError::Overload(_))
+    ) {
+        return ErrorClass::Transient;
+    }
+
+    let msg = e.to_string();
+    if TRANSIENT_ERRORS.iter().any(|name| msg.contains(name)) {
+        ErrorClass::Transient
+    } else {
+        ErrorClass::Permanent
+    }
+}
+
+// A short name for the kind of error, e.g. "OverloadedError", used to group
+// failures when reporting them.
+pub fn error_name(e: &Error) -> String {
+    let msg = e.to_string();
+    let name = msg
+        .split(|c: char| !c.is_alphanumeric())
+        .find(|word| word.len() > "Error".len() && word.ends_with("Error"));
+
+    match (name, e.downcast_ref::<io::Error>()) {
+        (Some(name), _) => name.to_string(),
+        (None, Some(ioe)) => format!("{:?}", ioe.kind()),
+        (None, None) => match e.downcast_ref::<InternalError>() {
+            Some(internal) => internal.class().to_string(),
+            None => String::from("UnknownError"),
+        },
+    }
+}
+
+/// Whether the request conflicted with an existing object, e.g. on a unique
+/// index or a conditional put.
+pub fn is_conflict(e: &Error) -> bool {
+    let msg = e.to_string();
+    CONFLICT_ERRORS.iter().any(|name| msg.contains(name))
+}
+
+/// Whether moray turned the request away because it was overloaded.
+pub fn is_overload(e: &Error) -> bool {
+    let msg = e.to_string();
+    OVERLOAD_ERRORS.iter().any(|name| msg.contains(name))
+}
+
+pub fn is_connection_error(e: &Error) -> bool {
+    match e.downcast_ref::<io::Error>() {
+        Some(ioe) => matches!(
+            ioe.kind(),
+            io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof
+        ),
+        None => false,
+    }
+}
+
+/// How transient failures are retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Total attempts per operation, including the first.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Up to this fraction of each delay is randomly removed so that workers
+    /// which failed together don't retry together.
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &Config) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: config.max_attempts.max(1),
+            base_delay: Duration::from_millis(config.retry_base_delay_ms),
+            max_delay: Duration::from_millis(config.retry_max_delay_ms),
+            jitter: config.retry_jitter.clamp(0.0, 1.0),
+        }
+    }
+
+    // The delay before retry number `retry`, starting from 1.  The delay
+    // doubles with every retry up to `max_delay`.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 1u32 << retry.saturating_sub(1).min(16);
+        let delay = self
+            .base_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+
+        if self.jitter > 0.0 {
+            let cut = rand::thread_rng().gen_range(0.0, self.jitter);
+            delay.mul_f64(1.0 - cut)
+        } else {
+            delay
+        }
+    }
+}
+
+// Run `op`, retrying transient failures according to the policy.  Every retry
+// is recorded in `stats`.
+pub fn retry<T, F>(policy: &RetryPolicy, stats: &PhaseStats, mut op: F) -> Result<T, Error>
+where
+    F: FnMut() -> Result<T, Error>,
+{
+    let mut attempt = 1;
+
+    loop {
+        match op() {
+            Err(ref e) if attempt < policy.max_attempts && classify(e) == ErrorClass::Transient => {
+                let delay = policy.delay(attempt);
+                debug!(
+                    logging::logger(),
+                    "retrying transient failure";
+                    "phase" => stats.phase,
+                    "attempt" => attempt,
+                    "delay_ms" => delay.as_millis() as u64,
+                    "error" => %e
+                );
+                stats.record_retry(delay);
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
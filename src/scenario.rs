@@ -0,0 +1,226 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use crate::{shutdown, BenchRunner, InternalError, RunSummary};
+use failure::Error;
+use libmanta::moray::MantaObject;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::Instant;
+
+/// An ordered list of phases to run against the test bucket, so that the same
+/// run can be repeated against different moray builds.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub phases: Vec<Step>,
+}
+
+/// One phase of a scenario.  Any other fields are settings from the config
+/// file, e.g. `"batch_size": 100`, that apply to this phase alone.
+#[derive(Debug, Deserialize)]
+pub struct Step {
+    pub phase: Phase,
+    #[serde(flatten)]
+    pub params: Map<String, Value>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    Seed,
+    SequentialUpdate,
+    BatchUpdate,
+    Comparison,
+    Find,
+    Get,
+    Delete,
+    UpdateObjects,
+    Rebalance,
+    DeleteLog,
+    Verify,
+    /// Takes a `drop_bucket` parameter, as `cleanup --drop-bucket` does.
+    Cleanup,
+}
+
+impl Phase {
+    // Whether the phase works on the test objects, which are fetched from
+    // the bucket unless an earlier phase seeded them.
+    fn needs_objects(self) -> bool {
+        match self {
+            Phase::Seed | Phase::Verify | Phase::Cleanup => false,
+            _ => true,
+        }
+    }
+}
+
+impl Scenario {
+    /// Read a scenario from a JSON file, or a TOML one if its name ends in
+    /// `.toml`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Scenario, Error> {
+        let contents = fs::read_to_string(&path)?;
+
+        if path.as_ref().extension().map_or(false, |ext| ext == "toml") {
+            toml::from_str(&contents).map_err(Error::from)
+        } else {
+            serde_json::from_str(&contents).map_err(Error::from)
+        }
+    }
+}
+
+// The config with a step's parameters applied, going through the config's
+// serialized form so that each parameter is read just as in the config file.
+pub(crate) fn apply(config: &Config, params: &Map<String, Value>) -> Result<Config, Error> {
+    let mut value = serde_json::to_value(config)?;
+
+    for (name, param) in params.iter() {
+        match value.get_mut(name) {
+            Some(setting) => *setting = param.clone(),
+            None => {
+                let message = format!("unknown parameter \"{}\"", name);
+                return Err(InternalError::InvalidScenario(message).into());
+            }
+        }
+    }
+
+    serde_json::from_value(value).map_err(Error::from)
+}
+
+/// Run each phase of `scenario` in turn, stopping at the first that fails,
+/// and gather their results as a bench run would.
+pub fn run(runner: &mut BenchRunner, scenario: &Scenario) -> Result<RunSummary, Error> {
+    let original = runner.config.clone();
+    let result = run_phases(runner, scenario);
+
+    // A phase that changed the number of workers sized the pool for them.
+    if runner.config.workers != original.workers
+        || runner.config.connections != original.connections
+    {
+        runner.pool = None;
+    }
+    runner.config = original;
+
+    result
+}
+
+fn run_phases(runner: &mut BenchRunner, scenario: &Scenario) -> Result<RunSummary, Error> {
+    let original = runner.config.clone();
+    let mut test_objects: Option<HashMap<String, MantaObject>> = None;
+    let mut summary = RunSummary::default();
+
+    if let Some(name) = &scenario.name {
+        println!("Running scenario {}", name);
+    }
+
+    for (i, step) in scenario.phases.iter().enumerate() {
+        if shutdown::requested() {
+            break;
+        }
+        let pass = i as u32 + 1;
+        let mut params = step.params.clone();
+        let drop_bucket = params
+            .remove("drop_bucket")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+
+        let config = apply(&original, &params)?;
+        if config.workers != runner.config.workers
+            || config.connections != runner.config.connections
+            || config.connections_per_worker != runner.config.connections_per_worker
+        {
+            runner.pool = None;
+        }
+        runner.config = config;
+
+        println!(
+            "\n ==== scenario phase {} of {}: {:?} ====",
+            pass,
+            scenario.phases.len(),
+            step.phase
+        );
+
+        if step.phase.needs_objects() && test_objects.is_none() {
+            println!("Fetching test objects");
+            test_objects = Some(runner.fetch_objects()?);
+        }
+        let objects = test_objects.as_ref();
+
+        match step.phase {
+            Phase::Seed => {
+                let start = Instant::now();
+                let sizes = runner.seed()?;
+                summary.seed = Some(start.elapsed());
+                summary.objects = u64::from(runner.config.objects);
+                summary.value_sizes = Some(sizes);
+                // The next phase fetches the objects as seeded.
+                test_objects = None;
+            }
+            Phase::SequentialUpdate => {
+                let objects = objects.expect("objects fetched");
+                summary
+                    .sequential
+                    .extend(runner.run_sequential_pass(objects, pass)?);
+            }
+            Phase::BatchUpdate => {
+                let objects = objects.expect("objects fetched");
+                summary.batch.extend(runner.run_batch_pass(objects, pass)?);
+            }
+            Phase::Comparison => {
+                let comparison = runner.run_comparison(objects.expect("objects fetched"))?;
+                summary.sequential.extend(comparison.sequential);
+                summary.batch.extend(comparison.batch);
+            }
+            Phase::Find => {
+                let objects = objects.expect("objects fetched");
+                summary.find.push(runner.run_find(objects)?);
+            }
+            Phase::Get => {
+                let objects = objects.expect("objects fetched");
+                summary.get.extend(runner.run_get(objects)?);
+            }
+            Phase::Delete => {
+                let objects = objects.expect("objects fetched");
+                summary
+                    .delete
+                    .extend(runner.run_delete_comparison(objects)?);
+            }
+            Phase::UpdateObjects => {
+                let objects = objects.expect("objects fetched");
+                summary
+                    .update_objects
+                    .push(runner.run_update_objects(objects)?);
+            }
+            Phase::Rebalance => {
+                let objects = objects.expect("objects fetched");
+                summary.rebalance.push(runner.run_rebalance(objects)?);
+            }
+            Phase::DeleteLog => {
+                let objects = objects.expect("objects fetched");
+                summary.delete_log.extend(runner.run_delete_log(objects)?);
+            }
+            Phase::Verify => runner.verify()?,
+            Phase::Cleanup => {
+                runner.cleanup(drop_bucket)?;
+                test_objects = None;
+            }
+        }
+    }
+
+    if summary.objects == 0 {
+        if let Some(objects) = &test_objects {
+            summary.objects = objects.len() as u64;
+        }
+    }
+    if !summary.sequential.is_empty() && !summary.batch.is_empty() {
+        summary.print_statistics();
+    }
+
+    Ok(summary)
+}
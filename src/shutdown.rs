@@ -0,0 +1,59 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use lazy_static::lazy_static;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref ABORTED: Mutex<Option<String>> = Mutex::new(None);
+}
+
+// The exit status of a process killed by SIGINT.
+const INTERRUPTED_STATUS: i32 = 130;
+
+/// Handle SIGINT and SIGTERM by asking the run to stop: workers finish the
+/// requests they have in flight but start no more, and each phase reports
+/// what it got through.  A second signal exits at once.
+pub fn install() -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(request)
+}
+
+/// Ask the run to stop as SIGINT does, exiting at once if it already was.
+pub fn request() {
+    if REQUESTED.swap(true, Ordering::SeqCst) {
+        eprintln!("\nInterrupted again, exiting now");
+        process::exit(INTERRUPTED_STATUS);
+    }
+    eprintln!("\nStopping once the requests in flight finish, interrupt again to exit now");
+}
+
+/// Whether a stop has been asked for.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Ask the run to stop as a signal would, because of `reason`.  Only the first
+/// reason is kept.
+pub fn abort(reason: String) {
+    let mut aborted = ABORTED.lock().unwrap();
+    if aborted.is_none() {
+        eprintln!("\nStopping once the requests in flight finish: {}", reason);
+        *aborted = Some(reason);
+    }
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Why the run was aborted, if it was.
+pub fn aborted() -> Option<String> {
+    ABORTED.lock().unwrap().clone()
+}
+
+/// The exit status for a run that was stopped early.
+pub fn exit_status() -> i32 {
+    INTERRUPTED_STATUS
+}
@@ -0,0 +1,212 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! Mann-Whitney U test for whether two sets of measurements, e.g. the
+//! throughput of every sequential and every batch run, differ by more than
+//! noise.  It makes no assumption about how the measurements are distributed,
+//! which suits a handful of runs against a live shard.
+
+use serde::Serialize;
+
+/// Results above this many measurements in total use the normal
+/// approximation rather than the exact distribution of U.
+const EXACT_LIMIT: usize = 20;
+
+/// Significance level used to call a difference significant.
+pub const ALPHA: f64 = 0.05;
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct MannWhitney {
+    /// The smaller of the two U statistics.
+    pub u: f64,
+    /// Two-sided p-value.
+    pub p: f64,
+    /// Whether `p` comes from the exact distribution of U rather than the
+    /// normal approximation.
+    pub exact: bool,
+}
+
+impl MannWhitney {
+    pub fn significant(&self) -> bool {
+        self.p < ALPHA
+    }
+}
+
+/// Compare two sets of measurements.  Each needs at least two measurements.
+pub fn mann_whitney(a: &[f64], b: &[f64]) -> Option<MannWhitney> {
+    if a.len() < 2 || b.len() < 2 {
+        return None;
+    }
+
+    let (n1, n2) = (a.len(), b.len());
+    let n = n1 + n2;
+
+    let mut all: Vec<(f64, bool)> = a
+        .iter()
+        .map(|&x| (x, true))
+        .chain(b.iter().map(|&x| (x, false)))
+        .collect();
+    all.sort_by(|x, y| x.0.partial_cmp(&y.0).unwrap_or(std::cmp::Ordering::Equal));
+
+    // Tied measurements all get the mean of the ranks they span.
+    let mut rank_sum_a = 0.0;
+    let mut tie_term = 0.0;
+    let mut i = 0;
+    while i < n {
+        let mut j = i;
+        while j + 1 < n && all[j + 1].0 == all[i].0 {
+            j += 1;
+        }
+
+        let rank = (i + j) as f64 / 2.0 + 1.0;
+        let ties = (j - i + 1) as f64;
+        tie_term += ties.powi(3) - ties;
+        rank_sum_a += rank * all[i..=j].iter().filter(|(_, in_a)| *in_a).count() as f64;
+
+        i = j + 1;
+    }
+
+    let u1 = rank_sum_a - (n1 * (n1 + 1)) as f64 / 2.0;
+    let u2 = (n1 * n2) as f64 - u1;
+    let u = u1.min(u2);
+
+    if n <= EXACT_LIMIT && tie_term == 0.0 {
+        let p = (2.0 * exact_cdf(n1, n2, u as usize)).min(1.0);
+        return Some(MannWhitney { u, p, exact: true });
+    }
+
+    let (n1, n2, n) = (n1 as f64, n2 as f64, n as f64);
+    let mean = n1 * n2 / 2.0;
+    let variance = n1 * n2 / 12.0 * ((n + 1.0) - tie_term / (n * (n - 1.0)));
+    if variance == 0.0 {
+        return Some(MannWhitney {
+            u,
+            p: 1.0,
+            exact: false,
+        });
+    }
+
+    // Continuity correction, since U only takes whole (or half) values.
+    let z = ((mean - u).abs() - 0.5).max(0.0) / variance.sqrt();
+    let p = (2.0 * (1.0 - normal_cdf(z))).min(1.0);
+
+    Some(MannWhitney { u, p, exact: false })
+}
+
+// P(U <= u) when there are no ties, counting the orderings of the two samples
+// that give each value of U.
+fn exact_cdf(n1: usize, n2: usize, u: usize) -> f64 {
+    let max_u = n1 * n2;
+
+    // counts[i][j][k] is the number of orderings of i and j measurements
+    // that give U = k.
+    let mut counts = vec![vec![vec![0.0f64; max_u + 1]; n2 + 1]; n1 + 1];
+    for i in 0..=n1 {
+        for j in 0..=n2 {
+            if i == 0 || j == 0 {
+                counts[i][j][0] = 1.0;
+                continue;
+            }
+            for k in 0..=i * j {
+                let mut c = counts[i][j - 1][k];
+                if k >= j {
+                    c += counts[i - 1][j][k - j];
+                }
+                counts[i][j][k] = c;
+            }
+        }
+    }
+
+    let total: f64 = counts[n1][n2].iter().sum();
+    counts[n1][n2][..=u.min(max_u)].iter().sum::<f64>() / total
+}
+
+// Standard normal CDF, using the Abramowitz and Stegun approximation of erf
+// (7.1.26), which is good to about 1e-7.
+fn normal_cdf(z: f64) -> f64 {
+    let x = z.abs() / std::f64::consts::SQRT_2;
+    let t = 1.0 / (1.0 + 0.327_591_1 * x);
+    let poly = t
+        * (0.254_829_592
+            + t * (-0.284_496_736
+                + t * (1.421_413_741 + t * (-1.453_152_027 + t * 1.061_405_429))));
+    let erf = 1.0 - poly * (-x * x).exp();
+
+    if z >= 0.0 {
+        (1.0 + erf) / 2.0
+    } else {
+        (1.0 - erf) / 2.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // p-values are checked against R's wilcox.test, which uses the same
+    // continuity correction when there are ties.
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "{} isn't close to {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn exact_small_samples() {
+        // wilcox.test(c(1, 2, 3), c(4, 5, 6)): W = 0, p-value = 0.1
+        let result = mann_whitney(&[1.0, 2.0, 3.0], &[4.0, 5.0, 6.0]).unwrap();
+        assert!(result.exact);
+        assert_eq!(result.u, 0.0);
+        assert_close(result.p, 0.1);
+        assert!(!result.significant());
+
+        // wilcox.test(c(1, 2, 3, 4), c(5, 6, 7, 8)): W = 0, p-value = 0.02857
+        let result = mann_whitney(&[1.0, 2.0, 3.0, 4.0], &[5.0, 6.0, 7.0, 8.0]).unwrap();
+        assert!(result.exact);
+        assert_close(result.p, 2.0 / 70.0);
+        assert!(result.significant());
+    }
+
+    #[test]
+    fn ties_use_normal_approximation() {
+        // wilcox.test(c(1, 2, 2, 3, 4), c(2, 5, 6, 7, 8)): W = 3,
+        // p-value = 0.05701
+        let result = mann_whitney(&[1.0, 2.0, 2.0, 3.0, 4.0], &[2.0, 5.0, 6.0, 7.0, 8.0]).unwrap();
+        assert!(!result.exact);
+        assert_eq!(result.u, 3.0);
+        assert_close(result.p, 0.05701);
+    }
+
+    #[test]
+    fn identical_samples() {
+        let result = mann_whitney(&[1.0, 2.0, 3.0], &[1.0, 2.0, 3.0]).unwrap();
+        assert_close(result.p, 1.0);
+
+        // Every measurement tied leaves U no variance at all.
+        let result = mann_whitney(&[5.0, 5.0], &[5.0, 5.0]).unwrap();
+        assert_eq!(result.p, 1.0);
+    }
+
+    #[test]
+    fn too_few_measurements() {
+        assert!(mann_whitney(&[1.0], &[2.0, 3.0]).is_none());
+        assert!(mann_whitney(&[1.0, 2.0], &[]).is_none());
+    }
+
+    #[test]
+    fn exact_cdf_sums_to_one() {
+        assert_close(exact_cdf(3, 3, 0), 1.0 / 20.0);
+        assert_close(exact_cdf(3, 3, 9), 1.0);
+    }
+
+    #[test]
+    fn normal_cdf_values() {
+        assert_close(normal_cdf(0.0), 0.5);
+        assert_close(normal_cdf(1.959_964), 0.975);
+        assert_close(normal_cdf(-1.959_964), 0.025);
+    }
+}
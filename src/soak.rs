@@ -0,0 +1,218 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use crate::stats;
+use failure::Error;
+use hdrhistogram::Histogram;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+// What a phase on a shard has done since the last interim report.
+struct Window {
+    requests: u64,
+    objects: u64,
+    failures: u64,
+    // Latency of each successful request, in microseconds.
+    latency: Histogram<u64>,
+}
+
+impl Window {
+    fn new() -> Window {
+        Window {
+            requests: 0,
+            objects: 0,
+            failures: 0,
+            latency: Histogram::new(3).expect("latency histogram"),
+        }
+    }
+}
+
+type Windows = BTreeMap<(u32, &'static str), Window>;
+
+lazy_static! {
+    // None unless a soak test is running.
+    static ref WINDOWS: Mutex<Option<Windows>> = Mutex::new(None);
+}
+
+/// Count a successful request towards the current interim report.
+pub fn record(shard: u32, phase: &'static str, objects: u64, latency: Duration) {
+    with_window(shard, phase, |window| {
+        window.requests += 1;
+        window.objects += objects;
+        window.latency.saturating_record(latency.as_micros() as u64);
+    });
+}
+
+/// Count a request that failed for good towards the current interim report.
+pub fn record_failure(shard: u32, phase: &'static str) {
+    with_window(shard, phase, |window| window.failures += 1);
+}
+
+fn with_window<F: FnOnce(&mut Window)>(shard: u32, phase: &'static str, f: F) {
+    if let Some(windows) = WINDOWS.lock().unwrap().as_mut() {
+        f(windows.entry((shard, phase)).or_insert_with(Window::new));
+    }
+}
+
+/// What one phase on one shard did during an interval of a soak test.
+#[derive(Debug, Serialize)]
+pub struct Interim {
+    /// Seconds since the epoch at the end of the interval.
+    pub time: f64,
+    /// Time since the soak test started.
+    pub elapsed_secs: u64,
+    pub shard: u32,
+    pub phase: &'static str,
+    pub requests: u64,
+    pub objects: u64,
+    pub failures: u64,
+    pub objects_per_sec: f64,
+    pub p50_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+/// Prints, and if `config.soak_report` is set writes as JSON lines, what
+/// every phase did every `config.soak_report_interval_secs` of a soak test.
+/// Each report starts afresh, so slow degradation over hours shows up as a
+/// trend rather than being averaged away.
+pub struct Reporter {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Reporter {
+    /// Start reporting if `config.soak_secs` is set.
+    pub fn start(config: &Config) -> Result<Reporter, Error> {
+        if config.soak_secs == 0 {
+            return Ok(Reporter {
+                stop: None,
+                handle: None,
+            });
+        }
+
+        let mut writer = match &config.soak_report {
+            Some(path) => Some(BufWriter::new(File::create(path)?)),
+            None => None,
+        };
+        let interval = Duration::from_secs(config.soak_report_interval_secs.max(1));
+        let (tx, rx) = mpsc::channel::<()>();
+        *WINDOWS.lock().unwrap() = Some(Windows::new());
+
+        let handle = thread::spawn(move || {
+            let start = Instant::now();
+            let mut last = start;
+
+            loop {
+                let done = !matches!(rx.recv_timeout(interval), Err(RecvTimeoutError::Timeout));
+
+                let reports = take_reports(start, last.elapsed());
+                last = Instant::now();
+                print_reports(&reports);
+                if let Some(writer) = writer.as_mut() {
+                    if let Err(e) = write_reports(writer, &reports) {
+                        eprintln!("Failed to write interim report: {}", e);
+                    }
+                }
+
+                if done {
+                    break;
+                }
+            }
+        });
+
+        Ok(Reporter {
+            stop: Some(tx),
+            handle: Some(handle),
+        })
+    }
+
+    /// Report on the time since the last report and stop.
+    pub fn stop(mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        *WINDOWS.lock().unwrap() = None;
+    }
+}
+
+// Turn the windows into reports covering `interval`, and start new ones.
+fn take_reports(start: Instant, interval: Duration) -> Vec<Interim> {
+    let mut windows = WINDOWS.lock().unwrap();
+    let windows = match windows.as_mut() {
+        Some(windows) => windows,
+        None => return vec![],
+    };
+    let time = stats::unix_time(SystemTime::now());
+    let secs = interval.as_secs_f64();
+
+    let reports = windows
+        .iter()
+        .map(|(&(shard, phase), window)| Interim {
+            time,
+            elapsed_secs: start.elapsed().as_secs(),
+            shard,
+            phase,
+            requests: window.requests,
+            objects: window.objects,
+            failures: window.failures,
+            objects_per_sec: if secs > 0.0 {
+                window.objects as f64 / secs
+            } else {
+                0.0
+            },
+            p50_us: window.latency.value_at_percentile(50.0),
+            p99_us: window.latency.value_at_percentile(99.0),
+            max_us: window.latency.max(),
+        })
+        .collect();
+
+    windows.clear();
+    reports
+}
+
+fn print_reports(reports: &[Interim]) {
+    let ms = |us: u64| us as f64 / 1000.0;
+
+    match reports.first() {
+        Some(report) => println!("\n ==== interim report at {}s ====", report.elapsed_secs),
+        None => return,
+    }
+    println!(
+        "{:>6} {:>11} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "shard", "phase", "obj/s", "p50 ms", "p99 ms", "max ms", "failures"
+    );
+
+    for report in reports.iter() {
+        println!(
+            "{:>6} {:>11} {:>10.1} {:>10.1} {:>10.1} {:>10.1} {:>10}",
+            report.shard,
+            report.phase,
+            report.objects_per_sec,
+            ms(report.p50_us),
+            ms(report.p99_us),
+            ms(report.max_us),
+            report.failures
+        );
+    }
+}
+
+fn write_reports(writer: &mut BufWriter<File>, reports: &[Interim]) -> Result<(), Error> {
+    for report in reports.iter() {
+        writeln!(writer, "{}", serde_json::to_string(report)?)?;
+    }
+
+    // Flush each report so that a long run can be watched as it goes.
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,145 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use crate::pool::Pool;
+use crate::stats::{PhaseReport, PhaseStats};
+use crate::timeseries::Sampler;
+use crate::workers::{self, Context};
+use crate::{latency_csv, retry, trace};
+use failure::Error;
+use opentelemetry::api::KeyValue;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+// One UPDATE statement's worth of objects, as keys and serialized values.
+type Rows = (usize, Vec<(String, String)>);
+
+// An UPDATE of `rows` objects' values in `bucket`'s table, taking each
+// object's key and value as a pair of parameters.
+fn update_statement(bucket: &str, rows: usize) -> String {
+    let values: Vec<String> = (0..rows)
+        .map(|i| format!("(${}, ${})", i * 2 + 1, i * 2 + 2))
+        .collect();
+
+    format!(
+        "UPDATE {} SET _value = v.value FROM (VALUES {}) AS v(key, value) \
+         WHERE {}._key = v.key",
+        bucket,
+        values.join(", "),
+        bucket
+    )
+}
+
+// Write the altered objects straight to the bucket's table with the sql RPC,
+// one UPDATE of `config.batch_size` rows at a time, spread across
+// `config.workers` threads.  This skips everything moray does for a put,
+// including etag and mtime bookkeeping, so it bounds what PostgreSQL alone can
+// do for the batch path.
+pub fn run_sql_test(
+    pool: &Pool,
+    config: &Config,
+    objects: HashMap<String, Value>,
+) -> Result<PhaseReport, Error> {
+    let batch_size = config.batch_size.max(1);
+
+    if config.workers > 1 {
+        println!(
+            "Updating objects with sql, {} rows at a time with {} workers",
+            batch_size, config.workers
+        );
+    } else {
+        println!("Updating objects with sql, {} rows at a time", batch_size);
+    }
+
+    let rows: Vec<(String, String)> = objects
+        .into_iter()
+        .map(|(key, value)| (key, value.to_string()))
+        .collect();
+    let statements: Vec<Rows> = rows
+        .chunks(batch_size as usize)
+        .map(|chunk| chunk.to_vec())
+        .enumerate()
+        .collect();
+    let stats = Arc::new(PhaseStats::new(config, "sql"));
+    let shares = workers::split(statements, config.workers as usize);
+    let sampler = Sampler::start(config, &stats);
+    let started = SystemTime::now();
+    let result = workers::run(config, pool, &stats, shares, update_rows);
+    sampler.stop();
+    latency_csv::flush();
+    let elapsed = result?;
+
+    println!(
+        "Done updating {} objects in {} statements: {}ms",
+        stats.objects(),
+        stats.requests(),
+        elapsed.as_millis()
+    );
+    stats.print_throughput(elapsed);
+    stats.print_latency();
+    stats.print_retries();
+    stats.print_failures();
+
+    let mut report = stats.report(started, elapsed);
+    report.batch_size = Some(batch_size);
+
+    Ok(report)
+}
+
+fn update_rows(ctx: &Context, statements: Vec<Rows>) -> Result<(), Error> {
+    for (id, rows) in workers::until_stopped(statements) {
+        let stmt = update_statement(&ctx.config.bucket, rows.len());
+        let bytes = rows
+            .iter()
+            .map(|(key, value)| (key.len() + value.len()) as u64)
+            .sum();
+
+        let start = ctx.pace();
+        let started = SystemTime::now() - start.elapsed();
+        let span = trace::span(
+            "sql",
+            vec![
+                KeyValue::new("shard", ctx.config.shard.to_string()),
+                KeyValue::new("size", rows.len().to_string()),
+            ],
+        );
+        let result = ctx.call(|c| {
+            let vals: Vec<&str> = rows
+                .iter()
+                .flat_map(|(key, value)| vec![key.as_str(), value.as_str()])
+                .collect();
+            c.sql(&stmt, vals, json!({}), |_| Ok(()))?;
+            Ok(())
+        });
+        latency_csv::record(
+            ctx.config.shard,
+            "sql",
+            &id.to_string(),
+            started,
+            start.elapsed(),
+            &result,
+        );
+        if let Err(e) = &result {
+            trace::set_error(&span, retry::error_name(e));
+        }
+        drop(span);
+
+        match result {
+            Ok(()) => ctx
+                .stats
+                .record_request(rows.len() as u64, bytes, start.elapsed()),
+            Err(e) if ctx.config.continue_on_error => {
+                eprintln!("sql update of {}: {}", rows.len(), e);
+                let keys = rows.into_iter().map(|(key, _)| key);
+                ctx.stats.record_failure(keys, &e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,891 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::chaos::ChaosReport;
+use crate::config::{Config, Warmup};
+use crate::readback::ReadBack;
+use crate::requeue::RequeueReport;
+use crate::throttle::ThrottleEvent;
+use crate::{error_class, metrics, retry, shutdown, soak, status};
+use failure::Error;
+use hdrhistogram::Histogram;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Seconds since the epoch, for timestamps in reports.
+pub fn unix_time(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+// Two-sided 95% critical values of Student's t distribution for 1 to 30
+// degrees of freedom.  Past that the normal distribution is close enough.
+static T_95: &[f64] = &[
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+const Z_95: f64 = 1.96;
+
+/// Mean and spread of a set of measurements.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Estimate {
+    pub samples: usize,
+    pub mean: f64,
+    /// Sample standard deviation.
+    pub stddev: f64,
+    /// Half the width of the 95% confidence interval for the mean.
+    pub ci95: f64,
+}
+
+impl Estimate {
+    pub fn new(samples: &[f64]) -> Estimate {
+        let n = samples.len();
+        if n == 0 {
+            return Estimate::default();
+        }
+
+        let mean = samples.iter().sum::<f64>() / n as f64;
+        if n == 1 {
+            return Estimate {
+                samples: n,
+                mean,
+                ..Estimate::default()
+            };
+        }
+
+        let variance = samples.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+        let stddev = variance.sqrt();
+        let t = T_95.get(n - 2).copied().unwrap_or(Z_95);
+
+        Estimate {
+            samples: n,
+            mean,
+            stddev,
+            ci95: t * stddev / (n as f64).sqrt(),
+        }
+    }
+}
+
+/// The serialized sizes of the test objects' values, in bytes.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ValueSizes {
+    pub min: u64,
+    pub mean: u64,
+    pub p50: u64,
+    pub p99: u64,
+    pub max: u64,
+    pub total: u64,
+}
+
+impl ValueSizes {
+    pub fn new<I: IntoIterator<Item = u64>>(sizes: I) -> ValueSizes {
+        let mut sizes: Vec<u64> = sizes.into_iter().collect();
+        if sizes.is_empty() {
+            return ValueSizes::default();
+        }
+        sizes.sort();
+
+        let at = |q: f64| sizes[((sizes.len() - 1) as f64 * q).round() as usize];
+        let total = sizes.iter().sum();
+
+        ValueSizes {
+            min: sizes[0],
+            mean: total / sizes.len() as u64,
+            p50: at(0.5),
+            p99: at(0.99),
+            max: sizes[sizes.len() - 1],
+            total,
+        }
+    }
+
+    pub fn print(&self) {
+        println!(
+            "Object values: min {} / mean {} / p50 {} / p99 {} / max {} bytes, {} KiB in all",
+            self.min,
+            self.mean,
+            self.p50,
+            self.p99,
+            self.max,
+            self.total / 1024
+        );
+    }
+}
+
+/// Latency percentiles of a phase's successful requests, in microseconds.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct LatencyReport {
+    pub mean_us: f64,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p95_us: u64,
+    pub p99_us: u64,
+    pub p999_us: u64,
+    pub max_us: u64,
+}
+
+/// How long the server said it spent on a phase's requests, and how much
+/// longer than that the client saw them take.
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerLatencyReport {
+    pub responses: u64,
+    pub server: LatencyReport,
+    pub client_overhead: LatencyReport,
+}
+
+impl LatencyReport {
+    fn new(latency: &Histogram<u64>) -> LatencyReport {
+        LatencyReport {
+            mean_us: latency.mean(),
+            p50_us: latency.value_at_percentile(50.0),
+            p90_us: latency.value_at_percentile(90.0),
+            p95_us: latency.value_at_percentile(95.0),
+            p99_us: latency.value_at_percentile(99.0),
+            p999_us: latency.value_at_percentile(99.9),
+            max_us: latency.max(),
+        }
+    }
+}
+
+/// The results of one sequential or batch phase.
+#[derive(Clone, Debug, Serialize)]
+pub struct PhaseReport {
+    pub phase: &'static str,
+    pub pass: u32,
+    pub iteration: u32,
+    pub workers: u32,
+    /// Connections each worker's requests took turns on.
+    pub connections_per_worker: u32,
+    /// Operations per batch request, for batch phases.
+    pub batch_size: Option<u32>,
+    /// Times the phase went over the objects, more than once with
+    /// `--duration`.
+    pub cycles: u64,
+    pub started_at: f64,
+    pub finished_at: f64,
+    /// Time spent warming up, which is left out of everything below.
+    pub warmup_ms: u64,
+    pub warmup_requests: u64,
+    #[serde(skip)]
+    pub elapsed: Duration,
+    pub elapsed_ms: u64,
+    pub requests: u64,
+    pub objects: u64,
+    pub bytes: u64,
+    pub objects_per_sec: f64,
+    pub bytes_per_sec: f64,
+    pub retries: u64,
+    pub backoff_ms: u64,
+    pub failed_requests: u64,
+    pub failed_objects: u64,
+    /// Requests that conflicted with an existing object.  Those that were not
+    /// retried are also counted in `failed_requests`.
+    pub conflicts: u64,
+    /// Objects never sent because an open-loop run found the queue full.
+    pub shed_objects: u64,
+    /// Attempts abandoned after --op-timeout-ms, whether or not a retry
+    /// succeeded.
+    pub timeouts: u64,
+    /// Changes to the rate the phase was capped at by `--throttle`.
+    pub throttle_events: Vec<ThrottleEvent>,
+    pub errors: BTreeMap<String, u64>,
+    /// Failed requests by class of error, e.g. Connect or Overload.
+    pub error_classes: BTreeMap<String, u64>,
+    pub latency: LatencyReport,
+    /// What the server reported about its handling of the requests, if it
+    /// reported anything.
+    pub server_latency: Option<ServerLatencyReport>,
+    /// What reading the objects back after the phase found, with
+    /// `--read-back`.
+    pub read_back: Option<ReadBack>,
+    /// What writing the failed keys again after the phase came to, with
+    /// `--requeue-failed`.
+    pub requeue: Option<RequeueReport>,
+    /// What the connection resets injected with `--chaos-reset` did.
+    pub chaos: Option<ChaosReport>,
+}
+
+/// Requests that failed during a phase that was allowed to continue past
+/// errors.
+#[derive(Debug, Default)]
+pub struct Failures {
+    pub requests: u64,
+    pub conflicts: u64,
+    pub keys: Vec<String>,
+    /// Number of failed requests for each kind of error.
+    pub errors: BTreeMap<String, u64>,
+    /// The same, by class of error.
+    pub classes: BTreeMap<String, u64>,
+}
+
+/// What one of a phase's workers is doing.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkerState {
+    /// Between requests, waiting for work or for its turn to send.
+    Idle,
+    /// Sending a request, retries included.
+    Sending,
+    Done,
+    Failed,
+}
+
+/// How one of a phase's workers is getting on.
+#[derive(Clone, Debug, Serialize)]
+pub struct WorkerStatus {
+    pub state: WorkerState,
+    pub requests: u64,
+    pub failed_requests: u64,
+    /// The error its last failed request ended with.
+    pub last_error: Option<String>,
+}
+
+// Requests a phase has to have made before its error rate is taken seriously,
+// so that one early failure doesn't stop the run.
+const MIN_REQUESTS_FOR_ERROR_RATE: u64 = 100;
+
+// Percentiles reported for each phase.
+static PERCENTILES: &[f64] = &[50.0, 90.0, 95.0, 99.0, 99.9];
+
+/// Statistics shared by every worker during one phase of a run.
+pub struct PhaseStats {
+    pub shard: u32,
+    pub phase: &'static str,
+    workers: u32,
+    connections_per_worker: u32,
+    warmup: Warmup,
+    created: Instant,
+    // Objects written so far, including during the warm-up.
+    warmup_seen: AtomicU64,
+    warmup_requests: AtomicU64,
+    // When the last warm-up request completed.
+    warmup_end: Mutex<Option<Instant>>,
+    retries: AtomicU64,
+    backoff_us: AtomicU64,
+    requests: AtomicU64,
+    objects: AtomicU64,
+    bytes: AtomicU64,
+    failures: Mutex<Failures>,
+    shed: AtomicU64,
+    timeouts: AtomicU64,
+    // Percentage of requests that may fail before the run is stopped.
+    max_error_rate: Option<f64>,
+    throttle_events: Mutex<Vec<ThrottleEvent>>,
+    // What each worker is up to, once they have been spawned.
+    worker_status: Mutex<Vec<WorkerStatus>>,
+    // Objects written, failed or shed so far, warm-up and all, for the
+    // progress bar.
+    completed: AtomicU64,
+    // Objects the phase is to write, or zero if it runs for a time instead.
+    total: AtomicU64,
+    // Latency of each successful put or batch request, in microseconds.
+    latency: Mutex<Histogram<u64>>,
+    // The same, but only since the last time series sample was taken.
+    interval_latency: Mutex<Histogram<u64>>,
+    // Time the server reported spending on each request that said, and the
+    // client's latency less that, in microseconds.
+    server_latency: Mutex<Histogram<u64>>,
+    client_overhead: Mutex<Histogram<u64>>,
+}
+
+impl PhaseStats {
+    /// Create the stats for a phase that is about to start.
+    pub fn new(config: &Config, phase: &'static str) -> PhaseStats {
+        PhaseStats {
+            shard: config.shard,
+            phase,
+            workers: config.workers.max(1),
+            connections_per_worker: config.connections_per_worker.max(1),
+            warmup: config.warmup,
+            created: Instant::now(),
+            warmup_seen: AtomicU64::new(0),
+            warmup_requests: AtomicU64::new(0),
+            warmup_end: Mutex::new(None),
+            retries: AtomicU64::new(0),
+            backoff_us: AtomicU64::new(0),
+            requests: AtomicU64::new(0),
+            objects: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            failures: Mutex::new(Failures::default()),
+            shed: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+            max_error_rate: config.max_error_rate,
+            throttle_events: Mutex::new(vec![]),
+            worker_status: Mutex::new(vec![]),
+            completed: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            latency: Mutex::new(Histogram::new(3).expect("latency histogram")),
+            interval_latency: Mutex::new(Histogram::new(3).expect("latency histogram")),
+            server_latency: Mutex::new(Histogram::new(3).expect("latency histogram")),
+            client_overhead: Mutex::new(Histogram::new(3).expect("latency histogram")),
+        }
+    }
+
+    /// Count a request that completed, the objects it wrote, the serialized
+    /// size of those objects, and how long it took including any retries.
+    /// Requests that are part of the warm-up are only counted as such.
+    pub fn record_request(&self, objects: u64, bytes: u64, latency: Duration) {
+        self.completed.fetch_add(objects, Ordering::Relaxed);
+        if self.in_warmup(objects) {
+            return;
+        }
+
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.objects.fetch_add(objects, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+
+        soak::record(self.shard, self.phase, objects, latency);
+
+        let us = latency.as_micros() as u64;
+        self.latency.lock().unwrap().saturating_record(us);
+        self.interval_latency.lock().unwrap().saturating_record(us);
+
+        let shard = self.shard.to_string();
+        let labels = [shard.as_str(), self.phase];
+        metrics::REQUESTS.with_label_values(&labels).inc();
+        metrics::OBJECTS
+            .with_label_values(&labels)
+            .inc_by(objects as i64);
+        metrics::LATENCY
+            .with_label_values(&labels)
+            .observe(latency.as_secs_f64());
+    }
+
+    // Whether a request that just completed, having written `objects`
+    // objects, belongs to the warm-up.
+    fn in_warmup(&self, objects: u64) -> bool {
+        let warming = match self.warmup {
+            Warmup::None => false,
+            Warmup::Objects(n) => self.warmup_seen.fetch_add(objects, Ordering::Relaxed) < n,
+            Warmup::Secs(secs) => self.created.elapsed() < Duration::from_secs(secs),
+        };
+
+        if warming {
+            self.warmup_requests.fetch_add(1, Ordering::Relaxed);
+
+            let now = Instant::now();
+            let mut end = self.warmup_end.lock().unwrap();
+            if end.map_or(true, |end| now > end) {
+                *end = Some(now);
+            }
+        }
+
+        warming
+    }
+
+    /// Count the time the server reported spending on a request that the
+    /// client saw take `client`.  Like latencies, these are left out during
+    /// the warm-up.
+    pub fn record_server_latency(&self, client: Duration, server: Duration) {
+        let warming = match self.warmup {
+            Warmup::None => false,
+            Warmup::Objects(n) => self.warmup_seen.load(Ordering::Relaxed) < n,
+            Warmup::Secs(secs) => self.created.elapsed() < Duration::from_secs(secs),
+        };
+        if warming {
+            return;
+        }
+
+        let overhead = client.checked_sub(server).unwrap_or_default();
+        self.server_latency
+            .lock()
+            .unwrap()
+            .saturating_record(server.as_micros() as u64);
+        self.client_overhead
+            .lock()
+            .unwrap()
+            .saturating_record(overhead.as_micros() as u64);
+    }
+
+    /// How long the warm-up lasted, from the start of the phase.
+    pub fn warmup_time(&self) -> Duration {
+        match *self.warmup_end.lock().unwrap() {
+            Some(end) => end - self.created,
+            None => Duration::from_secs(0),
+        }
+    }
+
+    pub fn warmup_requests(&self) -> u64 {
+        self.warmup_requests.load(Ordering::Relaxed)
+    }
+
+    /// The latency of requests recorded since the last call, in
+    /// microseconds.
+    pub fn take_interval_latency(&self) -> Histogram<u64> {
+        let mut interval = self.interval_latency.lock().unwrap();
+        let latency = interval.clone();
+        interval.reset();
+        latency
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn objects(&self) -> u64 {
+        self.objects.load(Ordering::Relaxed)
+    }
+
+    /// Record a request that failed for good, along with the keys it was
+    /// meant to write.
+    pub fn record_failure<I>(&self, keys: I, e: &Error)
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut failures = self.failures.lock().unwrap();
+
+        let name = retry::error_name(e);
+        let shard = self.shard.to_string();
+        metrics::ERRORS
+            .with_label_values(&[shard.as_str(), self.phase, name.as_str()])
+            .inc();
+
+        soak::record_failure(self.shard, self.phase);
+        status::record_error(self.shard, self.phase, name.clone(), e.to_string());
+        failures.requests += 1;
+        if retry::is_conflict(e) {
+            failures.conflicts += 1;
+        }
+        let before = failures.keys.len();
+        failures.keys.extend(keys);
+        self.completed
+            .fetch_add((failures.keys.len() - before) as u64, Ordering::Relaxed);
+        *failures.errors.entry(name).or_insert(0) += 1;
+        *failures
+            .classes
+            .entry(error_class(e).to_string())
+            .or_insert(0) += 1;
+
+        if let Some(max) = self.max_error_rate {
+            let total = failures.requests + self.requests();
+            let rate = failures.requests as f64 * 100.0 / total as f64;
+            if total >= MIN_REQUESTS_FOR_ERROR_RATE && rate > max {
+                shutdown::abort(format!(
+                    "{} of {} {} requests failed, over the {}% allowed",
+                    failures.requests, total, self.phase, max
+                ));
+            }
+        }
+    }
+
+    /// Record a request that conflicted with an existing object and is about
+    /// to be retried.
+    pub fn record_conflict(&self) {
+        self.failures.lock().unwrap().conflicts += 1;
+    }
+
+    /// Record work that an open-loop run dropped because the queue to the
+    /// workers was full.
+    pub fn record_shed(&self, objects: u64) {
+        self.shed.fetch_add(objects, Ordering::Relaxed);
+        self.completed.fetch_add(objects, Ordering::Relaxed);
+    }
+
+    /// Objects the phase is done with, whether they were written, failed or
+    /// shed, including those written during the warm-up.
+    pub fn completed(&self) -> u64 {
+        self.completed.load(Ordering::Relaxed)
+    }
+
+    /// Set the number of objects the phase is to write, for its progress.
+    pub fn set_total(&self, total: u64) {
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    /// The number of objects the phase is to write, if it was set.
+    pub fn total(&self) -> Option<u64> {
+        match self.total.load(Ordering::Relaxed) {
+            0 => None,
+            total => Some(total),
+        }
+    }
+
+    pub fn shed(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    // Report both rates so it's clear whether a phase is limited by the
+    // number of requests or by the size of their payloads.
+    pub fn print_throughput(&self, elapsed: Duration) {
+        if self.warmup_requests() > 0 {
+            println!(
+                "Excluded {} warm-up requests ({}ms)",
+                self.warmup_requests(),
+                self.warmup_time().as_millis()
+            );
+        }
+
+        let secs = elapsed.saturating_sub(self.warmup_time()).as_secs_f64();
+        if secs == 0.0 || self.requests() == 0 {
+            return;
+        }
+
+        println!(
+            "Throughput: {:.1} objects/s, {:.1} KiB/s ({} bytes, {} bytes per request)",
+            self.objects() as f64 / secs,
+            self.bytes() as f64 / 1024.0 / secs,
+            self.bytes(),
+            self.bytes() / self.requests()
+        );
+    }
+
+    /// The latency of every successful request so far, in microseconds.
+    pub fn latency(&self) -> Histogram<u64> {
+        self.latency.lock().unwrap().clone()
+    }
+
+    /// The latency of successful requests at the given percentile.
+    pub fn latency_percentile(&self, percentile: f64) -> Duration {
+        Duration::from_micros(self.latency.lock().unwrap().value_at_percentile(percentile))
+    }
+
+    // Total elapsed time hides the tail, which is what matters when choosing
+    // a batch size, so report the latency distribution as well.
+    pub fn print_latency(&self) {
+        if let Some(line) = self.latency_summary() {
+            println!("{}", line);
+        }
+        if let Some(line) = self.server_latency_summary() {
+            println!("{}", line);
+        }
+    }
+
+    /// The server's reported latency and the client's overhead on top of it,
+    /// or None if the server hasn't reported any.
+    pub fn server_latency_summary(&self) -> Option<String> {
+        let server = self.server_latency.lock().unwrap();
+        let overhead = self.client_overhead.lock().unwrap();
+
+        if server.is_empty() {
+            return None;
+        }
+
+        let ms = |us: u64| us as f64 / 1000.0;
+        Some(format!(
+            "Server latency ({} responses): p50={:.1}ms p99={:.1}ms max={:.1}ms, \
+             client overhead p50={:.1}ms p99={:.1}ms max={:.1}ms",
+            server.len(),
+            ms(server.value_at_percentile(50.0)),
+            ms(server.value_at_percentile(99.0)),
+            ms(server.max()),
+            ms(overhead.value_at_percentile(50.0)),
+            ms(overhead.value_at_percentile(99.0)),
+            ms(overhead.max())
+        ))
+    }
+
+    /// The latency percentiles of the requests so far, or None if there
+    /// haven't been any.
+    pub fn latency_summary(&self) -> Option<String> {
+        let latency = self.latency.lock().unwrap();
+
+        if latency.is_empty() {
+            return None;
+        }
+
+        let ms = |us: u64| us as f64 / 1000.0;
+        let mut line = format!("Request latency ({} requests):", latency.len());
+        for p in PERCENTILES.iter() {
+            line.push_str(&format!(
+                " p{}={:.1}ms",
+                p,
+                ms(latency.value_at_percentile(*p))
+            ));
+        }
+        line.push_str(&format!(" max={:.1}ms", ms(latency.max())));
+
+        Some(line)
+    }
+
+    /// Time since the phase started, including any warm-up.
+    pub fn elapsed(&self) -> Duration {
+        self.created.elapsed()
+    }
+
+    /// Summarize the phase, which started at `started` and took `elapsed`
+    /// including the warm-up.
+    pub fn report(&self, started: SystemTime, elapsed: Duration) -> PhaseReport {
+        let warmup = self.warmup_time().min(elapsed);
+        let elapsed = elapsed - warmup;
+        let secs = elapsed.as_secs_f64();
+        let rate = |n: u64| if secs > 0.0 { n as f64 / secs } else { 0.0 };
+        let failures = self.failures.lock().unwrap();
+        let latency = self.latency.lock().unwrap();
+        let server = self.server_latency.lock().unwrap();
+        let server_latency = if server.is_empty() {
+            None
+        } else {
+            Some(ServerLatencyReport {
+                responses: server.len(),
+                server: LatencyReport::new(&server),
+                client_overhead: LatencyReport::new(&self.client_overhead.lock().unwrap()),
+            })
+        };
+
+        PhaseReport {
+            phase: self.phase,
+            pass: 1,
+            iteration: 1,
+            workers: self.workers,
+            connections_per_worker: self.connections_per_worker,
+            batch_size: None,
+            cycles: 1,
+            started_at: unix_time(started),
+            finished_at: unix_time(started + warmup + elapsed),
+            warmup_ms: warmup.as_millis() as u64,
+            warmup_requests: self.warmup_requests(),
+            elapsed,
+            elapsed_ms: elapsed.as_millis() as u64,
+            requests: self.requests(),
+            objects: self.objects(),
+            bytes: self.bytes(),
+            objects_per_sec: rate(self.objects()),
+            bytes_per_sec: rate(self.bytes()),
+            retries: self.retries(),
+            backoff_ms: self.backoff().as_millis() as u64,
+            failed_requests: failures.requests,
+            failed_objects: failures.keys.len() as u64,
+            conflicts: failures.conflicts,
+            shed_objects: self.shed(),
+            timeouts: self.timeouts(),
+            throttle_events: self.throttle_events.lock().unwrap().clone(),
+            errors: failures.errors.clone(),
+            error_classes: failures.classes.clone(),
+            latency: LatencyReport::new(&latency),
+            server_latency,
+            read_back: None,
+            requeue: None,
+            chaos: None,
+        }
+    }
+
+    pub fn failed_requests(&self) -> u64 {
+        self.failures.lock().unwrap().requests
+    }
+
+    /// Number of requests that failed for good so far, for each kind of
+    /// error.
+    pub fn errors(&self) -> BTreeMap<String, u64> {
+        self.failures.lock().unwrap().errors.clone()
+    }
+
+    /// Start following `count` workers, all of them idle.
+    pub fn start_workers(&self, count: usize) {
+        let idle = WorkerStatus {
+            state: WorkerState::Idle,
+            requests: 0,
+            failed_requests: 0,
+            last_error: None,
+        };
+        *self.worker_status.lock().unwrap() = vec![idle; count];
+    }
+
+    pub fn set_worker_state(&self, worker: usize, state: WorkerState) {
+        if let Some(status) = self.worker_status.lock().unwrap().get_mut(worker) {
+            status.state = state;
+        }
+    }
+
+    /// Record that `worker` is done with a request, which failed with
+    /// `error` if that is set, and is idle again.
+    pub fn record_worker_request(&self, worker: usize, error: Option<String>) {
+        if let Some(status) = self.worker_status.lock().unwrap().get_mut(worker) {
+            status.state = WorkerState::Idle;
+            status.requests += 1;
+            if error.is_some() {
+                status.failed_requests += 1;
+                status.last_error = error;
+            }
+        }
+    }
+
+    pub fn worker_status(&self) -> Vec<WorkerStatus> {
+        self.worker_status.lock().unwrap().clone()
+    }
+
+    /// The keys of every request that failed for good so far.
+    pub fn failed_keys(&self) -> Vec<String> {
+        self.failures.lock().unwrap().keys.clone()
+    }
+
+    pub fn print_failures(&self) {
+        if self.shed() > 0 {
+            println!(
+                "{} objects were shed because the workers fell behind",
+                self.shed()
+            );
+        }
+
+        let failures = self.failures.lock().unwrap();
+
+        if failures.requests == 0 {
+            if failures.conflicts > 0 {
+                println!(
+                    "{} requests conflicted with existing objects and were retried",
+                    failures.conflicts
+                );
+            }
+            return;
+        }
+
+        println!(
+            "{} requests failed, {} objects were not written",
+            failures.requests,
+            failures.keys.len()
+        );
+        if failures.conflicts > 0 {
+            println!(
+                "{} requests conflicted with existing objects",
+                failures.conflicts
+            );
+        }
+        for (name, count) in failures.errors.iter() {
+            println!("    {:>8} {}", count, name);
+        }
+        println!("Failed keys:");
+        for key in failures.keys.iter() {
+            println!("    {}", key);
+        }
+    }
+
+    pub fn record_retry(&self, backoff: Duration) {
+        let shard = self.shard.to_string();
+        metrics::RETRIES
+            .with_label_values(&[shard.as_str(), self.phase])
+            .inc();
+
+        self.retries.fetch_add(1, Ordering::Relaxed);
+        self.backoff_us
+            .fetch_add(backoff.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn timeouts(&self) -> u64 {
+        self.timeouts.load(Ordering::Relaxed)
+    }
+
+    pub fn record_throttle(&self, event: ThrottleEvent) {
+        self.throttle_events.lock().unwrap().push(event);
+    }
+
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Total time workers spent sleeping between retries.
+    pub fn backoff(&self) -> Duration {
+        Duration::from_micros(self.backoff_us.load(Ordering::Relaxed))
+    }
+
+    // Retries are included in the phase's elapsed time, so call them out
+    // rather than let them silently inflate the numbers.
+    pub fn print_retries(&self) {
+        if self.retries() > 0 {
+            println!(
+                "Retried {} operations, {}ms spent backing off",
+                self.retries(),
+                self.backoff().as_millis()
+            );
+        }
+        if self.timeouts() > 0 {
+            println!(
+                "{} attempts timed out and their connections were replaced",
+                self.timeouts()
+            );
+        }
+
+        let events = self.throttle_events.lock().unwrap();
+        let cuts = events.iter().filter(|e| e.error.is_some()).count();
+        if cuts > 0 {
+            let lowest = events
+                .iter()
+                .filter_map(|e| e.to_rate)
+                .fold(f64::INFINITY, f64::min);
+            let last = events.last().and_then(|e| e.to_rate);
+            println!(
+                "Throttled {} times on overload, down to {:.1} requests/s, {}",
+                cuts,
+                lowest,
+                match last {
+                    Some(rate) => format!("ending at {:.1} requests/s", rate),
+                    None => String::from("and lifted again"),
+                }
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-3,
+            "{} isn't close to {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn estimate_without_spread() {
+        let none = Estimate::new(&[]);
+        assert_eq!(none.samples, 0);
+        assert_eq!(none.mean, 0.0);
+        assert_eq!(none.ci95, 0.0);
+
+        let one = Estimate::new(&[42.0]);
+        assert_eq!(one.samples, 1);
+        assert_eq!(one.mean, 42.0);
+        assert_eq!(one.stddev, 0.0);
+        assert_eq!(one.ci95, 0.0);
+    }
+
+    #[test]
+    fn estimate_ci_uses_t_for_degrees_of_freedom() {
+        // Two samples have one degree of freedom, the first entry of T_95.
+        let two = Estimate::new(&[1.0, 3.0]);
+        assert_close(two.mean, 2.0);
+        assert_close(two.stddev, 2.0_f64.sqrt());
+        assert_close(two.ci95, 12.706);
+
+        // t.test(c(2, 4, 4, 4, 6)) in R: 95% CI 2.244 to 5.756.
+        let five = Estimate::new(&[2.0, 4.0, 4.0, 4.0, 6.0]);
+        assert_eq!(five.samples, 5);
+        assert_close(five.mean, 4.0);
+        assert_close(five.stddev, 2.0_f64.sqrt());
+        assert_close(five.ci95, 1.7557);
+    }
+
+    #[test]
+    fn estimate_ci_past_the_t_table() {
+        // 31 samples have 30 degrees of freedom, the last entry of T_95, and
+        // 32 fall back to the normal distribution.
+        let alternating = |n: usize| -> Vec<f64> {
+            (0..n)
+                .map(|i| if i % 2 == 0 { 1.0 } else { -1.0 })
+                .collect()
+        };
+
+        let thirty_one = Estimate::new(&alternating(31));
+        let sd = thirty_one.stddev;
+        assert_close(thirty_one.ci95, 2.042 * sd / 31.0_f64.sqrt());
+
+        let thirty_two = Estimate::new(&alternating(32));
+        let sd = thirty_two.stddev;
+        assert_close(thirty_two.ci95, Z_95 * sd / 32.0_f64.sqrt());
+    }
+}
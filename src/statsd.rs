@@ -0,0 +1,78 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::timeseries::Sample;
+use failure::Error;
+use lazy_static::lazy_static;
+use std::net::UdpSocket;
+use std::sync::Mutex;
+
+struct Sink {
+    socket: UdpSocket,
+    prefix: String,
+}
+
+lazy_static! {
+    static ref SINK: Mutex<Option<Sink>> = Mutex::new(None);
+}
+
+/// Send every time series sample to the StatsD server at `addr` (HOST:PORT),
+/// with metric names starting with `prefix`.
+pub fn open(addr: &str, prefix: &str) -> Result<(), Error> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(addr)?;
+
+    *SINK.lock().unwrap() = Some(Sink {
+        socket,
+        prefix: prefix.to_string(),
+    });
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    SINK.lock().unwrap().is_some()
+}
+
+// Counts are sent as counters and the interval's latency percentiles as
+// timers, e.g.
+//
+//      moray_batch_test.shard2.batch.requests:40|c
+//      moray_batch_test.shard2.batch.latency.p99:12.250|ms
+//
+pub fn send(sample: &Sample) -> Result<(), Error> {
+    let sink = SINK.lock().unwrap();
+    let sink = match sink.as_ref() {
+        Some(sink) => sink,
+        None => return Ok(()),
+    };
+
+    let name = format!("{}.shard{}.{}", sink.prefix, sample.shard, sample.phase);
+    let counters = [
+        ("requests", sample.requests),
+        ("objects", sample.objects),
+        ("failures", sample.failures),
+        ("retries", sample.retries),
+    ];
+    let timers = [
+        ("p50", sample.p50_us),
+        ("p90", sample.p90_us),
+        ("p99", sample.p99_us),
+        ("max", sample.max_us),
+    ];
+
+    let mut lines: Vec<String> = counters
+        .iter()
+        .map(|(metric, count)| format!("{}.{}:{}|c", name, metric, count))
+        .collect();
+
+    // Without any requests in the interval there's no latency to report.
+    if sample.requests > 0 {
+        lines.extend(timers.iter().map(|(metric, us)| {
+            format!("{}.latency.{}:{:.3}|ms", name, metric, *us as f64 / 1000.0)
+        }));
+    }
+
+    sink.socket.send(lines.join("\n").as_bytes())?;
+    Ok(())
+}
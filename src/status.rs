@@ -0,0 +1,193 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! The state of the run as JSON at `http://<host>:<port>/status`, with
+//! `--status-port`, so that a run left going in a headnode zone can be
+//! checked on from elsewhere.
+//!
+//! The answer lists every phase running, on every shard, with how far it has
+//! got, its rates and latency so far, and the last few requests that failed
+//! for good:
+//!
+//! ```text
+//! {"time":..., "uptime_secs":..., "stopping":false, "aborted":null,
+//!  "phases":[{"shard":1, "phase":"batch", "elapsed_ms":..., "objects":...,
+//!             "total":..., "progress":0.42, "objects_per_sec":..., ...}],
+//!  "recent_errors":[{"time":..., "shard":1, "phase":"batch", "error":..., ...}]}
+//! ```
+
+use crate::dump;
+use crate::shutdown;
+use crate::stats::{self, PhaseStats};
+use failure::Error;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// Failed requests kept for the answer, most recent last.
+const RECENT_ERRORS: usize = 20;
+
+// How long a client has to send its request line, and to take the answer,
+// before it is given up on.  Requests are answered one at a time, so a client
+// that connects and says nothing mustn't hold up the rest.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    static ref ERRORS: Mutex<VecDeque<RecentError>> = Mutex::new(VecDeque::new());
+    static ref STARTED: Instant = Instant::now();
+}
+
+/// A request that failed for good.
+#[derive(Clone, Debug, Serialize)]
+pub struct RecentError {
+    /// Seconds since the epoch.
+    pub time: f64,
+    pub shard: u32,
+    pub phase: &'static str,
+    pub error: String,
+    pub message: String,
+}
+
+/// How one running phase is getting on.
+#[derive(Debug, Serialize)]
+pub struct PhaseStatus {
+    pub shard: u32,
+    pub phase: &'static str,
+    pub elapsed_ms: u64,
+    pub requests: u64,
+    pub objects: u64,
+    /// Objects the phase is done with, written or not, out of the total it
+    /// is to write, if it has one.
+    pub completed: u64,
+    pub total: Option<u64>,
+    pub progress: Option<f64>,
+    pub objects_per_sec: f64,
+    pub requests_per_sec: f64,
+    pub failed_requests: u64,
+    pub retries: u64,
+    pub timeouts: u64,
+    pub p50_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+/// The answer to `/status`.
+#[derive(Debug, Serialize)]
+pub struct Status {
+    /// Seconds since the epoch.
+    pub time: f64,
+    pub uptime_secs: u64,
+    /// Whether the run has been asked to stop, and why if it was aborted.
+    pub stopping: bool,
+    pub aborted: Option<String>,
+    pub phases: Vec<PhaseStatus>,
+    pub recent_errors: Vec<RecentError>,
+}
+
+/// Keep a request that failed for good among the recent errors.
+pub fn record_error(shard: u32, phase: &'static str, error: String, message: String) {
+    let mut errors = ERRORS.lock().unwrap();
+    if errors.len() == RECENT_ERRORS {
+        errors.pop_front();
+    }
+    errors.push_back(RecentError {
+        time: stats::unix_time(SystemTime::now()),
+        shard,
+        phase,
+        error,
+        message,
+    });
+}
+
+/// The state of the run right now.
+pub fn status() -> Status {
+    Status {
+        time: stats::unix_time(SystemTime::now()),
+        uptime_secs: STARTED.elapsed().as_secs(),
+        stopping: shutdown::requested(),
+        aborted: shutdown::aborted(),
+        phases: dump::live().iter().map(|stats| phase(stats)).collect(),
+        recent_errors: ERRORS.lock().unwrap().iter().cloned().collect(),
+    }
+}
+
+fn phase(stats: &PhaseStats) -> PhaseStatus {
+    let elapsed = stats.elapsed();
+    let secs = elapsed.as_secs_f64();
+    let rate = |n: u64| if secs > 0.0 { n as f64 / secs } else { 0.0 };
+    let latency = stats.latency();
+    let total = stats.total();
+
+    PhaseStatus {
+        shard: stats.shard,
+        phase: stats.phase,
+        elapsed_ms: elapsed.as_millis() as u64,
+        requests: stats.requests(),
+        objects: stats.objects(),
+        completed: stats.completed(),
+        total,
+        progress: total
+            .filter(|total| *total > 0)
+            .map(|total| stats.completed() as f64 / total as f64),
+        objects_per_sec: rate(stats.objects()),
+        requests_per_sec: rate(stats.requests()),
+        failed_requests: stats.failed_requests(),
+        retries: stats.retries(),
+        timeouts: stats.timeouts(),
+        p50_us: latency.value_at_percentile(50.0),
+        p99_us: latency.value_at_percentile(99.0),
+        max_us: latency.max(),
+    }
+}
+
+/// Serve the state of the run at `http://<host>:<port>/status` for the rest
+/// of the run.
+pub fn serve(port: u16) -> Result<(), Error> {
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, port))?;
+    lazy_static::initialize(&STARTED);
+    println!("Serving the run's status on port {}", port);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.map_err(Error::from).and_then(handle_connection);
+
+            if let Err(e) = result {
+                eprintln!("status request failed: {}", e);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// Answer a single request, going by its request line alone.
+fn handle_connection(mut stream: TcpStream) -> Result<(), Error> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    if path != "/status" {
+        stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")?;
+        return Ok(());
+    }
+
+    let body = serde_json::to_vec(&status())?;
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+
+    Ok(())
+}
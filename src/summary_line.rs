@@ -0,0 +1,133 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! One line summing up a run, printed last on stdout with `--summary-line`
+//! for CI jobs to parse, either as a JSON object or as space separated
+//! key=value pairs.  Its fields only ever get added to, so a job that looks
+//! for the ones it knows keeps working.
+//!
+//! Rates are summed across shards, and latencies are the worst of any phase
+//! of the kind.
+
+use crate::config::SummaryFormat;
+use crate::stats::PhaseReport;
+use crate::{shutdown, RunSummary};
+use failure::Error;
+use serde_json::Value;
+
+/// Print the summary line for `results`, whose comparison with the baseline,
+/// if there was one, came to `baseline`.
+pub fn print(
+    format: SummaryFormat,
+    results: &[(u32, String, Result<RunSummary, Error>)],
+    baseline: &Result<(), Error>,
+) {
+    let fields = fields(results, baseline);
+
+    let line = match format {
+        SummaryFormat::Json => {
+            let pairs: Vec<String> = fields
+                .iter()
+                .map(|(key, value)| format!("\"{}\":{}", key, value))
+                .collect();
+            format!("{{{}}}", pairs.join(","))
+        }
+        SummaryFormat::KeyValue => {
+            let pairs: Vec<String> = fields
+                .iter()
+                .map(|(key, value)| match value {
+                    Value::String(s) => format!("{}={}", key, s.replace(' ', "_")),
+                    value => format!("{}={}", key, value),
+                })
+                .collect();
+            pairs.join(" ")
+        }
+    };
+
+    println!("{}", line);
+}
+
+fn fields(
+    results: &[(u32, String, Result<RunSummary, Error>)],
+    baseline: &Result<(), Error>,
+) -> Vec<(&'static str, Value)> {
+    let summaries: Vec<&RunSummary> = results
+        .iter()
+        .filter_map(|(_, _, result)| result.as_ref().ok())
+        .collect();
+    let failed_runs = results.len() - summaries.len();
+    let phases = || summaries.iter().flat_map(|s| s.phases());
+    let sequential = || summaries.iter().flat_map(|s| s.sequential.iter());
+    let batch = || summaries.iter().flat_map(|s| s.batch.iter());
+
+    let status = if shutdown::aborted().is_some() {
+        "aborted"
+    } else if shutdown::requested() {
+        "stopped"
+    } else if failed_runs > 0 {
+        "failed"
+    } else if baseline.is_err() {
+        "regressed"
+    } else {
+        "ok"
+    };
+    let seq_ops_s: f64 = summaries.iter().map(|s| s.sequential_rate()).sum();
+    let batch_ops_s: f64 = summaries.iter().map(|s| s.batch_rate()).sum();
+    let speedup = if seq_ops_s > 0.0 {
+        batch_ops_s / seq_ops_s
+    } else {
+        0.0
+    };
+
+    vec![
+        ("status", json!(status)),
+        ("runs", json!(results.len())),
+        ("failed_runs", json!(failed_runs)),
+        (
+            "objects",
+            json!(summaries.iter().map(|s| s.objects).sum::<u64>()),
+        ),
+        ("seq_ops_s", json!(round(seq_ops_s))),
+        ("batch_ops_s", json!(round(batch_ops_s))),
+        ("speedup", json!(round(speedup))),
+        (
+            "batch_size",
+            json!(batch().filter_map(|p| p.batch_size).last()),
+        ),
+        (
+            "seq_p50_ms",
+            json!(worst(sequential(), |p| p.latency.p50_us)),
+        ),
+        (
+            "seq_p99_ms",
+            json!(worst(sequential(), |p| p.latency.p99_us)),
+        ),
+        ("batch_p50_ms", json!(worst(batch(), |p| p.latency.p50_us))),
+        ("batch_p99_ms", json!(worst(batch(), |p| p.latency.p99_us))),
+        ("retries", json!(phases().map(|p| p.retries).sum::<u64>())),
+        ("timeouts", json!(phases().map(|p| p.timeouts).sum::<u64>())),
+        (
+            "failed_requests",
+            json!(phases().map(|p| p.failed_requests).sum::<u64>()),
+        ),
+        (
+            "failed_objects",
+            json!(phases().map(|p| p.failed_objects).sum::<u64>()),
+        ),
+    ]
+}
+
+// The highest of a latency across `phases`, in milliseconds.
+fn worst<'a, I, F>(phases: I, latency_us: F) -> f64
+where
+    I: Iterator<Item = &'a PhaseReport>,
+    F: Fn(&PhaseReport) -> u64,
+{
+    let us = phases.map(|p| latency_us(p)).max().unwrap_or(0);
+    round(us as f64 / 1000.0)
+}
+
+fn round(n: f64) -> f64 {
+    (n * 1000.0).round() / 1000.0
+}
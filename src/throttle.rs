@@ -0,0 +1,198 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! Backing off the offered load when moray says it is overloaded.
+//!
+//! With `--throttle`, an attempt that fails with an overload error caps the
+//! rate every worker of the phase sends at to half of what they were sending,
+//! and each further overload halves it again, down to `--throttle-min-rate`.
+//! Once a window of attempts in a row has gone through without one, the cap
+//! is raised by a quarter, and lifted altogether once it is back above the
+//! rate the phase started being throttled at.  Each change is recorded in
+//! the phase's stats.
+
+use crate::config::Config;
+use crate::retry;
+use crate::stats::PhaseStats;
+use failure::Error;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Successful attempts in a row before the cap is raised.
+const RECOVERY_WINDOW: u32 = 50;
+
+// How much the cap is cut by on an overload and raised by on recovery.
+const BACKOFF: f64 = 0.5;
+const RECOVERY: f64 = 1.25;
+
+// Overloads this soon after the cap was last cut are put down to the requests
+// that were already in flight, and don't cut it again.
+const SETTLE: Duration = Duration::from_secs(1);
+
+// How far back attempts are counted to measure the rate the workers were
+// sending at before they were throttled.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// A change in the rate a phase was throttled to.
+#[derive(Clone, Debug, Serialize)]
+pub struct ThrottleEvent {
+    /// Time into the phase.
+    pub at_ms: u64,
+    /// Requests per second the phase was capped at before and after, or None
+    /// when it wasn't capped.
+    pub from_rate: Option<f64>,
+    pub to_rate: Option<f64>,
+    /// The error that brought the cap down, or None if it was raised.
+    pub error: Option<String>,
+}
+
+struct State {
+    rate: Option<f64>,
+    // The rate the workers were sending at when first throttled, above which
+    // the cap is lifted.
+    ceiling: f64,
+    // When the next attempt may be sent.
+    next: Instant,
+    last_cut: Option<Instant>,
+    successes: u32,
+    recent: VecDeque<Instant>,
+}
+
+/// Caps the rate of a phase's attempts while moray is overloaded, shared by
+/// every worker of the phase.
+pub struct Throttle {
+    min_rate: f64,
+    started: Instant,
+    state: Mutex<State>,
+}
+
+impl Throttle {
+    /// A throttle for a phase, or None unless `config.throttle` is set.
+    pub fn from_config(config: &Config) -> Option<Throttle> {
+        if !config.throttle {
+            return None;
+        }
+
+        let now = Instant::now();
+        Some(Throttle {
+            min_rate: config.throttle_min_rate.max(0.1),
+            started: now,
+            state: Mutex::new(State {
+                rate: None,
+                ceiling: 0.0,
+                next: now,
+                last_cut: None,
+                successes: 0,
+                recent: VecDeque::new(),
+            }),
+        })
+    }
+
+    /// Wait until the cap allows another attempt, if there is one.
+    pub fn wait(&self) {
+        let due = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            while state
+                .recent
+                .front()
+                .map_or(false, |t| now.duration_since(*t) > RATE_WINDOW)
+            {
+                state.recent.pop_front();
+            }
+            state.recent.push_back(now);
+
+            let rate = match state.rate {
+                Some(rate) => rate,
+                None => return,
+            };
+            let due = state.next.max(now);
+            state.next = due + Duration::from_secs_f64(1.0 / rate);
+            due
+        };
+
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+    }
+
+    /// Record how an attempt went, cutting the cap if moray was overloaded
+    /// and raising it after a window of attempts that went through.
+    pub fn record<T>(&self, stats: &PhaseStats, result: &Result<T, Error>) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        let error = match result {
+            Err(e) if retry::is_overload(e) => e,
+            _ => {
+                state.successes += 1;
+                if state.successes < RECOVERY_WINDOW {
+                    return;
+                }
+                state.successes = 0;
+
+                let rate = match state.rate {
+                    Some(rate) => rate,
+                    None => return,
+                };
+                let raised = rate * RECOVERY;
+                let to_rate = if raised >= state.ceiling {
+                    None
+                } else {
+                    Some(raised)
+                };
+                state.rate = to_rate;
+                stats.record_throttle(self.event(now, Some(rate), to_rate, None));
+                return;
+            }
+        };
+
+        state.successes = 0;
+        if state
+            .last_cut
+            .map_or(false, |t| now.duration_since(t) < SETTLE)
+        {
+            return;
+        }
+
+        let from_rate = state.rate;
+        let current = match from_rate {
+            Some(rate) => rate,
+            None => {
+                let sending = state.recent.len() as f64 / RATE_WINDOW.as_secs_f64();
+                state.ceiling = sending;
+                sending
+            }
+        };
+        let to_rate = (current * BACKOFF).max(self.min_rate);
+        state.rate = Some(to_rate);
+        state.last_cut = Some(now);
+        state.next = now;
+        stats.record_throttle(self.event(
+            now,
+            from_rate,
+            Some(to_rate),
+            Some(retry::error_name(error)),
+        ));
+    }
+
+    fn event(
+        &self,
+        now: Instant,
+        from_rate: Option<f64>,
+        to_rate: Option<f64>,
+        error: Option<String>,
+    ) -> ThrottleEvent {
+        ThrottleEvent {
+            at_ms: now.duration_since(self.started).as_millis() as u64,
+            from_rate,
+            to_rate,
+            error,
+        }
+    }
+}
@@ -0,0 +1,203 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use crate::stats::{self, PhaseStats};
+use crate::{dump, statsd};
+use failure::Error;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+static CSV_HEADER: &str = "time,shard,phase,elapsed_ms,requests,objects,failures,\
+                           retries,p50_us,p90_us,p99_us,max_us";
+
+enum Format {
+    Csv,
+    Json,
+}
+
+struct Output {
+    writer: BufWriter<File>,
+    format: Format,
+}
+
+lazy_static! {
+    static ref OUTPUT: Mutex<Option<Output>> = Mutex::new(None);
+}
+
+/// What happened during one interval of a phase.  Counts cover the interval
+/// only, not the phase so far.
+#[derive(Debug, Serialize)]
+pub struct Sample {
+    /// Seconds since the epoch at the end of the interval.
+    pub time: f64,
+    pub shard: u32,
+    pub phase: &'static str,
+    /// Time since the start of the phase.
+    pub elapsed_ms: u64,
+    pub requests: u64,
+    pub objects: u64,
+    pub failures: u64,
+    pub retries: u64,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+/// Write samples from every phase, on every shard, to `path`.  Files ending
+/// in `.json` or `.jsonl` get one JSON object per line, anything else gets
+/// CSV.
+pub fn open(path: &str) -> Result<(), Error> {
+    let format = if path.ends_with(".json") || path.ends_with(".jsonl") {
+        Format::Json
+    } else {
+        Format::Csv
+    };
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    if let Format::Csv = format {
+        writeln!(writer, "{}", CSV_HEADER)?;
+    }
+
+    *OUTPUT.lock().unwrap() = Some(Output { writer, format });
+    Ok(())
+}
+
+fn write_sample(sample: &Sample) -> Result<(), Error> {
+    let mut output = OUTPUT.lock().unwrap();
+    let output = match output.as_mut() {
+        Some(output) => output,
+        None => return Ok(()),
+    };
+
+    match output.format {
+        Format::Json => writeln!(output.writer, "{}", serde_json::to_string(sample)?)?,
+        Format::Csv => writeln!(
+            output.writer,
+            "{:.3},{},{},{},{},{},{},{},{},{},{},{}",
+            sample.time,
+            sample.shard,
+            sample.phase,
+            sample.elapsed_ms,
+            sample.requests,
+            sample.objects,
+            sample.failures,
+            sample.retries,
+            sample.p50_us,
+            sample.p90_us,
+            sample.p99_us,
+            sample.max_us
+        )?,
+    }
+
+    // Flush each sample so that a long run can be watched as it goes.
+    output.writer.flush()?;
+    Ok(())
+}
+
+// Running totals as of the previous sample.
+#[derive(Default)]
+struct Totals {
+    requests: u64,
+    objects: u64,
+    failures: u64,
+    retries: u64,
+}
+
+/// Takes a sample of a phase's stats every `config.timeseries_interval_ms`
+/// until it is stopped.
+pub struct Sampler {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Sampler {
+    /// Start sampling `stats`.  Nothing is sampled unless `open` was called
+    /// or samples are being sent to StatsD, but the phase is included in
+    /// stats dumps either way.
+    pub fn start(config: &Config, stats: &Arc<PhaseStats>) -> Sampler {
+        dump::track(stats);
+        if OUTPUT.lock().unwrap().is_none() && !statsd::enabled() {
+            return Sampler {
+                stop: None,
+                handle: None,
+            };
+        }
+
+        let (tx, rx) = mpsc::channel::<()>();
+        let stats = Arc::clone(stats);
+        let interval = Duration::from_millis(config.timeseries_interval_ms.max(1));
+
+        let handle = thread::spawn(move || {
+            let start = Instant::now();
+            let mut totals = Totals::default();
+
+            loop {
+                let done = !matches!(rx.recv_timeout(interval), Err(RecvTimeoutError::Timeout));
+
+                let sample = take_sample(&stats, start, &mut totals);
+                if let Err(e) = write_sample(&sample) {
+                    eprintln!("Failed to write time series sample: {}", e);
+                }
+                if let Err(e) = statsd::send(&sample) {
+                    eprintln!("Failed to send time series sample to statsd: {}", e);
+                }
+
+                if done {
+                    break;
+                }
+            }
+        });
+
+        Sampler {
+            stop: Some(tx),
+            handle: Some(handle),
+        }
+    }
+
+    /// Take a final sample covering the end of the phase and stop.
+    pub fn stop(mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn take_sample(stats: &PhaseStats, start: Instant, totals: &mut Totals) -> Sample {
+    let latency = stats.take_interval_latency();
+    let time = stats::unix_time(SystemTime::now());
+
+    let current = Totals {
+        requests: stats.requests(),
+        objects: stats.objects(),
+        failures: stats.failed_requests(),
+        retries: stats.retries(),
+    };
+
+    let sample = Sample {
+        time,
+        shard: stats.shard,
+        phase: stats.phase,
+        elapsed_ms: start.elapsed().as_millis() as u64,
+        requests: current.requests - totals.requests,
+        objects: current.objects - totals.objects,
+        failures: current.failures - totals.failures,
+        retries: current.retries - totals.retries,
+        p50_us: latency.value_at_percentile(50.0),
+        p90_us: latency.value_at_percentile(90.0),
+        p99_us: latency.value_at_percentile(99.0),
+        max_us: latency.max(),
+    };
+
+    *totals = current;
+    sample
+}
@@ -0,0 +1,141 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! Where the time goes in setting up connections and making requests.
+//!
+//! Every DNS lookup that isn't answered from the cache and every connection
+//! is timed, along with one request in every `config.timing_sample`: how long
+//! its objects take to encode as JSON, and how long the moray call takes
+//! once the connection is in hand.  The call includes the client's own
+//! encoding and the server's work, so a slow round trip alongside a fast
+//! encode points at the network or the server rather than at the client.  A
+//! reconnect is timed from losing the connection to having a new one.
+
+use crate::config::Config;
+use hdrhistogram::Histogram;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Step {
+    Dns,
+    Connect,
+    Serialize,
+    RoundTrip,
+    Reconnect,
+}
+
+static STEPS: &[Step] = &[
+    Step::Dns,
+    Step::Connect,
+    Step::Serialize,
+    Step::RoundTrip,
+    Step::Reconnect,
+];
+
+impl Step {
+    pub fn name(self) -> &'static str {
+        match self {
+            Step::Dns => "dns",
+            Step::Connect => "connect",
+            Step::Serialize => "serialize",
+            Step::RoundTrip => "round_trip",
+            Step::Reconnect => "reconnect",
+        }
+    }
+}
+
+lazy_static! {
+    // One histogram per step, in the order of STEPS, in microseconds.
+    static ref TIMINGS: Mutex<Vec<Histogram<u64>>> = Mutex::new(
+        STEPS
+            .iter()
+            .map(|_| Histogram::new(3).expect("timing histogram"))
+            .collect()
+    );
+}
+
+static SAMPLE_EVERY: AtomicU64 = AtomicU64::new(0);
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+/// Time one request in every `config.timing_sample`.
+pub fn init(config: &Config) {
+    SAMPLE_EVERY.store(u64::from(config.timing_sample), Ordering::Relaxed);
+}
+
+/// Whether the request about to be made should have its steps timed.
+pub fn sampled() -> bool {
+    let every = SAMPLE_EVERY.load(Ordering::Relaxed);
+    every > 0 && REQUESTS.fetch_add(1, Ordering::Relaxed) % every == 0
+}
+
+pub fn record(step: Step, duration: Duration) {
+    let i = STEPS.iter().position(|s| *s == step).expect("known step");
+    TIMINGS.lock().unwrap()[i].saturating_record(duration.as_micros() as u64);
+}
+
+/// Run `f`, recording how long it took as `step`.
+pub fn time<T, F: FnOnce() -> T>(step: Step, f: F) -> T {
+    let started = Instant::now();
+    let result = f();
+    record(step, started.elapsed());
+    result
+}
+
+#[derive(Serialize)]
+pub struct StepReport {
+    pub step: &'static str,
+    pub count: u64,
+    pub mean_us: f64,
+    pub p50_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+/// The timings of every step that happened at least once.
+pub fn report() -> Vec<StepReport> {
+    let timings = TIMINGS.lock().unwrap();
+
+    STEPS
+        .iter()
+        .zip(timings.iter())
+        .filter(|(_, histogram)| !histogram.is_empty())
+        .map(|(step, histogram)| StepReport {
+            step: step.name(),
+            count: histogram.len(),
+            mean_us: histogram.mean(),
+            p50_us: histogram.value_at_percentile(50.0),
+            p99_us: histogram.value_at_percentile(99.0),
+            max_us: histogram.max(),
+        })
+        .collect()
+}
+
+pub fn print() {
+    let steps = report();
+    if steps.is_empty() {
+        return;
+    }
+
+    let ms = |us: u64| us as f64 / 1000.0;
+    println!("\n ==== time by step ====");
+    println!(
+        "{:>12} {:>8} {:>10} {:>10} {:>10} {:>10}",
+        "step", "count", "mean", "p50", "p99", "max"
+    );
+    for step in steps.iter() {
+        println!(
+            "{:>12} {:>8} {:>8.2}ms {:>8.2}ms {:>8.2}ms {:>8.2}ms",
+            step.step,
+            step.count,
+            step.mean_us / 1000.0,
+            ms(step.p50_us),
+            ms(step.p99_us),
+            ms(step.max_us)
+        );
+    }
+}
@@ -0,0 +1,47 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use lazy_static::lazy_static;
+use opentelemetry::api::{KeyValue, Span, Tracer};
+use opentelemetry::global::{self, BoxedSpan};
+use opentelemetry_otlp::Uninstall;
+use std::sync::Mutex;
+
+static TRACER_NAME: &str = "moray_batch_test";
+
+lazy_static! {
+    // Dropping this flushes any spans that haven't been exported yet.
+    static ref UNINSTALL: Mutex<Option<Uninstall>> = Mutex::new(None);
+}
+
+/// Export spans to the OTLP collector at `endpoint` (HOST:PORT).  Until this
+/// is called spans are no-ops.
+pub fn init(endpoint: &str) {
+    let (_tracer, uninstall) = opentelemetry_otlp::new_pipeline()
+        .with_endpoint(endpoint)
+        .install();
+
+    *UNINSTALL.lock().unwrap() = Some(uninstall);
+}
+
+/// Flush outstanding spans and stop exporting.
+pub fn shutdown() {
+    UNINSTALL.lock().unwrap().take();
+}
+
+/// Start a span that ends when it is dropped.
+pub fn span(name: &'static str, attributes: Vec<KeyValue>) -> BoxedSpan {
+    let span = global::tracer(TRACER_NAME).start(name);
+
+    for attribute in attributes {
+        span.set_attribute(attribute);
+    }
+
+    span
+}
+
+/// Mark the span as failed, with the kind of error that caused it.
+pub fn set_error(span: &BoxedSpan, error: String) {
+    span.set_attribute(KeyValue::new("error", error));
+}
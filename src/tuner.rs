@@ -0,0 +1,111 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Number of batches whose results are looked at before each adjustment.
+const WINDOW: usize = 20;
+
+// Number of recent adjustments the converged batch size is taken from.
+const CONVERGED_WINDOWS: usize = 10;
+
+struct Window {
+    latencies: Vec<Duration>,
+    errors: u32,
+    // Batch size after each adjustment.
+    history: Vec<u32>,
+}
+
+/// Picks the batch size while the batch test runs, using additive increase,
+/// multiplicative decrease: grow the batch size by a fixed step while the p99
+/// latency of recent batches stays under the target and none of them fail,
+/// and halve it otherwise.
+pub struct Tuner {
+    size: AtomicU32,
+    step: u32,
+    max_size: u32,
+    target_p99: Duration,
+    window: Mutex<Window>,
+}
+
+impl Tuner {
+    /// Start from `config.batch_size`.
+    pub fn from_config(config: &Config) -> Tuner {
+        Tuner {
+            size: AtomicU32::new(config.batch_size.max(1)),
+            step: config.batch_size_step.max(1),
+            max_size: config.max_batch_size.max(1),
+            target_p99: Duration::from_millis(config.target_p99_ms),
+            window: Mutex::new(Window {
+                latencies: Vec::with_capacity(WINDOW),
+                errors: 0,
+                history: vec![],
+            }),
+        }
+    }
+
+    /// The size the next batch should be.
+    pub fn size(&self) -> u32 {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Record how a batch went, adjusting the batch size once a window's
+    /// worth of batches have completed.
+    pub fn record(&self, latency: Duration, success: bool) {
+        let mut window = self.window.lock().unwrap();
+
+        window.latencies.push(latency);
+        if !success {
+            window.errors += 1;
+        }
+
+        if window.latencies.len() < WINDOW {
+            return;
+        }
+
+        // With only WINDOW batches this is close to the slowest of them.
+        window.latencies.sort();
+        let p99 = window.latencies[(window.latencies.len() * 99 + 99) / 100 - 1];
+
+        let size = self.size();
+        let next = if window.errors == 0 && p99 <= self.target_p99 {
+            (size + self.step).min(self.max_size)
+        } else {
+            (size / 2).max(1)
+        };
+
+        if next != size {
+            println!(
+                "Batch size {} -> {} (p99 {}ms, {} errors)",
+                size,
+                next,
+                p99.as_millis(),
+                window.errors
+            );
+        }
+
+        self.size.store(next, Ordering::Relaxed);
+        window.latencies.clear();
+        window.errors = 0;
+        window.history.push(next);
+    }
+
+    /// The median batch size over the last few adjustments, which AIMD
+    /// saw-tooths around once it has found the limit.
+    pub fn converged(&self) -> u32 {
+        let window = self.window.lock().unwrap();
+        let start = window.history.len().saturating_sub(CONVERGED_WINDOWS);
+        let mut recent = window.history[start..].to_vec();
+
+        if recent.is_empty() {
+            return self.size();
+        }
+
+        recent.sort_unstable();
+        recent[recent.len() / 2]
+    }
+}
@@ -0,0 +1,191 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::batch::PutBatches;
+use crate::config::{Config, Index};
+use crate::pool::Pool;
+use crate::stats::{PhaseReport, PhaseStats};
+use crate::timeseries::Sampler;
+use crate::workers::{self, Context};
+use crate::{get_or_create_bucket, latency_csv, random_string, retry, trace};
+use failure::Error;
+use moray::client::MorayClient;
+use moray::objects::{self, BatchRequest};
+use opentelemetry::api::KeyValue;
+use rand::Rng;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// The field the unique index is on.  Manta objects don't carry it, so the
+/// workload adds it to each object it writes.
+pub static UNIQUE_FIELD: &str = "uniqueId";
+
+/// The variant of the test bucket the conflict workload writes to, with a
+/// unique index on `UNIQUE_FIELD` as well as the usual indexes.
+pub fn unique_config(config: &Config) -> Config {
+    let mut config = config.clone();
+
+    config.bucket = format!("{}_unique", config.bucket);
+    config.companion_buckets = vec![];
+    config.index.insert(
+        UNIQUE_FIELD.to_string(),
+        Index {
+            kind: String::from("string"),
+            unique: true,
+        },
+    );
+
+    config
+}
+
+// Give every object a fresh unique value, except that about `fraction` of
+// them take the value of the object before them in the same batch, so that
+// the batch fails with a UniqueAttributeError.  The first object of each batch
+// never conflicts, which caps the fraction at (batch_size - 1) / batch_size.
+fn assign_values(
+    objects: HashMap<String, Value>,
+    batch_size: u32,
+    fraction: f64,
+) -> (Vec<(String, Value)>, u64) {
+    let mut rng = rand::thread_rng();
+    let fraction = fraction.clamp(0.0, 1.0);
+    let mut objects: Vec<(String, Value)> = objects.into_iter().collect();
+    let mut conflicting = 0;
+
+    for batch in objects.chunks_mut(batch_size.max(1) as usize) {
+        let mut previous = random_string(&mut rng, 16);
+
+        for (i, (_, value)) in batch.iter_mut().enumerate() {
+            if i == 0 || !rng.gen_bool(fraction) {
+                previous = random_string(&mut rng, 16);
+            } else {
+                conflicting += 1;
+            }
+            value[UNIQUE_FIELD] = json!(previous);
+        }
+    }
+
+    (objects, conflicting)
+}
+
+/// Create the unique-index variant of the test bucket if it doesn't exist.
+pub fn create_bucket(client: &mut MorayClient, config: &Config) {
+    get_or_create_bucket(client, &unique_config(config));
+}
+
+// Write the altered objects to the unique-index bucket in batches of
+// `config.batch_size`, with `config.conflict_fraction` of them conflicting.
+// Conflicting batches fail as a whole and are counted apart from other
+// failures; they never stop the phase.
+pub fn run_unique_test(
+    pool: &Pool,
+    config: &Config,
+    objects: HashMap<String, Value>,
+) -> Result<PhaseReport, Error> {
+    let config = unique_config(config);
+    let batch_size = config.batch_size.max(1);
+    let (objects, conflicting) = assign_values(objects, batch_size, config.conflict_fraction);
+
+    println!(
+        "Updating objects in {} in batches of {}, {} of {} conflicting",
+        config.bucket,
+        batch_size,
+        conflicting,
+        objects.len()
+    );
+
+    let batches: Vec<(usize, Vec<BatchRequest>)> =
+        PutBatches::new(&config.bucket, objects.into_iter(), batch_size)
+            .with_max_bytes(config.max_batch_bytes)
+            .enumerate()
+            .collect();
+    let stats = Arc::new(PhaseStats::new(&config, "unique"));
+    let shares = workers::split(batches, config.workers as usize);
+    let sampler = Sampler::start(&config, &stats);
+    let started = SystemTime::now();
+    let result = workers::run(&config, pool, &stats, shares, put_batches);
+    sampler.stop();
+    latency_csv::flush();
+    let elapsed = result?;
+
+    println!(
+        "Done updating {} objects in {} batches: {}ms",
+        stats.objects(),
+        stats.requests(),
+        elapsed.as_millis()
+    );
+    stats.print_throughput(elapsed);
+    stats.print_latency();
+    stats.print_retries();
+    stats.print_failures();
+
+    let mut report = stats.report(started, elapsed);
+    report.batch_size = Some(batch_size);
+
+    Ok(report)
+}
+
+fn put_batches(ctx: &Context, batches: Vec<(usize, Vec<BatchRequest>)>) -> Result<(), Error> {
+    let opts = objects::MethodOptions::default();
+
+    for (id, batch) in workers::until_stopped(batches) {
+        let keys: Vec<String> = batch
+            .iter()
+            .filter_map(|req| match req {
+                BatchRequest::Put(op) => Some(op.key.clone()),
+                _ => None,
+            })
+            .collect();
+        let bytes = batch
+            .iter()
+            .map(|req| match req {
+                BatchRequest::Put(op) => (op.key.len() + op.value.to_string().len()) as u64,
+                _ => 0,
+            })
+            .sum();
+
+        let start = ctx.pace();
+        let started = SystemTime::now() - start.elapsed();
+        let span = trace::span(
+            "unique",
+            vec![
+                KeyValue::new("shard", ctx.config.shard.to_string()),
+                KeyValue::new("batch", id.to_string()),
+                KeyValue::new("size", batch.len().to_string()),
+            ],
+        );
+        let result = ctx.call(|c| {
+            c.batch(&batch, &opts, |_| Ok(()))?;
+            Ok(())
+        });
+        latency_csv::record(
+            ctx.config.shard,
+            "unique",
+            &id.to_string(),
+            started,
+            start.elapsed(),
+            &result,
+        );
+        if let Err(e) = &result {
+            trace::set_error(&span, retry::error_name(e));
+        }
+        drop(span);
+
+        match result {
+            Ok(()) => ctx
+                .stats
+                .record_request(keys.len() as u64, bytes, start.elapsed()),
+            Err(e) if retry::is_conflict(&e) => ctx.stats.record_failure(keys, &e),
+            Err(e) if ctx.config.continue_on_error => {
+                eprintln!("batch of {}: {}", keys.len(), e);
+                ctx.stats.record_failure(keys, &e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
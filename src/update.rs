@@ -0,0 +1,153 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::config::Config;
+use crate::pool::Pool;
+use crate::stats::{PhaseReport, PhaseStats};
+use crate::timeseries::Sampler;
+use crate::workers::{self, Context};
+use crate::{find, latency_csv, retry, trace};
+use failure::Error;
+use libmanta::moray::MantaObject;
+use moray::objects;
+use opentelemetry::api::KeyValue;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+// One updateobjects request: the filter selecting the objects, the indexed
+// fields to set on them, and how many objects it should match.
+struct UpdateMany {
+    id: usize,
+    filter: String,
+    fields: Value,
+    objects: u64,
+}
+
+// Group the test objects by type, `batch_size` objects to a request, and set
+// each group's type to the value it already has.  Moray still rewrites every
+// matching row, which is the cost being measured, while the objects stay
+// valid manta objects.
+fn requests(test_objects: &HashMap<String, MantaObject>, batch_size: u32) -> Vec<UpdateMany> {
+    let mut by_type: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for obj in test_objects.values() {
+        let value = serde_json::to_value(obj).unwrap();
+        let kind = value["type"].as_str().unwrap_or("object").to_string();
+        by_type
+            .entry(kind)
+            .or_insert_with(Vec::new)
+            .push(obj.object_id.clone());
+    }
+
+    let mut requests = vec![];
+    for (kind, ids) in by_type.iter() {
+        for chunk in ids.chunks(batch_size.max(1) as usize) {
+            requests.push(UpdateMany {
+                id: requests.len(),
+                filter: find::object_id_filter(chunk),
+                fields: json!({ "type": kind }),
+                objects: chunk.len() as u64,
+            });
+        }
+    }
+
+    requests
+}
+
+// Update the indexed fields of the test objects with updateobjects requests
+// covering `config.batch_size` objects each, spread across `config.workers`
+// threads.
+pub fn run_update_objects_test(
+    pool: &Pool,
+    config: &Config,
+    test_objects: &HashMap<String, MantaObject>,
+) -> Result<PhaseReport, Error> {
+    let batch_size = config.batch_size.max(1);
+
+    if config.workers > 1 {
+        println!(
+            "Updating objects with updateobjects, {} at a time with {} workers",
+            batch_size, config.workers
+        );
+    } else {
+        println!(
+            "Updating objects with updateobjects, {} at a time",
+            batch_size
+        );
+    }
+
+    let stats = Arc::new(PhaseStats::new(config, "update_objects"));
+    let shares = workers::split(requests(test_objects, batch_size), config.workers as usize);
+    let sampler = Sampler::start(config, &stats);
+    let started = SystemTime::now();
+    let result = workers::run(config, pool, &stats, shares, update_objects);
+    sampler.stop();
+    latency_csv::flush();
+    let elapsed = result?;
+
+    println!(
+        "Done updating {} objects in {} requests: {}ms",
+        stats.objects(),
+        stats.requests(),
+        elapsed.as_millis()
+    );
+    stats.print_throughput(elapsed);
+    stats.print_latency();
+    stats.print_retries();
+    stats.print_failures();
+
+    let mut report = stats.report(started, elapsed);
+    report.batch_size = Some(batch_size);
+
+    Ok(report)
+}
+
+fn update_objects(ctx: &Context, requests: Vec<UpdateMany>) -> Result<(), Error> {
+    let opts = objects::MethodOptions::default();
+    let bucket = ctx.config.bucket.as_str();
+
+    for req in workers::until_stopped(requests) {
+        let bytes = (req.filter.len() + req.fields.to_string().len()) as u64;
+        let start = ctx.pace();
+        let started = SystemTime::now() - start.elapsed();
+        let span = trace::span(
+            "update_objects",
+            vec![
+                KeyValue::new("shard", ctx.config.shard.to_string()),
+                KeyValue::new("size", req.objects.to_string()),
+            ],
+        );
+        let result = ctx.call(|c| {
+            c.update_objects(bucket, req.fields.clone(), &req.filter, &opts, |_| Ok(()))?;
+            Ok(())
+        });
+        latency_csv::record(
+            ctx.config.shard,
+            "update_objects",
+            &req.id.to_string(),
+            started,
+            start.elapsed(),
+            &result,
+        );
+        if let Err(e) = &result {
+            trace::set_error(&span, retry::error_name(e));
+        }
+        drop(span);
+
+        match result {
+            Ok(()) => ctx
+                .stats
+                .record_request(req.objects, bytes, start.elapsed()),
+            Err(e) if ctx.config.continue_on_error => {
+                eprintln!("updateobjects of {}: {}", req.objects, e);
+                ctx.stats.record_failure(vec![req.filter], &e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
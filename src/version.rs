@@ -0,0 +1,90 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! The version each moray instance reports, so that results can be tied to
+//! the server builds they were measured against.
+//!
+//! The moray client has no call for moray's `version` RPC, so it is sent over
+//! a connection of its own.
+
+use crate::config::Config;
+use crate::{configure_network, Target};
+use failure::{err_msg, Error};
+use rust_fast::client as fast;
+use rust_fast::protocol::FastMessageId;
+use serde::Serialize;
+use serde_json::Value;
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use uuid::Uuid;
+
+// An instance that doesn't answer this quickly is reported as such rather
+// than holding up the run.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What one moray instance said its version was, or why it couldn't say.
+#[derive(Clone, Debug, Serialize)]
+pub struct ServerVersion {
+    pub addr: SocketAddr,
+    pub version: Option<Value>,
+    pub error: Option<String>,
+}
+
+/// Ask every moray instance of the target for its version.  Instances behind
+/// one shard can run different builds partway through an upgrade, so each
+/// is asked.
+pub fn query(config: &Config) -> Result<Vec<ServerVersion>, Error> {
+    configure_network(config);
+    let addrs = Target::from_config(config).addrs()?;
+
+    Ok(addrs
+        .into_iter()
+        .map(|addr| match version(addr) {
+            Ok(version) => ServerVersion {
+                addr,
+                version: Some(version),
+                error: None,
+            },
+            Err(e) => ServerVersion {
+                addr,
+                version: None,
+                error: Some(e.to_string()),
+            },
+        })
+        .collect())
+}
+
+fn version(addr: SocketAddr) -> Result<Value, Error> {
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let args = json!([{ "req_id": Uuid::new_v4().to_string() }]);
+    fast::send(
+        String::from("version"),
+        args,
+        &mut FastMessageId::new(),
+        &mut stream,
+    )?;
+
+    let mut version = None;
+    fast::receive(&mut stream, |msg| {
+        if let Some(first) = msg.data.d.as_array().and_then(|d| d.first()) {
+            version = Some(first.clone());
+        }
+        Ok(())
+    })?;
+
+    version.ok_or_else(|| err_msg("empty version response"))
+}
+
+pub fn print(versions: &[ServerVersion]) {
+    for server in versions.iter() {
+        match (&server.version, &server.error) {
+            (Some(version), _) => println!("moray {} version {}", server.addr, version),
+            (None, Some(e)) => println!("moray {} version unknown: {}", server.addr, e),
+            (None, None) => (),
+        }
+    }
+}
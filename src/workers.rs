@@ -0,0 +1,544 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+use crate::breaker::CircuitBreaker;
+use crate::config::{Config, Profile};
+use crate::pool::{self, ClientSlot, Pool};
+use crate::retry::{self, ErrorClass, RetryPolicy};
+use crate::stats::{PhaseStats, WorkerState};
+use crate::throttle::Throttle;
+use crate::{logging, shutdown, InternalError};
+use failure::Error;
+use moray::client::MorayClient;
+use slog::{o, warn, Logger};
+use std::cell::Cell;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub type WorkFn<T> = fn(&Context, T) -> Result<(), Error>;
+
+/// Everything a worker needs besides its share of the work.
+pub struct Context {
+    // The worker's connections, which its requests take turns on.  Each is
+    // replaced with a new connection, for every worker sharing it, when a
+    // request on it times out.
+    clients: Vec<ClientSlot>,
+    next: Cell<usize>,
+    pub config: Config,
+    pub stats: Arc<PhaseStats>,
+    pub log: Logger,
+    // This worker's place among the phase's workers.
+    worker: usize,
+    retry_policy: RetryPolicy,
+    breaker: Arc<CircuitBreaker>,
+    pacer: Option<Arc<Pacer>>,
+    throttle: Option<Arc<Throttle>>,
+}
+
+impl Context {
+    // Wait for this worker's next turn to send a request and return the time
+    // its latency should be measured from.  Without --rate or --profile that is
+    // simply now.
+    pub fn pace(&self) -> Instant {
+        match &self.pacer {
+            Some(pacer) => pacer.wait(),
+            None => Instant::now(),
+        }
+    }
+
+    // The connection the next attempt should go out on.
+    fn next_client(&self) -> usize {
+        let i = self.next.get();
+        self.next.set((i + 1) % self.clients.len());
+        i
+    }
+
+    // Run `op` against this worker's next client, reconnecting if the connection
+    // drops and retrying transient failures according to the retry policy.
+    // Nothing is sent while the shard's circuit breaker is open, and an
+    // operation that failed while the breaker tripped is tried again once it
+    // closes.
+    pub fn call<T, F>(&self, mut op: F) -> Result<T, Error>
+    where
+        F: FnMut(&mut MorayClient) -> Result<T, Error>,
+    {
+        self.call_attempts(|| {
+            let client = Arc::clone(&*self.clients[self.next_client()].lock().unwrap());
+            pool::with_reconnect(&client, &self.config, &mut op)
+        })
+    }
+
+    // Like `call`, but each attempt is given up on if it hasn't finished
+    // within --op-timeout-ms, as a transient failure.  The attempt is left
+    // running on a thread of its own holding the connection, which is
+    // replaced with a new one in the pool, so `op` has to own what it sends.
+    // A worker sharing the connection that was stuck waiting for it times
+    // out in turn and picks up the new one.
+    pub fn call_with_timeout<T, F>(&self, op: F) -> Result<T, Error>
+    where
+        F: FnMut(&mut MorayClient) -> Result<T, Error> + Clone + Send + 'static,
+        T: Send + 'static,
+    {
+        if self.config.op_timeout_ms == 0 {
+            return self.call(op);
+        }
+        let timeout = Duration::from_millis(self.config.op_timeout_ms);
+
+        self.call_attempts(|| {
+            let slot = &self.clients[self.next_client()];
+            let client = Arc::clone(&*slot.lock().unwrap());
+            let config = self.config.clone();
+            let mut op = op.clone();
+            let (tx, rx) = mpsc::channel();
+            let attempt_client = Arc::clone(&client);
+            thread::spawn(move || {
+                let _ = tx.send(pool::with_reconnect(&attempt_client, &config, &mut op));
+            });
+
+            match rx.recv_timeout(timeout) {
+                Ok(result) => result,
+                Err(RecvTimeoutError::Timeout) => {
+                    warn!(self.log, "request timed out, abandoning its connection";
+                        "timeout_ms" => self.config.op_timeout_ms);
+                    self.stats.record_timeout();
+                    pool::replace(slot, &client, &self.config);
+                    Err(InternalError::Timeout(self.config.op_timeout_ms).into())
+                }
+                Err(RecvTimeoutError::Disconnected) => Err(InternalError::WorkerPanicked.into()),
+            }
+        })
+    }
+
+    fn call_attempts<T, F>(&self, mut attempt: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Result<T, Error>,
+    {
+        let mut attempt = || match &self.throttle {
+            Some(throttle) => {
+                throttle.wait();
+                let result = attempt();
+                throttle.record(&self.stats, &result);
+                result
+            }
+            None => attempt(),
+        };
+
+        self.stats
+            .set_worker_state(self.worker, WorkerState::Sending);
+        let result = loop {
+            self.breaker.wait();
+
+            let result = retry::retry(&self.retry_policy, &self.stats, &mut attempt);
+            // A conflict is moray doing its job, so it says nothing about
+            // the health of the shard.
+            self.breaker
+                .record(result.as_ref().map_or_else(retry::is_conflict, |_| true));
+
+            match result {
+                Err(ref e)
+                    if retry::classify(e) == ErrorClass::Transient
+                        && self.breaker.should_retry() => {}
+                // Say so if the retries ran out because the shard stayed
+                // overloaded, rather than leaving it to the error's text.
+                Err(e) if retry::is_overload(&e) => {
+                    break Err(InternalError::Overload(e.to_string()).into())
+                }
+                result => break result,
+            }
+        };
+
+        let error = result.as_ref().err().map(retry::error_name);
+        self.stats.record_worker_request(self.worker, error);
+        result
+    }
+}
+
+/// A token bucket limiting every worker in a phase to the offered load set by
+/// `--rate`, or by `--profile` as it changes over the phase.
+///
+/// With a burst of one, requests follow a fixed schedule in the style of
+/// wrk2: a request's latency is measured from when it was scheduled to start
+/// rather than when a worker got around to sending it, so a stall on the
+/// shard shows up in the percentiles instead of quietly lowering the offered
+/// load.  A larger burst lets workers that were held up by a slow request
+/// catch up with up to that many requests at once, and latency is then
+/// measured from when each request was actually sent.
+pub struct Pacer {
+    rate: f64,
+    profile: Profile,
+    burst: u32,
+    // When the first request was due and the theoretical arrival time of the
+    // next, or None before the first.
+    schedule: Mutex<Option<(Instant, Instant)>>,
+}
+
+// The slowest a profile sends requests, so that a ramp starting from zero
+// still gets going.
+const MIN_PROFILE_RATE: f64 = 1.0;
+
+impl Pacer {
+    // A pacer for a phase, or None if requests should go out as fast as the
+    // workers allow.
+    pub fn from_config(config: &Config) -> Option<Pacer> {
+        if config.rate <= 0.0 && config.profile == Profile::None {
+            return None;
+        }
+
+        Some(Pacer {
+            rate: config.rate,
+            profile: config.profile.clone(),
+            burst: config.burst.max(1),
+            schedule: Mutex::new(None),
+        })
+    }
+
+    // Time between requests `elapsed` into the phase.
+    fn interval(&self, elapsed: Duration) -> Duration {
+        let rate = match self.profile.rate_at(elapsed) {
+            Some(rate) => rate.max(MIN_PROFILE_RATE),
+            None => self.rate,
+        };
+
+        Duration::from_secs_f64(1.0 / rate)
+    }
+
+    // Sleep until a token is free and return the time the request's latency
+    // should be measured from.  On a fixed schedule that is when its slot
+    // began, which is already in the past if the workers have fallen behind.
+    pub fn wait(&self) -> Instant {
+        let due = {
+            let mut schedule = self.schedule.lock().unwrap();
+            let now = Instant::now();
+            let (start, tat) = schedule.unwrap_or((now, now));
+            let interval = self.interval(tat.saturating_duration_since(start));
+
+            if self.burst == 1 {
+                *schedule = Some((start, tat + interval));
+                tat
+            } else {
+                // Idle time earns at most a burst's worth of tokens.
+                let tat = tat.max(now);
+                *schedule = Some((start, tat + interval));
+                tat.checked_sub(interval * (self.burst - 1)).unwrap_or(now)
+            }
+        };
+
+        let now = Instant::now();
+        if due > now {
+            thread::sleep(due - now);
+        }
+
+        if self.burst == 1 {
+            due
+        } else {
+            Instant::now()
+        }
+    }
+}
+
+/// Work handed to the workers through a queue, with the time it was due to be
+/// sent if the run is open-loop.
+pub type Queued<T> = (Option<Instant>, T);
+
+/// The receiving end of a queue of work, shared by every worker taking from it.
+pub type Queue<T> = Arc<Mutex<Receiver<Queued<T>>>>;
+
+/// Feeds work to the workers through a queue holding at most
+/// `config.queue_depth` items.  Closed-loop, a full queue simply waits for
+/// the workers.  Open-loop, work is queued on the pacer's schedule whether or
+/// not the workers have kept up, and work that finds the queue full is shed
+/// and counted rather than waited for, so a slow shard can't slow the
+/// arrivals down.
+pub struct Dispatcher<T> {
+    tx: SyncSender<Queued<T>>,
+    pacer: Option<Pacer>,
+    stats: Arc<PhaseStats>,
+}
+
+// Create a queue for `stats`'s phase, returning the end to feed it through and
+// the end to share among the workers.
+pub fn queue<T>(config: &Config, stats: &Arc<PhaseStats>) -> (Dispatcher<T>, Queue<T>) {
+    let (tx, rx) = mpsc::sync_channel(config.queue_depth as usize);
+    let pacer = if config.open_loop {
+        Pacer::from_config(config)
+    } else {
+        None
+    };
+
+    let dispatcher = Dispatcher {
+        tx,
+        pacer,
+        stats: Arc::clone(stats),
+    };
+
+    (dispatcher, Arc::new(Mutex::new(rx)))
+}
+
+impl<T> Dispatcher<T> {
+    // Queue `item`, which writes `objects` objects.  Returns false once every
+    // worker has exited, in which case join will report why, or once the run
+    // has been asked to stop.
+    pub fn send(&self, objects: u64, item: T) -> bool {
+        if shutdown::requested() {
+            return false;
+        }
+
+        let pacer = match &self.pacer {
+            Some(pacer) => pacer,
+            None => return self.tx.send((None, item)).is_ok(),
+        };
+
+        let due = pacer.wait();
+        match self.tx.try_send((Some(due), item)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.stats.record_shed(objects);
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+// Take the next item from `queue`, and the time its latency should be
+// measured from, or None once the queue has been closed and emptied or the run
+// has been asked to stop.
+pub fn next<T>(ctx: &Context, queue: &Queue<T>) -> Option<(Instant, T)> {
+    let rx = queue.lock().unwrap();
+    let (due, item) = rx.recv().ok()?;
+
+    if shutdown::requested() {
+        // Throw away the rest until the queue is closed, so that a dispatcher
+        // waiting for room gets to see the stop.
+        while rx.recv().is_ok() {}
+        return None;
+    }
+    drop(rx);
+
+    let start = due.unwrap_or_else(|| ctx.pace());
+
+    Some((start, item))
+}
+
+// Go through a worker's share of the work until the run is asked to stop.
+pub fn until_stopped<I: IntoIterator>(items: I) -> impl Iterator<Item = I::Item> {
+    items.into_iter().take_while(|_| !shutdown::requested())
+}
+
+// Split `items` into `count` roughly equal shares, dealing them out round
+// robin.
+pub fn split<T>(items: impl IntoIterator<Item = T>, count: usize) -> Vec<Vec<T>> {
+    let mut shares: Vec<Vec<T>> = (0..count.max(1)).map(|_| vec![]).collect();
+    let len = shares.len();
+
+    for (i, item) in items.into_iter().enumerate() {
+        shares[i % len].push(item);
+    }
+
+    shares
+}
+
+/// Worker threads that have been spawned but are held at a barrier until
+/// `start` is called.
+pub struct Workers {
+    handles: Vec<thread::JoinHandle<Result<(), Error>>>,
+    barrier: Arc<Barrier>,
+}
+
+// Spawn one thread per share, each handed the next client from the pool, that
+// will run `work` once `start` is called.
+pub fn spawn<T>(
+    config: &Config,
+    pool: &Pool,
+    stats: &Arc<PhaseStats>,
+    shares: Vec<T>,
+    work: WorkFn<T>,
+) -> Workers
+where
+    T: Send + 'static,
+{
+    let barrier = Arc::new(Barrier::new(shares.len() + 1));
+    // Open-loop, the dispatcher does the pacing instead.
+    let pacer = if config.open_loop {
+        None
+    } else {
+        Pacer::from_config(config).map(Arc::new)
+    };
+    let throttle = Throttle::from_config(config).map(Arc::new);
+    stats.start_workers(shares.len());
+    // Handed out round-robin, so each worker gets connections of its own if
+    // the pool has enough of them and shares them otherwise.
+    let per_worker = config.connections_per_worker.max(1);
+
+    let handles = shares
+        .into_iter()
+        .enumerate()
+        .map(|(worker, share)| {
+            let barrier = Arc::clone(&barrier);
+            let ctx = Context {
+                clients: (0..per_worker).map(|_| pool.slot()).collect(),
+                next: Cell::new(0),
+                config: config.clone(),
+                stats: Arc::clone(stats),
+                log: logging::logger().new(o!(
+                    "shard" => config.shard,
+                    "phase" => stats.phase,
+                )),
+                worker,
+                retry_policy: RetryPolicy::from_config(config),
+                breaker: pool.breaker(),
+                pacer: pacer.clone(),
+                throttle: throttle.clone(),
+            };
+
+            thread::spawn(move || -> Result<(), Error> {
+                barrier.wait();
+                let result = work(&ctx, share);
+                let state = if result.is_ok() {
+                    WorkerState::Done
+                } else {
+                    WorkerState::Failed
+                };
+                ctx.stats.set_worker_state(ctx.worker, state);
+                result
+            })
+        })
+        .collect();
+
+    Workers { handles, barrier }
+}
+
+impl Workers {
+    // Let every worker begin at once.  Returns the time at which the work
+    // started.
+    pub fn start(&self) -> Instant {
+        self.barrier.wait();
+        Instant::now()
+    }
+
+    // Wait for every worker to finish, returning the first error if any.
+    pub fn join(self) -> Result<(), Error> {
+        let mut result = Ok(());
+
+        for handle in self.handles {
+            let worker_result = handle
+                .join()
+                .map_err(|_| Error::from(InternalError::WorkerPanicked))
+                .and_then(|r| r);
+
+            if result.is_ok() {
+                result = worker_result;
+            }
+        }
+
+        result
+    }
+}
+
+// Run `work` once per share on its own thread, returning the time taken for
+// every worker to finish.
+pub fn run<T>(
+    config: &Config,
+    pool: &Pool,
+    stats: &Arc<PhaseStats>,
+    shares: Vec<T>,
+    work: WorkFn<T>,
+) -> Result<Duration, Error>
+where
+    T: Send + 'static,
+{
+    let workers = spawn(config, pool, stats, shares, work);
+    let start = workers.start();
+
+    workers.join()?;
+
+    Ok(start.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockServer;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // Whether the next request should hang, which only the first one does.
+    static HANG: AtomicBool = AtomicBool::new(true);
+
+    fn hang_once(ctx: &Context, requests: u32) -> Result<(), Error> {
+        for _ in 0..requests {
+            ctx.call_with_timeout(|_: &mut MorayClient| {
+                if HANG.swap(false, Ordering::SeqCst) {
+                    thread::sleep(Duration::from_secs(3600));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    // Two workers share a single connection and a request on it never comes
+    // back.  Both should go on to finish their work on the connection that
+    // replaces it rather than waiting on the abandoned one.
+    #[test]
+    fn timed_out_request_does_not_hold_up_shared_connection() {
+        let mut config = Config::default();
+        let server = MockServer::start(&config).expect("mock server");
+        config.host = Some(server.addr());
+        config.workers = 2;
+        config.connections = 1;
+        config.op_timeout_ms = 200;
+
+        let pool = Pool::connect(&config).expect("pool");
+        let stats = Arc::new(PhaseStats::new(&config, "test"));
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(run(&config, &pool, &stats, vec![5, 5], hang_once));
+        });
+
+        let result = rx
+            .recv_timeout(Duration::from_secs(30))
+            .expect("workers stuck behind the abandoned request");
+        assert!(result.is_ok(), "workers failed: {:?}", result);
+    }
+
+    #[test]
+    fn pacer_interval() {
+        let mut config = Config::default();
+        assert!(Pacer::from_config(&config).is_none());
+
+        config.rate = 250.0;
+        let pacer = Pacer::from_config(&config).unwrap();
+        assert_eq!(
+            pacer.interval(Duration::from_secs(10)),
+            Duration::from_millis(4)
+        );
+
+        // A ramp from zero still sends at the slowest rate a profile allows.
+        config.rate = 0.0;
+        config.profile = "ramp:0-2000ops/60s".parse().unwrap();
+        let pacer = Pacer::from_config(&config).unwrap();
+        assert_eq!(
+            pacer.interval(Duration::from_secs(0)),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            pacer.interval(Duration::from_secs(30)),
+            Duration::from_millis(1)
+        );
+
+        config.profile = "step:100,500ops/30s".parse().unwrap();
+        let pacer = Pacer::from_config(&config).unwrap();
+        assert_eq!(
+            pacer.interval(Duration::from_secs(0)),
+            Duration::from_millis(10)
+        );
+        assert_eq!(
+            pacer.interval(Duration::from_secs(45)),
+            Duration::from_millis(2)
+        );
+    }
+}
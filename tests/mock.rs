@@ -0,0 +1,157 @@
+/*
+ * Copyright 2020 Joyent, Inc.
+ */
+
+//! Batch writes against the mock moray, checking what lands in the bucket.
+
+use batch_test::batch::PutBatches;
+use batch_test::config::Config;
+use batch_test::mock::MockServer;
+use batch_test::readback;
+use batch_test::retry::{self, RetryPolicy};
+use batch_test::stats::PhaseStats;
+use batch_test::{create_client, get_or_create_bucket, Target};
+use failure::Error;
+use moray::client::MorayClient;
+use moray::objects::{self, BatchPutOp, BatchRequest, Etag};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+const ALL: &str = "(_key=*)";
+
+// A mock with an empty test bucket, and a client connected to it.
+fn start(bucket: &str) -> (MockServer, MorayClient, Config) {
+    let mut config = Config::default();
+    config.bucket = bucket.to_string();
+    let server = MockServer::start(&config).expect("mock server");
+    config.host = Some(server.addr());
+
+    let mut client = create_client(&Target::from_config(&config)).expect("client");
+    get_or_create_bucket(&mut client, &config);
+
+    (server, client, config)
+}
+
+fn objects(count: usize, generation: u32) -> Vec<(String, Value)> {
+    (0..count)
+        .map(|i| {
+            let key = format!("key-{:03}", i);
+            let value = json!({ "key": key, "generation": generation });
+            (key, value)
+        })
+        .collect()
+}
+
+fn put(bucket: &str, key: &str, value: &Value, etag: Option<&String>) -> BatchRequest {
+    let mut options = objects::MethodOptions::default();
+    if let Some(etag) = etag {
+        options.etag = Etag::Specified(etag.clone());
+    }
+
+    BatchRequest::Put(BatchPutOp {
+        bucket: bucket.to_string(),
+        options,
+        key: key.to_string(),
+        value: value.clone(),
+    })
+}
+
+fn read_etags(client: &mut MorayClient, bucket: &str) -> HashMap<String, String> {
+    let mut etags = HashMap::new();
+    let opts = objects::MethodOptions::default();
+    client
+        .find_objects(bucket, ALL, &opts, |mobj| {
+            etags.insert(mobj.key.clone(), mobj._etag.clone());
+            Ok(())
+        })
+        .expect("findobjects");
+
+    etags
+}
+
+#[test]
+fn last_partial_batch_is_written() {
+    let (_server, mut client, config) = start("partial_batch");
+    let opts = objects::MethodOptions::default();
+
+    let batches: Vec<Vec<BatchRequest>> =
+        PutBatches::new(&config.bucket, objects(25, 1).into_iter(), 10).collect();
+    let sizes: Vec<usize> = batches.iter().map(Vec::len).collect();
+    assert_eq!(sizes, vec![10, 10, 5]);
+
+    for batch in batches.iter() {
+        client.batch(batch, &opts, |_| Ok(())).expect("batch");
+    }
+
+    let values = readback::fetch_values(&mut client, &config.bucket, ALL).expect("read back");
+    assert_eq!(values.len(), 25);
+    for (key, value) in objects(25, 1) {
+        assert_eq!(values.get(&key), Some(&value), "{}", key);
+    }
+}
+
+#[test]
+fn batch_is_retried_after_injected_failure() {
+    let (server, mut client, config) = start("retried_batch");
+    let stats = PhaseStats::new(&config, "batch");
+    let policy = RetryPolicy::from_config(&config);
+    let opts = objects::MethodOptions::default();
+    let batch = PutBatches::new(&config.bucket, objects(5, 1).into_iter(), 5)
+        .next()
+        .expect("a batch");
+
+    server.fail_next("batch", 1);
+    let result = retry::retry(&policy, &stats, || {
+        client.batch(&batch, &opts, |_| Ok(()))?;
+        Ok(())
+    });
+
+    assert!(result.is_ok(), "batch failed: {:?}", result);
+    assert_eq!(stats.retries(), 1);
+    let values = readback::fetch_values(&mut client, &config.bucket, ALL).expect("read back");
+    assert_eq!(values.len(), 5);
+}
+
+#[test]
+fn batch_with_stale_etag_writes_nothing() {
+    let (_server, mut client, config) = start("etag_batch");
+    let opts = objects::MethodOptions::default();
+
+    let first: Vec<BatchRequest> = objects(3, 1)
+        .iter()
+        .map(|(key, value)| put(&config.bucket, key, value, None))
+        .collect();
+    client.batch(&first, &opts, |_| Ok(())).expect("batch");
+    let mut etags = read_etags(&mut client, &config.bucket);
+
+    // Every put but the last is conditional on the etag the object has, and
+    // the last on one it no longer has.
+    etags.insert("key-002".to_string(), "STALE".to_string());
+    let conditional: Vec<BatchRequest> = objects(3, 2)
+        .iter()
+        .map(|(key, value)| put(&config.bucket, key, value, etags.get(key)))
+        .collect();
+    let result = client.batch(&conditional, &opts, |_| Ok(()));
+    let e = Error::from(result.expect_err("batch with a stale etag was applied"));
+    assert!(retry::is_conflict(&e), "unexpected error: {}", e);
+
+    let values = readback::fetch_values(&mut client, &config.bucket, ALL).expect("read back");
+    for (key, value) in objects(3, 1) {
+        assert_eq!(values.get(&key), Some(&value), "{} was written", key);
+    }
+
+    // With every etag current, the whole batch goes in.
+    let etags = read_etags(&mut client, &config.bucket);
+    let conditional: Vec<BatchRequest> = objects(3, 2)
+        .iter()
+        .map(|(key, value)| put(&config.bucket, key, value, etags.get(key)))
+        .collect();
+    client
+        .batch(&conditional, &opts, |_| Ok(()))
+        .expect("batch");
+
+    let values = readback::fetch_values(&mut client, &config.bucket, ALL).expect("read back");
+    for (key, value) in objects(3, 2) {
+        assert_eq!(values.get(&key), Some(&value), "{} wasn't written", key);
+    }
+}